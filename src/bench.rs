@@ -0,0 +1,84 @@
+use serde::Serialize;
+
+/// How many times its median a run must take to count as a spike rather than steady-state noise.
+pub const SPIKE_FACTOR: f64 = 3.0;
+
+/// Latency of one pipeline stage over repeated runs, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct StageStats {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub mean_ms: f64,
+    pub max_ms: f64,
+    /// Runs slower than [`SPIKE_FACTOR`] times the p50
+    pub spikes: usize,
+    /// Fraction of the variance those spikes account for; near 1 means a few outliers, such as a model
+    /// being loaded again, rather than steady-state jitter
+    pub spike_variance_share: f64,
+}
+
+impl StageStats {
+    /// Statistics of a non-empty set of samples, or zeros for none.
+    pub fn new(samples_ms: &[f64]) -> StageStats {
+        if samples_ms.is_empty() {
+            return StageStats::default();
+        }
+        let mut sorted = samples_ms.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let p50_ms = percentile(&sorted, 50.0);
+        let mean_ms = sorted.iter().sum::<f64>() / sorted.len() as f64;
+        let deviation = |sample: f64| (sample - mean_ms).powi(2);
+        let is_spike = |sample: f64| sample > SPIKE_FACTOR * p50_ms;
+        let variance: f64 = sorted.iter().map(|&sample| deviation(sample)).sum();
+        let spike_variance: f64 = sorted.iter().filter(|&&sample| is_spike(sample)).map(|&sample| deviation(sample)).sum();
+        StageStats {
+            p50_ms,
+            p95_ms: percentile(&sorted, 95.0),
+            mean_ms,
+            max_ms: sorted[sorted.len() - 1],
+            spikes: sorted.iter().filter(|&&sample| is_spike(sample)).count(),
+            spike_variance_share: if variance > 0.0 { spike_variance / variance } else { 0.0 },
+        }
+    }
+}
+
+/// The nearest-rank `p`th percentile of ascending `sorted` samples, or 0 for none.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted: Vec<f64> = (1..=20).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 50.0), 10.0);
+        assert_eq!(percentile(&sorted, 95.0), 19.0);
+        assert_eq!(percentile(&sorted, 100.0), 20.0);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&[7.0], 95.0), 7.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn a_reload_spike_dominates_the_variance_and_jitter_does_not() {
+        let mut samples = vec![100.0, 105.0, 95.0, 110.0, 90.0, 100.0, 102.0, 98.0];
+        let steady = StageStats::new(&samples);
+        assert_eq!((steady.spikes, steady.spike_variance_share), (0, 0.0));
+        assert_eq!(steady.max_ms, 110.0);
+
+        // One run that had to load the model again
+        samples.push(4000.0);
+        let stats = StageStats::new(&samples);
+        assert_eq!(stats.spikes, 1);
+        assert!(stats.spike_variance_share > 0.8, "{}", stats.spike_variance_share);
+        assert_eq!(stats.p50_ms, 100.0);
+        assert_eq!(StageStats::new(&[]), StageStats::default());
+    }
+}
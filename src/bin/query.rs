@@ -1,101 +1,3977 @@
-use clap::Parser;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Parser, ValueEnum};
+use futures::StreamExt;
 use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::parameters::{FormatType, JsonStructure, LogprobsData};
+use ollama_rs::models::ModelOptions;
 use ollama_rs::Ollama;
+use rag_system::bench::{StageStats, SPIKE_FACTOR};
+use rag_system::cache::RerankCache;
+use rag_system::embedder::{Embedder, EmbedderArgs};
+use rag_system::eval::{self, Configuration, EvalQuery, EvalReport, QueryScores, Scores};
+use rag_system::health;
+use rag_system::logging::{self, LogOutput};
+use rag_system::rerank::{self, Generator, ScoreAdjustment, DEFAULT_RERANK_MODEL, DEFAULT_SCORE_TOLERANCE};
+use rag_system::retry::{with_retry, RetryArgs, RetryPolicy};
+use rag_system::schema;
+use rag_system::metric::Metric;
+use rag_system::mmr;
+use rag_system::pack::{self, Placement};
+use rag_system::store::{self, content_hash, MetadataFilter, RetrievalFilters, VectorEncoding, DEFAULT_VECTOR_PRECISION, FILTER_PREDICATES};
+use rag_system::vector_store::{self, Hit, MergedStore, PgVectorStore, VectorStore};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use syntect::easy::HighlightLines;
+use tracing::{error, info, warn, Level};
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use unicode_segmentation::UnicodeSegmentation;
+
+// First number in a free-text reply, for models that ignore the JSON format ("Score: 0.8", fenced output, ...)
+static FIRST_NUMBER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"-?\d+(?:\.\d+)?").unwrap());
+
+// Default rerank prompt; `{query}` and `{document}` are substituted per document
+const DEFAULT_RERANK_PROMPT: &str = "Given the query: '{query}' and the document: '{document}'. Output only a single floating-point number between 0.0 and 1.0 representing the relevance score. No other text, explanation, or formatting.";
+
+// Default generation model and prompt for --answer; `{query}` and `{context}` are substituted
+const DEFAULT_ANSWER_MODEL: &str = "qwen3:4b";
+// Turns a chat follow-up into a query that can be embedded without the conversation
+const DEFAULT_CONDENSE_PROMPT: &str = "Rewrite the follow-up message below as a standalone code search query that can be understood without the conversation. Replace references like \"it\" or \"that function\" with what they refer to. Reply with only the query.\n\nConversation:\n{history}\nFollow-up message: {query}\n\nStandalone query:";
+
+// Asks for alternative phrasings for --expand; `{query}` and `{count}` are substituted
+const DEFAULT_EXPANSION_PROMPT: &str = "Write {count} alternative search queries for finding code and documentation relevant to the query below. Mix paraphrases that use different terminology with narrower sub-questions. Reply with JSON: {\"queries\": [...]}.\n\nQuery: {query}";
+
+// Asks for a passage answering the query for --hyde to embed in its place; `{query}` is substituted
+const DEFAULT_HYDE_PROMPT: &str = "Write a short passage of code or documentation that answers the question below, as it might appear in the codebase. Reply with only the passage.\n\nQuestion: {query}";
+
+const DEFAULT_ANSWER_PROMPT: &str = "Answer the question using only the numbered sources below. Cite every source you rely on inline as [n], e.g. [1] or [2][3]. If the sources don't contain the answer, say so.\n\nSources:\n{context}\nQuestion: {query}\n\nAnswer:";
+
+// Source markers like [1] in a generated answer
+static CITATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[(\d+)\]").unwrap());
+
+// Qwen3-Reranker's documented yes/no judgement format, sent raw so no chat template is applied on top
+const DEFAULT_YES_NO_PROMPT: &str = "<|im_start|>system\nJudge whether the Document meets the requirements based on the Query and the Instruct provided. Note that the answer can only be \"yes\" or \"no\".<|im_end|>\n<|im_start|>user\n<Instruct>: Given a code search query, retrieve relevant code snippets and documentation that answer the query\n<Query>: {query}\n<Document>: {document}<|im_end|>\n<|im_start|>assistant\n<think>\n\n</think>\n\n";
+
+/// How the reranker model is asked to judge relevance.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RerankMode {
+    /// Ask for a relevance score between 0 and 1
+    Score,
+    /// Ask for a strict "yes"/"no" relevance judgement, the format Qwen3-Reranker is trained on
+    #[value(name = "yes_no")]
+    YesNo,
+}
+
+impl std::fmt::Display for RerankMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RerankMode::Score => "score",
+            RerankMode::YesNo => "yes_no",
+        })
+    }
+}
+
+/// How results are written to stdout.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Numbered results with truncated snippets
+    Human,
+    /// A single JSON object with the results (full text), a summary and, with --explain, timings
+    Json,
+    /// One JSON result object per line
+    Jsonl,
+    /// `path:line:col: snippet` lines, as printed by ripgrep and read by editor quickfix lists
+    Grep,
+    /// A Markdown report with the query and a section per result, for pasting into docs and PRs
+    Markdown,
+    /// The results packed into --context-tokens as numbered `[n] path` blocks, for pasting into another
+    /// model's prompt; which documents made it in goes to stderr
+    Context,
+    /// Result ids one per line, with no progress at all; selected with --quiet
+    #[value(skip)]
+    Ids,
+}
+
+/// How chunk embeddings of a --query-file are combined.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Pooling {
+    /// Average each component across chunks
+    Mean,
+    /// Take each component's maximum across chunks
+    Max,
+}
+
+/// How documents are found.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SearchMode {
+    /// Nearest embeddings to the query's embedding
+    Vector,
+    /// Postgres full-text search over the stored text, without the embedding service
+    Keyword,
+    /// Vector search, falling back to keyword search when the query can't be embedded
+    Auto,
+}
+
+/// Which score orders the final results.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum SortBy {
+    /// The rerank score (blended with similarity by --rerank-weight)
+    Rerank,
+    /// The vector similarity, keeping the rerank scores for comparison
+    Vector,
+}
+
+// Reported instead of "no results" when there is nothing to search at all
+const EMPTY_INDEX_MESSAGE: &str = "The embeddings table is empty; run the ingester (rag-system) first to index your code";
+
+// Exit status when --min-score/--min-similarity leave no results, so scripts can tell it apart from errors
+const NO_RELEVANT_DOCUMENTS_EXIT_CODE: i32 = 3;
+
+// With --mmr, the nearest --limit * this many candidates are fetched to pick diverse candidates from
+const MMR_CANDIDATE_FACTOR: i64 = 4;
+
+// When --per-file leaves the page short, retrieval is repeated with a doubled --limit at most this many times
+const MAX_REFETCHES: usize = 3;
+
+// Characters per chunk when embedding a --query-file, small enough for typical embedding context windows
+const QUERY_FILE_CHUNK_CHARS: usize = 8000;
 
 /// A simple CLI to query and rerank documents from a pgvector database.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The query to search for
-    #[arg(short, long)]
-    query: String,
+    /// The query to search for, or `-` to read it from stdin
+    #[arg(short, long, required_unless_present_any = ["query_text_file", "query_file", "chat", "queries_file", "like_id", "eval", "eval_diff", "bench", "count"], conflicts_with = "query_file")]
+    query: Option<String>,
+
+    /// Read the query text from this file (`-` for stdin), for long or multi-line queries such as a pasted
+    /// error with its stack trace. Unlike --query-file, the contents are the question, not a document to match
+    #[arg(long, conflicts_with_all = ["query", "query_file", "like_id", "chat", "queries_file"])]
+    query_text_file: Option<PathBuf>,
+
+    /// Find documents similar to this stored document (its id, e.g. ../src/retry.rs), searching with its
+    /// stored vector so nothing is embedded. Results are in vector order and leave the document itself out
+    #[arg(long, conflicts_with_all = ["query", "query_file", "chat", "queries_file", "answer", "hyde", "hybrid", "expand"])]
+    like_id: Option<String>,
+
+    /// Run every query in this file (one per line, or JSON objects with per-query filters) and write one
+    /// JSON line of results per query
+    #[arg(long, conflicts_with_all = ["query", "query_file", "chat", "answer", "expand_context"])]
+    queries_file: Option<PathBuf>,
+
+    /// Score retrieval against this labeled evaluation set, a YAML or JSON list of {query, relevant}
+    /// objects whose relevant entries are paths or path globs. Reports recall@k, MRR and nDCG@k (k is
+    /// --top-n) with and without reranking; --format json writes the report for a later --eval-diff
+    #[arg(long, conflicts_with_all = ["query", "query_text_file", "query_file", "like_id", "chat", "queries_file", "answer", "save_session", "open", "page", "explain"])]
+    eval: Option<PathBuf>,
+
+    /// Compare two --eval reports written with --format json, per configuration and per query whose
+    /// scores changed. Needs no database or models
+    #[arg(long, num_args = 2, value_names = ["BEFORE", "AFTER"], conflicts_with_all = ["query", "query_text_file", "query_file", "like_id", "chat", "queries_file", "eval"])]
+    eval_diff: Vec<PathBuf>,
+
+    /// Measure the latency of each stage (query embedding, SQL retrieval, rerank and total) on the queries
+    /// in this file, in the --queries-file format, over --warmup and then --iterations passes run one query
+    /// at a time. Prints p50/p95 per stage and whether model loading dominates the variance
+    #[arg(long, conflicts_with_all = ["query", "query_text_file", "query_file", "like_id", "chat", "queries_file", "answer", "save_session", "open", "page", "explain", "eval", "eval_diff"])]
+    bench: Option<PathBuf>,
+
+    /// Print how many documents pass the filters (--filter, --language, --source, ...) and exit, without
+    /// embedding or reranking anything. Exits with 1 when none do
+    #[arg(long, conflicts_with_all = ["query", "query_text_file", "query_file", "like_id", "chat", "queries_file", "answer", "save_session", "open", "page", "explain", "eval", "eval_diff", "bench", "max_distance"])]
+    count: bool,
+
+    /// Timed passes over the --bench queries
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..), requires = "bench")]
+    iterations: u32,
+
+    /// Passes over the --bench queries before the timed ones, so models are loaded first; the first run
+    /// is still reported to show the cold start
+    #[arg(long, default_value_t = 2, requires = "bench")]
+    warmup: u32,
+
+    /// Number of --queries-file or --eval queries run concurrently
+    #[arg(long, default_value_t = 4)]
+    batch_concurrency: usize,
+
+    /// Stop a --queries-file batch at the first failed query instead of recording the error and continuing
+    #[arg(long, requires = "queries_file")]
+    fail_fast: bool,
+
+    /// Find documents similar to this file instead of a text query; it is embedded like an indexed document
+    #[arg(long)]
+    query_file: Option<PathBuf>,
+
+    /// How the chunk vectors of a large --query-file are combined into one query vector
+    #[arg(long, value_enum, default_value_t = Pooling::Mean)]
+    pooling: Pooling,
+
+    /// Search by vector, by keyword (full-text, no embedding service needed), or by vector with a keyword
+    /// fallback when the embedding service is unavailable (--query only)
+    #[arg(long, value_enum, default_value_t = SearchMode::Auto)]
+    mode: SearchMode,
 
     /// The number of initial documents to retrieve
     #[arg(short, long, default_value_t = 25)]
     limit: i32,
 
+    /// Tables to search, comma-separated (e.g. --table project_a,project_b). Hits from every table are
+    /// merged, reranked together and tagged with their table
+    #[arg(long, value_delimiter = ',', default_value = schema::DEFAULT_TABLE)]
+    table: Vec<String>,
+
+    /// Only retrieve documents in this language (repeatable, e.g. --language rust --language toml)
+    #[arg(long = "language")]
+    languages: Vec<String>,
+
+    /// Only retrieve documents whose path starts with this prefix (e.g. ../src/services/)
+    #[arg(long)]
+    path_prefix: Option<String>,
+
+    /// Leave out documents whose path matches this glob (repeatable, e.g. --exclude-path 'tests/**'
+    /// --exclude-path '*.snap'); the glob can match from any directory
+    #[arg(long = "exclude-path", value_name = "GLOB")]
+    exclude_paths: Vec<String>,
+
+    /// Distance used for retrieval; defaults to the metric the index was ingested for, or cosine
+    #[arg(long, value_enum)]
+    metric: Option<Metric>,
+
+    /// Leave out files the ingester flagged as generated code (ingested with --include-generated)
+    #[arg(long)]
+    exclude_generated: bool,
+
+    /// Only keep documents whose metadata field KEY equals VALUE (or is an array containing it), differs
+    /// from it with KEY!=VALUE, or just exists with a bare KEY, such as the fields of a sidecar file
+    /// (repeatable; every filter must match, e.g. --filter tags=auth --filter owner!=ana)
+    #[arg(long = "filter", value_name = "KEY=VALUE")]
+    metadata_filters: Vec<MetadataFilter>,
+
+    /// Only search documents ingested with this --source tag (e.g. codebase, docs)
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Only search documents embedded after this time: RFC 3339 (2026-10-01T12:00:00Z) or a date (2026-10-01, midnight UTC)
+    #[arg(long, value_parser = parse_timestamp)]
+    after: Option<DateTime<Utc>>,
+
+    /// With --after, also keep documents ingested before embedding times were recorded
+    #[arg(long, requires = "after")]
+    include_undated: bool,
+
+    /// Drop candidates whose distance from the query exceeds this, in --metric units (cosine: 0 = identical, 2 = opposite)
+    #[arg(long)]
+    max_distance: Option<f64>,
+
+    /// Skip this many results of the final ranking, to show the page after them; --limit is raised to
+    /// fetch enough candidates for the page
+    #[arg(long, default_value_t = 0)]
+    offset: u32,
+
+    /// After each page of --top-n results, ask on the terminal whether to show the next one
+    #[arg(long, conflicts_with_all = ["format", "answer", "chat", "queries_file", "quiet"])]
+    page: bool,
+
+    /// Open result number N in $EDITOR at its first line; without N, ask on the terminal which one to open
+    #[arg(long, value_name = "N", num_args = 0..=1, conflicts_with_all = ["format", "output", "answer", "chat", "queries_file", "quiet"])]
+    open: Option<Option<usize>>,
+
+    /// After the query, save the ids of the final ranking, with the database, tables and filters searched,
+    /// to this session file for a later --refine
+    #[arg(long, conflicts_with_all = ["chat", "queries_file"])]
+    save_session: Option<PathBuf>,
+
+    /// Search only the documents saved in this session file by an earlier --save-session, e.g. to narrow
+    /// "auth middleware" down with "token expiry"; the file may be the --save-session file of this query
+    #[arg(long)]
+    refine: Option<PathBuf>,
+
+    /// Search only the documents with the ids in this file, one per line
+    #[arg(long, conflicts_with = "refine")]
+    within_ids_file: Option<PathBuf>,
+
+    /// Ids --refine or --within-ids-file restrict the search to, once loaded
+    #[arg(skip)]
+    within_ids: Option<Vec<String>>,
+
+    /// Local checkout that stored paths are resolved against for --open, instead of the directory the
+    /// ingester ran in (leading ./ and ../ segments of stored paths are dropped)
+    #[arg(long)]
+    root: Option<PathBuf>,
+
     /// The number of final documents to return after reranking
     #[arg(short, long, default_value_t = 5)]
     top_n: usize,
+
+    /// Skip the reranker and return the top documents by vector similarity
+    #[arg(long)]
+    no_rerank: bool,
+
+    /// Ollama model used to score retrieved documents
+    #[arg(long, default_value = DEFAULT_RERANK_MODEL)]
+    rerank_model: String,
+
+    /// Weight of the rerank score in the final score; the rest goes to the vector similarity
+    /// (1.0 ranks by the rerank score alone)
+    #[arg(long, default_value_t = 1.0, value_parser = parse_weight)]
+    rerank_weight: f32,
+
+    /// Rescale the final scores into [0, 1] and multiply each by an exponential decay on the document's age
+    /// (from its `embedded_at` metadata) with this half-life in days, so recently ingested versions outrank
+    /// stale ones. Documents without a timestamp keep their rescaled score
+    #[arg(long, value_name = "HALF_LIFE_DAYS", value_parser = parse_half_life, conflicts_with = "sort_by")]
+    recency_boost: Option<f64>,
+
+    /// Order the reranked results by the rerank score or by vector similarity, to compare the two rankings
+    /// of the same candidates
+    #[arg(long, value_enum, default_value_t = SortBy::Rerank, conflicts_with = "no_rerank")]
+    sort_by: SortBy,
+
+    /// How the reranker judges relevance: a 0-1 score, or a yes/no answer mapped to 1.0/0.0
+    #[arg(long, value_enum, default_value_t = RerankMode::Score)]
+    rerank_mode: RerankMode,
+
+    /// File containing a rerank prompt template with {query} and {document} placeholders
+    /// (sent raw in yes_no mode, so it must include the model's chat formatting)
+    #[arg(long)]
+    rerank_prompt_file: Option<PathBuf>,
+
+    /// Maximum characters of each document included in the rerank prompt (0 for no limit).
+    /// Longer documents keep their head and tail and lose the middle; results still show the full text.
+    #[arg(long, default_value_t = 16000)]
+    rerank_max_chars: usize,
+
+    /// How far outside [0, 1] a rerank score may fall and still be clamped into range. Further out, scores
+    /// in (10, 100] are read as percentages and any others are discarded in favour of the vector similarity
+    #[arg(long, default_value_t = DEFAULT_SCORE_TOLERANCE)]
+    rerank_score_tolerance: f32,
+
+    /// Print each document's full rerank prompt, the model's raw reply and what was parsed from it to stderr
+    #[arg(long)]
+    debug_rerank: bool,
+
+    /// Set hnsw.ef_search for this query (HNSW indexes only; higher = better recall, slower)
+    #[arg(long)]
+    ef_search: Option<u32>,
+
+    /// Set ivfflat.probes for this query (IVFFlat indexes only; higher = better recall, slower)
+    #[arg(long)]
+    probes: Option<u32>,
+
+    /// Drop reranked results scoring below this (0-1); if none remain, exit with status 3
+    #[arg(long, conflicts_with = "no_rerank")]
+    min_score: Option<f32>,
+
+    /// Drop results whose similarity to the query is below this (cosine: -1 to 1, see --metric); if none remain, exit with status 3
+    #[arg(long)]
+    min_similarity: Option<f32>,
+
+    /// Keep at most this many results from any one file after reranking, backfilling from lower-ranked files
+    #[arg(long)]
+    per_file: Option<usize>,
+
+    /// Also search with this many model-written paraphrases or sub-questions of the query (see --answer-model)
+    /// and fuse the candidate lists with Reciprocal Rank Fusion before reranking
+    #[arg(long, default_value_t = 0, conflicts_with = "query_file")]
+    expand: usize,
+
+    /// Search with the embedding of a model-written hypothetical answer to the query (HyDE) instead of the
+    /// query's own embedding; full-text search in --hybrid mode still uses the query
+    #[arg(long, conflicts_with = "query_file")]
+    hyde: bool,
+
+    /// Ollama model that writes the --hyde hypothetical answer (defaults to --answer-model)
+    #[arg(long, requires = "hyde")]
+    hyde_model: Option<String>,
+
+    /// File containing a --hyde prompt template with a {query} placeholder
+    #[arg(long, requires = "hyde")]
+    hyde_prompt_file: Option<PathBuf>,
+
+    /// Weight of the hypothetical answer's embedding when averaged with the query's: 1.0 searches with the
+    /// hypothetical alone, 0.0 with the query alone
+    #[arg(long, default_value_t = 1.0, value_parser = parse_weight, requires = "hyde")]
+    hyde_mix: f32,
+
+    /// Diversify the retrieved candidates with Maximal Marginal Relevance: 1.0 ranks purely by similarity to the
+    /// query, lower values increasingly penalise similarity to documents already picked
+    #[arg(long, value_parser = parse_weight, conflicts_with = "hybrid")]
+    mmr: Option<f32>,
+
+    /// Combine full-text and vector retrieval with Reciprocal Rank Fusion before reranking
+    #[arg(long)]
+    hybrid: bool,
+
+    /// The k constant of Reciprocal Rank Fusion in --hybrid mode (higher flattens the rank weighting)
+    #[arg(long, default_value_t = 60)]
+    rrf_k: u32,
+
+    /// Number of rerank requests to run concurrently
+    #[arg(long, default_value_t = 3)]
+    rerank_concurrency: usize,
+
+    /// Always ask the reranker instead of reusing scores cached by earlier runs
+    #[arg(long)]
+    no_rerank_cache: bool,
+
+    /// Hours a cached rerank score stays valid
+    #[arg(long, value_name = "HOURS", default_value_t = 168)]
+    rerank_cache_ttl: u64,
+
+    /// Significant digits sent per query vector component with text vectors (0 for full f32 precision)
+    #[arg(long, default_value_t = DEFAULT_VECTOR_PRECISION)]
+    vector_precision: usize,
+
+    /// Send query vectors as text literals instead of pgvector's binary format, for servers or poolers
+    /// that reject binary vector parameters (detected automatically otherwise)
+    #[arg(long)]
+    text_vectors: bool,
+
+    /// Generate an answer to the query from the top-n documents instead of listing them
+    #[arg(long)]
+    answer: bool,
+
+    /// Ollama model used to generate answers with --answer, and to rewrite queries for --expand and --chat
+    #[arg(long, default_value = DEFAULT_ANSWER_MODEL)]
+    answer_model: String,
+
+    /// File containing an answer prompt template with {query} and {context} placeholders
+    #[arg(long)]
+    answer_prompt_file: Option<PathBuf>,
+
+    /// Print the whole answer once it's complete instead of streaming tokens as they're generated
+    #[arg(long)]
+    no_stream: bool,
+
+    /// Start an interactive chat that answers each message from retrieved documents and remembers the conversation
+    #[arg(long, conflicts_with_all = ["query", "query_file", "answer"])]
+    chat: bool,
+
+    /// Embed chat messages as typed instead of first rewriting follow-ups into standalone queries
+    #[arg(long, requires = "chat")]
+    no_condense: bool,
+
+    /// Approximate token budget for the chat history kept for follow-ups; the oldest turns are dropped first
+    #[arg(long, default_value_t = 2000)]
+    history_tokens: usize,
+
+    /// Token budget for the documents given to the answer model or printed by --format context, counted
+    /// with the cl100k_base tokenizer. Documents that don't fit whole are left out, lowest-ranked first
+    #[arg(long, default_value_t = 8000)]
+    context_tokens: usize,
+
+    /// Cut the first document that doesn't fit --context-tokens at a line boundary to fill the rest of
+    /// the budget, instead of leaving it out and trying the lower-ranked ones
+    #[arg(long)]
+    truncate_context: bool,
+
+    /// Show up to this many neighbouring chunks before and after each chunked result (rows with
+    /// `path` and `chunk_index` metadata)
+    #[arg(long, default_value_t = 0)]
+    expand_context: usize,
+
+    /// Total characters of neighbouring chunks --expand-context may add across all results
+    #[arg(long, default_value_t = 8000)]
+    expand_max_chars: usize,
+
+    /// Output format; the JSON formats include full text and metadata. Progress always goes to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+
+    /// Print only the ids of the results, one per line, and no progress; warnings and errors still go to stderr
+    #[arg(long, conflicts_with_all = ["format", "answer", "chat", "queries_file", "explain"])]
+    quiet: bool,
+
+    /// Log progress and warnings as one JSON object per line on stderr (with fields such as phase, path,
+    /// duration_ms and error) instead of plain text; results stay on stdout
+    #[arg(long)]
+    json_logs: bool,
+
+    /// Write the results to this file instead of stdout (any --format but human)
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Base URL that paths in --format markdown link to (e.g. https://github.com/org/repo/blob/main)
+    #[arg(long)]
+    repo_url: Option<String>,
+
+    /// Maximum characters of each result's text to display
+    #[arg(long, visible_alias = "max-chars", default_value_t = 500)]
+    snippet_len: usize,
+
+    /// Highlight query terms in the displayed snippets and centre each snippet on the first match
+    #[arg(long)]
+    highlight: bool,
+
+    /// Show at most this many lines of each result's text (--snippet-len still applies)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_lines: Option<u32>,
+
+    /// Print plain text without colors or syntax highlighting, even on a terminal
+    #[arg(long)]
+    no_color: bool,
+
+    /// Print intermediate steps for debugging, such as the hypothetical answer written by --hyde
+    #[arg(long)]
+    verbose: bool,
+
+    /// After the results, break down where the time went: query embedding, each SQL query with its
+    /// parameters, each document's rerank and the total. JSON output gets a `timings` key instead
+    #[arg(long, conflicts_with_all = ["chat", "queries_file"])]
+    explain: bool,
+
+    /// With --explain, also run the vector search under EXPLAIN ANALYZE to show its plan and whether
+    /// the vector index was used. This runs the search a second time
+    #[arg(long, requires = "explain")]
+    explain_analyze: bool,
+
+    #[command(flatten)]
+    embedder: EmbedderArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+}
+
+// Weights like the MMR lambda and the rerank weight would invert their trade-off outside [0, 1]
+fn parse_weight(value: &str) -> Result<f32, String> {
+    let weight: f32 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&weight) {
+        return Err(format!("{} is not between 0 and 1", weight));
+    }
+    Ok(weight)
+}
+
+fn parse_half_life(value: &str) -> Result<f64, String> {
+    let days: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(days.is_finite() && days > 0.0) {
+        return Err(format!("{} is not a positive number of days", days));
+    }
+    Ok(days)
+}
+
+// The values of the retrieval filters; --count applies the same ones without a query
+fn retrieval_filters(args: &Args) -> RetrievalFilters {
+    RetrievalFilters {
+        languages: (!args.languages.is_empty()).then(|| args.languages.iter().map(|l| l.to_lowercase()).collect()),
+        path_pattern: args.path_prefix.as_deref().map(|prefix| format!("{}%", escape_like(prefix))),
+        max_distance: args.max_distance,
+        exclude_generated: args.exclude_generated,
+        source: args.source.clone(),
+        after: args.after,
+        include_undated: args.include_undated,
+        exclude_id: args.like_id.clone(),
+        // Excluded in SQL, so the --limit candidates are all ones that can be shown
+        exclude_paths: (!args.exclude_paths.is_empty()).then(|| args.exclude_paths.iter().map(|glob| store::glob_regex(glob)).collect()),
+        within_ids: args.within_ids.clone(),
+        metadata: (!args.metadata_filters.is_empty()).then(|| args.metadata_filters.clone()),
+    }
+}
+
+// --after takes a full RFC 3339 timestamp or just a date, read as midnight UTC
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("{:?} is neither an RFC 3339 timestamp nor a YYYY-MM-DD date", value))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+    let output = if args.json_logs { LogOutput::Json } else { LogOutput::Stderr };
+    logging::init(output, if args.quiet { Level::WARN } else { Level::INFO });
+    let interrupt = Interrupt::install();
+    let result = run(args, &interrupt).await.map_err(schema::explain_missing_table);
+    // Like a shell, exit with 128 + SIGINT when Ctrl-C stopped the answer
+    if let Err(e) = &result {
+        if e.is::<Cancelled>() {
+            info!("{}.", e);
+            std::process::exit(130);
+        }
+    }
+    // In JSON mode a failure is one more event in the stream rather than a bare line on stderr
+    if let (Err(e), LogOutput::Json) = (&result, output) {
+        error!(phase = "exit", error = %e, "{}", e);
+        std::process::exit(1);
+    }
+    result
+}
+
+async fn run(mut args: Args, interrupt: &Interrupt) -> Result<(), Box<dyn Error>> {
+    // From here on a query read from a file or stdin is just a --query
+    if let Some(path) = args.query_text_file.take() {
+        args.query = Some(read_query_text(&path)?);
+    } else if args.query.as_deref() == Some("-") {
+        args.query = Some(read_query_text(Path::new("-"))?);
+    }
+    if args.quiet {
+        args.format = OutputFormat::Ids;
+    }
+    let explain = args.explain.then(Explain::new);
+    // There's no query text to rerank against, and skipping it keeps --like-id free of model calls
+    if args.like_id.is_some() {
+        args.no_rerank = true;
+    }
+    // --limit bounds what's fetched and --offset/--top-n pick the page shown from it, so the page has to
+    // be fetched too
+    let page_end = args.offset as usize + args.top_n;
+    if page_end > args.limit.max(0) as usize {
+        info!("Fetching {} candidates instead of --limit {} so results {}-{} can be shown.", page_end, args.limit, args.offset + 1, page_end);
+        args.limit = i32::try_from(page_end).map_err(|_| "--offset plus --top-n is too large")?;
+    }
+    if args.page && !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        warn!("--page needs a terminal to ask for the next page; showing one page.");
+        args.page = false;
+    }
+    if args.answer && args.format == OutputFormat::Grep {
+        return Err("--format grep lists matching locations and can't be combined with --answer".into());
+    }
+    if args.answer && args.format == OutputFormat::Context {
+        return Err("--format context prints the documents an answer would be generated from and can't be combined with --answer".into());
+    }
+    if args.chat && args.format != OutputFormat::Human {
+        return Err("--chat is interactive and only supports --format human".into());
+    }
+    if args.mode == SearchMode::Keyword && args.query.is_none() {
+        return Err("--mode keyword searches the text of a --query; it can't be used with --query-file, --like-id, --chat or --queries-file".into());
+    }
+    for table in &args.table {
+        schema::check_table_name(table)?;
+    }
+    if args.table.iter().collect::<HashSet<_>>().len() < args.table.len() {
+        return Err("--table lists the same table more than once".into());
+    }
+    // Only vector search goes through the store, which can merge tables; these query a single table directly
+    if args.table.len() > 1 && (args.hybrid || args.mode == SearchMode::Keyword || args.expand_context > 0) {
+        return Err("--hybrid, --mode keyword and --expand-context search a single --table".into());
+    }
+    if args.output.is_some() && args.format == OutputFormat::Human {
+        return Err("--output needs a file format: pass --format json, jsonl, grep or markdown".into());
+    }
+    if (args.eval.is_some() || !args.eval_diff.is_empty() || args.bench.is_some()) && !matches!(args.format, OutputFormat::Human | OutputFormat::Json) {
+        return Err("--eval, --eval-diff and --bench print a table or, with --format json, a report".into());
+    }
+    if let [before, after] = args.eval_diff.as_slice() {
+        return print_eval_diff(&args, before, after);
+    }
+    let retry_policy = args.retry.policy();
+    let default_rerank_template = match args.rerank_mode {
+        RerankMode::Score => DEFAULT_RERANK_PROMPT,
+        RerankMode::YesNo => DEFAULT_YES_NO_PROMPT,
+    };
+    let rerank_template = load_prompt_template(args.rerank_prompt_file.as_ref(), default_rerank_template, "{document}")?;
+    let answer_template = load_prompt_template(args.answer_prompt_file.as_ref(), DEFAULT_ANSWER_PROMPT, "{context}")?;
+    let hyde_template = load_prompt_template(args.hyde_prompt_file.as_ref(), DEFAULT_HYDE_PROMPT, "{query}")?;
+    // Parse the whole batch up front so a malformed line fails before any query runs
+    let batch = args.queries_file.as_ref().map(load_batch_queries).transpose()?;
+    let eval_queries = args.eval.as_deref().map(eval::load_queries).transpose()?;
+    let bench_queries = args.bench.as_ref().map(load_batch_queries).transpose()?;
+    // An empty query embeds to a meaningless vector that would "match" arbitrary rows
+    let query = match (&args.query, &args.query_file, &args.like_id) {
+        (Some(query), _, _) if query.trim().is_empty() => return Err("The query is empty; pass the text to search for with --query".into()),
+        (Some(query), _, _) => query.clone(),
+        (None, Some(path), _) => {
+            let query = std::fs::read_to_string(path).map_err(|e| format!("Failed to read query file {}: {}", path.display(), e))?;
+            if query.trim().is_empty() {
+                return Err(format!("Query file {} is empty", path.display()).into());
+            }
+            query
+        }
+        // Only shown as the Markdown report's title
+        (None, None, Some(id)) => format!("Documents like {}", id),
+        // --chat reads its queries interactively, and --queries-file, --eval and --bench from their files
+        (None, None, None) => String::new(),
+    };
+    // Color only a terminal whose user hasn't opted out (https://no-color.org)
+    let color = args.format == OutputFormat::Human
+        && !args.no_color
+        && std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty());
+    let highlighter = (args.highlight && args.like_id.is_none()).then(|| Highlighter::new(&query, color));
+    let colors = color.then(Colors::new);
+    let template_hash = content_hash(&rerank_template);
 
     // --- 1. Initialize Clients ---
-    let ollama = Ollama::new("http://localhost:11434".to_string(), 11434);
+    let embedder = args.embedder.build()?;
+    let ollama = Ollama::builder().host("http://localhost").port(11434).build();
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(database_url)
-        .await?;
+    // sqlx prepares each statement once per connection and reuses it. A chat session can sit idle between
+    // questions for longer than the pool's idle timeout, so it keeps its connections, and with them the
+    // prepared retrieval statement, for as long as it runs
+    let mut pool_options = PgPoolOptions::new().max_connections(5);
+    if args.chat {
+        pool_options = pool_options.idle_timeout(None).max_lifetime(None);
+    }
+    let pool = pool_options.connect(&database_url).await?;
+    let database = match args.format == OutputFormat::Markdown || args.save_session.is_some() || args.refine.is_some() {
+        true => sqlx::query_scalar("SELECT current_database();").fetch_one(&pool).await?,
+        false => String::new(),
+    };
+    if let Some(path) = &args.refine {
+        args.within_ids = Some(Session::load(path)?.ids_for(path, &database, &args)?);
+    } else if let Some(path) = &args.within_ids_file {
+        args.within_ids = Some(read_ids_file(path)?);
+    }
+    if args.count {
+        let filters = retrieval_filters(&args);
+        let names: Vec<&str> = args.table.iter().map(String::as_str).collect();
+        let mut count = 0;
+        for table in &args.table {
+            let filters = match names.len() {
+                1 => filters.clone(),
+                _ => vector_store::store_filters(&names, table, &filters),
+            };
+            count += store::count_rows(&pool, table, &filters).await?;
+        }
+        println!("{}", count);
+        if count == 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Check the models exist up front rather than failing after retrieval. With --mode auto, a plain
+    // --query on a single table falls back to keyword search when the embedding model can't be reached.
+    let table = args.table[0].as_str();
+    let mut keyword_only = args.mode == SearchMode::Keyword;
+    if let (false, None, Some(client)) = (keyword_only, &args.like_id, args.embedder.ollama_client()?) {
+        if let Err(e) = health::check_ollama_models(&client, &[&args.embedder.embedding_model]).await {
+            if args.mode == SearchMode::Vector || args.query.is_none() || args.table.len() > 1 {
+                return Err(e);
+            }
+            warn!("{} Falling back to keyword search.", e);
+            keyword_only = true;
+        }
+    }
+    // Keyword results are ordered by full-text rank and never reranked
+    if !args.no_rerank && !keyword_only {
+        health::check_ollama_models(&ollama, &[&args.rerank_model]).await?;
+    }
+    if args.answer || args.chat || args.expand > 0 {
+        health::check_ollama_models(&ollama, &[&args.answer_model]).await?;
+    }
+    if args.hyde && !keyword_only {
+        health::check_ollama_models(&ollama, &[args.hyde_model.as_ref().unwrap_or(&args.answer_model)]).await?;
+    }
+
+    // Without the ingester-maintained column, hybrid search still works but computes tsvectors per row
+    let text_search = match args.hybrid && !schema::has_text_search(&pool, table).await? {
+        true => {
+            warn!("{}.text_search is missing; run the ingester once to add it. Falling back to a slower full-text scan.", table);
+            "to_tsvector('simple', text)"
+        }
+        false => "text_search",
+    };
+    let metric = resolve_metric(&pool, &args.table, args.metric).await?;
+    let rerank_cache = (!args.no_rerank_cache).then(|| RerankCache::new(RerankCache::default_dir(), Duration::from_secs(args.rerank_cache_ttl * 3600)));
+    let vector_encoding = match args.text_vectors {
+        true => VectorEncoding::Text { precision: args.vector_precision },
+        false => VectorEncoding::detect(&pool, args.vector_precision).await,
+    };
+    let table_store = |table: &str| PgVectorStore::new(pool.clone(), metric, vector_encoding).with_index_settings(args.ef_search, args.probes).with_table(table);
+    let vector_store: Box<dyn VectorStore> = match args.table.as_slice() {
+        [table] => Box::new(table_store(table)),
+        tables => Box::new(MergedStore::new(tables.iter().map(|table| (table.clone(), Box::new(table_store(table)) as Box<dyn VectorStore>)).collect())),
+    };
+    let pipeline = Pipeline {
+        args: &args,
+        embedder: embedder.as_ref(),
+        pool: &pool,
+        table,
+        store: vector_store.as_ref(),
+        generator: &ollama,
+        retry_policy: &retry_policy,
+        rerank_template: &rerank_template,
+        rerank_cache: rerank_cache.as_ref(),
+        hyde_template: &hyde_template,
+        text_search,
+        normalize: index_is_normalized(&pool, &args.table).await?,
+        metric,
+        vector_encoding,
+        explain: explain.as_ref(),
+    };
+    let report = Report {
+        output: args.output.as_deref(),
+        query: &query,
+        tables: &args.table,
+        // Only the Markdown report and session files name the database searched
+        collection: database,
+        repo_url: args.repo_url.as_deref(),
+    };
+    // `docs` is the whole final ranking, of which the --offset page is shown
+    let output = |kind: &str, ranking: &[RankedDoc], rerank: Option<(&str, &str)>, summary: &RankSummary| -> Result<(), Box<dyn Error>> {
+        if let Some(path) = &args.save_session {
+            save_session(path, &report, &args, ranking)?;
+        }
+        let docs = page(&args, ranking);
+        let returned = docs.len();
+        if args.format == OutputFormat::Human {
+            let mut first = args.offset as usize;
+            loop {
+                print_results(&args, kind, &ranking[first.min(ranking.len())..], first, highlighter.as_ref(), colors.as_ref());
+                first += args.top_n;
+                if !args.page || first >= ranking.len() || !next_page_wanted(ranking.len() - first)? {
+                    break;
+                }
+            }
+            // Nothing was retrieved, so there's nothing to summarise
+            if summary.retrieved > 0 {
+                summary.print(returned);
+            }
+            if let Some(explain) = &explain {
+                explain.print();
+            }
+            return Ok(());
+        }
+        let mut out = report.writer()?;
+        match args.format {
+            OutputFormat::Grep => print_grep_results(&mut out, docs, args.top_n)?,
+            OutputFormat::Markdown => print_markdown_results(&mut out, &report, &args, kind, rerank, docs)?,
+            OutputFormat::Ids => {
+                for doc in docs.iter().take(args.top_n) {
+                    writeln!(out, "{}", doc.id)?;
+                }
+                out.flush()?;
+            }
+            OutputFormat::Context => {
+                let (context, _) = pack_context(docs, args.context_tokens, args.truncate_context, true);
+                write!(out, "{}", context)?;
+                out.flush()?;
+            }
+            // JSON carries the timings itself rather than mixing text into the output
+            _ => return print_json_results(&mut out, &args, docs, ranking.len(), rerank, summary.to_json(args.top_n, returned), explain.as_ref().map(Explain::to_json)),
+        }
+        if summary.retrieved > 0 {
+            summary.print(returned);
+        }
+        if let Some(explain) = &explain {
+            explain.print();
+        }
+        Ok(())
+    };
+    let answerer = Answerer {
+        generator: &ollama,
+        interrupt,
+        model: &args.answer_model,
+        retry_policy: retry_policy.clone(),
+        template: &answer_template,
+        context_tokens: args.context_tokens,
+        truncate_context: args.truncate_context,
+        // Only human output can show a partial answer; JSON needs the complete response
+        stream: !args.no_stream && args.format == OutputFormat::Human,
+    };
+    if args.chat {
+        let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+        check_dimension(&pool, &args.table, dimension).await?;
+        if all_empty(&pool, &args.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
+        }
+        warn_on_prefix_mismatch(&pool, &args.table, &args.embedder).await?;
+        return chat(&pipeline, &answerer).await;
+    }
+    if let Some(batch) = batch {
+        let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+        check_dimension(&pool, &args.table, dimension).await?;
+        if all_empty(&pool, &args.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
+        }
+        warn_on_prefix_mismatch(&pool, &args.table, &args.embedder).await?;
+        let rerank_info = (!args.no_rerank).then_some((args.rerank_model.as_str(), template_hash.as_str()));
+        return run_batch(&pipeline, &answerer, batch, rerank_info, &mut report.writer()?).await;
+    }
+    if let Some(eval_queries) = eval_queries {
+        let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+        check_dimension(&pool, &args.table, dimension).await?;
+        if all_empty(&pool, &args.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
+        }
+        warn_on_prefix_mismatch(&pool, &args.table, &args.embedder).await?;
+        return run_eval(&pipeline, &answerer, &eval_queries, &mut report.writer()?).await;
+    }
+    if let Some(bench_queries) = bench_queries {
+        let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+        check_dimension(&pool, &args.table, dimension).await?;
+        if all_empty(&pool, &args.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
+        }
+        warn_on_prefix_mismatch(&pool, &args.table, &args.embedder).await?;
+        return run_bench(&pipeline, &answerer, &bench_queries, &mut report.writer()?).await;
+    }
 
     // --- 2. Generate Embedding for the User Query ---
-    println!("Generating embedding for query...");
-    let query_embedding_request = ollama_rs::generation::embeddings::request::GenerateEmbeddingsRequest::new(
-        "dengcao/Qwen3-Embedding-4B:Q4_K_M".to_string(),
-        ollama_rs::generation::embeddings::request::EmbeddingsInput::Single(args.query.clone()),
-    );
-    let query_embedding_response = ollama.generate_embeddings(query_embedding_request).await?;
-    let query_vector = query_embedding_response.embeddings.into_iter().next().ok_or("Failed to get query embedding")?;
-    let query_vector_str = format!("[{}]", query_vector.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(","));
+    if keyword_only {
+        return keyword_search(&pipeline, &query, &output).await;
+    }
+    let stage = Instant::now();
+    let query_vector = if let Some(id) = &args.like_id {
+        info!("Searching with the stored vector of {}...", id);
+        vector_store
+            .vectors(std::slice::from_ref(id))
+            .await?
+            .remove(id)
+            .ok_or_else(|| format!("No embedded document has id {:?}; ids are the paths printed with each result", id))?
+    } else if args.query_file.is_some() {
+        info!("Generating embedding for query...");
+        // An example file is compared document-to-document, so it gets the document prefix, not the query instruction
+        let chunks: Vec<String> = chunk_text(&query, QUERY_FILE_CHUNK_CHARS)
+            .into_iter()
+            .map(|chunk| format!("{}{}", args.embedder.document_prefix, chunk))
+            .collect();
+        let chunk_vectors = with_retry(&retry_policy, "Query file embedding", || embedder.embed(&chunks)).await?;
+        if chunk_vectors.len() > 1 {
+            info!("Query file split into {} chunks ({:?} pooling).", chunk_vectors.len(), args.pooling);
+        }
+        pipeline.normalized(pool_vectors(&chunk_vectors, args.pooling).ok_or("Failed to get query file embedding")?)
+    } else {
+        info!("Generating embedding for query...");
+        match pipeline.search_vector(&answerer, &query).await {
+            Ok(query_vector) => query_vector,
+            Err(e) if args.mode == SearchMode::Auto && args.table.len() == 1 => {
+                warn!("Query embedding failed ({}). Falling back to keyword search.", e);
+                return keyword_search(&pipeline, &query, &output).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    if let Some(explain) = &explain {
+        explain.log().embed = Some(stage.elapsed());
+    }
+    if args.like_id.is_none() {
+        check_dimension(&pool, &args.table, query_vector.len()).await?;
+        warn_on_prefix_mismatch(&pool, &args.table, &args.embedder).await?;
+    }
 
     // --- 3. Initial Retrieval from Database ---
-    println!("Retrieving initial documents from database...");
-    let retrieved_docs: Vec<(String, String)> = sqlx::query_as(
-        r#"
-        SELECT id, text
-        FROM embeddings
-        ORDER BY vector <=> $1::vector
-        LIMIT $2;
-        "#,
-    )
-    .bind(query_vector_str)
-    .bind(args.limit)
-    .fetch_all(&pool)
-    .await?;
+    if pipeline.streams_jsonl() {
+        return pipeline.stream_jsonl(&query_vector, &mut report.writer()?).await;
+    }
+    let retrieved_docs = pipeline.retrieve_expanded(&answerer, &query, &query_vector).await?;
 
-    println!("Retrieved {} documents for reranking...", retrieved_docs.len());
+    if retrieved_docs.is_empty() {
+        if all_empty(&pool, &args.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
+        }
+        let filters = sql_filters(&args);
+        match filters.is_empty() {
+            true => info!("No documents found."),
+            false => info!("No documents matched the filters ({}).", filters.join(" ")),
+        }
+        // Machine-readable consumers still get a well-formed (empty) result
+        return match args.format {
+            OutputFormat::Human => Ok(()),
+            _ => output("", &[], None, &RankSummary::default()),
+        };
+    }
 
     // --- 4. Rerank the Retrieved Documents ---
-    let mut reranked_docs = Vec::new();
-    for (id, document_text) in retrieved_docs {
-        let rerank_prompt = format!(
-            "Given the query: '{}' and the document: '{}'. Output only a single floating-point number between 0.0 and 1.0 representing the relevance score. No other text, explanation, or formatting.",
-            args.query,
-            document_text
+    // With --no-rerank the SQL (or fused) ordering is final; use cosine similarity (or the RRF score) as the score
+    let mut scored_docs = pipeline.score(&query, retrieved_docs, &[]).await;
+    let (mut ranked_docs, mut summary) = pipeline.select(scored_docs.clone());
+    // --per-file drops candidates only after the SQL limit, so a page it leaves short is fetched again
+    // from a larger pool, as long as the last fetch filled its limit and so more rows may exist. Only the
+    // candidates the larger pool adds are reranked.
+    let mut refetch_args = args.clone();
+    for _ in 0..MAX_REFETCHES {
+        if ranked_docs.len() >= page_end || summary.per_file_dropped == 0 || summary.retrieved < refetch_args.limit.max(0) as usize {
+            break;
+        }
+        refetch_args.limit = refetch_args.limit.saturating_mul(2);
+        info!("--per-file left {} of the {} results wanted; fetching again with a limit of {}.", ranked_docs.len(), page_end, refetch_args.limit);
+        let pipeline = Pipeline { args: &refetch_args, ..pipeline };
+        let retrieved_docs = pipeline.retrieve_expanded(&answerer, &query, &query_vector).await?;
+        scored_docs = pipeline.score(&query, retrieved_docs, &scored_docs).await;
+        (ranked_docs, summary) = pipeline.select(scored_docs.clone());
+    }
+    // Retrieval found documents, so only the relevance thresholds can have removed them all
+    if ranked_docs.is_empty() {
+        info!(
+            "No sufficiently relevant documents found (--min-score {:?}, --min-similarity {:?}).",
+            args.min_score,
+            args.min_similarity
         );
+        match args.format {
+            OutputFormat::Human => summary.print(0),
+            _ => output("", &[], None, &summary)?,
+        }
+        std::process::exit(NO_RELEVANT_DOCUMENTS_EXIT_CODE);
+    }
+    if ranked_docs.len() < page_end {
+        warn_on_shortfall(&args, refetch_args.limit, &summary, page(&args, &ranked_docs).len());
+    }
+    if args.expand_context > 0 {
+        let shown = (args.offset as usize).min(ranked_docs.len())..page_end.min(ranked_docs.len());
+        let added = expand_context(&pool, table, &mut ranked_docs[shown], args.expand_context, args.expand_max_chars).await?;
+        info!("Added {} neighbouring chunks of context.", added);
+    }
+    let (kind, rerank_info) = match (args.no_rerank, args.hybrid) {
+        (true, true) => ("Hybrid", None),
+        (true, false) => ("Vector", None),
+        (false, _) => ("Reranked", Some((args.rerank_model.as_str(), template_hash.as_str()))),
+    };
 
-        let rerank_request = GenerationRequest::new(
-            "hf.co/mradermacher/Qwen3-Reranker-4B-GGUF:Q4_K_M".to_string(),
-            rerank_prompt,
-        );
+    // --- 5. Display Final Results, or answer from them ---
+    if args.answer {
+        if let Some(path) = &args.save_session {
+            save_session(path, &report, &args, &ranked_docs)?;
+        }
+        let top_docs = page(&args, &ranked_docs);
+        let (answer, context_docs) = answerer.answer(&query, top_docs).await?;
+        print_answer(args.format, &report, &answer, &args.answer_model, &context_docs, answerer.stream)?;
+        summary.print(top_docs.len());
+        if let Some(explain) = &explain {
+            explain.print();
+        }
+        return Ok(());
+    }
+    output(kind, &ranked_docs, rerank_info, &summary)?;
+    match args.open {
+        Some(Some(number)) => open_result(&args, &ranked_docs, number),
+        Some(None) => match pick_result(ranked_docs.len())? {
+            Some(number) => open_result(&args, &ranked_docs, number),
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
 
-        let response = ollama.generate(rerank_request).await?;
-        let last_line = response.response.trim().lines().last().unwrap_or("");
-        if let Ok(score) = last_line.parse::<f32>() {
-            reranked_docs.push((id, document_text, score));
-        } else {
-            eprintln!("Warning: Could not parse rerank score from line '{}' for document {}", last_line, id);
+// Reads messages until /quit or end of input. Each message is rewritten into a standalone query (unless
+// --no-condense), then retrieved, ranked and answered like --answer; the exchange is kept as history
+// within --history-tokens so follow-ups can refer back to earlier answers.
+async fn chat(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>) -> Result<(), Box<dyn Error>> {
+    println!("Chat started. /reset clears the conversation, /sources lists the documents behind the last answer, /quit exits.");
+    let mut history: Vec<(String, String)> = Vec::new();
+    let mut sources: Vec<RankedDoc> = Vec::new();
+    loop {
+        print!("\n> ");
+        std::io::stdout().flush()?;
+        // Read on a blocking thread so the runtime stays free to deliver Ctrl-C, which exits here
+        let (read, line) = tokio::task::spawn_blocking(|| {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).map(|read| (read, line))
+        })
+        .await??;
+        if read == 0 {
+            println!();
+            return Ok(());
+        }
+        let message = line.trim();
+        match message {
+            "" => continue,
+            "/quit" | "/exit" => return Ok(()),
+            "/reset" => {
+                history.clear();
+                sources.clear();
+                println!("Conversation cleared.");
+                continue;
+            }
+            "/sources" => {
+                print_sources(&sources);
+                continue;
+            }
+            _ if message.starts_with('/') => {
+                println!("Unknown command {}. Available: /reset, /sources, /quit.", message);
+                continue;
+            }
+            _ => {}
+        }
+
+        match chat_turn(pipeline, answerer, &history, message).await {
+            Ok((answer, used)) => {
+                history.push((message.to_string(), answer));
+                while !history.is_empty() && pack::count_tokens(&render_history(&history)) > pipeline.args.history_tokens {
+                    history.remove(0);
+                }
+                sources = used;
+            }
+            // Ctrl-C ends only the answer in progress, which is left out of the history
+            Err(e) if e.is::<Cancelled>() => println!("\n(cancelled)"),
+            // One failed turn shouldn't end the conversation
+            Err(e) => error!(error = %e, "Error: {}", e),
         }
     }
+}
 
-    // Sort by the new relevance score in descending order
-    reranked_docs.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+// Answers one chat message, returning the answer and the documents it was generated from
+async fn chat_turn(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>, history: &[(String, String)], message: &str) -> Result<(String, Vec<RankedDoc>), Box<dyn Error>> {
+    let transcript = render_history(history);
+    let query = if history.is_empty() || pipeline.args.no_condense {
+        message.to_string()
+    } else {
+        let query = answerer.condense(&transcript, message).await?;
+        println!("Searching for: {}", query);
+        query
+    };
 
-    // --- 5. Display Final Results ---
-    println!("\n--- Top {} Reranked Results ---", args.top_n);
-    for (i, (id, text, score)) in reranked_docs.iter().take(args.top_n).enumerate() {
-        println!("\n{}. ID: {} (Score: {:.4})", i + 1, id, score);
-        println!("--------------------------------------------------");
-        println!("{}", text.chars().take(500).collect::<String>());
-        if text.len() > 500 {
-            println!("... (truncated)");
+    let query_vector = pipeline.search_vector(answerer, &query).await?;
+    let retrieved_docs = pipeline.retrieve_expanded(answerer, &query, &query_vector).await?;
+    if retrieved_docs.is_empty() {
+        return Err("no documents found for this message".into());
+    }
+    let (ranked_docs, _) = pipeline.rank(&query, retrieved_docs).await;
+    if ranked_docs.is_empty() {
+        return Err("no sufficiently relevant documents found for this message".into());
+    }
+    let top_docs = page(pipeline.args, &ranked_docs);
+
+    // The answer model sees the conversation too, so it can build on what it already said
+    let question = if history.is_empty() {
+        message.to_string()
+    } else {
+        format!("Conversation so far:\n{}\nFollow-up: {}", transcript, message)
+    };
+    let (answer, context_docs) = answerer.answer(&question, top_docs).await?;
+    print_answer(OutputFormat::Human, &Report::default(), &answer, answerer.model, &context_docs, answerer.stream)?;
+    Ok((answer, context_docs))
+}
+
+// Earlier turns as a User/Assistant transcript. Their [n] citations referred to that turn's sources,
+// so they're removed to keep them from being confused with the current ones.
+fn render_history(history: &[(String, String)]) -> String {
+    history
+        .iter()
+        .map(|(message, answer)| format!("User: {}\nAssistant: {}\n", message, CITATION.replace_all(answer, "")))
+        .collect()
+}
+
+// Lists the documents the last chat answer was generated from, numbered as the answer cited them
+fn print_sources(docs: &[RankedDoc]) {
+    if docs.is_empty() {
+        println!("No answer yet.");
+        return;
+    }
+    for (i, doc) in docs.iter().enumerate() {
+        println!("[{}] {} ({} score: {:.4})", i + 1, document_path(doc), doc.source, doc.score);
+    }
+}
+
+// Answers a --query from the stored text alone, for --mode keyword or when the embedding service is down
+async fn keyword_search<F>(pipeline: &Pipeline<'_>, query: &str, output: F) -> Result<(), Box<dyn Error>>
+where
+    F: Fn(&str, &[RankedDoc], Option<(&str, &str)>, &RankSummary) -> Result<(), Box<dyn Error>>,
+{
+    let args = pipeline.args;
+    let kind = match args.mode {
+        SearchMode::Keyword => "Keyword",
+        _ => "Keyword fallback (embedding service unavailable)",
+    };
+    if args.max_distance.is_some() || args.min_similarity.is_some() {
+        warn!("--max-distance and --min-similarity need vectors and are ignored by keyword search.");
+    }
+    let docs = pipeline.retrieve_keyword(query).await?;
+    if docs.is_empty() {
+        if store::is_empty(pipeline.pool, pipeline.table).await? {
+            return Err(EMPTY_INDEX_MESSAGE.into());
         }
+        info!("No documents contain the query's words.");
+        return match args.format {
+            OutputFormat::Human => Ok(()),
+            _ => output(kind, &[], None, &RankSummary::default()),
+        };
+    }
+    let docs = match args.per_file {
+        Some(per_file) => limit_per_file(docs, per_file),
+        None => docs,
+    };
+    let summary = RankSummary { retrieved: docs.len(), ..Default::default() };
+    output(kind, &docs, None, &summary)
+}
+
+/// One query of a --queries-file: a plain line, or a JSON object that can also override the filters.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BatchQuery {
+    query: String,
+    #[serde(alias = "language")]
+    languages: Option<Vec<String>>,
+    path_prefix: Option<String>,
+    max_distance: Option<f64>,
+    exclude_generated: Option<bool>,
+    source: Option<String>,
+}
+
+// Time spent in each stage of one batch query
+#[derive(Default, Clone, Copy)]
+struct StageTimings {
+    embed: Duration,
+    retrieve: Duration,
+    rerank: Duration,
+}
+
+impl StageTimings {
+    fn add(&mut self, other: StageTimings) {
+        self.embed += other.embed;
+        self.retrieve += other.retrieve;
+        self.rerank += other.rerank;
     }
 
-    Ok(())
+    // Mean milliseconds per stage over `count` queries
+    fn mean_millis(self, count: u32) -> [f64; 3] {
+        [self.embed, self.retrieve, self.rerank].map(|total| (total / count.max(1)).as_secs_f64() * 1000.0)
+    }
+
+    fn to_json(self) -> serde_json::Value {
+        let [embed, retrieve, rerank] = self.mean_millis(1);
+        json!({ "embed": embed, "retrieve": retrieve, "rerank": rerank })
+    }
+}
+
+// How many candidates survived ranking, for the summary printed after the results
+#[derive(Default, Clone, Copy)]
+struct RankSummary {
+    retrieved: usize,
+    reranked: bool,
+    // Documents the reranker scored, and those it failed on, which keep their vector similarity as the score
+    scored: usize,
+    failed: usize,
+    // Candidates removed after retrieval, by the relevance thresholds and by --per-file
+    below_threshold: usize,
+    per_file_dropped: usize,
+}
+
+impl RankSummary {
+    // Without reranking nothing was scored or could fail, so those counts are null rather than 0
+    fn to_json(self, requested: usize, returned: usize) -> serde_json::Value {
+        json!({
+            "requested": requested,
+            "retrieved": self.retrieved,
+            "scored": self.reranked.then_some(self.scored),
+            "failed": self.reranked.then_some(self.failed),
+            "returned": returned,
+        })
+    }
+
+    fn print(self, returned: usize) {
+        match self.reranked {
+            true => info!(
+                "\nSummary: {} retrieved, {} scored by the reranker, {} failed, {} returned.",
+                self.retrieved,
+                self.scored,
+                self.failed,
+                returned
+            ),
+            false => info!("\nSummary: {} retrieved, {} returned (not reranked).", self.retrieved, returned),
+        }
+    }
+}
+
+// What --explain records while a query runs. Reranks run concurrently, so records go through a mutex.
+struct Explain {
+    started: Instant,
+    log: Mutex<ExplainLog>,
+}
+
+#[derive(Default)]
+struct ExplainLog {
+    // Producing the query vector: the embedding, or the stored vector's lookup with --like-id
+    embed: Option<Duration>,
+    queries: Vec<SqlTiming>,
+    rerank: Option<Duration>,
+    reranks: Vec<RerankTiming>,
+}
+
+struct SqlTiming {
+    stage: &'static str,
+    sql: String,
+    params: String,
+    duration: Duration,
+    // EXPLAIN ANALYZE output, one line per row, with --explain-analyze
+    plan: Option<Vec<String>>,
+}
+
+struct RerankTiming {
+    id: String,
+    duration: Duration,
+    cached: bool,
+}
+
+impl SqlTiming {
+    // Any index scan, including the bitmap scans full-text search uses, counts
+    fn index_used(&self) -> Option<bool> {
+        self.plan.as_ref().map(|plan| plan.iter().any(|line| line.contains("Index Scan")))
+    }
+}
+
+impl Explain {
+    fn new() -> Self {
+        Self { started: Instant::now(), log: Mutex::new(ExplainLog::default()) }
+    }
+
+    fn log(&self) -> std::sync::MutexGuard<'_, ExplainLog> {
+        self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let log = self.log();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let queries: Vec<serde_json::Value> = log
+            .queries
+            .iter()
+            .map(|query| {
+                json!({
+                    "stage": query.stage,
+                    "sql": query.sql.trim(),
+                    "params": query.params,
+                    "ms": millis(query.duration),
+                    "plan": query.plan,
+                    "index_used": query.index_used(),
+                })
+            })
+            .collect();
+        let reranks: Vec<serde_json::Value> = log.reranks.iter().map(|rerank| json!({ "id": rerank.id, "ms": millis(rerank.duration), "cached": rerank.cached })).collect();
+        json!({
+            "embed_ms": log.embed.map(millis),
+            "sql": queries,
+            "rerank_ms": log.rerank.map(millis),
+            "reranks": reranks,
+            "total_ms": millis(self.started.elapsed()),
+        })
+    }
+
+    fn print(&self) {
+        let mut log = self.log();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        info!("\n--- Explain ---");
+        if let Some(embed) = log.embed {
+            info!("Query vector: {:.1} ms", millis(embed));
+        }
+        for query in &log.queries {
+            info!("SQL ({}): {:.1} ms", query.stage, millis(query.duration));
+            for line in query.sql.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                info!("    {}", line);
+            }
+            info!("  Parameters: {}", query.params);
+            if let (Some(plan), Some(index_used)) = (&query.plan, query.index_used()) {
+                info!("  Index used: {}", if index_used { "yes" } else { "no (sequential scan)" });
+                for line in plan {
+                    info!("    {}", line);
+                }
+            }
+        }
+        if let Some(rerank) = log.rerank {
+            info!("Rerank: {:.1} ms for {} documents", millis(rerank), log.reranks.len());
+            // Slowest first, as those are the ones worth looking at
+            log.reranks.sort_by_key(|rerank| std::cmp::Reverse(rerank.duration));
+            for rerank in &log.reranks {
+                info!("  {}: {:.1} ms{}", rerank.id, millis(rerank.duration), if rerank.cached { " (cached)" } else { "" });
+            }
+        }
+        info!("Total: {:.1} ms", millis(self.started.elapsed()));
+    }
+}
+
+// The values bound to a search query for --explain: vectors by their dimension, and only the filters that
+// are set, as the unset ones are bound as NULL or false and disable their predicate
+fn describe_params(leading: &[String], filters: &RetrievalFilters) -> String {
+    let mut params: Vec<String> = leading.iter().enumerate().map(|(i, value)| format!("${} = {}", i + 1, value)).collect();
+    let RetrievalFilters { languages, path_pattern, max_distance, exclude_generated, source, after, include_undated, exclude_id, exclude_paths, within_ids, metadata } = filters;
+    if let Some(languages) = languages {
+        params.push(format!("$4 = {:?}", languages));
+    }
+    if let Some(path_pattern) = path_pattern {
+        params.push(format!("$5 = {:?}", path_pattern));
+    }
+    if let Some(max_distance) = max_distance {
+        params.push(format!("$6 = {}", max_distance));
+    }
+    if *exclude_generated {
+        params.push("$7 = true".to_string());
+    }
+    if let Some(source) = source {
+        params.push(format!("$8 = {:?}", source));
+    }
+    if let Some(after) = after {
+        params.push(format!("$9 = {}", after.to_rfc3339()));
+    }
+    if *include_undated {
+        params.push("$10 = true".to_string());
+    }
+    if let Some(exclude_id) = exclude_id {
+        params.push(format!("$11 = {:?}", exclude_id));
+    }
+    if let Some(exclude_paths) = exclude_paths {
+        params.push(format!("$12 = {:?}", exclude_paths));
+    }
+    if let Some(within_ids) = within_ids {
+        params.push(format!("$13 = <{} ids>", within_ids.len()));
+    }
+    if let Some(metadata) = metadata {
+        let filters: Vec<String> = metadata.iter().map(MetadataFilter::to_string).collect();
+        params.push(format!("$14..$16 = {:?}", filters));
+    }
+    params.join(", ")
+}
+
+// Lines starting with `{` are JSON query objects, anything else is the query text; blank lines are skipped
+fn load_batch_queries(path: &PathBuf) -> Result<Vec<BatchQuery>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read queries file {}: {}", path.display(), e))?;
+    let mut queries = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let query: BatchQuery = if line.starts_with('{') {
+            serde_json::from_str(line).map_err(|e| format!("{}:{}: invalid query object: {}", path.display(), number + 1, e))?
+        } else {
+            BatchQuery { query: line.to_string(), ..Default::default() }
+        };
+        if query.query.trim().is_empty() {
+            return Err(format!("{}:{}: the query is empty", path.display(), number + 1).into());
+        }
+        queries.push(query);
+    }
+    if queries.is_empty() {
+        return Err(format!("Queries file {} contains no queries", path.display()).into());
+    }
+    Ok(queries)
+}
+
+/// Runs every --queries-file query on the shared clients, --batch-concurrency at a time, and writes one
+/// JSON line per query in file order. A failed query gets an `error` line and the rest still run, unless
+/// --fail-fast is set. Mean stage latencies go to stderr at the end.
+async fn run_batch(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>, queries: Vec<BatchQuery>, rerank: Option<(&str, &str)>, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let args = pipeline.args;
+    // Results are always JSON lines, so progress has to stay off stdout whatever --format says
+    let batch_args = Args { format: OutputFormat::Jsonl, ..args.clone() };
+    let pipeline = Pipeline { args: &batch_args, ..*pipeline };
+    let started = Instant::now();
+    let total = queries.len();
+    let mut outcomes = futures::stream::iter(queries)
+        .map(|batch_query| async move {
+            let outcome = run_batch_query(&pipeline, answerer, &batch_query).await;
+            (batch_query, outcome)
+        })
+        .buffered(args.batch_concurrency.max(1));
+
+    let (mut succeeded, mut failed) = (0u32, 0);
+    let mut timing_totals = StageTimings::default();
+    while let Some((batch_query, outcome)) = outcomes.next().await {
+        let line = match outcome {
+            Ok((docs, timings)) => {
+                succeeded += 1;
+                timing_totals.add(timings);
+                let results: Vec<serde_json::Value> = page(args, &docs)
+                    .iter()
+                    .enumerate()
+                    .map(|(i, doc)| result_json(doc, args.offset as usize + i + 1, rerank, (args.min_score, args.min_similarity)))
+                    .collect();
+                json!({ "query": batch_query.query, "results": results, "timings_ms": timings.to_json() })
+            }
+            Err(e) if args.fail_fast => return Err(format!("Query '{}' failed: {}", batch_query.query, e).into()),
+            Err(e) => {
+                failed += 1;
+                json!({ "query": batch_query.query, "error": e.to_string() })
+            }
+        };
+        let line = serde_json::to_string(&line)?;
+        writeln!(out, "{}", line)?;
+        out.flush()?;
+    }
+
+    info!(phase = "batch", queries = total, failed, duration_ms = started.elapsed().as_millis() as u64, "Ran {} queries in {:.1}s ({} failed).", total, started.elapsed().as_secs_f64(), failed);
+    if succeeded > 0 {
+        let [embed, retrieve, rerank] = timing_totals.mean_millis(succeeded);
+        info!(phase = "batch", embed_ms = embed, retrieve_ms = retrieve, rerank_ms = rerank, "Mean latency per query (ms): embed {:.0}, retrieve {:.0}, rerank {:.0}.", embed, retrieve, rerank);
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} queries failed", failed, total).into());
+    }
+    Ok(())
+}
+
+// Runs one batch query with its filter overrides applied, timing each stage
+async fn run_batch_query(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>, batch_query: &BatchQuery) -> Result<(Vec<RankedDoc>, StageTimings), Box<dyn Error>> {
+    let mut args = pipeline.args.clone();
+    if let Some(languages) = &batch_query.languages {
+        args.languages = languages.clone();
+    }
+    if let Some(path_prefix) = &batch_query.path_prefix {
+        args.path_prefix = Some(path_prefix.clone());
+    }
+    if let Some(max_distance) = batch_query.max_distance {
+        args.max_distance = Some(max_distance);
+    }
+    if let Some(exclude_generated) = batch_query.exclude_generated {
+        args.exclude_generated = exclude_generated;
+    }
+    if let Some(source) = &batch_query.source {
+        args.source = Some(source.clone());
+    }
+    let pipeline = Pipeline { args: &args, ..*pipeline };
+    let query = &batch_query.query;
+
+    let stage = Instant::now();
+    let query_vector = pipeline.search_vector(answerer, query).await?;
+    let embed = stage.elapsed();
+    let stage = Instant::now();
+    let retrieved_docs = pipeline.retrieve_expanded(answerer, query, &query_vector).await?;
+    let retrieve = stage.elapsed();
+    let stage = Instant::now();
+    let (ranked_docs, _) = pipeline.rank(query, retrieved_docs).await;
+    let rerank = stage.elapsed();
+    Ok((ranked_docs, StageTimings { embed, retrieve, rerank }))
+}
+
+/// Runs every --eval query once, ranks its candidates without and (unless --no-rerank) with the reranker,
+/// and scores the top --top-n of each ranking against the query's relevant paths. Both rankings share
+/// the retrieval, so they differ only by the reranker. The first failed query stops the evaluation.
+async fn run_eval(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>, queries: &[EvalQuery], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let args = pipeline.args;
+    // --min-score and --sort-by only apply to reranked results
+    let unranked_args = Args { no_rerank: true, min_score: None, sort_by: SortBy::Rerank, ..args.clone() };
+    let unranked = Pipeline { args: &unranked_args, ..*pipeline };
+    let mut names = vec![if args.hybrid { "hybrid" } else { "vector" }];
+    if !args.no_rerank {
+        names.push("reranked");
+    }
+    let started = Instant::now();
+    let mut outcomes = futures::stream::iter(queries)
+        .map(|eval_query| async move {
+            let query = &eval_query.query;
+            let rankings = async {
+                let query_vector = pipeline.search_vector(answerer, query).await?;
+                let retrieved_docs = pipeline.retrieve_expanded(answerer, query, &query_vector).await?;
+                let mut rankings = vec![unranked.rank(query, retrieved_docs.clone()).await.0];
+                if !args.no_rerank {
+                    rankings.push(pipeline.rank(query, retrieved_docs).await.0);
+                }
+                Ok::<_, Box<dyn Error>>(rankings)
+            };
+            (eval_query, rankings.await)
+        })
+        .buffered(args.batch_concurrency.max(1));
+
+    let mut configurations: Vec<Configuration> = names.iter().map(|name| Configuration { name: name.to_string(), mean: Scores::default(), queries: Vec::new() }).collect();
+    while let Some((eval_query, rankings)) = outcomes.next().await {
+        let rankings = rankings.map_err(|e| format!("Query '{}' failed: {}", eval_query.query, e))?;
+        for (configuration, ranking) in configurations.iter_mut().zip(rankings) {
+            let results: Vec<&str> = ranking.iter().take(args.top_n).map(document_path).collect();
+            configuration.queries.push(QueryScores {
+                query: eval_query.query.clone(),
+                scores: eval::score(&results, &eval_query.relevant, args.top_n),
+                results: results.iter().map(|path| path.to_string()).collect(),
+            });
+        }
+    }
+    for configuration in &mut configurations {
+        configuration.mean = Scores::mean(&configuration.queries.iter().map(|query| query.scores).collect::<Vec<_>>());
+    }
+    info!(phase = "eval", queries = queries.len(), duration_ms = started.elapsed().as_millis() as u64, "Evaluated {} queries in {:.1}s.", queries.len(), started.elapsed().as_secs_f64());
+
+    let report = EvalReport { created_at: Utc::now().to_rfc3339(), k: args.top_n, settings: retrieval_settings(args), configurations };
+    if args.format == OutputFormat::Json {
+        serde_json::to_writer_pretty(&mut *out, &report)?;
+        writeln!(out)?;
+        out.flush()?;
+        return Ok(());
+    }
+    writeln!(out, "Retrieval quality over {} queries (k = {}):\n", queries.len(), report.k)?;
+    writeln!(out, "{:<14} {:>9} {:>7} {:>9}", "configuration", format!("recall@{}", report.k), "MRR", format!("nDCG@{}", report.k))?;
+    for configuration in &report.configurations {
+        let Scores { recall, reciprocal_rank, ndcg } = configuration.mean;
+        writeln!(out, "{:<14} {:>9.3} {:>7.3} {:>9.3}", configuration.name, recall, reciprocal_rank, ndcg)?;
+    }
+    for configuration in &report.configurations {
+        let missed: Vec<&QueryScores> = configuration.queries.iter().filter(|query| query.scores.recall == 0.0).collect();
+        if !missed.is_empty() {
+            writeln!(out, "\nNo relevant result in the top {} ({}):", report.k, configuration.name)?;
+            for query in missed {
+                writeln!(out, "  {}", query.query)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// The options that shape the retrieval of an --eval or --bench run, recorded in its report so runs can
+// be told apart
+fn retrieval_settings(args: &Args) -> serde_json::Value {
+    json!({
+        "tables": args.table,
+        "embedding_model": args.embedder.embedding_model,
+        "limit": args.limit,
+        "hybrid": args.hybrid,
+        "mmr": args.mmr,
+        "hyde": args.hyde,
+        "expand": args.expand,
+        "rerank_model": (!args.no_rerank).then_some(&args.rerank_model),
+        "rerank_mode": args.rerank_mode.to_string(),
+        "rerank_weight": args.rerank_weight,
+        "filters": sql_filters(args),
+    })
+}
+
+/// Runs --warmup and then --iterations passes over the --bench queries, one query at a time so runs don't
+/// contend, and reports each stage's latency over the timed passes. Rerank scores aren't cached, or every
+/// pass after the first would skip the reranker. The first failed query stops the benchmark.
+async fn run_bench(pipeline: &Pipeline<'_>, answerer: &Answerer<'_>, queries: &[BatchQuery], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    const STAGES: [&str; 4] = ["embed", "retrieve", "rerank", "total"];
+    let args = pipeline.args;
+    let pipeline = Pipeline { rerank_cache: None, ..*pipeline };
+    let passes = args.warmup + args.iterations;
+    // Milliseconds per stage of every timed run, and of the very first run
+    let mut samples: [Vec<f64>; 4] = Default::default();
+    let mut first_run = None;
+    for pass in 0..passes {
+        let started = Instant::now();
+        for batch_query in queries {
+            let run_started = Instant::now();
+            let (_, timings) = run_batch_query(&pipeline, answerer, batch_query).await.map_err(|e| format!("Query '{}' failed: {}", batch_query.query, e))?;
+            let run = [timings.embed, timings.retrieve, timings.rerank, run_started.elapsed()].map(|stage| stage.as_secs_f64() * 1000.0);
+            first_run.get_or_insert(run);
+            if pass >= args.warmup {
+                for (stage, milliseconds) in samples.iter_mut().zip(run) {
+                    stage.push(milliseconds);
+                }
+            }
+        }
+        let kind = if pass < args.warmup { "warmup" } else { "timed" };
+        info!(phase = "bench", pass = pass + 1, duration_ms = started.elapsed().as_millis() as u64, "Pass {}/{} ({}) took {:.1}s.", pass + 1, passes, kind, started.elapsed().as_secs_f64());
+    }
+    let stats = samples.each_ref().map(|stage| StageStats::new(stage));
+    let first_run = first_run.unwrap_or_default();
+
+    // Ollama loads a model on its first request, which takes seconds; smaller gaps are connection setup.
+    // Retrieval has no model, and without reranking there's nothing to load
+    let model_stages = [(0, true), (2, !args.no_rerank)];
+    let loaded: Vec<usize> = model_stages
+        .iter()
+        .filter(|&&(stage, has_model)| has_model && first_run[stage] > SPIKE_FACTOR * stats[stage].p50_ms && first_run[stage] - stats[stage].p50_ms >= 100.0)
+        .map(|&(stage, _)| stage)
+        .collect();
+    let reloading: Vec<usize> = model_stages.iter().filter(|&&(stage, has_model)| has_model && stats[stage].spike_variance_share > 0.5).map(|&(stage, _)| stage).collect();
+
+    if args.format == OutputFormat::Json {
+        let mut stages = serde_json::Map::new();
+        for (stage, name) in STAGES.iter().enumerate() {
+            let mut value = serde_json::to_value(stats[stage])?;
+            value["first_run_ms"] = json!(first_run[stage]);
+            value["model_load"] = json!(loaded.contains(&stage));
+            value["reload_spikes"] = json!(reloading.contains(&stage));
+            stages.insert(name.to_string(), value);
+        }
+        let report = json!({
+            "created_at": Utc::now().to_rfc3339(),
+            "queries": queries.len(),
+            "warmup": args.warmup,
+            "iterations": args.iterations,
+            "settings": retrieval_settings(args),
+            "stages": stages,
+        });
+        serde_json::to_writer_pretty(&mut *out, &report)?;
+        writeln!(out)?;
+        out.flush()?;
+        return Ok(());
+    }
+    let mode = match (args.hybrid, args.no_rerank) {
+        (false, false) => "vector, reranked",
+        (false, true) => "vector, not reranked",
+        (true, false) => "hybrid, reranked",
+        (true, true) => "hybrid, not reranked",
+    };
+    writeln!(out, "Latency of {} queries over {} timed passes after {} warmup passes ({}):\n", queries.len(), args.iterations, args.warmup, mode)?;
+    writeln!(out, "{:<9} {:>9} {:>9} {:>9} {:>9} {:>12}", "stage", "p50 ms", "p95 ms", "mean ms", "max ms", "first run ms")?;
+    for (stage, name) in STAGES.iter().enumerate() {
+        let StageStats { p50_ms, p95_ms, mean_ms, max_ms, .. } = stats[stage];
+        writeln!(out, "{:<9} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>12.1}", name, p50_ms, p95_ms, mean_ms, max_ms, first_run[stage])?;
+    }
+    writeln!(out)?;
+    for &stage in &loaded {
+        let advice = if args.warmup > 0 { "the warmup passes keep it out of the timed runs" } else { "pass --warmup to keep it out of the timed runs" };
+        writeln!(out, "The first {} took {:.0} ms against a p50 of {:.0} ms: that's the model loading; {}.", STAGES[stage], first_run[stage], stats[stage].p50_ms, advice)?;
+    }
+    for &stage in &reloading {
+        let StageStats { p50_ms, spikes, spike_variance_share, .. } = stats[stage];
+        writeln!(
+            out,
+            "{} of the timed {} runs took over {}x the p50 and make up {:.0}% of its variance, which points to the model being loaded again (see OLLAMA_KEEP_ALIVE, or another model evicting it) rather than steady-state jitter; the steady state is a p50 of {:.0} ms.",
+            spikes,
+            STAGES[stage],
+            SPIKE_FACTOR,
+            spike_variance_share * 100.0,
+            p50_ms
+        )?;
+    }
+    if loaded.is_empty() && reloading.is_empty() {
+        writeln!(out, "No model loads or reload spikes detected; the variance is steady state.")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Prints how the configurations of two --eval reports compare, and the queries whose scores moved
+fn print_eval_diff(args: &Args, before: &Path, after: &Path) -> Result<(), Box<dyn Error>> {
+    let (old, new) = (EvalReport::load(before)?, EvalReport::load(after)?);
+    if old.k != new.k {
+        warn!("{} was scored on the top {} results and {} on the top {}; the scores aren't directly comparable.", before.display(), old.k, after.display(), new.k);
+    }
+    let diffs = eval::diff(&old, &new);
+    for configuration in old.configurations.iter().chain(&new.configurations) {
+        if !diffs.iter().any(|diff| diff.name == configuration.name) {
+            warn!("Configuration {} is only in one of the reports and is left out.", configuration.name);
+        }
+    }
+    let mut out = Report { output: args.output.as_deref(), query: "", tables: &[], collection: String::new(), repo_url: None }.writer()?;
+    if args.format == OutputFormat::Json {
+        let output = json!({ "before": before, "after": after, "k": new.k, "configurations": diffs });
+        serde_json::to_writer_pretty(&mut *out, &output)?;
+        writeln!(out)?;
+        out.flush()?;
+        return Ok(());
+    }
+    let change = |before: f64, after: f64| format!("{:.3} -> {:.3} ({:+.3})", before, after, after - before);
+    writeln!(out, "{} -> {} (k = {}):", before.display(), after.display(), new.k)?;
+    for diff in &diffs {
+        writeln!(out, "\n{}", diff.name)?;
+        writeln!(out, "  recall@{:<4} {}", new.k, change(diff.before.recall, diff.after.recall))?;
+        writeln!(out, "  MRR{:<8} {}", "", change(diff.before.reciprocal_rank, diff.after.reciprocal_rank))?;
+        writeln!(out, "  nDCG@{:<6} {}", new.k, change(diff.before.ndcg, diff.after.ndcg))?;
+        for query in &diff.changed {
+            let direction = match query.after.ndcg.total_cmp(&query.before.ndcg) {
+                std::cmp::Ordering::Greater => "better",
+                std::cmp::Ordering::Less => "worse",
+                std::cmp::Ordering::Equal => "moved",
+            };
+            writeln!(out, "  {:<6} nDCG {:.3} -> {:.3}, recall {:.3} -> {:.3}: {}", direction, query.before.ndcg, query.after.ndcg, query.before.recall, query.after.recall, query.query)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Everything a search needs besides the query, shared by one-shot queries, chat turns and batches
+#[derive(Clone, Copy)]
+struct Pipeline<'a> {
+    args: &'a Args,
+    embedder: &'a dyn Embedder,
+    pool: &'a PgPool,
+    // The --table queried directly by full-text search, context expansion and streamed results, which all
+    // require a single table
+    table: &'a str,
+    // Vector search goes through the store, which merges the hits of every --table
+    store: &'a dyn VectorStore,
+    // Generates the rerank judgements
+    generator: &'a dyn Generator,
+    retry_policy: &'a RetryPolicy,
+    rerank_template: &'a str,
+    rerank_cache: Option<&'a RerankCache>,
+    hyde_template: &'a str,
+    // Expression for a document's tsvector in hybrid mode
+    text_search: &'static str,
+    // Whether the index holds unit-length vectors, so query vectors must be scaled to match
+    normalize: bool,
+    metric: Metric,
+    vector_encoding: VectorEncoding,
+    explain: Option<&'a Explain>,
+}
+
+impl Pipeline<'_> {
+    /// Embeds a text query with the query prefix.
+    async fn embed_query(&self, query: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        let query_texts = [format!("{}{}", self.args.embedder.query_prefix, query)];
+        let query_embeddings = with_retry(self.retry_policy, "Query embedding", || self.embedder.embed(&query_texts)).await?;
+        let query_vector = query_embeddings.into_iter().next().ok_or("Failed to get query embedding")?;
+        Ok(self.normalized(query_vector))
+    }
+
+    /// The vector to search for a query with: its embedding, or with --hyde the embedding of a hypothetical
+    /// answer, averaged with the query's by --hyde-mix. If no hypothetical can be written, the query is used.
+    async fn search_vector(&self, answerer: &Answerer<'_>, query: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+        if !self.args.hyde || self.args.hyde_mix == 0.0 {
+            return self.embed_query(query).await;
+        }
+        let model = self.args.hyde_model.as_deref().unwrap_or(&self.args.answer_model);
+        info!("Writing a hypothetical answer with {}...", model);
+        let hypothetical = match answerer.hypothesize(query, model, self.hyde_template).await {
+            Ok(hypothetical) if !hypothetical.is_empty() => hypothetical,
+            Ok(_) => {
+                warn!("The hypothetical answer was empty; searching with the query embedding instead.");
+                return self.embed_query(query).await;
+            }
+            Err(e) => {
+                warn!("Writing a hypothetical answer failed ({}); searching with the query embedding instead.", e);
+                return self.embed_query(query).await;
+            }
+        };
+        if self.args.verbose {
+            info!("--- Hypothetical answer ---\n{}\n---", hypothetical);
+        }
+
+        // The hypothetical stands in for a document, so it gets the document prefix rather than the query instruction
+        let mix = self.args.hyde_mix;
+        let mut texts = vec![format!("{}{}", self.args.embedder.document_prefix, hypothetical)];
+        if mix < 1.0 {
+            texts.push(format!("{}{}", self.args.embedder.query_prefix, query));
+        }
+        let vectors = with_retry(self.retry_policy, "Hypothetical answer embedding", || self.embedder.embed(&texts)).await?;
+        let mut vectors = vectors.into_iter();
+        let mut search_vector = vectors.next().ok_or("Failed to get hypothetical answer embedding")?;
+        if let Some(query_vector) = vectors.next() {
+            for (component, query_component) in search_vector.iter_mut().zip(query_vector) {
+                *component = mix * *component + (1.0 - mix) * query_component;
+            }
+        }
+        Ok(self.normalized(search_vector))
+    }
+
+    /// Scales a query vector to unit length when the index was built with --normalize.
+    fn normalized(&self, mut query_vector: Vec<f32>) -> Vec<f32> {
+        if self.normalize && !store::normalize(&mut query_vector) {
+            warn!("the query embedded to a zero vector, which can't be normalized");
+        }
+        query_vector
+    }
+
+    /// Fetches the candidate page for a query by vector similarity, fused with full-text rank in hybrid mode.
+    async fn retrieve(&self, query: &str, query_vector: &[f32]) -> Result<Vec<RankedDoc>, Box<dyn Error>> {
+        info!("Retrieving initial documents from database...");
+        // Candidates always come from the top of the ranking; --offset pages through the final ranking.
+        // The store breaks ties by id, so reruns with a later --offset never overlap or skip rows.
+        let limit = i64::from(self.args.limit.max(0));
+        // --mmr selects its candidates from a larger pool of the nearest ones
+        let fetch_limit = match (self.args.hybrid, self.args.mmr) {
+            (false, Some(_)) => limit * MMR_CANDIDATE_FACTOR,
+            _ => limit,
+        };
+        let params = self.filter_params();
+        let stage = Instant::now();
+        let vector_docs = self.store.query(query_vector, fetch_limit as usize, 0, &params).await?;
+        if let Some(explain) = self.explain {
+            let duration = stage.elapsed();
+            let mut plan = None;
+            if self.args.explain_analyze {
+                let mut lines = Vec::new();
+                for table in &self.args.table {
+                    if self.args.table.len() > 1 {
+                        lines.push(format!("-- {}", table));
+                    }
+                    lines.extend(self.explain_analyze(table, query_vector, fetch_limit, 0, &params).await?);
+                }
+                plan = Some(lines);
+            }
+            let leading = [format!("<{}-dimension vector>", query_vector.len()), fetch_limit.to_string(), "0".to_string()];
+            let params = describe_params(&leading, &params);
+            let sql = self.args.table.iter().map(|table| store::nearest_sql(self.metric, table)).collect();
+            explain.log().queries.push(SqlTiming { stage: "vector search", sql, params, duration, plan });
+        }
+
+        // Hybrid mode also ranks by full-text match so exact identifiers surface even when their vectors don't.
+        // Query terms are OR-ed: plainto_tsquery would require every word of a natural-language question.
+        let keyword_docs: Vec<Hit> = if self.args.hybrid {
+            let (distance, filters) = self.distance_and_filters();
+            let keyword_sql = format!(
+                r#"
+                WITH q AS (SELECT replace(plainto_tsquery('simple', $2)::text, '&', '|')::tsquery AS query)
+                SELECT id, text, {distance} AS distance, COALESCE(metadata, '{{}}'::jsonb) AS metadata
+                FROM {table}, q
+                WHERE {text_search} @@ q.query AND {filters}
+                ORDER BY ts_rank_cd({text_search}, q.query) DESC, id
+                LIMIT $3;
+                "#,
+                text_search = self.text_search,
+                table = self.table,
+            );
+            let keyword_query = sqlx::query_as(&keyword_sql).bind(self.vector_encoding.param(query_vector)).bind(query).bind(fetch_limit);
+            let stage = Instant::now();
+            let keyword_docs = params.bind(keyword_query).fetch_all(self.pool).await?;
+            if let Some(explain) = self.explain {
+                let leading = [format!("<{}-dimension vector>", query_vector.len()), format!("{:?}", query), fetch_limit.to_string()];
+                let params = describe_params(&leading, &params);
+                explain.log().queries.push(SqlTiming { stage: "full-text search", sql: keyword_sql, params, duration: stage.elapsed(), plan: None });
+            }
+            keyword_docs
+        } else {
+            Vec::new()
+        };
+
+        let retrieved_docs: Vec<RankedDoc> = if self.args.hybrid {
+            info!("Vector search found {} documents, full-text search {}.", vector_docs.len(), keyword_docs.len());
+            let [vector_docs, keyword_docs] = [vector_docs, keyword_docs].map(|rows| {
+                rows.into_iter().map(|hit| RankedDoc::from_vector(hit, self.metric)).collect()
+            });
+            fuse_rrf(vec![("vector", vector_docs), ("keyword", keyword_docs)], self.args.rrf_k)
+                .into_iter()
+                .take(limit as usize)
+                .collect()
+        } else {
+            let vector_docs = match self.args.mmr {
+                Some(lambda) => {
+                    let candidate_count = vector_docs.len();
+                    let ids: Vec<String> = vector_docs.iter().map(|hit| hit.id.clone()).collect();
+                    let vectors = self.store.vectors(&ids).await?;
+                    let diverse = diversify(vector_docs, &vectors, lambda, limit as usize, self.metric)?;
+                    info!("MMR (lambda {}) picked {} of {} nearest candidates.", lambda, diverse.len(), candidate_count);
+                    diverse
+                }
+                None => vector_docs,
+            };
+            vector_docs.into_iter().map(|hit| RankedDoc::from_vector(hit, self.metric)).collect()
+        };
+
+        if let Some(max_distance) = self.args.max_distance {
+            if retrieved_docs.len() < limit as usize {
+                info!("{} documents are within --max-distance {}.", retrieved_docs.len(), max_distance);
+            }
+        }
+        Ok(retrieved_docs)
+    }
+
+    // Runs the vector search of `table` again under EXPLAIN ANALYZE, with the same index settings, and returns the plan
+    async fn explain_analyze(&self, table: &str, query_vector: &[f32], limit: i64, offset: i64, params: &RetrievalFilters) -> Result<Vec<String>, Box<dyn Error>> {
+        let sql = format!("EXPLAIN (ANALYZE, BUFFERS) {}", store::nearest_sql(self.metric, table));
+        let mut tx = store::begin_search(self.pool, self.args.ef_search, self.args.probes).await?;
+        let query = sqlx::query_as::<_, (String,)>(&sql)
+            .bind(self.vector_encoding.param(query_vector))
+            .bind(limit)
+            .bind(offset);
+        let plan = params.bind(query).fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(plan.into_iter().map(|(line,)| line).collect())
+    }
+
+    // Filters go in the WHERE clause so they're applied before the limit, not after
+    fn filter_params(&self) -> RetrievalFilters {
+        retrieval_filters(self.args)
+    }
+
+    // The distance expression to $1 and the WHERE predicates binding $4 onwards
+    fn distance_and_filters(&self) -> (String, String) {
+        let distance = format!("vector {} $1::vector", self.metric.operator());
+        let filters = FILTER_PREDICATES.replace("{distance}", &distance);
+        (distance, filters)
+    }
+
+    /// Whether results can be written straight from the database cursor: plain vector search printed as
+    /// JSON lines, with nothing that needs the whole result set before the first line can be written.
+    fn streams_jsonl(&self) -> bool {
+        self.args.format == OutputFormat::Jsonl
+            && self.args.no_rerank
+            && !self.args.hybrid
+            && self.args.mmr.is_none()
+            && self.args.expand == 0
+            && self.args.expand_context == 0
+            && !self.args.answer
+            && !self.args.explain
+            && self.args.recency_boost.is_none()
+            && self.args.save_session.is_none()
+            && self.args.table.len() == 1
+    }
+
+    /// Writes each vector search result as a JSON line as soon as the database returns it, applying
+    /// --min-similarity and --per-file on the way. A failure part-way through is returned as an error
+    /// for stderr; every line already written is a complete JSON object.
+    async fn stream_jsonl(&self, query_vector: &[f32], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        let params = self.filter_params();
+        // The cursor needs the Postgres store's SQL directly; VectorStore::query returns whole pages
+        let sql = store::nearest_sql(self.metric, self.table);
+        let mut tx = store::begin_search(self.pool, self.args.ef_search, self.args.probes).await?;
+        let query = sqlx::query_as::<_, Hit>(&sql)
+            .bind(self.vector_encoding.param(query_vector))
+            .bind(i64::from(self.args.limit.max(0)))
+            .bind(0i64);
+        let mut rows = params.bind(query).fetch(&mut *tx);
+
+        let mut per_file_counts: HashMap<String, usize> = HashMap::new();
+        // Results before --offset are counted off like the buffered path's page, after the same filtering
+        let (mut relevant, mut below_threshold, mut skipped, mut shown) = (0, 0, 0, 0);
+        while shown < self.args.top_n {
+            let Some(row) = rows.next().await else { break };
+            let hit = row.map_err(|e| format!("Retrieval failed after {} results: {}", shown, e))?;
+            let doc = RankedDoc::from_vector(hit, self.metric);
+            // Rows arrive nearest first, so once one is below --min-similarity the rest are too
+            if !self.is_relevant_enough(&doc) {
+                below_threshold += 1;
+                break;
+            }
+            relevant += 1;
+            if let Some(per_file) = self.args.per_file {
+                let count = per_file_counts.entry(document_path(&doc).to_string()).or_insert(0);
+                *count += 1;
+                if *count > per_file {
+                    continue;
+                }
+            }
+            if skipped < self.args.offset {
+                skipped += 1;
+                continue;
+            }
+            // Serialise before writing so an error can never leave half a line on stdout
+            let line = serde_json::to_string(&result_json(&doc, self.args.offset as usize + shown + 1, None, (self.args.min_score, self.args.min_similarity)))?;
+            writeln!(out, "{}", line)?;
+            out.flush()?;
+            shown += 1;
+        }
+        drop(rows);
+        tx.commit().await?;
+
+        // The thresholds removing every row gets its own exit code; an --offset past the last result is
+        // just an empty page
+        if relevant == 0 && below_threshold > 0 {
+            std::process::exit(NO_RELEVANT_DOCUMENTS_EXIT_CODE);
+        }
+        Ok(())
+    }
+
+    /// Fetches the candidate page by full-text rank alone, without a query vector. Uses the same filters
+    /// as vector retrieval except --max-distance.
+    async fn retrieve_keyword(&self, query: &str) -> Result<Vec<RankedDoc>, Box<dyn Error>> {
+        info!("Searching stored text for the query's words...");
+        let text_search = match self.args.hybrid || schema::has_text_search(self.pool, self.table).await? {
+            true => self.text_search,
+            false => "to_tsvector('simple', text)",
+        };
+        let params = RetrievalFilters { max_distance: None, ..self.filter_params() };
+        let filters = FILTER_PREDICATES.replace("{distance}", "NULL::float8");
+        // Words are OR-ed as in --hybrid, so documents matching more of them rank higher rather than all being required
+        let sql = format!(
+            r#"
+            WITH q AS (SELECT replace(plainto_tsquery('simple', $1)::text, '&', '|')::tsquery AS query)
+            SELECT id, text, ts_rank_cd({text_search}, q.query)::float8, COALESCE(metadata, '{{}}'::jsonb)
+            FROM {table}, q
+            WHERE {text_search} @@ q.query AND {filters}
+            ORDER BY ts_rank_cd({text_search}, q.query) DESC, id
+            LIMIT $2 OFFSET $3;
+            "#,
+            table = self.table,
+        );
+        // Like the vector search, this fetches from the top; --offset pages through the final ranking
+        let keyword_query = sqlx::query_as(&sql).bind(query).bind(i64::from(self.args.limit.max(0))).bind(0i64);
+        let stage = Instant::now();
+        let rows: Vec<(String, String, f64, serde_json::Value)> = params.bind(keyword_query).fetch_all(self.pool).await?;
+        if let Some(explain) = self.explain {
+            let leading = [format!("{:?}", query), self.args.limit.to_string(), "0".to_string()];
+            let params = describe_params(&leading, &params);
+            explain.log().queries.push(SqlTiming { stage: "keyword search", sql, params, duration: stage.elapsed(), plan: None });
+        }
+        Ok(rows.into_iter().map(|(id, text, rank, metadata)| RankedDoc::from_keyword(id, text, rank, metadata)).collect())
+    }
+
+    /// Like [`Pipeline::retrieve`], but with --expand also retrieves for model-written variants of the query
+    /// and fuses all the rankings with RRF. If no variants can be generated, only the query is searched.
+    async fn retrieve_expanded(&self, answerer: &Answerer<'_>, query: &str, query_vector: &[f32]) -> Result<Vec<RankedDoc>, Box<dyn Error>> {
+        if self.args.expand == 0 {
+            return self.retrieve(query, query_vector).await;
+        }
+        let variants = match answerer.expand(query, self.args.expand).await {
+            Ok(variants) if !variants.is_empty() => variants,
+            Ok(_) => {
+                warn!("Query expansion produced no variants; searching with the original query only.");
+                return self.retrieve(query, query_vector).await;
+            }
+            Err(e) => {
+                warn!("Query expansion failed ({}); searching with the original query only.", e);
+                return self.retrieve(query, query_vector).await;
+            }
+        };
+        info!("Also searching for:");
+        for variant in &variants {
+            info!("  - {}", variant);
+        }
+
+        let mut rankings = vec![("query", self.retrieve(query, query_vector).await?)];
+        for variant in &variants {
+            let variant_vector = self.embed_query(variant).await?;
+            rankings.push(("expansion", self.retrieve(variant, &variant_vector).await?));
+        }
+        let fused = fuse_rrf(rankings, self.args.rrf_k);
+        info!("{} query variants found {} distinct documents.", variants.len() + 1, fused.len());
+        Ok(fused.into_iter().take(self.args.limit.max(0) as usize).collect())
+    }
+
+    /// Reranks retrieved documents (or keeps their retrieval order with --no-rerank), then applies the
+    /// relevance thresholds and --per-file.
+    async fn rank(&self, query: &str, retrieved_docs: Vec<RankedDoc>) -> (Vec<RankedDoc>, RankSummary) {
+        let scored_docs = self.score(query, retrieved_docs, &[]).await;
+        self.select(scored_docs)
+    }
+
+    // Reranks documents given in retrieval order (unless --no-rerank) and orders them by --sort-by. Documents
+    // in `scored`, reranked by an earlier call on a smaller retrieval, keep their scores, so only the new
+    // candidates are sent to the reranker.
+    async fn score(&self, query: &str, retrieved_docs: Vec<RankedDoc>, scored: &[RankedDoc]) -> Vec<RankedDoc> {
+        if self.args.no_rerank {
+            info!("Retrieved {} documents (reranking skipped).", retrieved_docs.len());
+            return retrieved_docs;
+        }
+        let retrieval_rank: HashMap<String, usize> = retrieved_docs.iter().enumerate().map(|(rank, doc)| (doc.id.clone(), rank)).collect();
+        let known: HashMap<&str, &RankedDoc> = scored.iter().map(|doc| (doc.id.as_str(), doc)).collect();
+        let (mut reranked_docs, mut new_docs) = (Vec::new(), Vec::new());
+        for doc in retrieved_docs {
+            match known.get(doc.id.as_str()) {
+                Some(known) => reranked_docs.push((*known).clone()),
+                None => new_docs.push(doc),
+            }
+        }
+        let stage = Instant::now();
+        if !new_docs.is_empty() {
+            reranked_docs.extend(rerank_documents(self, query, new_docs).await);
+        }
+        if let Some(explain) = self.explain {
+            let mut log = explain.log();
+            log.rerank = Some(log.rerank.unwrap_or_default() + stage.elapsed());
+        }
+        // The same order rerank_documents gives a single pass: by score, ties in retrieval order
+        reranked_docs.sort_by_key(|doc| retrieval_rank[&doc.id]);
+        rerank::sort_by_score(&mut reranked_docs, |doc| doc.score);
+        if self.args.sort_by == SortBy::Vector {
+            // Stable, so equal similarities keep their rerank order
+            reranked_docs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+            info!("Sorted by vector similarity (--sort-by vector); rerank scores are shown for comparison.");
+        }
+        reranked_docs
+    }
+
+    // Applies the relevance thresholds, --recency-boost and --per-file to documents ordered by score
+    fn select(&self, ranked_docs: Vec<RankedDoc>) -> (Vec<RankedDoc>, RankSummary) {
+        let mut summary = RankSummary { retrieved: ranked_docs.len(), reranked: !self.args.no_rerank, ..Default::default() };
+        if summary.reranked {
+            summary.failed = ranked_docs.iter().filter(|doc| doc.source == ScoreSource::VectorFallback).count();
+            summary.scored = ranked_docs.len() - summary.failed;
+        }
+        let ranked_count = ranked_docs.len();
+        let ranked_docs: Vec<RankedDoc> = ranked_docs.into_iter().filter(|doc| self.is_relevant_enough(doc)).collect();
+        summary.below_threshold = ranked_count - ranked_docs.len();
+        if ranked_docs.len() < ranked_count {
+            info!("Dropped {} of {} documents below the --min-score/--min-similarity thresholds.", ranked_count - ranked_docs.len(), ranked_count);
+        }
+        // The thresholds judge relevance alone; age only reorders what passed them
+        let ranked_docs = match self.args.recency_boost {
+            Some(half_life) => boost_recent(ranked_docs, half_life, Utc::now()),
+            None => ranked_docs,
+        };
+        let ranked_docs = match self.args.per_file {
+            Some(per_file) => {
+                let ranked_count = ranked_docs.len();
+                let kept = limit_per_file(ranked_docs, per_file);
+                summary.per_file_dropped = ranked_count - kept.len();
+                if kept.len() < ranked_count {
+                    info!("--per-file {} dropped {} documents from files already shown.", per_file, ranked_count - kept.len());
+                }
+                kept
+            }
+            None => ranked_docs,
+        };
+        (ranked_docs, summary)
+    }
+
+    fn is_relevant_enough(&self, doc: &RankedDoc) -> bool {
+        let similarity = doc.similarity as f32;
+        self.args.min_score.is_none_or(|min_score| doc.score >= min_score)
+            && self.args.min_similarity.is_none_or(|min_similarity| similarity >= min_similarity)
+    }
+}
+
+// Rescales the scores into [0, 1] by the lowest and highest of them, then multiplies each by the document's
+// recency factor and re-sorts. Scaling first keeps the decay pulling every score down: a negative score
+// multiplied directly would rise towards zero as it aged. Documents without a parsable `embedded_at` keep
+// their rescaled score.
+fn boost_recent(mut docs: Vec<RankedDoc>, half_life: f64, now: DateTime<Utc>) -> Vec<RankedDoc> {
+    let finite = || docs.iter().map(|doc| doc.score).filter(|score| score.is_finite());
+    let (low, high) = (finite().fold(f32::INFINITY, f32::min), finite().fold(f32::NEG_INFINITY, f32::max));
+    for doc in &mut docs {
+        // Equal scores all count as the best, so age alone orders them
+        doc.score = if high > low { (doc.score - low) / (high - low) } else { 1.0 };
+    }
+    let mut undated = 0;
+    for doc in &mut docs {
+        let embedded_at = doc.metadata.get("embedded_at").and_then(|value| value.as_str()).and_then(|value| DateTime::parse_from_rfc3339(value).ok());
+        let Some(embedded_at) = embedded_at else {
+            undated += 1;
+            continue;
+        };
+        let age_days = (now - embedded_at.with_timezone(&Utc)).num_seconds() as f64 / 86_400.0;
+        let factor = rerank::recency_factor(age_days, half_life);
+        doc.score = (doc.score as f64 * factor) as f32;
+        doc.recency = Some((age_days, factor));
+    }
+    rerank::sort_by_score(&mut docs, |doc| doc.score);
+    info!("Boosted recent documents (half-life {} days); {} without an embedded_at timestamp kept their rescaled score.", half_life, undated);
+    docs
+}
+
+// Reranks every retrieved document and sorts by the new scores; a failed or unparsable rerank falls back
+// to the vector similarity instead of dropping the document, and the retrieval rank breaks ties
+async fn rerank_documents(pipeline: &Pipeline<'_>, query: &str, retrieved_docs: Vec<RankedDoc>) -> Vec<RankedDoc> {
+    let (args, template, cache) = (pipeline.args, pipeline.rerank_template.to_string(), pipeline.rerank_cache);
+    info!("Retrieved {} documents for reranking...", retrieved_docs.len());
+    // The template hash identifies which prompt produced these scores when comparing runs
+    info!(
+        "Reranking with {} ({} mode, prompt template sha256 {}).",
+        args.rerank_model,
+        args.rerank_mode,
+        &content_hash(&template)[..12]
+    );
+
+    let reranker = Reranker {
+        generator: pipeline.generator,
+        model: args.rerank_model.clone(),
+        mode: args.rerank_mode,
+        retry_policy: pipeline.retry_policy.clone(),
+        template,
+        max_chars: args.rerank_max_chars,
+        score_tolerance: args.rerank_score_tolerance,
+        debug: args.debug_rerank,
+        cache,
+        cache_hits: AtomicUsize::new(0),
+        explain: pipeline.explain,
+    };
+    let retrieved_count = retrieved_docs.len();
+    let mut reranked_docs: Vec<(usize, RankedDoc)> = futures::stream::iter(retrieved_docs.into_iter().enumerate())
+        .map(|(rank, mut doc)| {
+            let reranker = &reranker;
+            async move {
+                let similarity = doc.similarity as f32;
+                let (score, source) = reranker.score(query, &doc.id, &doc.text).await.unwrap_or((similarity, ScoreSource::VectorFallback));
+                // A fallback has no rerank score of its own, so it isn't reported as one
+                doc.rerank_score = (source != ScoreSource::VectorFallback).then_some(score);
+                doc.score = rerank::blend(score, similarity, args.rerank_weight);
+                doc.source = source;
+                (rank, doc)
+            }
+        })
+        .buffer_unordered(args.rerank_concurrency.max(1))
+        .collect()
+        .await;
+
+    // Sort by the new (combined) relevance score in descending order; equal scores keep their vector-distance order
+    reranked_docs.sort_by_key(|(rank, _)| *rank);
+    rerank::sort_by_score(&mut reranked_docs, |(_, doc)| doc.score);
+    let reranked_docs: Vec<RankedDoc> = reranked_docs.into_iter().map(|(_, doc)| doc).collect();
+    if cache.is_some() {
+        let hits = reranker.cache_hits.load(Ordering::Relaxed);
+        info!("Rerank cache: {} hits, {} misses.", hits, retrieved_count - hits);
+    }
+
+    let count_of = |source| reranked_docs.iter().filter(|doc| doc.source == source).count();
+    let (text_count, fallback_count) = (count_of(ScoreSource::RerankText), count_of(ScoreSource::VectorFallback));
+    if text_count > 0 || fallback_count > 0 {
+        info!(
+            "Rerank scores for {} documents: {} from JSON, {} extracted from free text, {} fell back to vector similarity.",
+            retrieved_count,
+            count_of(ScoreSource::Rerank),
+            text_count,
+            fallback_count
+        );
+    }
+    reranked_docs
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScoreSource {
+    Rerank,
+    RerankText,
+    Vector,
+    Fused,
+    VectorFallback,
+    Keyword,
+}
+
+impl std::fmt::Display for ScoreSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ScoreSource::Rerank => "rerank",
+            ScoreSource::RerankText => "rerank (text)",
+            ScoreSource::Vector => "vector",
+            ScoreSource::Fused => "rrf",
+            ScoreSource::VectorFallback => "vector fallback",
+            ScoreSource::Keyword => "keyword",
+        })
+    }
+}
+
+#[derive(Clone)]
+struct RankedDoc {
+    id: String,
+    text: String,
+    distance: f64,
+    // The distance as a similarity where higher is closer (see Metric::similarity)
+    similarity: f64,
+    metadata: serde_json::Value,
+    score: f32,
+    source: ScoreSource,
+    // Which retrievers returned the document in --hybrid mode; empty otherwise
+    retrievers: Vec<&'static str>,
+    // Neighbouring chunks attached by --expand-context
+    expansion: Option<Expansion>,
+    // The reranker's own score, before --rerank-weight blends in the vector similarity
+    rerank_score: Option<f32>,
+    // Age in days and the --recency-boost factor the score was multiplied by, for documents with a timestamp
+    recency: Option<(f64, f64)>,
+}
+
+// Text of the chunks just before and after a matched chunk, in document order
+#[derive(Clone, Default)]
+struct Expansion {
+    before: String,
+    after: String,
+}
+
+impl RankedDoc {
+    // Scores a vector search hit by its similarity to the query under `metric`
+    fn from_vector(hit: Hit, metric: Metric) -> Self {
+        let Hit { id, text, distance, metadata } = hit;
+        let similarity = metric.similarity(distance);
+        Self {
+            id,
+            text,
+            distance,
+            similarity,
+            metadata,
+            score: similarity as f32,
+            source: ScoreSource::Vector,
+            retrievers: Vec::new(),
+            expansion: None,
+            rerank_score: None,
+            recency: None,
+        }
+    }
+
+    // Scores a keyword-only match by its full-text rank; there's no vector to measure a distance from
+    fn from_keyword(id: String, text: String, rank: f64, metadata: serde_json::Value) -> Self {
+        Self {
+            id,
+            text,
+            distance: f64::NAN,
+            similarity: f64::NAN,
+            metadata,
+            score: rank as f32,
+            source: ScoreSource::Keyword,
+            retrievers: Vec::new(),
+            expansion: None,
+            rerank_score: None,
+            recency: None,
+        }
+    }
+}
+
+// Reorders nearest-first rows by Maximal Marginal Relevance and keeps the first `count`,
+// using the similarity to the query as the relevance
+fn diversify(hits: Vec<Hit>, vectors: &HashMap<String, Vec<f32>>, lambda: f32, count: usize, metric: Metric) -> Result<Vec<Hit>, Box<dyn Error>> {
+    let relevance: Vec<f32> = hits.iter().map(|hit| metric.similarity(hit.distance) as f32).collect();
+    let vectors = hits
+        .iter()
+        .map(|hit| vectors.get(&hit.id).cloned().ok_or_else(|| format!("document {} has no vector", hit.id)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let order = mmr::select(&relevance, &vectors, lambda, count);
+    let mut hits: Vec<Option<Hit>> = hits.into_iter().map(Some).collect();
+    Ok(order.into_iter().filter_map(|i| hits[i].take()).collect())
+}
+
+// Reciprocal Rank Fusion: every retriever adds 1 / (k + rank) for each document it returned.
+// Equal scores keep the order documents were first seen in, so the vector ranking breaks ties.
+fn fuse_rrf(retrievers: Vec<(&'static str, Vec<RankedDoc>)>, k: u32) -> Vec<RankedDoc> {
+    let mut fused: Vec<RankedDoc> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (name, docs) in retrievers {
+        for (rank, mut doc) in docs.into_iter().enumerate() {
+            let index = *positions.entry(doc.id.clone()).or_insert_with(|| {
+                doc.score = 0.0;
+                doc.source = ScoreSource::Fused;
+                fused.push(doc);
+                fused.len() - 1
+            });
+            fused[index].score += 1.0 / (k as f32 + rank as f32 + 1.0);
+            if !fused[index].retrievers.contains(&name) {
+                fused[index].retrievers.push(name);
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    fused
+}
+
+// Structured output schema requested for --expand
+#[derive(Deserialize, JsonSchema)]
+struct QueryExpansions {
+    queries: Vec<String>,
+}
+
+// Structured output schema requested from the reranker
+#[derive(Deserialize, JsonSchema)]
+struct RerankScore {
+    score: f32,
+}
+
+struct Reranker<'a> {
+    generator: &'a dyn Generator,
+    model: String,
+    mode: RerankMode,
+    retry_policy: RetryPolicy,
+    template: String,
+    max_chars: usize,
+    score_tolerance: f32,
+    debug: bool,
+    cache: Option<&'a RerankCache>,
+    // Scores this reranker took from the cache instead of the model
+    cache_hits: AtomicUsize,
+    explain: Option<&'a Explain>,
+}
+
+impl Reranker<'_> {
+    /// Asks the reranker model for a relevance score in [0, 1] and says how it was read from the reply,
+    /// or returns `None` if the call fails or the reply contains no usable judgement.
+    async fn score(&self, query: &str, id: &str, document_text: &str) -> Option<(f32, ScoreSource)> {
+        let started = Instant::now();
+        let record = |cached: bool| {
+            if let Some(explain) = self.explain {
+                explain.log().reranks.push(RerankTiming { id: id.to_string(), duration: started.elapsed(), cached });
+            }
+        };
+        let prompt_text = truncate_middle(document_text, self.max_chars);
+        let rerank_prompt = render_prompt(&self.template, query, "{document}", &prompt_text);
+        let mode = self.mode.to_string();
+        if let Some(cache) = self.cache {
+            if let Some((score, from_text)) = cache.get(&self.model, &mode, &rerank_prompt).await {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                record(true);
+                if self.debug {
+                    info!(phase = "rerank", path = %id, "\n--- Rerank debug: {} ---\nPrompt:\n{}\n\nCached score {} (pass --no-rerank-cache to ask the model)", id, rerank_prompt, score);
+                }
+                return Some((score, if from_text { ScoreSource::RerankText } else { ScoreSource::Rerank }));
+            }
+        }
+
+        let response = with_retry(&self.retry_policy, &format!("Reranking {}", id), || {
+            let rerank_request = GenerationRequest::new(
+                self.model.clone(),
+                rerank_prompt.as_str(),
+            );
+            let rerank_request = match self.mode {
+                RerankMode::Score => rerank_request.format(FormatType::StructuredJson(Box::new(JsonStructure::new::<RerankScore>()))),
+                // Greedy decoding of a few tokens is enough for the answer and keeps judgements repeatable
+                RerankMode::YesNo => rerank_request
+                    .raw(true)
+                    .logprobs(true)
+                    .options(ModelOptions::default().temperature(0.0).num_predict(4)),
+            };
+            self.generator.generate(rerank_request)
+        })
+        .await;
+        record(false);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Reranking failed for document {}: {}", id, e);
+                return None;
+            }
+        };
+
+        let reply = response.response.trim();
+        let (score, parsed) = self.parse_reply(reply, response.logprobs.as_deref());
+        if self.debug {
+            // A single write per document keeps concurrent reranks from interleaving
+            info!(
+                phase = "rerank",
+                path = %id,
+                "\n--- Rerank debug: {} ---\nPrompt:\n{}\n\nRaw response:\n{}\n\nParsed: {}",
+                id, rerank_prompt, response.response, parsed
+            );
+        }
+        if score.is_none() {
+            match self.mode {
+                RerankMode::YesNo => warn!("Expected a yes/no rerank reply but got '{}' for document {}", reply, id),
+                RerankMode::Score => warn!("Could not get a rerank score from reply '{}' for document {} ({})", reply, id, parsed),
+            }
+        }
+        if let (Some(cache), Some((score, source))) = (self.cache, score) {
+            if let Err(e) = cache.put(&self.model, &mode, &rerank_prompt, score, source == ScoreSource::RerankText).await {
+                warn!("Failed to cache the rerank score for document {}: {}", id, e);
+            }
+        }
+        score
+    }
+
+    // Reads the score from a reply, along with a description of what was parsed for --debug-rerank
+    fn parse_reply(&self, reply: &str, logprobs: Option<&[LogprobsData]>) -> (Option<(f32, ScoreSource)>, String) {
+        if self.mode == RerankMode::YesNo {
+            return match yes_no_score(reply, logprobs) {
+                Some(score) => (Some((score, ScoreSource::Rerank)), format!("yes/no answer, score {:.4}", score)),
+                None => (None, "no yes/no answer found".to_string()),
+            };
+        }
+
+        // Prefer the structured reply, then the first number anywhere in the text
+        if let Ok(parsed) = serde_json::from_str::<RerankScore>(reply) {
+            return self.in_range(parsed.score, ScoreSource::Rerank, format!("structured JSON score {}", parsed.score));
+        }
+        let Some(number) = FIRST_NUMBER.find(reply) else {
+            return (None, "no number found".to_string());
+        };
+        let line_number = reply[..number.start()].matches('\n').count() + 1;
+        let line = reply.lines().nth(line_number - 1).unwrap_or_default();
+        let description = format!("number {} from line {}: '{}'", number.as_str(), line_number, line);
+        match number.as_str().parse::<f32>() {
+            Ok(score) => self.in_range(score, ScoreSource::RerankText, description),
+            Err(_) => (None, description),
+        }
+    }
+
+    // Brings a parsed score into [0, 1] (see rerank::normalize_score), noting any adjustment in the description
+    fn in_range(&self, score: f32, source: ScoreSource, description: String) -> (Option<(f32, ScoreSource)>, String) {
+        match rerank::normalize_score(score, self.score_tolerance) {
+            Some((normalized, ScoreAdjustment::None)) => (Some((normalized, source)), description),
+            Some((normalized, ScoreAdjustment::Clamped)) => (Some((normalized, source)), format!("{}, clamped to {}", description, normalized)),
+            Some((normalized, ScoreAdjustment::Percent)) => (Some((normalized, source)), format!("{}, read as a percentage: {}", description, normalized)),
+            None => (None, format!("{}, outside [0, 1] on an unknown scale", description)),
+        }
+    }
+}
+
+// Maps a yes/no reply to a score: P(yes) when the server returns the answer token's log probability
+// (assuming the remaining mass is on the other answer), otherwise 1.0 for yes and 0.0 for no
+fn yes_no_score(reply: &str, logprobs: Option<&[LogprobsData]>) -> Option<f32> {
+    let answer = reply.split_whitespace().next()?.trim_matches(|c: char| !c.is_alphabetic()).to_lowercase();
+    let is_yes = match answer.as_str() {
+        "yes" => true,
+        "no" => false,
+        _ => return None,
+    };
+    let probability = logprobs
+        .and_then(|tokens| tokens.iter().find(|t| t.token.trim().eq_ignore_ascii_case(&answer)))
+        .map(|t| t.logprob.exp() as f32);
+    Some(match (is_yes, probability) {
+        (true, Some(p)) => p,
+        (false, Some(p)) => 1.0 - p,
+        (true, None) => 1.0,
+        (false, None) => 0.0,
+    })
+}
+
+// Splits text into pieces of at most `max_chars` characters, breaking after newlines where possible
+fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+    for line in text.split_inclusive('\n') {
+        for piece in line.chars().collect::<Vec<_>>().chunks(max_chars) {
+            if current_chars + piece.len() > max_chars && !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+            current.extend(piece);
+            current_chars += piece.len();
+        }
+    }
+    if !current.is_empty() || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+// Combines chunk embeddings component-wise into a single vector
+fn pool_vectors(vectors: &[Vec<f32>], pooling: Pooling) -> Option<Vec<f32>> {
+    let first = vectors.first()?;
+    let mut pooled = first.clone();
+    for vector in &vectors[1..] {
+        for (acc, &value) in pooled.iter_mut().zip(vector) {
+            match pooling {
+                Pooling::Mean => *acc += value,
+                Pooling::Max => *acc = acc.max(value),
+            }
+        }
+    }
+    if pooling == Pooling::Mean {
+        pooled.iter_mut().for_each(|component| *component /= vectors.len() as f32);
+    }
+    Some(pooled)
+}
+
+// Escapes LIKE wildcards so a path prefix like `src/my_module` matches literally
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+struct Answerer<'a> {
+    generator: &'a dyn Generator,
+    interrupt: &'a Interrupt,
+    model: &'a str,
+    retry_policy: RetryPolicy,
+    template: &'a str,
+    context_tokens: usize,
+    truncate_context: bool,
+    stream: bool,
+}
+
+impl Answerer<'_> {
+    /// Generates an answer from the documents that fit the context budget, returning it with those documents.
+    async fn answer(&self, query: &str, docs: &[RankedDoc]) -> Result<(String, Vec<RankedDoc>), Box<dyn Error>> {
+        let (context, context_docs) = pack_context(docs, self.context_tokens, self.truncate_context, false);
+        if context_docs.is_empty() {
+            return Err(format!("not even the first line of the top document fits --context-tokens {}", self.context_tokens).into());
+        }
+        info!("Generating answer with {} from {} documents...", self.model, context_docs.len());
+
+        let prompt = render_prompt(self.template, query, "{context}", &context);
+        let response = if self.stream {
+            self.stream_answer(&prompt).await?
+        } else {
+            // A whole answer can take minutes on a CPU, so it isn't held to --request-timeout
+            with_retry(&self.retry_policy.clone().with_timeout(None), "Answer generation", || {
+                self.generator.generate(GenerationRequest::new(self.model.to_string(), prompt.as_str()))
+            })
+            .await?
+            .response
+        };
+        let answer = &response[answer_start(&response).unwrap_or(0)..];
+        Ok((answer.trim().to_string(), context_docs))
+    }
+
+    /// Writes a short hypothetical document answering the query, for --hyde to search with.
+    async fn hypothesize(&self, query: &str, model: &str, template: &str) -> Result<String, Box<dyn Error>> {
+        let prompt = template.replace("{query}", query);
+        let response = with_retry(&self.retry_policy, "Hypothetical answer generation", || {
+            self.generator.generate(GenerationRequest::new(model.to_string(), prompt.as_str()))
+        })
+        .await?
+        .response;
+        Ok(response[answer_start(&response).unwrap_or(0)..].trim().to_string())
+    }
+
+    /// Asks the model for up to `count` paraphrases or sub-questions of the query to search with as well.
+    async fn expand(&self, query: &str, count: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        let prompt = render_prompt(DEFAULT_EXPANSION_PROMPT, query, "{count}", &count.to_string());
+        let response = with_retry(&self.retry_policy, "Query expansion", || {
+            let request = GenerationRequest::new(self.model.to_string(), prompt.as_str())
+                .format(FormatType::StructuredJson(Box::new(JsonStructure::new::<QueryExpansions>())));
+            self.generator.generate(request)
+        })
+        .await?
+        .response;
+        let expansions: QueryExpansions = serde_json::from_str(response[answer_start(&response).unwrap_or(0)..].trim())
+            .map_err(|e| format!("unexpected reply '{}': {}", response.trim(), e))?;
+        let mut variants: Vec<String> = Vec::new();
+        for variant in expansions.queries.iter().map(|variant| variant.trim()) {
+            if !variant.is_empty() && variant != query && !variants.iter().any(|seen| seen == variant) {
+                variants.push(variant.to_string());
+            }
+        }
+        variants.truncate(count);
+        Ok(variants)
+    }
+
+    /// Rewrites a chat follow-up into a standalone search query using the conversation so far.
+    async fn condense(&self, transcript: &str, message: &str) -> Result<String, Box<dyn Error>> {
+        let prompt = render_prompt(DEFAULT_CONDENSE_PROMPT, message, "{history}", transcript);
+        let response = with_retry(&self.retry_policy, "Query rewriting", || {
+            self.generator.generate(GenerationRequest::new(self.model.to_string(), prompt.as_str()))
+        })
+        .await?
+        .response;
+        let query = response[answer_start(&response).unwrap_or(0)..].trim();
+        // Searching for nothing helps no one, so an empty rewrite keeps the message as typed
+        Ok(if query.is_empty() { message.to_string() } else { query.to_string() })
+    }
+
+    // Prints tokens as they arrive and returns the full response. Only the initial request is retried.
+    // Ctrl-C drops the stream, which closes the connection so the server stops generating, and returns
+    // a `Cancelled` error.
+    async fn stream_answer(&self, prompt: &str) -> Result<String, Box<dyn Error>> {
+        let mut stream = with_retry(&self.retry_policy, "Answer generation", || {
+            self.generator.generate_stream(GenerationRequest::new(self.model.to_string(), prompt))
+        })
+        .await?;
+        let mut interrupted = self.interrupt.subscribe();
+        println!("\n--- Answer ({}) ---\n", self.model);
+
+        let mut stdout = std::io::stdout();
+        let mut response = String::new();
+        let mut shown = 0;
+        loop {
+            let chunk = tokio::select! {
+                chunk = stream.next() => chunk,
+                _ = interrupted.changed() => {
+                    drop(stream);
+                    println!();
+                    return Err(Box::new(Cancelled));
+                }
+            };
+            let Some(chunk) = chunk else { break };
+            for part in chunk? {
+                response.push_str(&part.response);
+            }
+            // Hold output back while a leading <think> block streams, and skip whitespace before the answer
+            let Some(start) = answer_start(&response) else { continue };
+            let visible = response[start..].trim_start();
+            if visible.is_empty() {
+                continue;
+            }
+            let from = shown.max(response.len() - visible.len());
+            print!("{}", &response[from..]);
+            stdout.flush()?;
+            shown = response.len();
+        }
+        println!();
+        Ok(response)
+    }
+}
+
+// The process's one Ctrl-C handler, installed at startup. Code that can stop early, such as a streaming
+// answer, subscribes while it runs and is told of each Ctrl-C; with no subscriber, Ctrl-C exits with 130
+// as it would without a handler.
+#[derive(Clone)]
+struct Interrupt(Arc<tokio::sync::watch::Sender<()>>);
+
+impl Interrupt {
+    fn new() -> Self {
+        Interrupt(Arc::new(tokio::sync::watch::channel(()).0))
+    }
+
+    fn install() -> Self {
+        let interrupt = Interrupt::new();
+        let handler = interrupt.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                handler.raise();
+            }
+        });
+        interrupt
+    }
+
+    fn subscribe(&self) -> tokio::sync::watch::Receiver<()> {
+        self.0.subscribe()
+    }
+
+    fn raise(&self) {
+        if self.0.send(()).is_err() {
+            std::process::exit(130);
+        }
+    }
+}
+
+// Returned when Ctrl-C stops a streaming answer, so callers can tell it from a failure
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Answer generation cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+// Byte offset where the visible answer starts: after a leading <think>...</think> block that reasoning
+// models emit, or None while that block (or something that may become its opening tag) is still arriving
+fn answer_start(response: &str) -> Option<usize> {
+    const OPEN: &str = "<think>";
+    const CLOSE: &str = "</think>";
+    let trimmed = response.trim_start();
+    if trimmed.starts_with(OPEN) {
+        return response.find(CLOSE).map(|i| i + CLOSE.len());
+    }
+    if OPEN.starts_with(trimmed) {
+        return None;
+    }
+    Some(0)
+}
+
+// Explains why the page has fewer than --top-n results: what the SQL filters let through, then what the
+// thresholds and --per-file removed from it
+fn warn_on_shortfall(args: &Args, limit: i32, summary: &RankSummary, returned: usize) {
+    let mut reasons = Vec::new();
+    if summary.retrieved < limit.max(0) as usize {
+        let filters = sql_filters(args);
+        reasons.push(match filters.is_empty() {
+            true => format!("the index has only {} matching documents", summary.retrieved),
+            false => format!("retrieval found only {} documents matching {}", summary.retrieved, filters.join(" ")),
+        });
+    }
+    if summary.below_threshold > 0 {
+        reasons.push(format!("{} fell below --min-score/--min-similarity", summary.below_threshold));
+    }
+    if let (Some(per_file), true) = (args.per_file, summary.per_file_dropped > 0) {
+        reasons.push(format!("--per-file {} dropped {} from files already shown", per_file, summary.per_file_dropped));
+    }
+    if args.offset > 0 {
+        reasons.push(format!("--offset {} skipped the first results", args.offset));
+    }
+    let hint = match summary.retrieved >= limit.max(0) as usize {
+        true => " Raise --limit to rank more candidates.",
+        false => "",
+    };
+    warn!("only {} of the {} requested results are shown: {}.{}", returned, args.top_n, reasons.join("; "), hint);
+}
+
+// The SQL-side filters in effect, as the flags that set them
+fn sql_filters(args: &Args) -> Vec<String> {
+    let mut filters = Vec::new();
+    if !args.languages.is_empty() {
+        filters.push(format!("--language {}", args.languages.join(",")));
+    }
+    if let Some(path_prefix) = &args.path_prefix {
+        filters.push(format!("--path-prefix {}", path_prefix));
+    }
+    for glob in &args.exclude_paths {
+        filters.push(format!("--exclude-path {}", glob));
+    }
+    if let Some(max_distance) = args.max_distance {
+        filters.push(format!("--max-distance {}", max_distance));
+    }
+    if args.exclude_generated {
+        filters.push("--exclude-generated".to_string());
+    }
+    if let Some(source) = &args.source {
+        filters.push(format!("--source {}", source));
+    }
+    if let Some(after) = args.after {
+        filters.push(format!("--after {}", after.to_rfc3339()));
+    }
+    for filter in &args.metadata_filters {
+        filters.push(format!("--filter {}", filter));
+    }
+    match (&args.refine, &args.within_ids_file) {
+        (Some(path), _) => filters.push(format!("--refine {}", path.display())),
+        (None, Some(path)) => filters.push(format!("--within-ids-file {}", path.display())),
+        (None, None) => {}
+    }
+    filters
+}
+
+// What --save-session writes: the ids of a final ranking, with what was searched to get them so --refine
+// can check a later query searches the same collection
+#[derive(Serialize, Deserialize)]
+struct Session {
+    query: String,
+    database: String,
+    tables: Vec<String>,
+    // The SQL-side filters, as the flags that set them
+    filters: Vec<String>,
+    ids: Vec<String>,
+}
+
+impl Session {
+    fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read session file {}: {}", path.display(), e))?;
+        serde_json::from_str(&content).map_err(|e| format!("{} is not a session file written by --save-session: {}", path.display(), e).into())
+    }
+
+    // The saved ids, if the session searched the same database and tables as `args`
+    fn ids_for(self, path: &Path, database: &str, args: &Args) -> Result<Vec<String>, Box<dyn Error>> {
+        if self.database != database || self.tables != args.table {
+            return Err(format!(
+                "{} was saved from --table {} in database {}, but this query searches --table {} in {}",
+                path.display(),
+                self.tables.join(","),
+                self.database,
+                args.table.join(","),
+                database
+            )
+            .into());
+        }
+        if self.ids.is_empty() {
+            return Err(format!("{} holds no results to refine", path.display()).into());
+        }
+        let filters = sql_filters(args);
+        if self.filters != filters {
+            info!("The session was saved with filters [{}]; this query's filters [{}] apply on top.", self.filters.join(" "), filters.join(" "));
+        }
+        info!("Refining the {} results of {:?}.", self.ids.len(), self.query);
+        Ok(self.ids)
+    }
+}
+
+// Writes the final ranking's ids to `path` for a later --refine
+fn save_session(path: &Path, report: &Report, args: &Args, ranking: &[RankedDoc]) -> Result<(), Box<dyn Error>> {
+    let session = Session {
+        query: report.query.to_string(),
+        database: report.collection.clone(),
+        tables: args.table.clone(),
+        filters: sql_filters(args),
+        ids: ranking.iter().map(|doc| doc.id.clone()).collect(),
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&session)? + "\n").map_err(|e| format!("Failed to write session file {}: {}", path.display(), e))?;
+    info!("Saved {} result ids to {}.", session.ids.len(), path.display());
+    Ok(())
+}
+
+// One id per line; blank lines are skipped
+fn read_ids_file(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read ids file {}: {}", path.display(), e))?;
+    let ids: Vec<String> = content.lines().map(str::trim).filter(|id| !id.is_empty()).map(str::to_string).collect();
+    if ids.is_empty() {
+        return Err(format!("{} lists no ids", path.display()).into());
+    }
+    Ok(ids)
+}
+
+// Keeps at most `per_file` documents from each path, in rank order, so lower-ranked documents from
+// other files move up to fill the shown results
+fn limit_per_file(docs: Vec<RankedDoc>, per_file: usize) -> Vec<RankedDoc> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    docs.into_iter()
+        .filter(|doc| {
+            let count = counts.entry(document_path(doc).to_string()).or_insert(0);
+            *count += 1;
+            *count <= per_file
+        })
+        .collect()
+}
+
+// (path, chunk_index) of a document that is one chunk of a larger file
+fn chunk_position(doc: &RankedDoc) -> Option<(String, i64)> {
+    let path = doc.metadata.get("path")?.as_str()?;
+    let index = doc.metadata.get("chunk_index")?.as_i64()?;
+    Some((path.to_string(), index))
+}
+
+// Attaches up to `radius` neighbouring chunks on each side of every chunked document, fetched with one
+// query, and returns how many were attached. Each chunk is shown at most once: results never absorb
+// another result's chunk, a neighbour shared by two results goes to the higher-ranked one, and a side
+// stops growing at the first chunk that's missing, already shown or over the `max_chars` total.
+async fn expand_context(pool: &PgPool, table: &str, docs: &mut [RankedDoc], radius: usize, max_chars: usize) -> Result<usize, Box<dyn Error>> {
+    let positions: Vec<Option<(String, i64)>> = docs.iter().map(chunk_position).collect();
+    let (paths, indexes): (Vec<String>, Vec<i64>) = positions.iter().flatten().cloned().unzip();
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    let radius = radius as i64;
+    let sql = format!(
+        r#"
+        SELECT DISTINCT e.metadata->>'path', (e.metadata->>'chunk_index')::bigint, e.text
+        FROM {table} e
+        JOIN unnest($1::text[], $2::bigint[]) AS hit(path, chunk_index)
+          ON e.metadata->>'path' = hit.path
+         AND (e.metadata->>'chunk_index')::bigint BETWEEN hit.chunk_index - $3 AND hit.chunk_index + $3;
+        "#,
+    );
+    let rows: Vec<(String, i64, String)> = sqlx::query_as(&sql)
+        .bind(&paths)
+        .bind(&indexes)
+        .bind(radius)
+        .fetch_all(pool)
+        .await?;
+    let chunks: HashMap<(String, i64), String> = rows.into_iter().map(|(path, index, text)| ((path, index), text)).collect();
+
+    let mut shown: HashSet<(String, i64)> = positions.iter().flatten().cloned().collect();
+    let mut budget = max_chars;
+    let mut added = 0;
+    for (doc, position) in docs.iter_mut().zip(&positions) {
+        let Some((path, index)) = position else { continue };
+        let mut take = |neighbour: i64| -> Option<&String> {
+            let key = (path.clone(), neighbour);
+            let text = chunks.get(&key).filter(|text| !shown.contains(&key) && text.len() <= budget)?;
+            budget -= text.len();
+            shown.insert(key);
+            added += 1;
+            Some(text)
+        };
+        let mut before: Vec<&String> = (1..=radius).map_while(|distance| take(index - distance)).collect();
+        before.reverse();
+        let after: Vec<&String> = (1..=radius).map_while(|distance| take(index + distance)).collect();
+        if !before.is_empty() || !after.is_empty() {
+            doc.expansion = Some(Expansion { before: join_chunks(&before), after: join_chunks(&after) });
+        }
+    }
+    Ok(added)
+}
+
+// Concatenates consecutive chunks, keeping each one on its own lines
+fn join_chunks(chunks: &[&String]) -> String {
+    let mut joined = String::new();
+    for chunk in chunks {
+        if !joined.is_empty() && !joined.ends_with('\n') {
+            joined.push('\n');
+        }
+        joined.push_str(chunk);
+    }
+    joined
+}
+
+// The --offset page of --top-n results from the final ranking
+fn page<'d>(args: &Args, ranking: &'d [RankedDoc]) -> &'d [RankedDoc] {
+    let start = (args.offset as usize).min(ranking.len());
+    &ranking[start..(start + args.top_n).min(ranking.len())]
+}
+
+// Asks on the terminal whether to show the next --page; `remaining` results are left to show
+fn next_page_wanted(remaining: usize) -> Result<bool, Box<dyn Error>> {
+    eprint!("\n-- {} more results. Press Enter or n for the next page, q to quit: ", remaining);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        return Ok(false);
+    }
+    Ok(matches!(answer.trim(), "" | "n" | "N"))
+}
+
+// Path a document was ingested from, falling back to its id
+fn document_path(doc: &RankedDoc) -> &str {
+    doc.metadata.get("path").and_then(|path| path.as_str()).unwrap_or(&doc.id)
+}
+
+// Stored paths start with the ingest root (`../` by default); dropping leading `./` and `../` segments
+// leaves the path within the repository
+fn without_leading_parents(path: &str) -> &str {
+    let mut relative = path;
+    while let Some(rest) = relative.strip_prefix("./").or_else(|| relative.strip_prefix("../")) {
+        relative = rest;
+    }
+    relative
+}
+
+// Where a document's file is on this machine: under --root when given, otherwise under the `ingest_dir`
+// the ingester recorded (older rows without one resolve from the current directory)
+fn local_path(doc: &RankedDoc, root: Option<&Path>) -> Result<PathBuf, Box<dyn Error>> {
+    let stored = document_path(doc);
+    let resolved = match (root, doc.metadata.get("ingest_dir").and_then(|dir| dir.as_str())) {
+        _ if Path::new(stored).is_absolute() => PathBuf::from(stored),
+        (Some(root), _) => root.join(without_leading_parents(stored)),
+        (None, Some(ingest_dir)) => Path::new(ingest_dir).join(stored),
+        (None, None) => PathBuf::from(stored),
+    };
+    if !resolved.is_file() {
+        return Err(format!("{} doesn't exist locally (looked for {}); pass --root with the path of your checkout", stored, resolved.display()).into());
+    }
+    Ok(resolved)
+}
+
+// How editors take the line to open a file at, by program name
+const EDITOR_LINE_SYNTAX: &[(&[&str], EditorLine)] = &[
+    (&["vi", "vim", "nvim", "gvim", "nano", "emacs", "emacsclient", "kak", "micro", "ne"], EditorLine::Plus),
+    (&["code", "code-insiders", "codium", "cursor", "windsurf"], EditorLine::Goto),
+    (&["subl", "hx", "helix", "zed", "mate"], EditorLine::Colon),
+];
+
+#[derive(Debug, Clone, Copy)]
+enum EditorLine {
+    // vim +12 path
+    Plus,
+    // code --goto path:12
+    Goto,
+    // subl path:12
+    Colon,
+}
+
+// Opens result `number` (as numbered in the output) in $EDITOR at its first line, waiting for the editor to exit
+fn open_result(args: &Args, ranking: &[RankedDoc], number: usize) -> Result<(), Box<dyn Error>> {
+    let doc = number
+        .checked_sub(1)
+        .and_then(|index| ranking.get(index))
+        .ok_or_else(|| format!("--open {} is not a result number; there are {} results", number, ranking.len()))?;
+    let path = local_path(doc, args.root.as_deref())?;
+    let (line, _, _) = first_line(doc);
+
+    let editor = std::env::var("EDITOR").map_err(|_| "$EDITOR is not set; set it to the editor --open should launch")?;
+    // $EDITOR may carry its own arguments, such as `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or("$EDITOR is empty")?;
+    let name = Path::new(program).file_stem().and_then(|name| name.to_str()).unwrap_or(program);
+    let mut command = std::process::Command::new(program);
+    command.args(words);
+    match EDITOR_LINE_SYNTAX.iter().find(|(names, _)| names.contains(&name)).map(|(_, syntax)| *syntax) {
+        Some(EditorLine::Plus) => command.arg(format!("+{}", line)).arg(&path),
+        Some(EditorLine::Goto) => command.arg("--goto").arg(format!("{}:{}", path.display(), line)),
+        Some(EditorLine::Colon) => command.arg(format!("{}:{}", path.display(), line)),
+        None => {
+            warn!("don't know how {} takes a line number; opening {} at the top.", name, path.display());
+            command.arg(&path)
+        }
+    };
+    info!("Opening {} at line {} with {}...", path.display(), line, name);
+    let status = command.status().map_err(|e| format!("Failed to start $EDITOR ({}): {}", editor, e))?;
+    if !status.success() {
+        return Err(format!("$EDITOR ({}) exited with {}", editor, status).into());
+    }
+    Ok(())
+}
+
+// Asks on the terminal which of `count` results to open; None when the answer is empty or input ends
+fn pick_result(count: usize) -> Result<Option<usize>, Box<dyn Error>> {
+    if !std::io::stdin().is_terminal() {
+        return Err("--open without a number asks on the terminal; pass the result number, e.g. --open 1".into());
+    }
+    loop {
+        eprint!("\nOpen which result? (1-{}, Enter to skip): ", count);
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(None);
+        }
+        match answer.trim() {
+            "" | "q" => return Ok(None),
+            answer => match answer.parse::<usize>() {
+                Ok(number) if (1..=count).contains(&number) => return Ok(Some(number)),
+                _ => eprintln!("Enter a number from 1 to {}.", count),
+            },
+        }
+    }
+}
+
+// Packs documents, best first, into `budget` tokens and reports what didn't fit and why (or, with `all`,
+// every document's placement). Returns the context and the documents in it, numbered as it cites them.
+fn pack_context(docs: &[RankedDoc], budget: usize, truncate_last: bool, all: bool) -> (String, Vec<RankedDoc>) {
+    let documents: Vec<(&str, &str)> = docs.iter().map(|doc| (document_path(doc), doc.text.as_str())).collect();
+    let packed = pack::pack(&documents, budget, truncate_last);
+    let included = packed.included();
+    if all || packed.decisions.iter().any(|d| d.placement != Placement::Included) {
+        info!("Packed {} of {} documents into {} of {} context tokens:", included.len(), docs.len(), packed.tokens, budget);
+        for (doc, decision) in docs.iter().zip(&packed.decisions) {
+            let outcome = match decision.placement {
+                Placement::Included => format!("included ({} tokens)", decision.tokens),
+                Placement::Truncated { kept, lines } => format!("truncated to its first {} of {} lines ({} tokens whole)", kept, lines, decision.tokens),
+                Placement::Cut { kept, chars } => format!("cut to its first {} of {} characters ({} tokens whole)", kept, chars, decision.tokens),
+                Placement::Excluded { remaining } => format!("left out, {} tokens with {} of the budget left", decision.tokens, remaining),
+            };
+            if all || decision.placement != Placement::Included {
+                info!("  {}: {}", document_path(doc), outcome);
+            }
+        }
+    }
+    (packed.context, included.into_iter().map(|i| docs[i].clone()).collect())
+}
+
+// Prints the answer followed by the documents it cited ([n] markers), or as a single JSON object.
+// A `streamed` answer is already on screen, so only the sources are printed.
+fn print_answer(format: OutputFormat, report: &Report, answer: &str, model: &str, context_docs: &[RankedDoc], streamed: bool) -> Result<(), Box<dyn Error>> {
+    let cited: std::collections::BTreeSet<usize> = CITATION
+        .captures_iter(answer)
+        .filter_map(|c| c[1].parse().ok())
+        .filter(|n| (1..=context_docs.len()).contains(n))
+        .collect();
+
+    if format == OutputFormat::Markdown {
+        let mut out = report.writer()?;
+        report.write_header(&mut out)?;
+        writeln!(out, "## Answer ({})\n\n{}\n\n## Sources\n", model, answer)?;
+        for (i, doc) in context_docs.iter().enumerate() {
+            let cited_marker = if cited.contains(&(i + 1)) { "" } else { " (not cited)" };
+            writeln!(out, "{}. {}{}", i + 1, report.path_link(doc), cited_marker)?;
+        }
+        out.flush()?;
+        return Ok(());
+    }
+    if format != OutputFormat::Human {
+        let sources: Vec<serde_json::Value> = context_docs
+            .iter()
+            .enumerate()
+            .map(|(i, doc)| json!({ "marker": i + 1, "id": doc.id, "path": document_path(doc), "score": doc.score, "cited": cited.contains(&(i + 1)) }))
+            .collect();
+        let result = json!({ "answer": answer, "answer_model": model, "sources": sources });
+        let rendered = if format == OutputFormat::Jsonl { serde_json::to_string(&result)? } else { serde_json::to_string_pretty(&result)? };
+        let mut out = report.writer()?;
+        writeln!(out, "{}", rendered)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    if !streamed {
+        println!("\n--- Answer ({}) ---\n", model);
+        println!("{}", answer);
+    }
+    if cited.is_empty() {
+        println!("\nThe answer cited no sources. Context documents:");
+        for (i, doc) in context_docs.iter().enumerate() {
+            println!("[{}] {}", i + 1, document_path(doc));
+        }
+    } else {
+        println!("\nSources:");
+        for n in cited {
+            println!("[{}] {}", n, document_path(&context_docs[n - 1]));
+        }
+    }
+    Ok(())
+}
+
+// Every searched table must hold vectors the query's can be compared with. The default table gets
+// schema::check_dimension's advice on recreating it; other tables were set up by hand.
+async fn check_dimension(pool: &sqlx::PgPool, tables: &[String], dimension: usize) -> Result<(), Box<dyn Error>> {
+    for table in tables {
+        if table == schema::DEFAULT_TABLE {
+            schema::check_dimension(pool, dimension, false).await?;
+            continue;
+        }
+        match schema::column_dimension(pool, table).await? {
+            None => return Err(format!("table {} does not exist", table).into()),
+            Some(Some(column_dimension)) if column_dimension != dimension => {
+                return Err(format!("model produces {}-dim vectors but table {}.vector is vector({})", dimension, table, column_dimension).into());
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+async fn all_empty(pool: &sqlx::PgPool, tables: &[String]) -> Result<bool, Box<dyn Error>> {
+    for table in tables {
+        if !store::is_empty(pool, table).await? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Vectors embedded with different prefixes than the index was built with compare poorly, so flag it
+async fn warn_on_prefix_mismatch(pool: &sqlx::PgPool, tables: &[String], embedder_args: &EmbedderArgs) -> Result<(), Box<dyn Error>> {
+    let mut indexed: Vec<(Option<String>, Option<String>)> = Vec::new();
+    for table in tables {
+        let sql = format!(
+            r#"
+            SELECT DISTINCT metadata->>'query_prefix', metadata->>'document_prefix'
+            FROM {table}
+            WHERE metadata ? 'query_prefix'
+            LIMIT 10;
+            "#,
+        );
+        for prefixes in sqlx::query_as(&sql).fetch_all(pool).await? {
+            if !indexed.contains(&prefixes) {
+                indexed.push(prefixes);
+            }
+        }
+    }
+    for (query_prefix, document_prefix) in indexed {
+        if query_prefix.as_deref() != Some(embedder_args.query_prefix.as_str())
+            || document_prefix.as_deref() != Some(embedder_args.document_prefix.as_str())
+        {
+            warn!(
+                "index was built with query prefix {:?} and document prefix {:?}, but this query uses {:?} and {:?}",
+                query_prefix.unwrap_or_default(),
+                document_prefix.unwrap_or_default(),
+                embedder_args.query_prefix,
+                embedder_args.document_prefix
+            );
+        }
+    }
+    Ok(())
+}
+
+// The --metric to search with: the requested one, else the one the tables were built for, else cosine.
+// Warns when the request disagrees with the tables or they record more than one metric between them.
+async fn resolve_metric(pool: &sqlx::PgPool, tables: &[String], requested: Option<Metric>) -> Result<Metric, Box<dyn Error>> {
+    let mut recorded: Vec<Metric> = Vec::new();
+    for table in tables {
+        let sql = format!("SELECT DISTINCT metadata->>'metric' FROM {} WHERE metadata ? 'metric' LIMIT 10;", table);
+        let names: Vec<(String,)> = sqlx::query_as(&sql).fetch_all(pool).await?;
+        for metric in names.iter().filter_map(|(name,)| Metric::from_str(name, true).ok()) {
+            if !recorded.contains(&metric) {
+                recorded.push(metric);
+            }
+        }
+    }
+    match (requested, recorded.as_slice()) {
+        (Some(requested), recorded) if !recorded.is_empty() && !recorded.contains(&requested) => {
+            warn!("searching with --metric {} but the index was built for {}", requested, join_metrics(recorded));
+            Ok(requested)
+        }
+        (Some(requested), _) => Ok(requested),
+        (None, [metric]) => Ok(*metric),
+        (None, []) => Ok(Metric::Cosine),
+        (None, recorded) => {
+            warn!("the index records several metrics ({}); searching with cosine. Pass --metric to choose.", join_metrics(recorded));
+            Ok(Metric::Cosine)
+        }
+    }
+}
+
+fn join_metrics(metrics: &[Metric]) -> String {
+    metrics.iter().map(|metric| metric.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+// Whether the ingester stored unit-length vectors (run with --normalize) in any of the tables
+async fn index_is_normalized(pool: &sqlx::PgPool, tables: &[String]) -> Result<bool, Box<dyn Error>> {
+    for table in tables {
+        let sql = format!("SELECT EXISTS (SELECT 1 FROM {} WHERE metadata @> '{{\"normalized\": true}}');", table);
+        let (normalized,): (bool,) = sqlx::query_as(&sql).fetch_one(pool).await?;
+        if normalized {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// The query text in `path`, or on stdin for `-`
+fn read_query_text(path: &Path) -> Result<String, Box<dyn Error>> {
+    let text = match path.to_str() {
+        Some("-") => std::io::read_to_string(std::io::stdin()).map_err(|e| format!("Failed to read the query from stdin: {}", e))?,
+        _ => std::fs::read_to_string(path).map_err(|e| format!("Failed to read query text file {}: {}", path.display(), e))?,
+    };
+    if text.trim().is_empty() {
+        return Err(format!("The query read from {} is empty", if path.to_str() == Some("-") { "stdin".to_string() } else { path.display().to_string() }).into());
+    }
+    Ok(text.trim().to_string())
+}
+
+// Reads a prompt template from `path`, or uses `default`; templates need `{query}` plus their content `slot`
+fn load_prompt_template(path: Option<&PathBuf>, default: &str, slot: &str) -> Result<String, Box<dyn Error>> {
+    let Some(path) = path else {
+        return Ok(default.to_string());
+    };
+    let template = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read prompt file {}: {}", path.display(), e))?;
+    // Catch broken templates before spending time on embedding and retrieval
+    for placeholder in ["{query}", slot] {
+        if !template.contains(placeholder) {
+            return Err(format!("Prompt file {} is missing the {} placeholder", path.display(), placeholder).into());
+        }
+    }
+    Ok(template)
+}
+
+fn render_prompt(template: &str, query: &str, slot: &str, content: &str) -> String {
+    // Split on the content slot first so placeholder-like text inside the query is never expanded
+    template
+        .split(slot)
+        .map(|part| part.replace("{query}", query))
+        .collect::<Vec<_>>()
+        .join(content)
+}
+
+// Keep the first and last halves of `max_chars` so both a file's header and its tail reach the reranker
+fn truncate_middle(text: &str, max_chars: usize) -> std::borrow::Cow<'_, str> {
+    let char_count = text.chars().count();
+    if max_chars == 0 || char_count <= max_chars {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    let head: String = text.chars().take(max_chars / 2).collect();
+    let tail: String = text.chars().skip(char_count - (max_chars - max_chars / 2)).collect();
+    std::borrow::Cow::Owned(format!("{}\n[... {} characters omitted ...]\n{}", head, char_count - max_chars, tail))
+}
+
+// Common query words that would otherwise highlight half of every document
+const HIGHLIGHT_STOPWORDS: &[&str] = &[
+    "and", "are", "can", "does", "for", "from", "how", "into", "the", "that", "this", "what", "when", "where", "which", "who", "why", "with",
+];
+
+// Wraps query terms found in displayed snippets: ANSI bold red on a terminal, `**` markers when piped
+struct Highlighter {
+    terms: Vec<String>,
+    open: &'static str,
+    close: &'static str,
+}
+
+impl Highlighter {
+    fn new(query: &str, color: bool) -> Self {
+        // Significant terms are words of 3+ characters that aren't stopwords, longest first so
+        // "tokenizer" wins over "token" at the same position
+        let mut terms: Vec<String> = query
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map(str::to_lowercase)
+            .filter(|word| word.chars().count() >= 3 && !HIGHLIGHT_STOPWORDS.contains(&word.as_str()))
+            .collect();
+        terms.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        terms.dedup();
+
+        let (open, close) = if color { ("\x1b[1;31m", RESET) } else { ("**", "**") };
+        Self { terms, open, close }
+    }
+
+    /// Byte ranges of non-overlapping, case-insensitive (ASCII) term occurrences, in order.
+    fn find_matches(&self, text: &str) -> Vec<Range<usize>> {
+        let bytes = text.as_bytes();
+        let mut matches = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            let term = self.terms.iter().find(|term| {
+                let end = i + term.len();
+                end <= text.len() && bytes[i..end].eq_ignore_ascii_case(term.as_bytes()) && text.is_char_boundary(end)
+            });
+            match term {
+                Some(term) => {
+                    matches.push(i..i + term.len());
+                    i += term.len();
+                }
+                None => i += text[i..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        matches
+    }
+
+    fn render(&self, text: &str, window: Range<usize>, matches: &[Range<usize>]) -> String {
+        let mut rendered = String::with_capacity(window.len());
+        let mut position = window.start;
+        for m in matches.iter().filter(|m| m.start >= window.start && m.end <= window.end) {
+            rendered.push_str(&text[position..m.start]);
+            rendered.push_str(self.open);
+            rendered.push_str(&text[m.clone()]);
+            rendered.push_str(self.close);
+            position = m.end;
+        }
+        rendered.push_str(&text[position..window.end]);
+        rendered
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+// Colors human output on a terminal: snippets are syntax highlighted by their language, headers are bold
+// and truncation notices dim. Only loaded when color is on.
+struct Colors {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+impl Colors {
+    fn new() -> Self {
+        let mut themes = ThemeSet::load_defaults();
+        let theme = themes.themes.remove("base16-ocean.dark").unwrap_or_default();
+        Self { syntaxes: SyntaxSet::load_defaults_newlines(), theme }
+    }
+
+    fn paint(&self, text: &str, code: &str) -> String {
+        format!("\x1b[{}m{}{}", code, text, RESET)
+    }
+
+    // Highlights `text` by the stored language name, or failing that the path's extension; None for
+    // languages syntect doesn't know
+    fn highlight(&self, text: &str, language: Option<&str>, path: &str) -> Option<String> {
+        let extension = Path::new(path).extension().and_then(|extension| extension.to_str());
+        let syntax = language
+            .and_then(|language| self.syntaxes.find_syntax_by_token(language))
+            .or_else(|| extension.and_then(|extension| self.syntaxes.find_syntax_by_extension(extension)))?;
+        let mut lines = HighlightLines::new(syntax, &self.theme);
+        let mut highlighted = String::with_capacity(text.len() * 2);
+        for line in LinesWithEndings::from(text) {
+            let ranges = lines.highlight_line(line, &self.syntaxes).ok()?;
+            highlighted.push_str(&as_24_bit_terminal_escaped(&ranges, false));
+        }
+        highlighted.push_str(RESET);
+        Some(highlighted)
+    }
+}
+
+// Shrinks a snippet window to whole lines, and to at most `max_lines` of them. A partial first or last line
+// is dropped unless it is the only line, as in minified files.
+fn whole_lines(text: &str, window: Range<usize>, max_lines: Option<u32>) -> Range<usize> {
+    let (mut start, mut end) = (window.start, window.end);
+    if start > 0 && !text[..start].ends_with('\n') {
+        if let Some(newline) = text[start..end].find('\n') {
+            start += newline + 1;
+        }
+    }
+    if end < text.len() && !text[end..].starts_with('\n') {
+        if let Some(newline) = text[start..end].rfind('\n') {
+            end = start + newline;
+        }
+    }
+    if let Some(newline) = max_lines.and_then(|max_lines| text[start..end].match_indices('\n').nth(max_lines as usize - 1)) {
+        end = start + newline.0;
+    }
+    start..end
+}
+
+// Byte range of at most `max_len` graphemes, centred on the `focus` byte offset when given.
+// Cutting on grapheme boundaries means combining marks and emoji sequences are never split.
+fn snippet_range(text: &str, max_len: usize, focus: Option<usize>) -> Range<usize> {
+    let boundaries: Vec<usize> = text.grapheme_indices(true).map(|(i, _)| i).chain(std::iter::once(text.len())).collect();
+    let grapheme_count = boundaries.len() - 1;
+    let focus_index = focus.map_or(0, |byte| boundaries.partition_point(|&b| b <= byte) - 1);
+    let start = focus_index.saturating_sub(max_len / 2).min(grapheme_count.saturating_sub(max_len));
+    let end = (start + max_len).min(grapheme_count);
+    boundaries[start]..boundaries[end]
+}
+
+// Writes `docs`, the page shown, with full text and metadata. `total` is the number of results in the
+// whole ranking, for callers paging with --offset; `rerank` is the (model, template sha256) that scored
+// them and `thresholds` the (--min-score, --min-similarity) they passed. JSON is one object holding the
+// `results`, `total_retrieved`, the `summary` and with --explain the `timings`; JSON lines are the results
+// alone, one per line, followed by a `{"timings": ...}` line with --explain.
+fn print_json_results(out: &mut dyn Write, args: &Args, docs: &[RankedDoc], total: usize, rerank: Option<(&str, &str)>, summary: serde_json::Value, timings: Option<serde_json::Value>) -> Result<(), Box<dyn Error>> {
+    let thresholds = (args.min_score, args.min_similarity);
+    let results = docs.iter().enumerate().map(|(i, doc)| result_json(doc, args.offset as usize + i + 1, rerank, thresholds));
+
+    if args.format == OutputFormat::Jsonl {
+        // Flush per line so consumers can start on the first result immediately
+        for result in results {
+            serde_json::to_writer(&mut *out, &result)?;
+            writeln!(out)?;
+            out.flush()?;
+        }
+        if let Some(timings) = timings {
+            serde_json::to_writer(&mut *out, &json!({ "timings": timings }))?;
+            writeln!(out)?;
+        }
+    } else {
+        let mut output = json!({ "results": results.collect::<Vec<_>>(), "total_retrieved": total, "summary": summary });
+        if let Some(timings) = timings {
+            output["timings"] = timings;
+        }
+        serde_json::to_writer_pretty(&mut *out, &output)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// The first and last line of the file a document spans: from `start_line`/`end_line` metadata, with the
+// end worked out from the text when only the start is recorded. None for rows ingested before spans were.
+fn line_span(doc: &RankedDoc) -> Option<(u64, u64)> {
+    let start_line = doc.metadata.get("start_line")?.as_u64()?;
+    let end_line = doc.metadata.get("end_line").and_then(|line| line.as_u64()).unwrap_or(start_line + (doc.text.lines().count() as u64).saturating_sub(1));
+    Some((start_line, end_line))
+}
+
+// SHA-256 of the file version that was indexed, to compare against the working tree: `file_sha256` when a
+// chunking pipeline recorded it, otherwise the content hash of a row that holds a whole file
+fn indexed_file_hash(doc: &RankedDoc) -> Option<&str> {
+    let text = |key: &str| doc.metadata.get(key).and_then(|value| value.as_str());
+    // Chunks and doc comment rows hash only their own text
+    let whole_file = doc.metadata.get("chunk_index").is_none() && text("kind") != Some("doc");
+    text("file_sha256").or_else(|| whole_file.then(|| text("content_hash")).flatten())
+}
+
+// One result as printed by --format json and jsonl, with a citation span (`path`, `start_line`,
+// `end_line`, `sha`) that is always present and null where unknown
+fn result_json(doc: &RankedDoc, rank: usize, rerank: Option<(&str, &str)>, thresholds: (Option<f32>, Option<f32>)) -> serde_json::Value {
+    let (rerank_model, rerank_template_sha256) = rerank.unzip();
+    let (min_score, min_similarity) = thresholds;
+    let (start_line, end_line) = line_span(doc).unzip();
+    json!({
+        "rank": rank,
+        "id": doc.id,
+        "path": document_path(doc),
+        "start_line": start_line,
+        "end_line": end_line,
+        "sha": indexed_file_hash(doc),
+        "score": doc.score,
+        "score_source": doc.source.to_string(),
+        "rerank_score": doc.rerank_score,
+        "vector_distance": doc.distance,
+        "vector_similarity": doc.similarity,
+        "retrievers": doc.retrievers,
+        "rerank_model": rerank_model,
+        "rerank_template_sha256": rerank_template_sha256,
+        "min_score": min_score,
+        "min_similarity": min_similarity,
+        "metadata": doc.metadata,
+        "text": doc.text,
+        "context_before": doc.expansion.as_ref().map(|expansion| &expansion.before),
+        "context_after": doc.expansion.as_ref().map(|expansion| &expansion.after),
+        "age_days": doc.recency.map(|(age_days, _)| age_days),
+        "recency_factor": doc.recency.map(|(_, factor)| factor),
+    })
+}
+
+/// Where results are written, and what a --format markdown report says about the search.
+#[derive(Default)]
+struct Report<'a> {
+    // --output, or stdout when unset
+    output: Option<&'a std::path::Path>,
+    query: &'a str,
+    tables: &'a [String],
+    // The database searched, named in the Markdown header
+    collection: String,
+    repo_url: Option<&'a str>,
+}
+
+impl Report<'_> {
+    fn writer(&self) -> Result<Box<dyn Write>, Box<dyn Error>> {
+        Ok(match self.output {
+            Some(path) => {
+                let file = std::fs::File::create(path).map_err(|e| format!("Failed to create output file {}: {}", path.display(), e))?;
+                Box::new(std::io::BufWriter::new(file))
+            }
+            None => Box::new(std::io::stdout()),
+        })
+    }
+
+    fn write_header(&self, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        writeln!(out, "# Retrieval report\n")?;
+        for line in self.query.trim().lines() {
+            writeln!(out, "> {}", line)?;
+        }
+        writeln!(out)?;
+        writeln!(out, "- Generated: {}", chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true))?;
+        let tables: Vec<String> = self.tables.iter().map(|table| format!("`{}`", table)).collect();
+        writeln!(out, "- Collection: {} in database `{}`", tables.join(", "), self.collection)?;
+        Ok(())
+    }
+
+    // The document's path, linked under --repo-url when set, relative to the repository
+    fn path_link(&self, doc: &RankedDoc) -> String {
+        let path = document_path(doc);
+        let Some(base) = self.repo_url else {
+            return format!("`{}`", path);
+        };
+        let relative = without_leading_parents(path);
+        // Whole files start at line 1, which needs no anchor
+        let anchor = doc.metadata.get("start_line").and_then(|line| line.as_u64()).filter(|&line| line > 1).map(|line| format!("#L{}", line)).unwrap_or_default();
+        format!("[{}]({}/{}{})", path, base.trim_end_matches('/'), relative.replace(' ', "%20"), anchor)
+    }
+}
+
+// One section per result with the text in a fenced block tagged with the document's language
+fn print_markdown_results(out: &mut dyn Write, report: &Report, args: &Args, kind: &str, rerank: Option<(&str, &str)>, docs: &[RankedDoc]) -> Result<(), Box<dyn Error>> {
+    let max_chars = args.snippet_len;
+    report.write_header(out)?;
+    match rerank {
+        Some((model, _)) => writeln!(out, "- Ranking: {} with `{}`\n", kind, model)?,
+        None if !kind.is_empty() => writeln!(out, "- Ranking: {}\n", kind)?,
+        None => writeln!(out)?,
+    }
+    if docs.is_empty() {
+        writeln!(out, "No results.")?;
+    }
+    for (i, doc) in docs.iter().take(args.top_n).enumerate() {
+        writeln!(out, "## {}. {}\n", args.offset as usize + i + 1, report.path_link(doc))?;
+        match doc.rerank_score {
+            Some(_) => writeln!(out, "Score {:.4} ({}), similarity {:.4}\n", doc.score, doc.source, doc.similarity)?,
+            None => writeln!(out, "Score {:.4} ({})\n", doc.score, doc.source)?,
+        }
+        let window = snippet_range(&doc.text, max_chars, None);
+        let snippet = doc.text[window.clone()].trim_end_matches('\n');
+        // A fence longer than any backtick run in the text can't be closed early by it
+        let longest_run = snippet.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        let language = doc.metadata.get("language").and_then(|language| language.as_str()).unwrap_or("");
+        writeln!(out, "{}{}\n{}\n{}", fence, language, snippet, fence)?;
+        if window.start > 0 || window.end < doc.text.len() {
+            writeln!(out, "\n_Truncated to {} characters._", max_chars)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// The line number, column and text of the document's first non-blank line in its file. Chunks that
+// record a `start_line` in their metadata are offset from it; whole files start at line 1.
+fn first_line(doc: &RankedDoc) -> (u64, usize, &str) {
+    let start_line = doc.metadata.get("start_line").and_then(|line| line.as_u64()).unwrap_or(1);
+    let (line_offset, line) = doc
+        .text
+        .lines()
+        .enumerate()
+        .find(|(_, line)| !line.trim().is_empty())
+        .unwrap_or((0, ""));
+    let indent = line.len() - line.trim_start().len();
+    (start_line + line_offset as u64, line[..indent].chars().count() + 1, line)
+}
+
+// One `path:line:col: snippet` line per result, pointing at the document's first non-blank line
+fn print_grep_results(out: &mut dyn Write, docs: &[RankedDoc], top_n: usize) -> Result<(), Box<dyn Error>> {
+    for doc in docs.iter().take(top_n) {
+        let (line_number, column, line) = first_line(doc);
+        writeln!(out, "{}:{}:{}: {}", document_path(doc), line_number, column, line.trim())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Prints up to --top-n of `docs`, which start at index `offset` of the final ranking. Results are
+// numbered from `offset + 1` so pages read as one continuous list.
+fn print_results(args: &Args, kind: &str, docs: &[RankedDoc], offset: usize, highlighter: Option<&Highlighter>, colors: Option<&Colors>) {
+    let top_n = args.top_n;
+    let paint = |text: String, code: &str| match colors {
+        Some(colors) => colors.paint(&text, code),
+        None => text,
+    };
+    println!("\n{}", paint(format!("--- Top {} {} Results ---", top_n.min(docs.len()), kind), "1"));
+    // Results sharing a file are marked so chunks of one file read as a group
+    let mut file_ranks: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, doc) in docs.iter().take(top_n).enumerate() {
+        file_ranks.entry(document_path(doc)).or_default().push(offset + i + 1);
+    }
+    for (i, doc) in docs.iter().take(top_n).enumerate() {
+        let (id, text) = (&doc.id, &doc.text);
+        // Keyword matches have no vector distance
+        let distance = if doc.distance.is_finite() { format!(", distance: {:.4}", doc.distance) } else { String::new() };
+        println!("\n{} {} ({} score: {}{})", paint(format!("{}.", offset + i + 1), "1"), paint(format!("ID: {}", id), "1;36"), doc.source, paint(format!("{:.4}", doc.score), "33"), distance);
+        if !doc.retrievers.is_empty() {
+            println!("   Found by: {}", doc.retrievers.join(" + "));
+        }
+        // Both signals side by side show whether the reranker changes anything over the vector ranking
+        if doc.rerank_score.is_some() || doc.source == ScoreSource::VectorFallback {
+            let rerank = doc.rerank_score.map_or("failed".to_string(), |rerank_score| format!("{:.2}", rerank_score));
+            let combined = if doc.rerank_score.is_some_and(|rerank_score| rerank_score != doc.score) { format!(" combined={:.2}", doc.score) } else { String::new() };
+            println!("   sim={:.2} rerank={}{}", doc.similarity, rerank, combined);
+        }
+        if let Some((age_days, factor)) = doc.recency {
+            println!("   age={:.0}d recency={:.2} final={:.4}", age_days, factor, doc.score);
+        }
+        let ranks = &file_ranks[document_path(doc)];
+        if ranks.len() > 1 {
+            let others: Vec<String> = ranks.iter().filter(|&&rank| rank != offset + i + 1).map(|rank| format!("#{}", rank)).collect();
+            println!("   Same file as: {}", others.join(", "));
+        }
+        println!("--------------------------------------------------");
+        if let Some(expansion) = doc.expansion.as_ref().filter(|expansion| !expansion.before.is_empty()) {
+            println!("{}", expansion.before.trim_end_matches('\n'));
+            println!(">>>>> matched chunk >>>>>");
+        }
+        let matches = highlighter.map(|h| h.find_matches(text)).unwrap_or_default();
+        let window = whole_lines(text, snippet_range(text, args.snippet_len, matches.first().map(|m| m.start)), args.max_lines);
+        if window.start > 0 {
+            println!("{}", paint("...".to_string(), "2"));
+        }
+        // Query terms are marked instead of syntax highlighting, as the two would fight over the colors
+        let language = doc.metadata.get("language").and_then(|language| language.as_str());
+        match (highlighter, colors) {
+            (Some(highlighter), _) => println!("{}", highlighter.render(text, window.clone(), &matches)),
+            (None, Some(colors)) => match colors.highlight(&text[window.clone()], language, document_path(doc)) {
+                Some(highlighted) => println!("{}", highlighted.trim_end_matches('\n')),
+                None => println!("{}", &text[window.clone()]),
+            },
+            (None, None) => println!("{}", &text[window.clone()]),
+        }
+        if window.end < text.len() {
+            println!("{}", paint("... (truncated)".to_string(), "2"));
+        }
+        if let Some(expansion) = doc.expansion.as_ref().filter(|expansion| !expansion.after.is_empty()) {
+            println!("<<<<< end of matched chunk <<<<<");
+            println!("{}", expansion.after.trim_end_matches('\n'));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rag_system::embedder::MockEmbedder;
+    use rag_system::rerank::MockGenerator;
+    use rag_system::vector_store::InMemoryStore;
+
+    // What a Pipeline borrows, for tests that run it without Ollama or Postgres: the pool is never
+    // connected, vectors come from the mock embedder and the in-memory store
+    struct Fixture {
+        args: Args,
+        embedder: MockEmbedder,
+        pool: PgPool,
+        store: InMemoryStore,
+        retry_policy: RetryPolicy,
+    }
+
+    impl Fixture {
+        fn new(flags: &[&str]) -> Self {
+            let args = Args::parse_from(["query", "--query", "unused"].iter().chain(flags));
+            let retry_policy = args.retry.policy();
+            Self {
+                args,
+                embedder: MockEmbedder::new(16),
+                pool: PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap(),
+                store: InMemoryStore::new(Metric::Cosine),
+                retry_policy,
+            }
+        }
+
+        fn pipeline<'a>(&'a self, generator: &'a dyn Generator) -> Pipeline<'a> {
+            Pipeline {
+                args: &self.args,
+                embedder: &self.embedder,
+                pool: &self.pool,
+                table: schema::DEFAULT_TABLE,
+                store: &self.store,
+                generator,
+                retry_policy: &self.retry_policy,
+                rerank_template: DEFAULT_RERANK_PROMPT,
+                rerank_cache: None,
+                hyde_template: DEFAULT_HYDE_PROMPT,
+                text_search: "text_search",
+                normalize: true,
+                metric: Metric::Cosine,
+                vector_encoding: VectorEncoding::Binary,
+                explain: None,
+            }
+        }
+    }
+
+    // Documents in retrieval order, the first nearest
+    fn retrieved(count: usize) -> Vec<RankedDoc> {
+        (0..count)
+            .map(|i| {
+                let hit = Hit { id: format!("doc{}.rs", i), text: format!("document {}", i), distance: 0.1 * i as f64, metadata: json!({}) };
+                RankedDoc::from_vector(hit, Metric::Cosine)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snippet_windows_shrink_to_whole_lines() {
+        let text = "alpha\nbravo\ncharlie\ndelta\n";
+        // Cut inside "alpha" and inside "charlie": both partial lines go
+        assert_eq!(&text[whole_lines(text, 2..15, None)], "bravo");
+        // A window already on line boundaries is kept, up to max_lines lines
+        assert_eq!(&text[whole_lines(text, 0..25, None)], "alpha\nbravo\ncharlie\ndelta");
+        assert_eq!(&text[whole_lines(text, 0..25, Some(2))], "alpha\nbravo");
+    }
+
+    #[test]
+    fn a_single_long_line_is_cut_rather_than_dropped() {
+        let minified = "x".repeat(1000);
+        assert_eq!(whole_lines(&minified, 100..200, None), 100..200);
+        assert_eq!(whole_lines(&minified, 100..200, Some(1)), 100..200);
+        // Inside one long line among short ones, that line is still the only one in the window
+        let text = format!("short\n{}\nshort", "y".repeat(500));
+        assert_eq!(whole_lines(&text, 50..150, None), 50..150);
+    }
+
+    #[tokio::test]
+    async fn reranking_reorders_retrieved_documents() {
+        let documents = [
+            ("src/retry.rs", "fn with_retry retries transient failures with exponential backoff"),
+            ("src/store.rs", "fn upsert_rows inserts or replaces rows in a single transaction"),
+            ("docs/retries.md", "Retries back off exponentially and give up after --max-attempts"),
+            ("scripts/load.py", "def load(path): read a CSV file into the embeddings table"),
+        ];
+        let fixture = Fixture::new(&["--query-prefix", ""]);
+        let texts: Vec<String> = documents.iter().map(|(_, text)| text.to_string()).collect();
+        let vectors = fixture.embedder.embed(&texts).await.unwrap();
+        let rows: Vec<store::EmbeddingRow> = documents
+            .iter()
+            .zip(vectors)
+            .map(|((path, text), mut vector)| {
+                store::normalize(&mut vector);
+                store::EmbeddingRow { id: path.to_string(), text: text.to_string(), vector, metadata: json!({ "path": path }) }
+            })
+            .collect();
+        fixture.store.upsert(&rows).await.unwrap();
+
+        let query = documents[0].1;
+        let unused = MockGenerator::new(|_| unreachable!("nothing is reranked"));
+        let pipeline = fixture.pipeline(&unused);
+        let retrieved = pipeline.retrieve(query, &pipeline.embed_query(query).await.unwrap()).await.unwrap();
+        let retrieved_ids: Vec<String> = retrieved.iter().map(|doc| doc.id.clone()).collect();
+        assert_eq!(retrieved_ids[0], documents[0].0);
+
+        // A reranker that prefers exactly the reverse of the vector order, and can't judge the third document
+        let scores: Vec<(String, Option<f32>)> = retrieved
+            .iter()
+            .enumerate()
+            .map(|(rank, doc)| (doc.text.clone(), (rank != 2).then_some(rank as f32 / retrieved.len() as f32)))
+            .collect();
+        // The query is a document's text too, so only the document part of the prompt tells them apart
+        let reverse = MockGenerator::new(move |prompt| match scores.iter().find(|(text, _)| prompt.contains(&format!("the document: '{}'", text))) {
+            Some((_, Some(score))) => format!(r#"{{"score": {}}}"#, score),
+            _ => "I can't tell.".to_string(),
+        });
+        let ranked = |weight: &str| {
+            let fixture = Fixture::new(&["--query-prefix", "", "--rerank-weight", weight]);
+            let (reverse, retrieved) = (&reverse, retrieved.clone());
+            async move { rerank_documents(&fixture.pipeline(reverse), query, retrieved).await }
+        };
+
+        let reranked = ranked("1.0").await;
+        let judged: Vec<&str> = reranked.iter().filter(|doc| doc.source == ScoreSource::Rerank).map(|doc| doc.id.as_str()).collect();
+        assert_eq!(judged, [&retrieved_ids[3], &retrieved_ids[1], &retrieved_ids[0]]);
+        let fallback = reranked.iter().find(|doc| doc.id == retrieved_ids[2]).unwrap();
+        assert_eq!((fallback.source, fallback.rerank_score), (ScoreSource::VectorFallback, None));
+        assert_eq!(fallback.score, fallback.similarity as f32);
+
+        // With no weight on the rerank score, the vector order stands
+        let reranked = ranked("0.0").await;
+        assert_eq!(reranked.iter().map(|doc| doc.id.clone()).collect::<Vec<_>>(), retrieved_ids);
+    }
+
+    #[tokio::test]
+    async fn a_larger_retrieval_reranks_only_its_new_candidates() {
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let generator = MockGenerator::new({
+            let prompts = prompts.clone();
+            move |prompt| {
+                prompts.lock().unwrap().push(prompt.to_string());
+                // Later documents score higher, so the reranked order is the reverse of retrieval
+                let number: f32 = prompt.rsplit("document ").next().unwrap()[..1].parse().unwrap();
+                format!(r#"{{"score": {}}}"#, number / 10.0)
+            }
+        });
+        let fixture = Fixture::new(&["--query-prefix", ""]);
+        let pipeline = fixture.pipeline(&generator);
+
+        let first = pipeline.score("query", retrieved(3), &[]).await;
+        let doubled = pipeline.score("query", retrieved(6), &first).await;
+        assert_eq!(prompts.lock().unwrap().len(), 6);
+        assert_eq!(doubled.iter().map(|doc| doc.id.as_str()).collect::<Vec<_>>(), ["doc5.rs", "doc4.rs", "doc3.rs", "doc2.rs", "doc1.rs", "doc0.rs"]);
+        assert!(doubled.iter().all(|doc| doc.source == ScoreSource::Rerank));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reranking_takes_one_model_latency_per_round_of_concurrent_requests() {
+        let generator = MockGenerator::new(|_| r#"{"score": 0.5}"#.to_string()).with_latency(Duration::from_millis(100));
+        for (count, concurrency) in [(10, 1), (10, 3), (10, 10), (7, 16)] {
+            let fixture = Fixture::new(&["--rerank-concurrency", &concurrency.to_string()]);
+            let started = tokio::time::Instant::now();
+            let reranked = rerank_documents(&fixture.pipeline(&generator), "query", retrieved(count)).await;
+            let elapsed = started.elapsed();
+            assert_eq!(reranked.len(), count);
+            assert!(reranked.iter().all(|doc| doc.source == ScoreSource::Rerank));
+            let expected = Duration::from_millis(100) * count.div_ceil(concurrency) as u32;
+            assert!(elapsed >= expected && elapsed < expected + Duration::from_millis(50), "{} documents at concurrency {} took {:?}", count, concurrency, elapsed);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_ctrl_c_cancels_the_answer_streaming_at_the_time() {
+        let fixture = Fixture::new(&[]);
+        let generator = MockGenerator::new(|_| "one two three four five six".to_string()).with_latency(Duration::from_millis(100));
+        let interrupt = Interrupt::new();
+        let answerer = Answerer {
+            generator: &generator,
+            interrupt: &interrupt,
+            model: "mock",
+            retry_policy: fixture.retry_policy.clone(),
+            template: DEFAULT_ANSWER_PROMPT,
+            context_tokens: 8000,
+            truncate_context: false,
+            stream: true,
+        };
+        // The second Ctrl-C must reach the second answer as the first did, not be spent on the first
+        for _ in 0..2 {
+            let (result, ()) = tokio::join!(answerer.stream_answer("prompt"), async {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                interrupt.raise();
+            });
+            assert!(result.unwrap_err().is::<Cancelled>());
+        }
+        assert_eq!(answerer.stream_answer("prompt").await.unwrap(), "one two three four five six");
+    }
+
+    fn doc(id: &str, score: f32, embedded_at: Option<&str>) -> RankedDoc {
+        let metadata = embedded_at.map_or(json!({}), |embedded_at| json!({ "embedded_at": embedded_at }));
+        let hit = Hit { id: id.to_string(), text: String::new(), distance: 0.0, metadata };
+        RankedDoc { score, ..RankedDoc::from_vector(hit, Metric::Cosine) }
+    }
+
+    fn ids(docs: &[RankedDoc]) -> Vec<&str> {
+        docs.iter().map(|doc| doc.id.as_str()).collect()
+    }
+
+    #[test]
+    fn recency_boost_sinks_stale_copies_below_newer_ones() {
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        // The stale copy scores slightly higher on relevance alone
+        let docs = vec![
+            doc("src/old/client.rs", 0.82, Some("2025-01-02T00:00:00Z")),
+            doc("src/client.rs", 0.78, Some("2025-06-24T00:00:00Z")),
+            doc("notes.md", 0.70, None),
+            doc("docs/client.md", 0.60, Some("2025-06-29T00:00:00Z")),
+            doc("README.md", 0.40, Some("2025-06-30T00:00:00Z")),
+        ];
+        let boosted = boost_recent(docs, 30.0, now);
+        assert_eq!(ids(&boosted), ["src/client.rs", "notes.md", "docs/client.md", "src/old/client.rs", "README.md"]);
+        let notes = boosted.iter().find(|doc| doc.id == "notes.md").unwrap();
+        assert!(notes.recency.is_none(), "an undated document has no recency factor");
+        assert!(boosted.iter().all(|doc| (0.0..=1.0).contains(&doc.score)));
+    }
+
+    #[test]
+    fn recency_boost_lowers_negative_scores_as_they_age() {
+        let now = DateTime::parse_from_rfc3339("2025-07-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        // Multiplying the negative scores by the decay directly would lift the stale copy towards zero and
+        // above the fresh one
+        let docs = vec![
+            doc("src/old/client.rs", -0.30, Some("2025-01-02T00:00:00Z")),
+            doc("src/client.rs", -0.35, Some("2025-06-30T00:00:00Z")),
+            doc("best.rs", 0.90, Some("2025-06-30T00:00:00Z")),
+            doc("undated.rs", -0.20, None),
+            doc("worst.rs", -0.50, Some("2025-06-30T00:00:00Z")),
+        ];
+        let boosted = boost_recent(docs, 30.0, now);
+        assert_eq!(ids(&boosted), ["best.rs", "undated.rs", "src/client.rs", "src/old/client.rs", "worst.rs"]);
+        let undated = boosted.iter().find(|doc| doc.id == "undated.rs").unwrap();
+        assert!((undated.score - 0.3 / 1.4).abs() < 1e-6, "an undated document keeps its rescaled score");
+    }
 }
@@ -0,0 +1,179 @@
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+// `$XDG_CACHE_HOME/turborag`, falling back to `~/.cache/turborag`
+fn cache_root() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("turborag")
+}
+
+// Model names contain '/' and ':' so they need flattening into a single path component
+fn model_dir(model: &str) -> String {
+    model.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+// Writes to a temporary file first so an interrupted run never leaves a truncated entry
+async fn write_entry(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, bytes).await?;
+    tokio::fs::rename(tmp_path, path).await
+}
+
+/// A directory of embedding vectors keyed by `(model, sha256(content))`.
+///
+/// Each model gets its own subdirectory and each vector is stored as raw little-endian f32s,
+/// so rebuilding the database never has to re-embed unchanged content.
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl EmbeddingCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) }
+    }
+
+    /// `$XDG_CACHE_HOME/turborag/embeddings`, falling back to `~/.cache/turborag/embeddings`.
+    pub fn default_dir() -> PathBuf {
+        cache_root().join("embeddings")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn entry_path(&self, model: &str, content: &str) -> PathBuf {
+        let digest = Sha256::digest(content.as_bytes());
+        self.dir.join(model_dir(model)).join(format!("{:x}.bin", digest))
+    }
+
+    /// Returns the cached vector for `content`, counting the lookup as a hit or a miss.
+    pub async fn get(&self, model: &str, content: &str) -> Option<Vec<f32>> {
+        let vector = match tokio::fs::read(self.entry_path(model, content)).await {
+            Ok(bytes) if bytes.len() % 4 == 0 => Some(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let counter = if vector.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        vector
+    }
+
+    pub async fn put(&self, model: &str, content: &str, vector: &[f32]) -> std::io::Result<()> {
+        let bytes: Vec<u8> = vector.iter().flat_map(|f| f.to_le_bytes()).collect();
+        write_entry(&self.entry_path(model, content), &bytes).await
+    }
+
+    /// Removes every cached vector, returning how many entries were deleted.
+    pub async fn clear(&self) -> std::io::Result<usize> {
+        let mut removed = 0;
+        let mut models = match tokio::fs::read_dir(&self.dir).await {
+            Ok(models) => models,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        while let Some(model) = models.next_entry().await? {
+            if model.file_type().await?.is_dir() {
+                let mut entries = tokio::fs::read_dir(model.path()).await?;
+                while entries.next_entry().await?.is_some() {
+                    removed += 1;
+                }
+                tokio::fs::remove_dir_all(model.path()).await?;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A directory of rerank scores keyed by `(model, sha256(mode, prompt))`.
+///
+/// The prompt is the fully rendered one, so it already covers the template, the query and the
+/// (truncated) document: changing any of them, or the model or mode, looks up a different entry and
+/// never reuses a stale score. Entries older than the TTL count as misses and are overwritten.
+pub struct RerankCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl RerankCache {
+    pub fn new(dir: PathBuf, ttl: Duration) -> Self {
+        Self { dir, ttl }
+    }
+
+    /// `$XDG_CACHE_HOME/turborag/rerank`, falling back to `~/.cache/turborag/rerank`.
+    pub fn default_dir() -> PathBuf {
+        cache_root().join("rerank")
+    }
+
+    fn entry_path(&self, model: &str, mode: &str, prompt: &str) -> PathBuf {
+        // The mode is length-prefixed so no mode/prompt pair can collide with another
+        let mut hasher = Sha256::new();
+        hasher.update((mode.len() as u64).to_le_bytes());
+        hasher.update(mode.as_bytes());
+        hasher.update(prompt.as_bytes());
+        self.dir.join(model_dir(model)).join(format!("{:x}.bin", hasher.finalize()))
+    }
+
+    /// Returns the cached score for `prompt` and whether it was extracted from free text rather than
+    /// parsed from JSON, or `None` if there is no entry younger than the TTL.
+    pub async fn get(&self, model: &str, mode: &str, prompt: &str) -> Option<(f32, bool)> {
+        let path = self.entry_path(model, mode, prompt);
+        let modified = tokio::fs::metadata(&path).await.ok()?.modified().ok()?;
+        if SystemTime::now().duration_since(modified).unwrap_or_default() > self.ttl {
+            return None;
+        }
+        match tokio::fs::read(&path).await.ok()?.as_slice() {
+            [a, b, c, d, from_text] => Some((f32::from_le_bytes([*a, *b, *c, *d]), *from_text != 0)),
+            _ => None,
+        }
+    }
+
+    pub async fn put(&self, model: &str, mode: &str, prompt: &str, score: f32, from_text: bool) -> std::io::Result<()> {
+        let mut bytes = score.to_le_bytes().to_vec();
+        bytes.push(u8::from(from_text));
+        write_entry(&self.entry_path(model, mode, prompt), &bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rerank_scores_are_reused_only_for_the_same_model_mode_and_prompt() {
+        let dir = std::env::temp_dir().join(format!("turborag-rerank-cache-{}", std::process::id()));
+        let cache = RerankCache::new(dir.clone(), Duration::from_secs(3600));
+        cache.put("reranker:4b", "score", "Query: q\nDocument: d", 0.75, true).await.unwrap();
+
+        assert_eq!(cache.get("reranker:4b", "score", "Query: q\nDocument: d").await, Some((0.75, true)));
+        assert_eq!(cache.get("reranker:8b", "score", "Query: q\nDocument: d").await, None);
+        assert_eq!(cache.get("reranker:4b", "yes_no", "Query: q\nDocument: d").await, None);
+        assert_eq!(cache.get("reranker:4b", "score", "Query: q\nDocument: d2").await, None);
+
+        let expired = RerankCache::new(dir.clone(), Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(expired.get("reranker:4b", "score", "Query: q\nDocument: d").await, None);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use ollama_rs::error::OllamaError;
+use ollama_rs::generation::embeddings::request::{EmbeddingsInput, GenerateEmbeddingsRequest};
+use ollama_rs::Ollama;
+use serde::Deserialize;
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+
+use crate::retry::{with_retry, RetryPolicy, Retryable, TimedOut};
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "dengcao/Qwen3-Embedding-4B:Q4_K_M";
+// Qwen3-Embedding is trained with an instruction on the query side and raw text on the document side
+pub const DEFAULT_QUERY_PREFIX: &str = "Instruct: Given a code search query, retrieve relevant code snippets and documentation that answer the query\nQuery: ";
+pub const DEFAULT_DOCUMENT_PREFIX: &str = "";
+
+/// A backend that turns texts into embedding vectors.
+///
+/// Both the ingester and the query binary go through this trait so index-time and
+/// query-time embeddings always come from the same backend and model.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// The model name, used to key caches and report configuration.
+    fn model(&self) -> &str;
+
+    /// Embeds every text, returning one vector per input in the same order.
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError>;
+}
+
+#[derive(Debug)]
+pub enum EmbedError {
+    Ollama(OllamaError),
+    Http(reqwest::Error),
+    Status { status: u16, body: String },
+    Invalid(String),
+    Timeout(std::time::Duration),
+}
+
+impl fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbedError::Ollama(e) => write!(f, "Ollama error: {}", e),
+            EmbedError::Http(e) => write!(f, "HTTP error: {}", e),
+            EmbedError::Status { status, body } => write!(f, "embedding server returned {}: {}", status, body),
+            EmbedError::Invalid(message) => write!(f, "invalid embedding response: {}", message),
+            EmbedError::Timeout(timeout) => write!(f, "embedding request timed out after {:?}", timeout),
+        }
+    }
+}
+
+impl Error for EmbedError {}
+
+impl From<OllamaError> for EmbedError {
+    fn from(e: OllamaError) -> Self {
+        EmbedError::Ollama(e)
+    }
+}
+
+impl From<reqwest::Error> for EmbedError {
+    fn from(e: reqwest::Error) -> Self {
+        EmbedError::Http(e)
+    }
+}
+
+impl From<TimedOut> for EmbedError {
+    fn from(timed_out: TimedOut) -> Self {
+        EmbedError::Timeout(timed_out.0)
+    }
+}
+
+impl Retryable for EmbedError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            EmbedError::Ollama(e) => e.is_retryable(),
+            EmbedError::Http(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            // Rate limiting and server errors are transient; other client errors are not
+            EmbedError::Status { status, .. } => *status == 429 || *status >= 500,
+            EmbedError::Invalid(_) => false,
+            EmbedError::Timeout(_) => true,
+        }
+    }
+}
+
+pub struct OllamaEmbedder {
+    client: Ollama,
+    model: String,
+}
+
+impl OllamaEmbedder {
+    pub fn new(client: Ollama, model: String) -> Self {
+        Self { client, model }
+    }
+}
+
+#[async_trait]
+impl Embedder for OllamaEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let input = match texts {
+            [text] => EmbeddingsInput::Single(text.clone()),
+            _ => EmbeddingsInput::Multiple(texts.to_vec()),
+        };
+        let response = self
+            .client
+            .generate_embeddings(GenerateEmbeddingsRequest::new(self.model.clone(), input))
+            .await?;
+        check_count(texts, response.embeddings)
+    }
+}
+
+/// Talks to any server implementing the OpenAI `/v1/embeddings` API (vLLM, TEI, LiteLLM, ...).
+pub struct OpenAiEmbedder {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl OpenAiEmbedder {
+    /// `base_url` is the API root (e.g. `http://localhost:8000/v1`); `/embeddings` is appended.
+    pub fn new(base_url: &str, api_key: Option<String>, model: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!("{}/embeddings", base_url.trim_end_matches('/')),
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let mut request = self.client.post(&self.url).json(&json!({
+            "model": self.model,
+            "input": texts,
+        }));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(EmbedError::Status { status: status.as_u16(), body });
+        }
+
+        // The API doesn't promise to return embeddings in input order, only tagged with their index
+        let mut data = response.json::<OpenAiEmbeddingResponse>().await?.data;
+        data.sort_by_key(|d| d.index);
+        check_count(texts, data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+/// Deterministic, offline embedder for tests: vectors are derived from a hash of each input.
+pub struct MockEmbedder {
+    dimension: usize,
+}
+
+impl MockEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension }
+    }
+}
+
+#[async_trait]
+impl Embedder for MockEmbedder {
+    fn model(&self) -> &str {
+        "mock"
+    }
+
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError> {
+        use sha2::{Digest, Sha256};
+        Ok(texts
+            .iter()
+            .map(|text| {
+                let digest = Sha256::digest(text.as_bytes());
+                (0..self.dimension)
+                    .map(|i| digest[i % digest.len()] as f32 / 255.0 - 0.5)
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+/// Embeds `texts` in one request (retried per `retry_policy`). If that fails, the texts are split in half
+/// and each half is embedded the same way, down to single texts, so one text the model rejects (such as
+/// a file longer than its context window) is skipped instead of failing the whole batch. A text that
+/// fails on its own is reported on stderr by its label and gets `None`; the others get their vectors.
+pub fn embed_bisecting<'a>(embedder: &'a dyn Embedder, retry_policy: &'a RetryPolicy, labels: &'a [String], texts: &'a [String]) -> BoxFuture<'a, Vec<Option<Vec<f32>>>> {
+    Box::pin(async move {
+        if texts.is_empty() {
+            return Vec::new();
+        }
+        let label = match labels {
+            [label] => format!("Embedding {}", label),
+            _ => format!("Embedding a batch of {} documents", texts.len()),
+        };
+        match with_retry(retry_policy, &label, || embedder.embed(texts)).await {
+            Ok(vectors) => vectors.into_iter().map(Some).collect(),
+            Err(e) if texts.len() == 1 => {
+                tracing::error!(phase = "embed", path = %labels[0], error = %e, "Failed to generate embedding for {}: {}; skipping it", labels[0], e);
+                vec![None]
+            }
+            Err(e) => {
+                tracing::warn!(phase = "embed", documents = texts.len(), error = %e, "{} failed ({}); splitting it to isolate the failing document", label, e);
+                let middle = texts.len() / 2;
+                let mut vectors = embed_bisecting(embedder, retry_policy, &labels[..middle], &texts[..middle]).await;
+                vectors.extend(embed_bisecting(embedder, retry_policy, &labels[middle..], &texts[middle..]).await);
+                vectors
+            }
+        }
+    })
+}
+
+fn check_count(texts: &[String], embeddings: Vec<Vec<f32>>) -> Result<Vec<Vec<f32>>, EmbedError> {
+    if embeddings.len() != texts.len() {
+        return Err(EmbedError::Invalid(format!(
+            "expected {} embeddings but received {}",
+            texts.len(),
+            embeddings.len()
+        )));
+    }
+    Ok(embeddings)
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmbedderKind {
+    Ollama,
+    Openai,
+}
+
+/// Embedding backend flags shared by the ingester and the query binary.
+#[derive(clap::Args, Debug, Clone)]
+pub struct EmbedderArgs {
+    /// Embedding backend to use
+    #[arg(long, value_enum, default_value_t = EmbedderKind::Ollama)]
+    pub embedder: EmbedderKind,
+
+    /// Base URL of the embedding server (defaults to http://localhost:11434 for Ollama)
+    #[arg(long)]
+    pub embed_url: Option<String>,
+
+    /// Environment variable holding the API key for the OpenAI-compatible backend
+    #[arg(long, default_value = "OPENAI_API_KEY")]
+    pub api_key_env: String,
+
+    /// Embedding model name
+    #[arg(long, visible_alias = "embed-model", default_value = DEFAULT_EMBEDDING_MODEL)]
+    pub embedding_model: String,
+
+    /// Text prepended to queries before embedding (the instruction for asymmetric models)
+    #[arg(long, default_value = DEFAULT_QUERY_PREFIX)]
+    pub query_prefix: String,
+
+    /// Text prepended to documents before embedding
+    #[arg(long, visible_alias = "doc-prefix", default_value = DEFAULT_DOCUMENT_PREFIX)]
+    pub document_prefix: String,
+}
+
+impl EmbedderArgs {
+    /// The Ollama client embeddings are sent to, or `None` for non-Ollama backends.
+    pub fn ollama_client(&self) -> Result<Option<Ollama>, Box<dyn Error>> {
+        if self.embedder != EmbedderKind::Ollama {
+            return Ok(None);
+        }
+        Ok(Some(match &self.embed_url {
+            Some(url) => Ollama::try_new(url.as_str())?,
+            None => Ollama::builder().host("http://localhost").port(11434).build(),
+        }))
+    }
+
+    pub fn build(&self) -> Result<Box<dyn Embedder>, Box<dyn Error>> {
+        match self.embedder {
+            EmbedderKind::Ollama => {
+                let client = self.ollama_client()?.ok_or("Ollama embedder requires an Ollama client")?;
+                Ok(Box::new(OllamaEmbedder::new(client, self.embedding_model.clone())))
+            }
+            EmbedderKind::Openai => {
+                let url = self.embed_url.as_deref().ok_or("--embed-url is required with --embedder openai")?;
+                let api_key = std::env::var(&self.api_key_env).ok();
+                Ok(Box::new(OpenAiEmbedder::new(url, api_key, self.embedding_model.clone())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rejects any request containing a text longer than `max_chars`, like a model with a small context window
+    struct ContextLimitedEmbedder {
+        max_chars: usize,
+        requests: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Embedder for ContextLimitedEmbedder {
+        fn model(&self) -> &str {
+            "context-limited"
+        }
+
+        async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, EmbedError> {
+            self.requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if texts.iter().any(|text| text.len() > self.max_chars) {
+                return Err(EmbedError::Status { status: 400, body: "input exceeds the context length".to_string() });
+            }
+            Ok(texts.iter().map(|text| vec![text.len() as f32]).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn an_oversized_text_is_isolated_and_skipped() {
+        let embedder = ContextLimitedEmbedder { max_chars: 10, requests: Default::default() };
+        let texts: Vec<String> = ["a", "bb", "this one is far too long", "dddd", "eeeee"].map(String::from).to_vec();
+        let labels: Vec<String> = (0..texts.len()).map(|i| format!("doc{}", i)).collect();
+
+        let vectors = embed_bisecting(&embedder, &RetryPolicy::new(1, 0), &labels, &texts).await;
+
+        assert_eq!(vectors, vec![Some(vec![1.0]), Some(vec![2.0]), None, Some(vec![4.0]), Some(vec![5.0])]);
+    }
+
+    #[tokio::test]
+    async fn a_good_batch_takes_one_request() {
+        let embedder = ContextLimitedEmbedder { max_chars: 10, requests: Default::default() };
+        let texts: Vec<String> = ["a", "bb", "ccc"].map(String::from).to_vec();
+        let labels = texts.clone();
+
+        let vectors = embed_bisecting(&embedder, &RetryPolicy::new(1, 0), &labels, &texts).await;
+
+        assert_eq!(vectors.len(), 3);
+        assert_eq!(embedder.requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
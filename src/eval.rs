@@ -0,0 +1,228 @@
+use crate::store::glob_regex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+
+/// One labeled query of an evaluation set: the query text and the documents a good ranking returns.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalQuery {
+    pub query: String,
+    /// Paths or path globs (`src/retry.rs`, `docs/**/*.md`) of the relevant documents, matched like
+    /// `--exclude-path` from any directory, so the ingest root's leading `../` can be left out
+    pub relevant: Vec<String>,
+}
+
+/// Reads an evaluation set: a YAML (`.yaml`/`.yml`) or JSON list of [`EvalQuery`]s.
+pub fn load_queries(path: &Path) -> Result<Vec<EvalQuery>, Box<dyn Error>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read evaluation set {}: {}", path.display(), e))?;
+    let queries: Vec<EvalQuery> = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?,
+        _ => serde_json::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?,
+    };
+    for (number, query) in queries.iter().enumerate() {
+        if query.query.trim().is_empty() {
+            return Err(format!("{}: query {} is empty", path.display(), number + 1).into());
+        }
+        if query.relevant.is_empty() {
+            return Err(format!("{}: query {:?} lists no relevant documents", path.display(), query.query).into());
+        }
+    }
+    if queries.is_empty() {
+        return Err(format!("Evaluation set {} contains no queries", path.display()).into());
+    }
+    Ok(queries)
+}
+
+/// Retrieval quality of one ranking, or the mean over a set of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scores {
+    /// Fraction of the relevant entries matched by at least one of the top k results
+    pub recall: f64,
+    /// 1 / rank of the first relevant result in the top k, or 0 (the mean is the MRR)
+    pub reciprocal_rank: f64,
+    /// Binary-relevance nDCG@k: results only gain for an entry no higher result matched, so several
+    /// chunks of one relevant file count once
+    pub ndcg: f64,
+}
+
+impl Scores {
+    /// Mean of each score, or zeros for no scores.
+    pub fn mean(scores: &[Scores]) -> Scores {
+        let count = scores.len().max(1) as f64;
+        Scores {
+            recall: scores.iter().map(|s| s.recall).sum::<f64>() / count,
+            reciprocal_rank: scores.iter().map(|s| s.reciprocal_rank).sum::<f64>() / count,
+            ndcg: scores.iter().map(|s| s.ndcg).sum::<f64>() / count,
+        }
+    }
+}
+
+/// Scores the top `k` of a ranking, given as each result's path, against the `relevant` entries
+/// of an [`EvalQuery`].
+pub fn score(ranking: &[&str], relevant: &[String], k: usize) -> Scores {
+    let targets: Vec<Regex> = relevant.iter().map(|entry| Regex::new(&glob_regex(entry)).expect("glob_regex escapes everything else")).collect();
+    let mut found = vec![false; targets.len()];
+    let mut scores = Scores::default();
+    let mut dcg = 0.0;
+    for (rank, path) in ranking.iter().take(k).enumerate() {
+        let matched: Vec<usize> = (0..targets.len()).filter(|&i| targets[i].is_match(path)).collect();
+        if matched.is_empty() {
+            continue;
+        }
+        if scores.reciprocal_rank == 0.0 {
+            scores.reciprocal_rank = 1.0 / (rank + 1) as f64;
+        }
+        if matched.iter().any(|&i| !found[i]) {
+            dcg += discount(rank);
+        }
+        for i in matched {
+            found[i] = true;
+        }
+    }
+    let ideal: f64 = (0..targets.len().min(k)).map(discount).sum();
+    scores.recall = found.iter().filter(|&&found| found).count() as f64 / targets.len().max(1) as f64;
+    scores.ndcg = if ideal > 0.0 { dcg / ideal } else { 0.0 };
+    scores
+}
+
+// Gain of a relevant result at a 0-based rank
+fn discount(rank: usize) -> f64 {
+    1.0 / (rank as f64 + 2.0).log2()
+}
+
+/// One query's scores in an [`EvalReport`], with the top k it was scored on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryScores {
+    pub query: String,
+    #[serde(flatten)]
+    pub scores: Scores,
+    pub results: Vec<String>,
+}
+
+/// The scores of one retrieval configuration (e.g. with or without reranking) over an evaluation set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Configuration {
+    pub name: String,
+    pub mean: Scores,
+    pub queries: Vec<QueryScores>,
+}
+
+/// A whole evaluation run, as written with `--format json` and read back to diff two runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvalReport {
+    pub created_at: String,
+    pub k: usize,
+    /// The retrieval options the run used, to tell runs apart
+    pub settings: serde_json::Value,
+    pub configurations: Vec<Configuration>,
+}
+
+impl EvalReport {
+    pub fn load(path: &Path) -> Result<EvalReport, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read evaluation report {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&content).map_err(|e| format!("{} is not an evaluation report written with --format json: {}", path.display(), e))?)
+    }
+}
+
+/// How one configuration scored in two runs, with the queries whose scores changed.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigurationDiff {
+    pub name: String,
+    pub before: Scores,
+    pub after: Scores,
+    pub changed: Vec<QueryDiff>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryDiff {
+    pub query: String,
+    pub before: Scores,
+    pub after: Scores,
+}
+
+/// Compares the configurations and queries the two reports share, matched by name and query text.
+pub fn diff(before: &EvalReport, after: &EvalReport) -> Vec<ConfigurationDiff> {
+    let mut diffs = Vec::new();
+    for new in &after.configurations {
+        let Some(old) = before.configurations.iter().find(|old| old.name == new.name) else { continue };
+        let changed = new
+            .queries
+            .iter()
+            .filter_map(|query| {
+                let previous = old.queries.iter().find(|previous| previous.query == query.query)?;
+                (previous.scores != query.scores).then(|| QueryDiff { query: query.query.clone(), before: previous.scores, after: query.scores })
+            })
+            .collect();
+        diffs.push(ConfigurationDiff { name: new.name.clone(), before: old.mean, after: new.mean, changed });
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relevant(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn scores_the_top_k_against_paths_and_globs() {
+        let ranking = ["../README.md", "../src/retry.rs", "../docs/setup.md", "../src/store.rs"];
+
+        let scores = score(&ranking, &relevant(&["src/retry.rs"]), 3);
+        assert_eq!(scores.recall, 1.0);
+        assert_eq!(scores.reciprocal_rank, 0.5);
+        assert!((scores.ndcg - 1.0 / 3f64.log2()).abs() < 1e-9);
+
+        // One glob entry is satisfied by its first match; the store is past k
+        let scores = score(&ranking, &relevant(&["docs/**/*.md", "src/store.rs"]), 3);
+        assert_eq!(scores.recall, 0.5);
+        assert!((scores.reciprocal_rank - 1.0 / 3.0).abs() < 1e-9);
+
+        let scores = score(&ranking, &relevant(&["src/main.rs"]), 4);
+        assert_eq!(scores, Scores::default());
+    }
+
+    #[test]
+    fn a_perfect_ranking_scores_one_and_repeated_matches_gain_nothing() {
+        let ranking = ["../src/retry.rs", "../src/store.rs", "../src/retry.rs"];
+        let scores = score(&ranking[..2], &relevant(&["src/retry.rs", "src/store.rs"]), 5);
+        assert_eq!(scores, Scores { recall: 1.0, reciprocal_rank: 1.0, ndcg: 1.0 });
+
+        // A second chunk of an already matched file doesn't count as another relevant result
+        let scores = score(&["../src/retry.rs", "../src/retry.rs"], &relevant(&["src/retry.rs", "src/store.rs"]), 2);
+        assert_eq!(scores.recall, 0.5);
+        assert!((scores.ndcg - 1.0 / (1.0 + 1.0 / 3f64.log2())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn diffs_shared_configurations_and_changed_queries() {
+        let query = |query: &str, recall: f64| QueryScores { query: query.to_string(), scores: Scores { recall, ..Default::default() }, results: Vec::new() };
+        let report = |queries: Vec<QueryScores>| EvalReport {
+            created_at: String::new(),
+            k: 5,
+            settings: serde_json::Value::Null,
+            configurations: vec![Configuration {
+                name: "reranked".to_string(),
+                mean: Scores::mean(&queries.iter().map(|query| query.scores).collect::<Vec<_>>()),
+                queries,
+            }],
+        };
+        let before = report(vec![query("retries", 0.5), query("auth", 1.0)]);
+        let after = report(vec![query("retries", 1.0), query("auth", 1.0), query("new", 0.0)]);
+
+        let diffs = diff(&before, &after);
+        assert_eq!(diffs.len(), 1);
+        assert_eq!((diffs[0].before.recall, diffs[0].after.recall), (0.75, 2.0 / 3.0));
+        assert_eq!(diffs[0].changed.len(), 1);
+        assert_eq!((diffs[0].changed[0].query.as_str(), diffs[0].changed[0].after.recall), ("retries", 1.0));
+
+        // A report round-trips through its JSON form
+        let json = serde_json::to_string(&after).unwrap();
+        let read: EvalReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.configurations[0].queries[0].scores.recall, 1.0);
+    }
+}
@@ -0,0 +1,73 @@
+use ollama_rs::Ollama;
+use std::error::Error;
+use std::time::Duration;
+
+// Upper bound for the preflight request so an unreachable server fails quickly
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lists the models the Ollama server has pulled, failing with a hint when it can't be reached.
+pub async fn list_ollama_models(client: &Ollama) -> Result<Vec<String>, Box<dyn Error>> {
+    let listed = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, client.list_local_models())
+        .await
+        .map_err(|_| format!("Ollama at {} did not respond within {:?}. Is `ollama serve` running?", client.url_str(), HEALTH_CHECK_TIMEOUT))?
+        .map_err(|e| format!("Could not reach Ollama at {}: {}. Is `ollama serve` running?", client.url_str(), e))?;
+    Ok(listed.into_iter().map(|m| m.name).collect())
+}
+
+/// Whether `model` is among `listed`; Ollama reports untagged models with an explicit `:latest` tag.
+pub fn has_model(listed: &[String], model: &str) -> bool {
+    listed.iter().any(|name| name == model || *name == format!("{}:latest", model))
+}
+
+/// Verifies the Ollama server answers and has every model in `models` pulled.
+pub async fn check_ollama_models(client: &Ollama, models: &[&str]) -> Result<(), Box<dyn Error>> {
+    let listed = list_ollama_models(client).await?;
+    let missing: Vec<&str> = models.iter().copied().filter(|model| !has_model(&listed, model)).collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "Ollama at {} is missing model(s): {}. Pull them with `ollama pull <model>` first.",
+            client.url_str(),
+            missing.join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Returns the installed pgvector version, or fails if the `vector` extension isn't installed.
+pub async fn check_vector_extension(pool: &sqlx::PgPool) -> Result<String, Box<dyn Error>> {
+    let version: Option<(String,)> = sqlx::query_as("SELECT extversion FROM pg_extension WHERE extname = 'vector';")
+        .fetch_optional(pool)
+        .await?;
+    let (version,) = version.ok_or("the `vector` extension is not installed. Run `CREATE EXTENSION vector;` as a superuser.")?;
+    Ok(version)
+}
+
+/// Verifies the `embeddings` table exists with the columns and types both binaries rely on.
+pub async fn check_embeddings_table(pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    let columns: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT column_name::text, udt_name::text
+        FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'embeddings';
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    if columns.is_empty() {
+        return Err("table embeddings does not exist. Run `rag-system init` to create it, or run ingestion with --create-table-if-missing.".into());
+    }
+
+    let problems: Vec<String> = [("id", "text"), ("text", "text"), ("vector", "vector"), ("metadata", "jsonb")]
+        .into_iter()
+        .filter_map(|(name, expected)| match columns.iter().find(|(column, _)| column == name) {
+            None => Some(format!("missing column {}", name)),
+            Some((_, actual)) if actual != expected => Some(format!("column {} is {} but should be {}", name, actual, expected)),
+            Some(_) => None,
+        })
+        .collect();
+    if !problems.is_empty() {
+        return Err(format!("table embeddings has the wrong shape: {}", problems.join("; ")).into());
+    }
+    Ok(())
+}
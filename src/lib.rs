@@ -0,0 +1,15 @@
+pub mod bench;
+pub mod cache;
+pub mod embedder;
+pub mod eval;
+pub mod health;
+pub mod logging;
+pub mod metric;
+pub mod mmr;
+pub mod pack;
+pub mod rerank;
+pub mod retry;
+pub mod schema;
+pub mod split;
+pub mod store;
+pub mod vector_store;
@@ -0,0 +1,75 @@
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Where a binary's progress and warnings go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOutput {
+    /// Progress as plain lines on stdout, warnings and errors on stderr
+    Stdout,
+    /// Everything as plain lines on stderr, keeping stdout for results
+    Stderr,
+    /// One JSON object per event on stderr, with its fields (phase, path, duration_ms, error, ...)
+    Json,
+}
+
+/// Installs the global subscriber for the binaries' `tracing` events, logging those up to `level`. Plain
+/// output prints only each event's message, prefixing warnings with `Warning: `, so it reads like ordinary
+/// progress lines; the structured fields only show up as JSON. `Level::WARN` logs only warnings and
+/// errors. `Level::DEBUG` adds the ingester's debug events, but dependencies still log at most `INFO`.
+pub fn init(output: LogOutput, level: Level) {
+    let filter = Targets::new().with_default(level.min(Level::INFO)).with_target("rag_system", level);
+    match output {
+        LogOutput::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_max_level(level)
+            .with_writer(std::io::stderr)
+            .finish()
+            .with(filter)
+            .init(),
+        LogOutput::Stdout => tracing_subscriber::fmt()
+            .event_format(Plain)
+            .with_max_level(level)
+            .with_writer(std::io::stderr.with_max_level(Level::WARN).or_else(std::io::stdout))
+            .finish()
+            .with(filter)
+            .init(),
+        LogOutput::Stderr => tracing_subscriber::fmt().event_format(Plain).with_max_level(level).with_writer(std::io::stderr).finish().with(filter).init(),
+    }
+}
+
+// Formats an event as its message alone
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, _ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        if *event.metadata().level() == Level::WARN {
+            write!(writer, "Warning: ")?;
+        }
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}", message.0)
+    }
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
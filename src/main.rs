@@ -1,14 +1,28 @@
+use clap::{Parser, Subcommand};
+use futures::StreamExt;
+use rag_system::cache::EmbeddingCache;
+use rag_system::embedder::{embed_bisecting, Embedder, EmbedderArgs};
+use rag_system::health;
+use rag_system::logging::{self, LogOutput};
+use rag_system::metric::Metric;
+use rag_system::rerank::DEFAULT_RERANK_MODEL;
+use rag_system::retry::{with_retry, RetryArgs, RetryPolicy};
+use rag_system::schema::{self, IndexMethod, VectorIndex};
+use rag_system::split::{self, DocBlock};
+use rag_system::store::{self, EmbeddingRow, VectorEncoding, DEFAULT_DB_BATCH_SIZE, DEFAULT_VECTOR_PRECISION};
+use rag_system::vector_store::{PgVectorStore, VectorStore};
 use sqlx::postgres::PgPoolOptions;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn, Level};
 use walkdir::WalkDir;
+use notify::{RecursiveMode, Watcher};
+use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::models::ModelOptions;
+use ollama_rs::Ollama;
 use serde_json::json;
-use ollama_rs::{Ollama, generation::embeddings::request::GenerateEmbeddingsRequest};
-
-// Helper function to format a vector for SQL insertion
-fn format_vector(vector: &[f32]) -> String {
-    format!("[{}]", vector.iter().map(|f| f.to_string()).collect::<Vec<_>>().join(","))
-}
 
 // Helper function to get language from file extension
 fn get_language(path: &Path) -> &str {
@@ -30,128 +44,1562 @@ fn get_language(path: &Path) -> &str {
     }
 }
 
+// Prepended to each document before embedding so file names and languages are searchable
+const DEFAULT_CONTEXT_HEADER: &str = "// file: {path}\n// language: {language}\n";
+
+const DEFAULT_SUMMARY_MODEL: &str = "qwen3:4b";
+// Asks for the terms a search would use rather than a prose overview, since the summary is only embedded
+const SUMMARY_PROMPT: &str = "Summarize the file below for a code search index. In a few sentences, say what it does and name its main functions, types, settings and concepts, using the words a developer would search for. Reply with only the summary.\n\nFile: {path}\n\n{content}\n\nSummary:";
+
+/// Ingest the codebase into the pgvector embeddings table.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Root directory to ingest
+    #[arg(long, default_value = "..")]
+    root: String,
+
+    /// Only ingest files with this extension (repeatable, e.g. --ext rs --ext toml); default is all text files
+    #[arg(long = "ext")]
+    extensions: Vec<String>,
+
+    /// Only ingest files changed between this git ref and HEAD (e.g. origin/main), pruning rows for deleted ones
+    #[arg(long, conflicts_with_all = ["export", "import", "reindex"])]
+    since: Option<String>,
+
+    /// Embed text read from stdin as a single document stored under --id, instead of reading the codebase
+    #[arg(long, requires = "id", conflicts_with_all = ["since", "export", "import", "reindex", "watch"])]
+    from_stdin: bool,
+
+    /// Id (and `path` metadata) of the document read with --from-stdin; its extension sets the language
+    #[arg(long, requires = "from_stdin")]
+    id: Option<String>,
+
+    /// Tag stored as `source` in each document's metadata, so queries can search one kind of content with
+    /// --source (default `codebase`, or `stdin` with --from-stdin)
+    #[arg(long)]
+    source: Option<String>,
+
+    /// Keep running after the initial ingest and re-embed files as they change
+    #[arg(long)]
+    watch: bool,
+
+    /// Milliseconds to wait for file events to settle before re-ingesting in watch mode
+    #[arg(long, default_value_t = 1000)]
+    debounce_ms: u64,
+
+    /// Dump every stored row (id, text, vector, metadata) to a newline-delimited JSON file and exit
+    #[arg(long, conflicts_with_all = ["import", "watch"])]
+    export: Option<PathBuf>,
+
+    /// Load rows from a file written by --export instead of embedding the codebase
+    #[arg(long, conflicts_with = "watch")]
+    import: Option<PathBuf>,
+
+    /// Ingest files the generated-code heuristics match instead of skipping them; they're flagged
+    /// `generated` in their metadata so queries can leave them out with --exclude-generated
+    #[arg(long)]
+    include_generated: bool,
+
+    /// Let `<file>.meta.json` sidecars replace the `source`, `language`, `path` and `generated` fields
+    /// the ingester records, instead of ignoring those keys
+    #[arg(long)]
+    sidecar_overrides: bool,
+
+    /// Number of files read concurrently while loading the codebase
+    #[arg(long, default_value_t = 32)]
+    read_concurrency: usize,
+
+    /// Skip files larger than this many bytes, such as minified bundles (0 = no limit)
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_file_size: u64,
+
+    /// Ingest files that look minified instead of skipping them: names like *.min.js, *.min.css and
+    /// *.map, or an average line length over --max-average-line-length
+    #[arg(long)]
+    no_heuristic_skip: bool,
+
+    /// Average characters per line above which a file is taken for minified and skipped
+    #[arg(long, default_value_t = 500)]
+    max_average_line_length: usize,
+
+    /// Also log debug events, such as each file skipped by --max-file-size or the minified-file heuristics
+    #[arg(long)]
+    verbose: bool,
+
+    /// Log progress and warnings as one JSON object per line on stderr (with fields such as phase, path,
+    /// duration_ms and error) instead of plain text, for log aggregation
+    #[arg(long)]
+    json_logs: bool,
+
+    /// Embed only the first this many characters of longer documents, so one oversized file can't fail its
+    /// batch or come back as a meaningless vector; the full text is still stored (0 = no limit)
+    #[arg(long, default_value_t = 24_000)]
+    max_embed_chars: usize,
+
+    /// Embed a model-written summary of each document instead of its raw content, which helps retrieval
+    /// on long or verbose files at the cost of a generation per document. The content is still stored
+    /// for display; a document whose summary fails is embedded from its content
+    #[arg(long)]
+    summarize: bool,
+
+    /// Also store each doc comment, with the declaration it documents, as its own row tagged
+    /// `kind: doc`, and tag file rows `kind: code`, so queries can match intent and implementation
+    /// separately (--filter kind=doc). Rust, Python, Go, JavaScript, TypeScript, Java, C and C++
+    #[arg(long)]
+    split_docs: bool,
+
+    /// Ollama model that writes the --summarize summaries
+    #[arg(long, default_value = DEFAULT_SUMMARY_MODEL, requires = "summarize")]
+    summary_model: String,
+
+    /// Number of --summarize summaries generated concurrently
+    #[arg(long, default_value_t = 4, requires = "summarize")]
+    summary_concurrency: usize,
+
+    /// Number of documents to embed and commit per batch
+    #[arg(long, default_value_t = 32)]
+    batch_size: usize,
+
+    /// Number of rows written per INSERT statement; a statement that fails is retried row by row
+    #[arg(long, default_value_t = DEFAULT_DB_BATCH_SIZE, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    db_batch_size: usize,
+
+    /// Warn after a run when the table holds at least this many rows but no vector index, so every
+    /// query scans all of them (0 = never)
+    #[arg(long, default_value_t = 100_000)]
+    index_warning_rows: u64,
+
+    /// How a run's writes are committed. Client memory is the same in every mode, since rows are sent
+    /// batch by batch; the modes differ in how long the server holds locks and old row versions
+    #[arg(long, value_enum, default_value_t = TxMode::None)]
+    tx_mode: TxMode,
+
+    /// Re-embed every document even if its content and settings are unchanged
+    #[arg(long)]
+    force: bool,
+
+    /// Re-embed the text already stored in the table with the configured model, without reading files
+    #[arg(long, conflicts_with_all = ["export", "import", "watch"])]
+    reindex: bool,
+
+    /// Scan the table for rows whose vector is missing, has the wrong dimension or non-finite or all-zero
+    /// components, or whose text no longer matches its content hash; list them and exit
+    #[arg(long, conflicts_with_all = ["export", "import", "watch", "reindex"])]
+    verify: bool,
+
+    /// With --verify, re-embed the rows it finds from their stored text
+    #[arg(long, requires = "verify")]
+    repair: bool,
+
+    /// Create the embeddings table (sized to the model's dimension) if it doesn't exist yet
+    #[arg(long)]
+    create_table_if_missing: bool,
+
+    /// Header embedded before each document's content; {path} and {language} are substituted.
+    /// Only the embedding sees it; the stored text stays the original content. Pass "" to disable.
+    #[arg(long, default_value = DEFAULT_CONTEXT_HEADER)]
+    context_header: String,
+
+    /// Scale every embedding to unit length before storing it (recorded as `normalized` in the metadata)
+    #[arg(long)]
+    normalize: bool,
+
+    /// Distance metric the index is meant to be searched with; recorded so the query tool defaults to it
+    #[arg(long, value_enum, default_value_t = Metric::Cosine)]
+    metric: Metric,
+
+    /// Significant digits stored per vector component with text vectors (0 for full f32 precision)
+    #[arg(long, default_value_t = DEFAULT_VECTOR_PRECISION)]
+    vector_precision: usize,
+
+    /// Send vectors as text literals instead of pgvector's binary format, for servers or poolers that
+    /// reject binary vector parameters (detected automatically otherwise)
+    #[arg(long)]
+    text_vectors: bool,
+
+    /// Always call the embedding model instead of reusing vectors from the local cache
+    #[arg(long)]
+    no_cache: bool,
+
+    #[command(flatten)]
+    embedder: EmbedderArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create the vector extension and the embeddings table, sized for the embedding model, skipping
+    /// whatever already exists
+    Init {
+        /// Vector dimension for the table, instead of probing the embedding model (which then isn't needed)
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        dim: Option<usize>,
+
+        /// Also create an approximate vector index for --metric, so searches stay fast on large tables at
+        /// a small cost in recall (at most 2000 dimensions). Built concurrently, so a live table stays usable
+        #[arg(long, value_enum)]
+        index: Option<IndexMethod>,
+
+        /// HNSW: links per node; more improves recall at the cost of build time and size [default: 16]
+        #[arg(long)]
+        m: Option<u32>,
+
+        /// HNSW: candidates considered while building; more improves recall, slows the build [default: 64]
+        #[arg(long)]
+        ef_construction: Option<u32>,
+
+        /// IVFFlat: number of lists; about rows / 1000 suits up to a million rows [default: 100]
+        #[arg(long)]
+        lists: Option<u32>,
+    },
+    /// Inspect or rebuild the vector index on the embeddings table
+    Index {
+        #[command(subcommand)]
+        action: IndexAction,
+    },
+    /// Manage the local embedding cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Verify Ollama, the models, the database and the embeddings table are ready, then exit
+    Check {
+        /// Rerank model the query binary will use
+        #[arg(long, default_value = DEFAULT_RERANK_MODEL)]
+        rerank_model: String,
+
+        /// Don't check the rerank model (for setups that only query with --no-rerank)
+        #[arg(long)]
+        no_rerank: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TxMode {
+    /// Each batch's rows commit as they're stored, with no record of the run; a run that dies leaves the
+    /// batches before it stored and the rest to the next run
+    None,
+    /// Each --batch-size batch commits together with the run's progress in the ingest_runs table, which the
+    /// next run reports when this one didn't finish. Row locks last one batch
+    PerBatch,
+    /// The whole run, --since pruning included, is one transaction: queries see the old index until it
+    /// commits, and an error (even one rejected row) or Ctrl-C leaves the table untouched. Every written row stays locked and
+    /// every replaced row version stays on disk until the end, so a large run blocks concurrent writers
+    /// to those rows and holds back vacuum for as long as it takes
+    PerRun,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Delete every cached embedding
+    Clear,
+}
+
+#[derive(Subcommand, Debug)]
+enum IndexAction {
+    /// Show the table's vector indexes, their parameters, size and validity, and its row count
+    Status,
+    /// Rebuild the vector indexes concurrently with their current parameters, such as after loading an
+    /// IVFFlat-indexed table or when a build failed part-way
+    Reindex,
+}
+
+// Runs every setup check, printing a PASS/FAIL line for each, and fails if any check failed
+async fn preflight(args: &Args, rerank_model: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let (mut passed, mut failed) = (0, 0);
+    let mut report = |name: &str, outcome: Result<String, Box<dyn Error>>| match outcome {
+        Ok(detail) => {
+            passed += 1;
+            println!("[PASS] {}: {}", name, detail);
+        }
+        Err(e) => {
+            failed += 1;
+            println!("[FAIL] {}: {}", name, e);
+        }
+    };
+
+    // The query binary always reranks through the local Ollama, which may differ from the embedding server
+    let mut ollama_targets: Vec<(Ollama, Vec<(&str, &str)>)> = Vec::new();
+    if let Some(client) = args.embedder.ollama_client()? {
+        ollama_targets.push((client, vec![("Embedding model", args.embedder.embedding_model.as_str())]));
+    }
+    if let Some(model) = rerank_model {
+        let client = Ollama::builder().host("http://localhost").port(11434).build();
+        match ollama_targets.iter_mut().find(|(existing, _)| existing.url_str() == client.url_str()) {
+            Some((_, models)) => models.push(("Rerank model", model)),
+            None => ollama_targets.push((client, vec![("Rerank model", model)])),
+        }
+    }
+    for (client, models) in &ollama_targets {
+        match health::list_ollama_models(client).await {
+            Ok(listed) => {
+                report("Ollama", Ok(format!("reachable at {}", client.url_str())));
+                for (name, model) in models {
+                    let outcome = if health::has_model(&listed, model) {
+                        Ok(model.to_string())
+                    } else {
+                        Err(format!("{} is not pulled. Run `ollama pull {}`.", model, model).into())
+                    };
+                    report(name, outcome);
+                }
+            }
+            Err(e) => {
+                report("Ollama", Err(e));
+                for (name, model) in models {
+                    report(name, Err(format!("could not check {}: Ollama is unreachable", model).into()));
+                }
+            }
+        }
+    }
+
+    // An actual embedding call also covers non-Ollama backends and tells us the dimension to compare against
+    let dimension = match args.embedder.build() {
+        Ok(embedder) => schema::probe_dimension(embedder.as_ref(), &args.retry.policy()).await,
+        Err(e) => Err(e),
+    };
+    report("Embedding", dimension.as_ref().map(|d| format!("{} produces {}-dim vectors", args.embedder.embedding_model, d)).map_err(|e| e.to_string().into()));
+
+    let pool = match std::env::var("DATABASE_URL") {
+        Ok(database_url) => PgPoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(10))
+            .connect(&database_url)
+            .await
+            .map_err(|e| format!("could not connect: {}", e)),
+        Err(_) => Err("DATABASE_URL is not set".to_string()),
+    };
+    report("Database", pool.as_ref().map(|_| "connected".to_string()).map_err(|e| e.clone().into()));
+
+    if let Ok(pool) = &pool {
+        report("pgvector extension", health::check_vector_extension(pool).await.map(|version| format!("version {}", version)));
+        let table = health::check_embeddings_table(pool).await;
+        let table_ok = table.is_ok();
+        report("Embeddings table", table.map(|_| "id, text, vector and metadata columns present".to_string()));
+        if let (true, Ok(dimension)) = (table_ok, &dimension) {
+            let outcome = schema::check_dimension(pool, *dimension, false).await;
+            report("Vector dimension", outcome.map(|_| format!("column fits {}-dim vectors", dimension)));
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} checks failed", failed, passed + failed).into());
+    }
+    println!("All {} checks passed.", passed);
+    Ok(())
+}
+
+// Everything needed to turn documents into stored vectors, shared by the initial ingest and watch mode
+struct EmbedContext<'a> {
+    embedder: &'a dyn Embedder,
+    retry_policy: RetryPolicy,
+    cache: Option<EmbeddingCache>,
+    document_prefix: String,
+    context_header: String,
+    vector_encoding: VectorEncoding,
+    normalize: bool,
+    max_embed_chars: usize,
+    summarizer: Option<Summarizer>,
+}
+
+// Writes the --summarize summaries that are embedded in place of document content
+struct Summarizer {
+    ollama: Ollama,
+    model: String,
+    concurrency: usize,
+}
+
+impl Summarizer {
+    async fn summarize(&self, retry_policy: &RetryPolicy, path: &str, content: &str) -> Result<String, Box<dyn Error>> {
+        let prompt = SUMMARY_PROMPT.replace("{path}", path).replace("{content}", content);
+        // Greedy decoding gives an unchanged file the same summary, so its vector can come from the cache
+        let response = with_retry(retry_policy, "Summary generation", || {
+            let request = GenerationRequest::new(self.model.clone(), prompt.as_str()).options(ModelOptions::default().temperature(0.0));
+            self.ollama.generate(request)
+        })
+        .await?
+        .response;
+        // Reasoning models put a <think>...</think> block before the summary
+        let summary = match (response.trim_start().starts_with("<think>"), response.find("</think>")) {
+            (true, Some(end)) => &response[end + "</think>".len()..],
+            _ => response.as_str(),
+        };
+        match summary.trim() {
+            "" => Err("the model returned an empty summary".into()),
+            summary => Ok(summary.to_string()),
+        }
+    }
+}
+
+// A document as embedded: its path and stored content, the vector, and what the vector was made from
+struct Embedded {
+    path: String,
+    content: String,
+    vector: Vec<f32>,
+    // Only the first --max-embed-chars characters were embedded (or summarized)
+    truncated: bool,
+    // The --summarize summary embedded in place of the content
+    summary: Option<String>,
+}
+
+impl Embedded {
+    // The metadata describing how the vector was made; the summary fields are null without one so that
+    // patching a row clears a stale summary
+    fn embedding_metadata(&self, summary_model: Option<&str>) -> serde_json::Value {
+        json!({
+            "truncated": self.truncated,
+            "summary": self.summary,
+            "summary_model": self.summary.as_ref().and(summary_model),
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // 1. Load the project's codebase (excluding the /target/ folder)
-    let documents = load_documents().await?;
-    println!("Loaded {} documents.", documents.len());
+    let args = Args::parse();
+    let output = if args.json_logs { LogOutput::Json } else { LogOutput::Stdout };
+    logging::init(output, if args.verbose { Level::DEBUG } else { Level::INFO });
+    let result = run(args).await.map_err(schema::explain_missing_table);
+    // In JSON mode a failure is one more event in the stream rather than a bare line on stderr
+    if let (Err(e), LogOutput::Json) = (&result, output) {
+        error!(phase = "exit", error = %e, "{}", e);
+        std::process::exit(1);
+    }
+    result
+}
 
-    // 2. Initialize the Ollama client for embeddings
-    let ollama = Ollama::new("http://localhost:11434".to_string(), 11434);
-    println!("Ollama client initialized.");
+// Text with --text-vectors, otherwise whatever the server accepts
+async fn vector_encoding(pool: &sqlx::PgPool, args: &Args) -> VectorEncoding {
+    match args.text_vectors {
+        true => VectorEncoding::Text { precision: args.vector_precision },
+        false => VectorEncoding::detect(pool, args.vector_precision).await,
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let retry_policy = args.retry.policy();
 
-    // 3. Generate embeddings for the documents
-    let embeddings = generate_embeddings(&ollama, &documents).await?;
-    println!("Generated {} embeddings.", embeddings.len());
+    match &args.command {
+        Some(Command::Cache { action: CacheAction::Clear }) => {
+            let cache = EmbeddingCache::new(EmbeddingCache::default_dir());
+            let removed = cache.clear().await?;
+            info!(phase = "cache", removed, "Removed {} cached embeddings from {}.", removed, cache.dir().display());
+            return Ok(());
+        }
+        Some(Command::Check { rerank_model, no_rerank }) => {
+            return preflight(&args, (!no_rerank).then_some(rerank_model.as_str())).await;
+        }
+        Some(Command::Init { .. } | Command::Index { .. }) | None => {}
+    }
+    let cache = (!args.no_cache).then(|| EmbeddingCache::new(EmbeddingCache::default_dir()));
 
-    // 4. Initialize the database connection pool
+    // 1. Initialize the embedding backend
+    let embedder = args.embedder.build()?;
+    info!(phase = "init", model = embedder.model(), "Embedder initialized ({:?}, {}).", args.embedder.embedder, embedder.model());
+
+    // 2. Initialize the database connection pool
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     let pool = PgPoolOptions::new()
         .max_connections(5)
-        .connect(database_url)
+        .connect(&database_url)
         .await?;
-    println!("Database pool initialized.");
+    info!(phase = "init", "Database pool initialized.");
+
+    match &args.command {
+        Some(Command::Index { action: IndexAction::Status }) => return index_status(&pool).await,
+        Some(Command::Index { action: IndexAction::Reindex }) => {
+            let rebuilt = schema::reindex_vector_indexes(&pool).await?;
+            match rebuilt.is_empty() {
+                true => println!("{} has no vector index to rebuild; `init --index hnsw` creates one.", schema::DEFAULT_TABLE),
+                false => println!("Rebuilt {}.", rebuilt.join(", ")),
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    if let Some(Command::Init { dim, index, m, ef_construction, lists }) = &args.command {
+        let index = match (index, m.or(*ef_construction), lists) {
+            (Some(IndexMethod::Ivfflat), Some(_), _) => return Err("--m and --ef-construction only apply to --index hnsw".into()),
+            (Some(IndexMethod::Hnsw), _, Some(_)) => return Err("--lists only applies to --index ivfflat".into()),
+            (None, Some(_), _) | (None, _, Some(_)) => return Err("--m, --ef-construction and --lists need --index".into()),
+            (Some(method), _, _) => Some(match VectorIndex::defaults(*method) {
+                VectorIndex::Hnsw { m: default_m, ef_construction: default_ef } => {
+                    VectorIndex::Hnsw { m: m.unwrap_or(default_m), ef_construction: ef_construction.unwrap_or(default_ef) }
+                }
+                VectorIndex::IvfFlat { lists: default_lists } => VectorIndex::IvfFlat { lists: lists.unwrap_or(default_lists) },
+            }),
+            (None, None, None) => None,
+        };
+        let dimension = match dim {
+            Some(dimension) => *dimension,
+            None => {
+                if let Some(client) = args.embedder.ollama_client()? {
+                    health::check_ollama_models(&client, &[&args.embedder.embedding_model]).await?;
+                }
+                let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+                info!(phase = "init", dimension, "Embedding model produces {}-dim vectors.", dimension);
+                dimension
+            }
+        };
+        for step in schema::init(&pool, dimension, index, args.metric).await? {
+            println!("{}", step);
+        }
+        return Ok(());
+    }
+
+    // Export and import move existing vectors around without touching the embedding model
+    if let Some(path) = &args.export {
+        let count = store::export_rows(&pool, path).await?;
+        info!(phase = "export", rows = count, path = %path.display(), "Exported {} rows to {}.", count, path.display());
+        return Ok(());
+    }
+    if let Some(path) = &args.import {
+        let (count, skipped) = store::import_rows(&pool, path, args.create_table_if_missing, vector_encoding(&pool, &args).await).await?;
+        info!(phase = "import", rows = count, path = %path.display(), "Imported {} rows from {}.", count, path.display());
+        return match skipped.len() {
+            0 => Ok(()),
+            failed => Err(format!("{} rows could not be imported: {}", failed, skipped.join(", ")).into()),
+        };
+    }
+    // Verifying only reads the table; repairing goes on to check the model like any other embedding run
+    let invalid = match args.verify {
+        true => Some(verify(&pool).await?),
+        false => None,
+    };
+    match &invalid {
+        Some(invalid) if invalid.is_empty() => return Ok(()),
+        Some(invalid) if !args.repair => return Err(format!("{} rows can't be searched correctly; rerun with --verify --repair to re-embed them", invalid.len()).into()),
+        _ => {}
+    }
+
+    // 3. Fail fast if Ollama is down, the model isn't pulled, or its vectors don't fit the table
+    if let Some(client) = args.embedder.ollama_client()? {
+        health::check_ollama_models(&client, &[&args.embedder.embedding_model]).await?;
+    }
+    let dimension = schema::probe_dimension(embedder.as_ref(), &retry_policy).await?;
+    info!(phase = "init", dimension, "Embedding model produces {}-dim vectors.", dimension);
+    // Summaries come from the embedding server when it's Ollama, otherwise from the local one
+    let summarizer = match args.summarize {
+        true => {
+            let ollama = args.embedder.ollama_client()?.unwrap_or_else(|| Ollama::builder().host("http://localhost").port(11434).build());
+            health::check_ollama_models(&ollama, &[&args.summary_model]).await?;
+            Some(Summarizer { ollama, model: args.summary_model.clone(), concurrency: args.summary_concurrency.max(1) })
+        }
+        false => None,
+    };
+    let run_metadata = json!({
+        "embed_model": embedder.model(),
+        "document_prefix": args.embedder.document_prefix,
+        "query_prefix": args.embedder.query_prefix,
+        "context_header": args.context_header,
+        "normalized": args.normalize,
+        "metric": args.metric.to_string(),
+    });
+    let context = EmbedContext {
+        embedder: embedder.as_ref(),
+        retry_policy,
+        cache,
+        document_prefix: args.embedder.document_prefix.clone(),
+        context_header: args.context_header.clone(),
+        vector_encoding: vector_encoding(&pool, &args).await,
+        normalize: args.normalize,
+        max_embed_chars: args.max_embed_chars,
+        summarizer,
+    };
+
+    if let Some(invalid) = invalid {
+        schema::check_dimension(&pool, dimension, false).await?;
+        return repair(&pool, &context, &run_metadata, &invalid, args.batch_size.max(1)).await;
+    }
+
+    // Reindexing switches models in place, so a dimension change resizes the column instead of failing
+    if args.reindex {
+        if let Some(Some(column_dimension)) = schema::column_dimension(&pool, schema::DEFAULT_TABLE).await? {
+            if column_dimension != dimension {
+                info!(phase = "reindex", from = column_dimension, to = dimension, "Resizing embeddings.vector from vector({}) to vector({}).", column_dimension, dimension);
+                schema::resize_vector_column(&pool, dimension).await?;
+            }
+        }
+        schema::check_dimension(&pool, dimension, false).await?;
+        return reindex(&pool, &context, &run_metadata, args.batch_size.max(1), install_ctrl_c_handler()).await;
+    }
+    schema::check_dimension(&pool, dimension, args.create_table_if_missing).await?;
+    schema::ensure_text_search(&pool).await?;
+    let vector_store = PgVectorStore::new(pool.clone(), args.metric, context.vector_encoding).with_batch_size(args.db_batch_size);
+
+    // 4. Load the project's codebase (excluding the /target/ folder), only the files changed since --since,
+    // or the single document piped in with --from-stdin
+    let started = Instant::now();
+    let (documents, deleted) = match (&args.since, &args.id) {
+        (_, Some(id)) if args.from_stdin => (vec![(id.clone(), read_stdin_document().await?)], Vec::new()),
+        (Some(since), _) => load_changed_documents(&args, since).await?,
+        _ => (load_documents(&args).await?, Vec::new()),
+    };
+    info!(phase = "load", documents = documents.len(), duration_ms = started.elapsed().as_millis() as u64, "Loaded {} documents.", documents.len());
+    // An empty --since diff is routine, but finding nothing at all usually means a wrong --root or --ext
+    if documents.is_empty() && args.since.is_none() {
+        match args.extensions.is_empty() {
+            true => warn!(phase = "load", "No text files were found under {}; check --root.", args.root),
+            false => warn!(phase = "load", "No files with extensions {} were found under {}; check --root and --ext.", args.extensions.join(", "), args.root),
+        }
+    }
+
+    // 5. Skip documents whose content and embedding settings are unchanged since the last run
+    let source = source_tag(&args);
+    let pending = if args.force { documents } else { skip_unchanged(&pool, documents, &run_metadata, source, context.summary_model(), args.split_docs).await? };
+    info!(phase = "diff", pending = pending.len(), "{} documents are new or changed.", pending.len());
 
-    // 5. Store the embeddings in the TimescaleDB database
-    store_embeddings(&pool, &embeddings).await?;
-    println!("Successfully stored embeddings in the database.");
+    // With --tx-mode per-run everything from the pruning on is written in this transaction. It starts
+    // only now, so its connection doesn't sit idle in a transaction while files load.
+    let mut run_tx = match args.tx_mode {
+        TxMode::PerRun => Some(pool.begin().await?),
+        TxMode::PerBatch | TxMode::None => None,
+    };
+    if let (false, Some(since)) = (deleted.is_empty(), &args.since) {
+        let removed = match &mut run_tx {
+            Some(tx) => store::delete_rows(&mut **tx, &deleted).await?,
+            None => store::delete_rows(&pool, &deleted).await?,
+        };
+        info!(phase = "prune", rows = removed, "Pruned {} rows for files deleted since {}.", removed, since);
+    }
+    // Doc rows are rebuilt with their file, so the old ones go even if --split-docs is now off
+    let changed: Vec<String> = pending.iter().map(|(path, _)| path.clone()).collect();
+    match &mut run_tx {
+        Some(tx) => store::delete_doc_rows(&mut **tx, &changed).await?,
+        None => store::delete_doc_rows(&pool, &changed).await?,
+    };
+    let (pending, docs) = add_doc_rows(&args, pending);
+    let doc_paths = doc_paths(docs.as_ref());
+
+    let run = match args.tx_mode {
+        TxMode::PerBatch => {
+            schema::ensure_ingest_runs(&pool).await?;
+            if let Some((stored, total, started_at)) = store::unfinished_run(&pool, source).await? {
+                info!(phase = "diff", stored, total, "The previous run ({}) stopped after storing {} of {} documents; resuming with the rest.", started_at.to_rfc3339(), stored, total);
+            }
+            Some(store::start_run(&pool, source, pending.len()).await?)
+        }
+        TxMode::PerRun | TxMode::None => None,
+    };
+
+    // 6. Embed and store in batches; Ctrl-C stops after the in-flight batch is committed
+    let cancel = install_ctrl_c_handler();
+    let mut stored = 0;
+    let mut storing = Duration::ZERO;
+    // Rows the database rejected on their own; the run fails at the end when there are any
+    let mut failed_rows = Vec::new();
+    for batch in pending.chunks(args.batch_size.max(1)) {
+        if *cancel.borrow() {
+            break;
+        }
+        let started = Instant::now();
+        let embeddings = generate_embeddings(&context, batch, &doc_paths).await?;
+        let store_started = Instant::now();
+        let skipped = match (&mut run_tx, run) {
+            (Some(tx), _) => {
+                let rows = embedding_rows(&embeddings, source, &run_metadata, context.summary_model(), args.sidecar_overrides, docs.as_ref()).await;
+                // All or nothing: a rejected row fails the run, which rolls back
+                store::write_rows(tx, schema::DEFAULT_TABLE, &rows, context.vector_encoding, args.db_batch_size, false).await?
+            }
+            (None, Some(run)) => {
+                let rows = embedding_rows(&embeddings, source, &run_metadata, context.summary_model(), args.sidecar_overrides, docs.as_ref()).await;
+                let mut tx = pool.begin().await?;
+                let skipped = store::write_rows(&mut tx, schema::DEFAULT_TABLE, &rows, context.vector_encoding, args.db_batch_size, true).await?;
+                store::record_progress(&mut tx, run, stored + embeddings.len() - skipped.len()).await?;
+                tx.commit().await?;
+                skipped
+            }
+            (None, None) => store_embeddings(&vector_store, &embeddings, source, &run_metadata, context.summary_model(), args.sidecar_overrides, docs.as_ref()).await?,
+        };
+        storing += store_started.elapsed();
+        stored += embeddings.len() - skipped.len();
+        failed_rows.extend(skipped);
+        info!(phase = "store", stored, total = pending.len(), duration_ms = started.elapsed().as_millis() as u64, "Stored {}/{} embeddings.", stored, pending.len());
+    }
+    if let Some(cache) = &context.cache {
+        info!(phase = "cache", hits = cache.hits(), misses = cache.misses(), "Embedding cache: {} hits, {} misses.", cache.hits(), cache.misses());
+    }
+    if *cancel.borrow() {
+        if run_tx.take().is_some() {
+            warn!(phase = "store", total = pending.len(), "Interrupted: rolled back the run, so none of the {} pending documents were stored.", pending.len());
+            return Ok(());
+        }
+        warn!(phase = "store", stored, total = pending.len(), "Interrupted: stored {} of {} pending documents. Rerun to resume with the rest.", stored, pending.len());
+        return match failed_rows.len() {
+            0 => Ok(()),
+            failed => Err(format!("{} rows could not be stored and were skipped: {}", failed, failed_rows.join(", ")).into()),
+        };
+    }
+    if let Some(tx) = run_tx {
+        let committing = Instant::now();
+        tx.commit().await?;
+        storing += committing.elapsed();
+    }
+    if let Some(run) = run {
+        store::finish_run(&pool, run).await?;
+    }
+    let rows_per_sec = stored as f64 / storing.as_secs_f64().max(f64::EPSILON);
+    info!(phase = "store", stored, duration_ms = storing.as_millis() as u64, rows_per_sec, "Successfully stored embeddings in the database ({:.0} rows/s).", rows_per_sec);
+    if args.index_warning_rows > 0 {
+        match schema::unindexed_rows(&pool).await? {
+            Some(rows) if rows >= args.index_warning_rows as i64 => warn!(
+                phase = "store",
+                rows,
+                "{} has {} rows and no vector index, so every query scans them all; `rag-system init --index hnsw` builds one without blocking the table.",
+                schema::DEFAULT_TABLE,
+                rows
+            ),
+            _ => {}
+        }
+    }
+
+    if !failed_rows.is_empty() {
+        return Err(format!("{} rows could not be stored and were skipped: {}", failed_rows.len(), failed_rows.join(", ")).into());
+    }
+
+    // 7. Optionally keep the index fresh as files change
+    if args.watch {
+        watch(&args, &context, &pool, &vector_store, &run_metadata, cancel).await?;
+    }
 
     Ok(())
 }
 
-async fn load_documents() -> Result<Vec<(String, String)>, Box<dyn Error>> {
-    let mut documents = Vec::new();
-    // Choose the filetypes to ignore during ingestion to reduce query noise
-    let ignored_files: Vec<&str> = vec![".gitignore", "Cargo.lock", "yarn.lock", "package-lock.json", "debug_log.txt", "Cargo.toml", "Dockerfile", ".env"];
-    let ignored_dirs: Vec<&str> = vec!["/target/", "/.git/", "/venv/", "/__pycache__/", "/.sqlx/"];
+// Choose the filetypes to ignore during ingestion to reduce query noise
+const IGNORED_FILES: &[&str] = &[".gitignore", "Cargo.lock", "yarn.lock", "package-lock.json", "debug_log.txt", "Cargo.toml", "Dockerfile", ".env"];
+const IGNORED_DIRS: &[&str] = &["/target/", "/.git/", "/venv/", "/__pycache__/", "/.sqlx/"];
+// Editor swap and backup files churn constantly while editing and are never worth embedding; sidecars
+// are merged into the metadata of the document they describe
+const IGNORED_SUFFIXES: &[&str] = &[".swp", ".swx", ".swo", "~", SIDECAR_SUFFIX];
+
+// Minified assets, source maps and bundles: one huge line each, and nothing a query should match
+const MINIFIED_SUFFIXES: &[&str] = &[".min.js", ".min.css", ".map"];
+
+// `notes.md.meta.json` holds extra metadata (tags, title, owner, ...) for `notes.md`
+const SIDECAR_SUFFIX: &str = ".meta.json";
+// Fields a sidecar may replace with --sidecar-overrides; the rest record how the row was embedded and
+// are always the ingester's
+const SIDECAR_OVERRIDABLE: &[&str] = &["source", "language", "path", "generated"];
+
+fn is_excluded(path: &Path) -> bool {
+    let path_str = path.to_string_lossy();
+    // Check if any part of the path contains an ignored directory
+    if IGNORED_DIRS.iter().any(|dir| path_str.contains(dir)) {
+        return true;
+    }
+    // Check if the file name itself is in the ignored_files list
+    if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
+        if IGNORED_FILES.contains(&file_name) || IGNORED_SUFFIXES.iter().any(|suffix| file_name.ends_with(suffix)) {
+            return true;
+        }
+    }
+    false
+}
+
+// Filter out specific auto-generated or boilerplate code that adds noise but little
+// semantic value for RAG. Users should customize these filters based on their project's
+// specific needs to improve context quality and reduce token count.
+fn is_generated(path: &Path, content: &str) -> bool {
+    path.extension().and_then(|s| s.to_str()) == Some("rs") &&
+        (content.contains("/// This module was auto-generated with ethers-rs Abigen.") ||
+         content.contains("pub struct OnnxModels {"))
+}
+
+// Whether the minified-file heuristics skip a file by its name, naming it in a debug event (--verbose)
+fn has_minified_name(path: &Path, args: &Args) -> bool {
+    let minified = !args.no_heuristic_skip
+        && path.file_name().and_then(|n| n.to_str()).is_some_and(|file_name| MINIFIED_SUFFIXES.iter().any(|suffix| file_name.ends_with(suffix)));
+    if minified {
+        debug!(phase = "load", path = %path.display(), "Skipping {} (named like a minified asset or source map).", path.display());
+    }
+    minified
+}
+
+// Whether the minified-file heuristics skip a file by its average line length, naming it in a debug
+// event (--verbose)
+fn has_minified_lines(path: &Path, content: &str, args: &Args) -> bool {
+    if args.no_heuristic_skip || content.is_empty() {
+        return false;
+    }
+    let average = content.chars().count() / content.lines().count().max(1);
+    let minified = average > args.max_average_line_length;
+    if minified {
+        debug!(phase = "load", path = %path.display(), average, "Skipping {} ({} characters per line on average, over --max-average-line-length {}).", path.display(), average, args.max_average_line_length);
+    }
+    minified
+}
 
-    for entry in WalkDir::new("..")
+// Why read_document left a file out
+#[derive(Debug, PartialEq, Eq)]
+enum Unread {
+    TooLarge,
+    // Not UTF-8, which likely means binary, or gone before it could be read
+    Unreadable,
+    Generated,
+    Minified,
+}
+
+async fn read_document(path: &Path, args: &Args) -> Result<(String, String), Unread> {
+    // Checked before reading so a huge file is never loaded into memory
+    let size = tokio::fs::metadata(path).await.map_err(|_| Unread::Unreadable)?.len();
+    if exceeds_max_file_size(path, size, args) {
+        return Err(Unread::TooLarge);
+    }
+    let content = tokio::fs::read_to_string(path).await.map_err(|_| Unread::Unreadable)?;
+    if !args.include_generated && is_generated(path, &content) {
+        return Err(Unread::Generated);
+    }
+    if has_minified_lines(path, &content, args) {
+        return Err(Unread::Minified);
+    }
+    Ok((path.to_string_lossy().to_string(), content))
+}
+
+// The document a sidecar file describes, or None if `path` isn't a sidecar
+fn sidecar_document(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let document = file_name.strip_suffix(SIDECAR_SUFFIX).filter(|document| !document.is_empty())?;
+    Some(path.with_file_name(document))
+}
+
+// The fields of the sidecar next to `path` and a hash of its content; None when there's no sidecar or
+// it isn't a JSON object, which is warned about and otherwise ignored
+async fn read_sidecar(path: &str) -> Option<(serde_json::Map<String, serde_json::Value>, String)> {
+    let sidecar = format!("{}{}", path, SIDECAR_SUFFIX);
+    let content = tokio::fs::read_to_string(&sidecar).await.ok()?;
+    match serde_json::from_str(&content) {
+        Ok(serde_json::Value::Object(fields)) => Some((fields, store::content_hash(&content))),
+        _ => {
+            warn!(phase = "load", path = %sidecar, "Ignoring {}: a sidecar must hold a JSON object.", sidecar);
+            None
+        }
+    }
+}
+
+// An empty allowlist accepts every extension
+fn has_allowed_extension(path: &Path, extensions: &[String]) -> bool {
+    if extensions.is_empty() {
+        return true;
+    }
+    let Some(extension) = path.extension().and_then(|s| s.to_str()) else {
+        return false;
+    };
+    extensions.iter().any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(extension))
+}
+
+// Whether a file is over --max-file-size, naming it in a debug event (--verbose)
+fn exceeds_max_file_size(path: &Path, size: u64, args: &Args) -> bool {
+    let too_large = args.max_file_size > 0 && size > args.max_file_size;
+    if too_large {
+        debug!(phase = "load", path = %path.display(), size, "Skipping {} ({} bytes, over --max-file-size {}).", path.display(), size, args.max_file_size);
+    }
+    too_large
+}
+
+// Walks the tree for candidate paths first, then reads up to --read-concurrency files at a time
+async fn load_documents(args: &Args) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let extensions = &args.extensions;
+    let mut paths = Vec::new();
+    let (mut skipped_by_extension, mut skipped_by_size, mut skipped_by_name) = (0, 0, 0);
+    for entry in WalkDir::new(&args.root)
         .into_iter()
         .filter_map(Result::ok)
-        .filter(|e| {
-            let path_str = e.path().to_string_lossy();
-            // Check if any part of the path contains an ignored directory
-            if ignored_dirs.iter().any(|dir| path_str.contains(dir)) {
-                return false;
-            }
-            // Check if the file name itself is in the ignored_files list
-            if let Some(file_name) = e.path().file_name().and_then(|n| n.to_str()) {
-                if ignored_files.contains(&file_name) {
-                    return false;
-                }
-            }
-            true
-        })
+        .filter(|e| !is_excluded(e.path()))
         .filter(|e| e.file_type().is_file())
     {
-        let path = entry.path();
-        let path_str = path.to_string_lossy().to_string();
-        if let Ok(content) = tokio::fs::read_to_string(path).await {
-            // Filter out specific auto-generated or boilerplate code that adds noise but little
-            // semantic value for RAG. Users should customize these filters based on their project's
-            // specific needs to improve context quality and reduce token count.
-            if path.extension().and_then(|s| s.to_str()) == Some("rs") && 
-               (content.contains("/// This module was auto-generated with ethers-rs Abigen.") || 
-                content.contains("pub struct OnnxModels {")) {
-                continue;
-            }
-            documents.push((path_str, content));
-        } else {
-            // If reading as UTF-8 fails, it's likely a binary file, so skip it.
+        if !has_allowed_extension(entry.path(), extensions) {
+            skipped_by_extension += 1;
             continue;
         }
+        // The walk already has the size, so oversized files are dropped without another stat
+        if exceeds_max_file_size(entry.path(), entry.metadata().map(|metadata| metadata.len()).unwrap_or(0), args) {
+            skipped_by_size += 1;
+            continue;
+        }
+        if has_minified_name(entry.path(), args) {
+            skipped_by_name += 1;
+            continue;
+        }
+        paths.push(entry.into_path());
+    }
+    if !extensions.is_empty() {
+        info!(phase = "load", skipped = skipped_by_extension, "Skipped {} files not matching --ext {}.", skipped_by_extension, extensions.join(", "));
+    }
+    if skipped_by_size > 0 {
+        info!(phase = "load", skipped = skipped_by_size, "Skipped {} files larger than --max-file-size {} bytes{}.", skipped_by_size, args.max_file_size, if args.verbose { "" } else { " (--verbose lists them)" });
     }
+
+    let reads: Vec<Result<(String, String), Unread>> = futures::stream::iter(paths)
+        .map(|path| async move { read_document(&path, args).await })
+        .buffer_unordered(args.read_concurrency.max(1))
+        .collect()
+        .await;
+    let skipped_by_lines = reads.iter().filter(|read| matches!(read, Err(Unread::Minified))).count();
+    // Both counts, even when zero, so a run shows the heuristics were applied
+    if !args.no_heuristic_skip {
+        let hint = if args.verbose { "" } else { "; --verbose lists them" };
+        info!(phase = "load", skipped = skipped_by_name, "Skipped {} minified assets and source maps by name ({}{}).", skipped_by_name, MINIFIED_SUFFIXES.join(", "), hint);
+        info!(phase = "load", skipped = skipped_by_lines, "Skipped {} files averaging over {} characters per line as minified{}.", skipped_by_lines, args.max_average_line_length, if args.verbose { "" } else { " (--verbose lists them)" });
+    }
+    let mut documents: Vec<(String, String)> = reads.into_iter().filter_map(Result::ok).collect();
+    // Reads finish in any order; sorting keeps batches the same from run to run
+    documents.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
     Ok(documents)
 }
 
-async fn generate_embeddings(ollama: &Ollama, documents: &[(String, String)]) -> Result<Vec<(String, String, Vec<f32>)>, Box<dyn Error>> {
-    let mut embeddings = Vec::new();
+// The `source` metadata tag for this run's documents
+fn source_tag(args: &Args) -> &str {
+    match &args.source {
+        Some(source) => source,
+        None if args.from_stdin => "stdin",
+        None => "codebase",
+    }
+}
+
+// Reads all of stdin as the content of one --from-stdin document
+async fn read_stdin_document() -> Result<String, Box<dyn Error>> {
+    let mut content = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut tokio::io::stdin(), &mut content)
+        .await
+        .map_err(|e| format!("Failed to read stdin as UTF-8 text: {}", e))?;
+    if content.trim().is_empty() {
+        return Err("--from-stdin read no text from stdin".into());
+    }
+    Ok(content)
+}
+
+// Asks git which files under the root changed between `since` and HEAD, returning the readable ones that
+// pass the usual exclusion rules and the ids of deleted ones. Renames count as a deletion plus an addition.
+async fn load_changed_documents(args: &Args, since: &str) -> Result<(Vec<(String, String)>, Vec<String>), Box<dyn Error>> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(&args.root)
+        .args(["diff", "--name-status", "--no-renames", "--relative", "-z", since, "HEAD", "--"])
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git diff {} HEAD failed: {}", since, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+
+    // With -z the output is a sequence of NUL-terminated status and path fields
+    let fields: Vec<&[u8]> = output.stdout.split(|&b| b == 0).filter(|field| !field.is_empty()).collect();
+    let mut documents: Vec<(String, String)> = Vec::new();
+    let mut deleted = Vec::new();
+    for pair in fields.chunks(2) {
+        let [status, relative] = pair else { continue };
+        // Ids are the walked paths, which start with the root exactly as given
+        let path = Path::new(&args.root).join(String::from_utf8_lossy(relative).as_ref());
+        // A changed or deleted sidecar changes its document's metadata, so the document is read again
+        let (path, sidecar) = match sidecar_document(&path) {
+            Some(document) => (document, true),
+            None => (path, false),
+        };
+        if is_excluded(&path) || !has_allowed_extension(&path, &args.extensions) || has_minified_name(&path, args) {
+            continue;
+        }
+        if status.starts_with(b"D") && !sidecar {
+            deleted.push(path.to_string_lossy().to_string());
+        } else if documents.iter().any(|(id, _)| Path::new(id) == path) {
+            continue;
+        } else if let Ok(document) = read_document(&path, args).await {
+            documents.push(document);
+        }
+    }
+    info!(phase = "load", changed = documents.len(), deleted = deleted.len(), "git reports {} changed and {} deleted files since {}.", documents.len(), deleted.len(), since);
+    Ok((documents, deleted))
+}
+
+// The first Ctrl-C flips the returned flag so the ingest can stop between batches; a second one exits immediately
+fn install_ctrl_c_handler() -> tokio::sync::watch::Receiver<bool> {
+    let (tx, rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Ctrl-C received; finishing the current batch (press Ctrl-C again to abort)...");
+            let _ = tx.send(true);
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    rx
+}
+
+async fn skip_unchanged(pool: &sqlx::PgPool, documents: Vec<(String, String)>, run_metadata: &serde_json::Value, source: &str, summary_model: Option<&str>, split_docs: bool) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    // Only rows embedded with the same model and prefixes (metadata @> run settings) count as up to date,
+    // and only under the same source tag so re-tagged content is stored again. Rows must also have been
+    // summarized by the same --summary-model, or not at all without --summarize, so toggling it (or a
+    // summary that failed last time) embeds them again. The same goes for --split-docs and the kind tag
+    let ids: Vec<&str> = documents.iter().map(|(path, _)| path.as_str()).collect();
+    let stored: HashMap<String, (Option<String>, Option<String>)> = sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+        "SELECT id, metadata->>'content_hash', metadata->>'sidecar_hash' FROM embeddings \
+         WHERE id = ANY($1) AND metadata @> $2 AND metadata->>'source' = $3 AND metadata->>'summary_model' IS NOT DISTINCT FROM $4 \
+         AND metadata->>'kind' IS NOT DISTINCT FROM $5;",
+    )
+    .bind(&ids)
+    .bind(run_metadata)
+    .bind(source)
+    .bind(summary_model)
+    .bind(split_docs.then_some("code"))
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|(id, content_hash, sidecar_hash)| (id, (content_hash, sidecar_hash)))
+    .collect();
+
+    // A document is also stored again when its sidecar was added, edited or removed
+    let mut pending = Vec::new();
     for (path, content) in documents {
-        let request = GenerateEmbeddingsRequest::new(
-            "dengcao/Qwen3-Embedding-4B:Q4_K_M".to_string(),
-            ollama_rs::generation::embeddings::request::EmbeddingsInput::Single(content.clone()),
-        );
+        let sidecar_hash = read_sidecar(&path).await.map(|(_, hash)| hash);
+        if stored.get(&path) != Some(&(Some(store::content_hash(&content)), sidecar_hash)) {
+            pending.push((path, content));
+        }
+    }
+    Ok(pending)
+}
 
-        match ollama.generate_embeddings(request).await {
-            Ok(response) => {
-                if let Some(embedding) = response.embeddings.into_iter().next() {
-                    embeddings.push((path.clone(), content.clone(), embedding));
+// The first --max-embed-chars characters of `content`, or None when it's short enough to embed whole
+fn truncate_for_embedding<'c>(context: &EmbedContext<'_>, content: &'c str) -> Option<&'c str> {
+    if context.max_embed_chars == 0 {
+        return None;
+    }
+    content.char_indices().nth(context.max_embed_chars).map(|(end, _)| &content[..end])
+}
+
+impl EmbedContext<'_> {
+    fn summary_model(&self) -> Option<&str> {
+        self.summarizer.as_ref().map(|summarizer| summarizer.model.as_str())
+    }
+}
+
+// With --summarize, a summary of each document's (possibly truncated) content; None for each document
+// whose summary failed, which is warned about and embedded from its content instead
+async fn summarize_documents(context: &EmbedContext<'_>, documents: &[(String, String)], doc_paths: &DocPaths) -> Vec<Option<String>> {
+    let Some(summarizer) = &context.summarizer else {
+        return vec![None; documents.len()];
+    };
+    let started = Instant::now();
+    let summaries: Vec<Option<String>> = futures::stream::iter(documents)
+        .map(|(path, content)| async move {
+            // A doc comment is already a summary of its declaration
+            if doc_paths.contains_key(path) {
+                return None;
+            }
+            let content = truncate_for_embedding(context, content).unwrap_or(content);
+            match summarizer.summarize(&context.retry_policy, path, content).await {
+                Ok(summary) => Some(summary),
+                Err(e) => {
+                    warn!(phase = "summarize", path = %path, error = %e, "Summarizing {} failed ({}); embedding its content instead.", path, e);
+                    None
                 }
-            },
-            Err(e) => {
-                eprintln!("Failed to generate embedding for {}: {}", path, e);
             }
+        })
+        .buffered(summarizer.concurrency)
+        .collect()
+        .await;
+    let summarized = summaries.iter().flatten().count();
+    let total = documents.len() - documents.iter().filter(|(path, _)| doc_paths.contains_key(path)).count();
+    info!(phase = "summarize", summarized, total, duration_ms = started.elapsed().as_millis() as u64, "Summarized {}/{} documents with {}.", summarized, total, summarizer.model);
+    summaries
+}
+
+// Embeds documents, returning each one that could be embedded. Doc comment rows (`doc_paths`) get their
+// file's path and language in the context header and are never summarized.
+async fn generate_embeddings(context: &EmbedContext<'_>, documents: &[(String, String)], doc_paths: &DocPaths) -> Result<Vec<Embedded>, Box<dyn Error>> {
+    let embedder = context.embedder;
+    let mut vectors: Vec<Option<Vec<f32>>> = vec![None; documents.len()];
+    let mut truncated = vec![false; documents.len()];
+    let summaries = summarize_documents(context, documents, doc_paths).await;
+    // Cache misses are embedded together in one request, as (document index, label, input)
+    let (mut miss_indices, mut miss_labels, mut miss_inputs) = (Vec::new(), Vec::new(), Vec::new());
+    for (i, (path, content)) in documents.iter().enumerate() {
+        // The prefix and header are part of what gets embedded, so they're part of the cache key too
+        let file = doc_paths.get(path).unwrap_or(path);
+        let header = context
+            .context_header
+            .replace("{language}", get_language(Path::new(file)))
+            .replace("{path}", file);
+        let embedded_content = match truncate_for_embedding(context, content) {
+            Some(prefix) => {
+                truncated[i] = true;
+                prefix
+            }
+            None => content,
+        };
+        let embedded_content = summaries[i].as_deref().unwrap_or(embedded_content);
+        let input = format!("{}{}{}", context.document_prefix, header, embedded_content);
+        if let Some(cache) = &context.cache {
+            if let Some(embedding) = cache.get(embedder.model(), &input).await {
+                vectors[i] = Some(embedding);
+                continue;
+            }
+        }
+        miss_indices.push(i);
+        miss_labels.push(path.clone());
+        miss_inputs.push(input);
+    }
+
+    // A failed batch is split until the documents that can't be embedded are isolated and skipped
+    let embedded = embed_bisecting(embedder, &context.retry_policy, &miss_labels, &miss_inputs).await;
+    for ((i, input), embedding) in miss_indices.into_iter().zip(&miss_inputs).zip(embedded) {
+        let Some(embedding) = embedding else { continue };
+        if let Some(cache) = &context.cache {
+            if let Err(e) = cache.put(embedder.model(), input, &embedding).await {
+                warn!(phase = "cache", path = %documents[i].0, error = %e, "Failed to cache embedding for {}: {}", documents[i].0, e);
+            }
+        }
+        vectors[i] = Some(embedding);
+    }
+
+    let truncated_paths: Vec<&str> = documents.iter().zip(&truncated).filter(|(_, &cut)| cut).map(|((path, _), _)| path.as_str()).collect();
+    if !truncated_paths.is_empty() {
+        warn!(
+            phase = "embed",
+            paths = %truncated_paths.join(", "),
+            "Embedded only the first {} characters of {} documents (--max-embed-chars): {}",
+            context.max_embed_chars,
+            truncated_paths.len(),
+            truncated_paths.join(", ")
+        );
+    }
+
+    // The cache holds the model's raw output, so normalization happens after lookup
+    Ok(documents
+        .iter()
+        .zip(vectors)
+        .zip(truncated)
+        .zip(summaries)
+        .filter_map(|((((path, content), embedding), truncated), summary)| {
+            let mut vector = embedding?;
+            if context.normalize && !store::normalize(&mut vector) {
+                warn!(phase = "embed", path = %path, "{} embedded to a zero vector, which can't be normalized; storing it unchanged", path);
+            }
+            Some(Embedded { path: path.clone(), content: content.clone(), vector, truncated, summary })
+        })
+        .collect())
+}
+
+async fn store_embeddings(vector_store: &dyn VectorStore, embeddings: &[Embedded], source: &str, run_metadata: &serde_json::Value, summary_model: Option<&str>, sidecar_overrides: bool, docs: Option<&DocRows>) -> Result<Vec<String>, Box<dyn Error>> {
+    vector_store.upsert(&embedding_rows(embeddings, source, run_metadata, summary_model, sidecar_overrides, docs).await).await
+}
+
+// A doc comment row added by --split-docs: the path and content hash of the file it comes from, and the
+// comment itself
+struct DocRow {
+    path: String,
+    file_sha256: String,
+    block: DocBlock,
+}
+
+// Doc comment rows by row id
+type DocRows = HashMap<String, DocRow>;
+
+// The file each doc comment row comes from, by row id
+type DocPaths = HashMap<String, String>;
+
+fn doc_paths(docs: Option<&DocRows>) -> DocPaths {
+    docs.into_iter().flatten().map(|(id, doc)| (id.clone(), doc.path.clone())).collect()
+}
+
+// With --split-docs, `documents` followed by a row for each doc comment in them, and what those rows hold
+fn add_doc_rows(args: &Args, mut documents: Vec<(String, String)>) -> (Vec<(String, String)>, Option<DocRows>) {
+    if !args.split_docs {
+        return (documents, None);
+    }
+    let mut docs = DocRows::new();
+    for (path, content) in &documents {
+        for block in split::extract_docs(get_language(Path::new(path)), content) {
+            // Like a GitHub line anchor, so ids stay unique when a symbol name repeats
+            let id = format!("{}#L{}-L{}", path, block.start_line, block.end_line);
+            docs.insert(id, DocRow { path: path.clone(), file_sha256: store::content_hash(content), block });
         }
     }
-    Ok(embeddings)
+    documents.extend(docs.iter().map(|(id, doc)| (id.clone(), doc.block.text.clone())));
+    (documents, Some(docs))
 }
 
-async fn store_embeddings(pool: &sqlx::PgPool, embeddings: &[(String, String, Vec<f32>)]) -> Result<(), Box<dyn Error>> {
-    for (path, content, vector) in embeddings {
-        let metadata = json!({
-            "source": "codebase",
+// The rows to store for a batch of embedded documents, with their metadata. With --split-docs (`docs`),
+// rows are tagged with their kind, and doc rows describe their file and span.
+async fn embedding_rows(embeddings: &[Embedded], source: &str, run_metadata: &serde_json::Value, summary_model: Option<&str>, sidecar_overrides: bool, docs: Option<&DocRows>) -> Vec<EmbeddingRow> {
+    let embedded_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    // Stored paths are relative to where the ingester ran, so the query binary can find the files again
+    let ingest_dir = std::env::current_dir().ok().map(|dir| dir.to_string_lossy().to_string());
+    let mut rows = Vec::with_capacity(embeddings.len());
+    for embedded in embeddings {
+        let Embedded { path: id, content, vector, .. } = embedded;
+        let doc = docs.and_then(|docs| docs.get(id));
+        let path = doc.map_or(id, |doc| &doc.path);
+        let mut metadata = json!({
+            "source": source,
+            "embedded_at": embedded_at,
             "language": get_language(Path::new(path)),
             "path": path,
+            "ingest_dir": ingest_dir,
+            "content_hash": store::content_hash(content),
+            "char_count": content.chars().count(),
+            "line_count": content.lines().count(),
+            // File rows are whole files, so the span is every line
+            "start_line": doc.map_or(1, |doc| doc.block.start_line),
+            "end_line": doc.map_or(content.lines().count().max(1), |doc| doc.block.end_line),
+            "generated": is_generated(Path::new(path), content),
         });
-        let vector_str = format_vector(vector);
-
-        // Use INSERT ON CONFLICT to update existing entries
-        sqlx::query(
-            r#"
-            INSERT INTO embeddings (id, text, vector, metadata)
-            VALUES ($1, $2, $3::vector, $4)
-            ON CONFLICT (id) DO UPDATE
-            SET text = EXCLUDED.text,
-                vector = EXCLUDED.vector,
-                metadata = EXCLUDED.metadata;
-            "#,
-        )
-        .bind(path)
-        .bind(content)
-        .bind(vector_str)
-        .bind(metadata)
-        .execute(pool)
-        .await?;
+        if let (Some(docs), Some(metadata)) = (docs, metadata.as_object_mut()) {
+            match docs.get(id) {
+                // content_hash is the comment's, so the file's is kept for citations
+                Some(doc) => metadata.extend([
+                    ("kind".to_string(), json!("doc")),
+                    ("symbol".to_string(), json!(doc.block.symbol)),
+                    ("file_sha256".to_string(), json!(doc.file_sha256)),
+                ]),
+                None => metadata.extend([("kind".to_string(), json!("code"))]),
+            }
+        }
+        // Merge run-wide settings (prefixes, ...) so later queries can check they embed consistently
+        if let (Some(metadata), Some(run_metadata), serde_json::Value::Object(embedding_metadata)) =
+            (metadata.as_object_mut(), run_metadata.as_object(), embedded.embedding_metadata(summary_model))
+        {
+            metadata.extend(run_metadata.clone());
+            metadata.extend(embedding_metadata);
+        }
+        if let (Some(metadata), Some((fields, hash))) = (metadata.as_object_mut(), read_sidecar(path).await) {
+            merge_sidecar(metadata, fields, sidecar_overrides, path);
+            metadata.insert("sidecar_hash".to_string(), json!(hash));
+        }
+        rows.push(EmbeddingRow { id: id.clone(), text: content.clone(), vector: vector.clone(), metadata });
+    }
+    rows
+}
+
+// Adds a sidecar's fields to a row's metadata. Fields the ingester already set are kept, except those in
+// SIDECAR_OVERRIDABLE with --sidecar-overrides; the ones kept are named in a warning.
+fn merge_sidecar(metadata: &mut serde_json::Map<String, serde_json::Value>, fields: serde_json::Map<String, serde_json::Value>, overrides: bool, path: &str) {
+    let mut ignored = Vec::new();
+    for (key, value) in fields {
+        if metadata.contains_key(&key) && !(overrides && SIDECAR_OVERRIDABLE.contains(&key.as_str())) {
+            ignored.push(key);
+            continue;
+        }
+        metadata.insert(key, value);
+    }
+    if !ignored.is_empty() {
+        let hint = if overrides { "" } else { " (--sidecar-overrides lets source, language, path and generated through)" };
+        warn!(phase = "store", path = %path, "Kept the ingester's {} for {} over its sidecar{}.", ignored.join(", "), path, hint);
+    }
+}
+
+// The file a stored doc comment row comes from, NULL for other rows
+const DOC_PATH_SQL: &str = "CASE WHEN metadata->>'kind' = 'doc' THEN metadata->>'path' END";
+
+// Stored (id, text, doc comment file) rows as documents to embed and the files of the doc rows
+fn split_doc_paths(rows: Vec<(String, String, Option<String>)>) -> (Vec<(String, String)>, DocPaths) {
+    let mut doc_paths = DocPaths::new();
+    let documents = rows
+        .into_iter()
+        .map(|(id, text, file)| {
+            if let Some(file) = file {
+                doc_paths.insert(id.clone(), file);
+            }
+            (id, text)
+        })
+        .collect();
+    (documents, doc_paths)
+}
+
+async fn reindex(pool: &sqlx::PgPool, context: &EmbedContext<'_>, run_metadata: &serde_json::Value, batch_size: usize, cancel: tokio::sync::watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM embeddings;").fetch_one(pool).await?;
+    info!(phase = "reindex", total, model = context.embedder.model(), "Reindexing {} rows with {}...", total, context.embedder.model());
+
+    // Page through rows by id so each batch is a cheap index range scan
+    let mut last_id = String::new();
+    let (mut reindexed, mut failed) = (0, 0);
+    loop {
+        if *cancel.borrow() {
+            warn!(phase = "reindex", reindexed, total, "Interrupted: reindexed {} of {} rows.", reindexed, total);
+            return Ok(());
+        }
+        let rows: Vec<(String, String, Option<String>)> = sqlx::query_as(&format!("SELECT id, text, {} FROM embeddings WHERE id > $1 ORDER BY id LIMIT $2;", DOC_PATH_SQL))
+            .bind(&last_id)
+            .bind(batch_size as i64)
+            .fetch_all(pool)
+            .await?;
+        let Some((id, _, _)) = rows.last() else { break };
+        last_id = id.clone();
+        let started = Instant::now();
+
+        let (batch, doc_paths) = split_doc_paths(rows);
+        let embeddings = generate_embeddings(context, &batch, &doc_paths).await?;
+        failed += batch.len() - embeddings.len();
+        let embedded_at = json!(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+        // Rows whose patches match (such as every whole, unsummarized row) are written in one update
+        let mut groups: Vec<(serde_json::Value, Vec<_>)> = Vec::new();
+        for embedded in embeddings {
+            let mut metadata_patch = run_metadata.clone();
+            metadata_patch["embedded_at"] = embedded_at.clone();
+            if let (Some(patch), serde_json::Value::Object(embedding_metadata)) = (metadata_patch.as_object_mut(), embedded.embedding_metadata(context.summary_model())) {
+                patch.extend(embedding_metadata);
+            }
+            match groups.iter_mut().find(|(patch, _)| *patch == metadata_patch) {
+                Some((_, updates)) => updates.push((embedded.path, embedded.vector)),
+                None => groups.push((metadata_patch, vec![(embedded.path, embedded.vector)])),
+            }
+        }
+        for (metadata_patch, updates) in groups {
+            store::update_vectors(pool, &updates, &metadata_patch, context.vector_encoding).await?;
+            reindexed += updates.len();
+        }
+        info!(phase = "reindex", reindexed, total, duration_ms = started.elapsed().as_millis() as u64, "Reindexed {}/{} rows.", reindexed, total);
+    }
+
+    if failed > 0 {
+        warn!(phase = "reindex", failed, "{} rows could not be re-embedded and kept their previous vector (or none, if the column was resized).", failed);
+    }
+    info!(phase = "reindex", reindexed, "Successfully reindexed {} rows.", reindexed);
+    Ok(())
+}
+
+// Prints the table's row count and vector indexes for `index status`
+async fn index_status(pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    if schema::column_dimension(pool, schema::DEFAULT_TABLE).await?.is_none() {
+        return Err("table embeddings does not exist; `rag-system init` creates it".into());
+    }
+    let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM embeddings;").fetch_one(pool).await?;
+    println!("Table embeddings: {} rows.", rows);
+    let indexes = schema::vector_indexes(pool).await?;
+    if indexes.is_empty() {
+        println!("No vector index, so every query scans all {} rows; `init --index hnsw` builds one.", rows);
+    }
+    for index in &indexes {
+        let options = match index.options.is_empty() {
+            true => "default parameters".to_string(),
+            false => index.options.join(", "),
+        };
+        let validity = match index.valid {
+            true => "valid",
+            false => "INVALID: a build failed part-way and queries don't use it; `index reindex` rebuilds it",
+        };
+        println!("  {}: {} ({}), {:.1} MiB, {}", index.name, index.method, options, index.size_bytes as f64 / (1024.0 * 1024.0), validity);
+        println!("    {}", index.definition);
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+// Lists the rows store::invalid_rows finds and returns their ids
+async fn verify(pool: &sqlx::PgPool) -> Result<Vec<String>, Box<dyn Error>> {
+    let dimension = match schema::column_dimension(pool, schema::DEFAULT_TABLE).await? {
+        Some(dimension) => dimension,
+        None => return Err("table embeddings does not exist, so there is nothing to verify; `rag-system init` creates it".into()),
+    };
+    let invalid = store::invalid_rows(pool, dimension).await?;
+    for (id, problem) in &invalid {
+        println!("  {}: {}", id, problem);
+    }
+    match invalid.len() {
+        0 => println!("Every stored vector is valid."),
+        count => println!("{} rows can't be searched correctly.", count),
+    }
+    Ok(invalid.into_iter().map(|(id, _)| id).collect())
+}
+
+// Re-embeds the given rows from their stored text, then verifies again. Rows that embed to a zero vector
+// again (such as empty text) are still reported, and make the run fail.
+async fn repair(pool: &sqlx::PgPool, context: &EmbedContext<'_>, run_metadata: &serde_json::Value, ids: &[String], batch_size: usize) -> Result<(), Box<dyn Error>> {
+    info!(phase = "repair", total = ids.len(), model = context.embedder.model(), "Re-embedding {} rows with {}...", ids.len(), context.embedder.model());
+    let mut repaired = 0;
+    for batch_ids in ids.chunks(batch_size) {
+        let rows = sqlx::query_as(&format!("SELECT id, text, {} FROM embeddings WHERE id = ANY($1) ORDER BY id;", DOC_PATH_SQL))
+            .bind(batch_ids)
+            .fetch_all(pool)
+            .await?;
+        let (batch, doc_paths) = split_doc_paths(rows);
+        let embedded_at = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        for embedded in generate_embeddings(context, &batch, &doc_paths).await? {
+            // The vector now comes from the stored text, so that's what the hash describes
+            let mut metadata_patch = run_metadata.clone();
+            metadata_patch["embedded_at"] = json!(embedded_at);
+            metadata_patch["content_hash"] = json!(store::content_hash(&embedded.content));
+            if let (Some(patch), serde_json::Value::Object(embedding_metadata)) = (metadata_patch.as_object_mut(), embedded.embedding_metadata(context.summary_model())) {
+                patch.extend(embedding_metadata);
+            }
+            store::update_vectors(pool, &[(embedded.path, embedded.vector)], &metadata_patch, context.vector_encoding).await?;
+            repaired += 1;
+        }
+    }
+    info!(phase = "repair", repaired, total = ids.len(), "Re-embedded {} of {} rows.", repaired, ids.len());
+
+    let remaining = verify(pool).await?;
+    if !remaining.is_empty() {
+        return Err(format!("{} rows are still invalid after repair", remaining.len()).into());
+    }
+    Ok(())
+}
+
+async fn watch(args: &Args, context: &EmbedContext<'_>, pool: &sqlx::PgPool, vector_store: &dyn VectorStore, run_metadata: &serde_json::Value, mut cancel: tokio::sync::watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    let (root, extensions, debounce) = (args.root.as_str(), &args.extensions, Duration::from_millis(args.debounce_ms));
+    // notify reports absolute paths; map them back onto `root` so ids match the initial walk
+    let canonical_root = std::fs::canonicalize(root)?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            for path in event.paths {
+                let _ = tx.send(path);
+            }
+        }
+    })?;
+    watcher.watch(&canonical_root, RecursiveMode::Recursive)?;
+    info!(phase = "watch", path = %canonical_root.display(), "Watching {} for changes (Ctrl-C to stop)...", canonical_root.display());
+
+    loop {
+        let first = tokio::select! {
+            path = rx.recv() => match path {
+                Some(path) => path,
+                None => break,
+            },
+            _ = cancel.changed() => break,
+        };
+        // Debounce: keep collecting paths until no event arrives for a full interval
+        let mut changed = HashSet::from([first]);
+        while let Ok(Some(path)) = tokio::time::timeout(debounce, rx.recv()).await {
+            changed.insert(path);
+        }
+        // An edited sidecar re-reads the document it describes
+        let changed: HashSet<PathBuf> = changed.into_iter().map(|path| sidecar_document(&path).unwrap_or(path)).collect();
+
+        let mut updated = Vec::new();
+        let mut deleted = Vec::new();
+        for path in changed {
+            let Ok(relative) = path.strip_prefix(&canonical_root) else { continue };
+            let path = Path::new(root).join(relative);
+            if is_excluded(&path) || !has_allowed_extension(&path, extensions) || has_minified_name(&path, args) {
+                continue;
+            }
+            if path.is_file() {
+                if let Ok(document) = read_document(&path, args).await {
+                    updated.push(document);
+                }
+            } else if !path.exists() {
+                deleted.push(path.to_string_lossy().to_string());
+            }
+        }
+
+        if !updated.is_empty() {
+            // Editors often rewrite files without changing them
+            let updated = skip_unchanged(pool, updated, run_metadata, source_tag(args), context.summary_model(), args.split_docs).await?;
+            store::delete_doc_rows(pool, &updated.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>()).await?;
+            let (updated, docs) = add_doc_rows(args, updated);
+            let embeddings = generate_embeddings(context, &updated, &doc_paths(docs.as_ref())).await?;
+            let skipped = store_embeddings(vector_store, &embeddings, source_tag(args), run_metadata, context.summary_model(), args.sidecar_overrides, docs.as_ref()).await?;
+            // Watching goes on; the skipped rows are retried the next time their file changes
+            if !skipped.is_empty() {
+                warn!(phase = "watch", skipped = skipped.len(), "{} rows could not be stored and were skipped: {}", skipped.len(), skipped.join(", "));
+            }
+            let stored = embeddings.len() - skipped.len();
+            info!(phase = "watch", stored, "Re-embedded {} changed file(s).", stored);
+        }
+        if !deleted.is_empty() {
+            let pruned = store::delete_rows(pool, &deleted).await?;
+            if pruned > 0 {
+                info!(phase = "watch", pruned, "Pruned {} deleted file(s).", pruned);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rag_system::embedder::MockEmbedder;
+    use rag_system::store::RetrievalFilters;
+    use rag_system::vector_store::InMemoryStore;
+
+    // Embeds without a header or prefix, so a document's own text embeds to its stored vector
+    fn context(embedder: &dyn Embedder) -> EmbedContext<'_> {
+        EmbedContext {
+            embedder,
+            retry_policy: RetryPolicy::new(1, 0),
+            cache: None,
+            document_prefix: String::new(),
+            context_header: String::new(),
+            vector_encoding: VectorEncoding::Binary,
+            normalize: true,
+            max_embed_chars: 0,
+            summarizer: None,
+        }
+    }
+
+    // Embeds and stores (path, content) documents the way a run without --tx-mode does
+    async fn ingest(context: &EmbedContext<'_>, vector_store: &dyn VectorStore, documents: &[(&str, &str)]) {
+        let documents: Vec<(String, String)> = documents.iter().map(|(path, content)| (path.to_string(), content.to_string())).collect();
+        let embeddings = generate_embeddings(context, &documents, &DocPaths::new()).await.unwrap();
+        let skipped = store_embeddings(vector_store, &embeddings, "codebase", &json!({}), None, false, None).await.unwrap();
+        assert!(skipped.is_empty());
+    }
+
+    async fn nearest(embedder: &dyn Embedder, vector_store: &dyn VectorStore, text: &str) -> Vec<rag_system::vector_store::Hit> {
+        let mut vector = embedder.embed(&[text.to_string()]).await.unwrap().remove(0);
+        store::normalize(&mut vector);
+        vector_store.query(&vector, 10, 0, &RetrievalFilters::default()).await.unwrap()
+    }
+
+    #[test]
+    fn minified_assets_are_skipped_by_name() {
+        let args = Args::parse_from(["rag-system"]);
+        for name in ["dist/app.min.js", "static/site.min.css", "dist/app.js.map"] {
+            assert!(has_minified_name(Path::new(name), &args), "{}", name);
+        }
+        for name in ["src/app.js", "src/min.rs", "docs/mapping.md", "src/app.min.jsx"] {
+            assert!(!has_minified_name(Path::new(name), &args), "{}", name);
+        }
+        let no_skip = Args::parse_from(["rag-system", "--no-heuristic-skip"]);
+        assert!(!has_minified_name(Path::new("dist/app.min.js"), &no_skip));
+    }
+
+    #[test]
+    fn files_with_long_lines_are_skipped() {
+        let args = Args::parse_from(["rag-system", "--max-average-line-length", "100"]);
+        let path = Path::new("vendor/bundle.js");
+        let bundle = format!("{}\n{}", "x".repeat(400), "y".repeat(400));
+        assert!(has_minified_lines(path, &bundle, &args));
+        // One long line among many short ones keeps the average down
+        let source = format!("{}\n{}", "z".repeat(400), "fn short() {}\n".repeat(20));
+        assert!(!has_minified_lines(path, &source, &args));
+        assert!(!has_minified_lines(path, "", &args));
+
+        let no_skip = Args::parse_from(["rag-system", "--max-average-line-length", "100", "--no-heuristic-skip"]);
+        assert!(!has_minified_lines(path, &bundle, &no_skip));
+    }
+
+    #[tokio::test]
+    async fn ingested_files_are_found_by_their_text_and_replaced_when_they_change() {
+        let (embedder, vector_store) = (MockEmbedder::new(16), InMemoryStore::new(Metric::Cosine));
+        let context = context(&embedder);
+        let documents = [
+            ("src/retry.rs", "fn with_retry retries transient failures with exponential backoff"),
+            ("docs/retries.md", "Retries back off exponentially and give up after --max-attempts"),
+            ("scripts/load.py", "def load(path): read a CSV file into the embeddings table"),
+        ];
+        ingest(&context, &vector_store, &documents).await;
+
+        for (path, content) in documents {
+            let hits = nearest(&embedder, &vector_store, content).await;
+            assert_eq!(hits.len(), documents.len());
+            assert_eq!((hits[0].id.as_str(), hits[0].text.as_str()), (path, content));
+            assert!(hits[0].distance.abs() < 1e-6, "{} is {} from its own text", path, hits[0].distance);
+            assert_eq!(hits[0].metadata["path"], path);
+            assert_eq!(hits[0].metadata["content_hash"], store::content_hash(content));
+        }
+        assert_eq!(nearest(&embedder, &vector_store, documents[0].1).await[0].metadata["language"], "rust");
+
+        // Re-ingesting a path replaces its row rather than adding another
+        let edited = "fn with_retry now caps the backoff at --max-delay";
+        ingest(&context, &vector_store, &[("src/retry.rs", edited)]).await;
+        let hits = nearest(&embedder, &vector_store, edited).await;
+        assert_eq!(hits.len(), documents.len());
+        assert_eq!((hits[0].id.as_str(), hits[0].text.as_str()), ("src/retry.rs", edited));
+    }
+}
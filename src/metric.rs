@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// How vectors are compared, and the pgvector operator that computes it.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// Cosine distance (`<=>`)
+    Cosine,
+    /// Euclidean distance (`<->`)
+    L2,
+    /// Negative inner product (`<#>`); equivalent to cosine for normalized vectors
+    Ip,
+}
+
+impl Metric {
+    /// The pgvector distance operator for this metric; smaller results are closer.
+    pub fn operator(self) -> &'static str {
+        match self {
+            Metric::Cosine => "<=>",
+            Metric::L2 => "<->",
+            Metric::Ip => "<#>",
+        }
+    }
+
+    /// The pgvector operator class an index needs to speed up searches with [`Metric::operator`].
+    pub fn index_ops(self) -> &'static str {
+        match self {
+            Metric::Cosine => "vector_cosine_ops",
+            Metric::L2 => "vector_l2_ops",
+            Metric::Ip => "vector_ip_ops",
+        }
+    }
+
+    /// The distance between two vectors as pgvector's [`Metric::operator`] computes it, for searching
+    /// outside the database. Cosine distance involving an all-zero vector is NaN, as in pgvector.
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f64 {
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| f64::from(*x) * f64::from(*y)).sum::<f64>();
+        match self {
+            Metric::Cosine => 1.0 - dot(a, b) / (dot(a, a).sqrt() * dot(b, b).sqrt()),
+            Metric::L2 => a.iter().zip(b).map(|(x, y)| (f64::from(*x) - f64::from(*y)).powi(2)).sum::<f64>().sqrt(),
+            Metric::Ip => -dot(a, b),
+        }
+    }
+
+    /// Turns a distance returned by [`Metric::operator`] into a similarity where higher is closer.
+    ///
+    /// Cosine gives 1 - distance (-1 to 1), L2 gives 1 / (1 + distance) (0 to 1) and inner product
+    /// gives the inner product itself, which for unit vectors is the cosine similarity.
+    pub fn similarity(self, distance: f64) -> f64 {
+        match self {
+            Metric::Cosine => 1.0 - distance,
+            Metric::L2 => 1.0 / (1.0 + distance),
+            Metric::Ip => -distance,
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Metric::Cosine => "cosine",
+            Metric::L2 => "l2",
+            Metric::Ip => "ip",
+        })
+    }
+}
@@ -0,0 +1,65 @@
+/// Cosine similarity of two vectors, or 0 when either is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+/// Picks up to `k` candidates by Maximal Marginal Relevance, returning their indices in selection order.
+///
+/// Each step takes the candidate maximising `lambda * relevance - (1 - lambda) * max similarity to the
+/// already selected`, so `lambda = 1.0` is plain relevance order and lower values favour diversity.
+/// Ties go to the earlier candidate, so with `lambda = 1.0` and candidates already sorted by relevance
+/// the input order is kept exactly.
+pub fn select(relevance: &[f32], vectors: &[Vec<f32>], lambda: f32, k: usize) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(k.min(relevance.len()));
+    // Highest similarity of each candidate to anything selected so far
+    let mut redundancy = vec![f32::NEG_INFINITY; relevance.len()];
+    let mut remaining: Vec<usize> = (0..relevance.len()).collect();
+
+    while selected.len() < k && !remaining.is_empty() {
+        let marginal = |i: usize| {
+            let penalty = if selected.is_empty() { 0.0 } else { redundancy[i] };
+            lambda * relevance[i] - (1.0 - lambda) * penalty
+        };
+        let mut best = 0;
+        for position in 1..remaining.len() {
+            if marginal(remaining[position]) > marginal(remaining[best]) {
+                best = position;
+            }
+        }
+        let chosen = remaining.remove(best);
+        for &i in &remaining {
+            redundancy[i] = redundancy[i].max(cosine_similarity(&vectors[i], &vectors[chosen]));
+        }
+        selected.push(chosen);
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_duplicates_are_demoted() {
+        // Two almost identical documents close to the query, and a distinct one slightly less relevant
+        let vectors = vec![vec![1.0, 0.0, 0.0], vec![0.99, 0.01, 0.0], vec![0.0, 1.0, 0.0]];
+        let relevance = [0.95, 0.94, 0.80];
+
+        assert_eq!(select(&relevance, &vectors, 0.5, 3), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn lambda_one_keeps_relevance_order() {
+        let vectors = vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0], vec![0.7, 0.7]];
+        let relevance = [0.9, 0.9, 0.5, 0.4];
+
+        assert_eq!(select(&relevance, &vectors, 1.0, 4), vec![0, 1, 2, 3]);
+        assert_eq!(select(&relevance, &vectors, 1.0, 2), vec![0, 1]);
+    }
+}
@@ -0,0 +1,227 @@
+use tiktoken_rs::cl100k_base_singleton;
+
+/// Number of tokens in `text` under the cl100k_base encoding.
+///
+/// Local answer models each have their own vocabulary, so this is an estimate of what they will count,
+/// not an exact measure.
+pub fn count_tokens(text: &str) -> usize {
+    cl100k_base_singleton().encode_ordinary(text).len()
+}
+
+/// What [`pack`] did with one document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// The whole document is in the context
+    Included,
+    /// Only its first `kept` of `lines` lines fit
+    Truncated { kept: usize, lines: usize },
+    /// Not even its first line fit, so it was cut after `kept` of its `chars` characters
+    Cut { kept: usize, chars: usize },
+    /// Left out because it didn't fit in the `remaining` tokens of the budget
+    Excluded { remaining: usize },
+}
+
+/// One document's placement, with the tokens its whole `[n] source` block would have taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub placement: Placement,
+    pub tokens: usize,
+}
+
+/// A packed context and what happened to each document offered, in the order they were offered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packed {
+    pub context: String,
+    pub tokens: usize,
+    pub decisions: Vec<Decision>,
+}
+
+impl Packed {
+    /// Indices of the documents in the context, in the order they're numbered there.
+    pub fn included(&self) -> Vec<usize> {
+        self.decisions.iter().enumerate().filter(|(_, d)| !matches!(d.placement, Placement::Excluded { .. })).map(|(i, _)| i).collect()
+    }
+}
+
+/// Packs `(source, text)` documents, best first, into `[n] source` blocks of at most `budget` tokens.
+///
+/// Documents are added whole while they fit; one that doesn't is left out and the lower-ranked ones are
+/// still tried. With `truncate_last`, the first document that doesn't fit is instead cut at a line
+/// boundary to fill the rest of the budget, and everything after it is left out. The top document is
+/// always cut rather than left out, so an oversized one still gives some context; when not even its first
+/// line fits, it is cut within that line instead. Blocks are numbered
+/// consecutively as they're added, and the token count of the whole context never exceeds `budget`.
+pub fn pack(documents: &[(&str, &str)], budget: usize, truncate_last: bool) -> Packed {
+    let mut context = String::new();
+    let mut tokens = 0;
+    let mut decisions = Vec::with_capacity(documents.len());
+    let mut numbered = 0;
+    let mut full = false;
+
+    for &(source, text) in documents {
+        let number = numbered + 1;
+        let entry = format!("[{}] {}\n{}\n\n", number, source, text);
+        let whole = count_tokens(&entry);
+        // Counting the candidate context as a whole, rather than summing blocks, keeps the guarantee
+        // exact even where the tokenizer would merge across a block boundary
+        let with_entry = if full { budget + 1 } else { count_tokens(&format!("{}{}", context, entry)) };
+        if with_entry <= budget {
+            context.push_str(&entry);
+            tokens = with_entry;
+            numbered += 1;
+            decisions.push(Decision { placement: Placement::Included, tokens: whole });
+            continue;
+        }
+
+        if !full && (truncate_last || number == 1) {
+            if let Some((entry, kept, lines, with_entry)) = truncated_entry(&context, number, source, text, budget) {
+                context.push_str(&entry);
+                tokens = with_entry;
+                numbered += 1;
+                full = true;
+                decisions.push(Decision { placement: Placement::Truncated { kept, lines }, tokens: whole });
+                continue;
+            }
+        }
+        if number == 1 {
+            if let Some((entry, kept, chars, with_entry)) = cut_entry(number, source, text, budget) {
+                context.push_str(&entry);
+                tokens = with_entry;
+                numbered += 1;
+                full = true;
+                decisions.push(Decision { placement: Placement::Cut { kept, chars }, tokens: whole });
+                continue;
+            }
+        }
+        decisions.push(Decision { placement: Placement::Excluded { remaining: budget - tokens }, tokens: whole });
+    }
+    Packed { context, tokens, decisions }
+}
+
+// The longest line prefix of `text` whose block still fits in `budget` after `context`, with how many
+// of how many lines it kept and the context's token count with it; None when not even one line fits
+fn truncated_entry(context: &str, number: usize, source: &str, text: &str, budget: usize) -> Option<(String, usize, usize, usize)> {
+    let ends: Vec<usize> = text.match_indices('\n').map(|(i, _)| i + 1).chain([text.len()]).collect();
+    let lines = ends.len();
+    let entry = |kept: usize| {
+        let prefix = text[..ends[kept - 1]].trim_end_matches('\n');
+        format!("[{}] {} (first {} of {} lines)\n{}\n\n", number, source, kept, lines, prefix)
+    };
+    let fits = |kept: usize| {
+        let with_entry = count_tokens(&format!("{}{}", context, entry(kept)));
+        (with_entry <= budget).then_some(with_entry)
+    };
+
+    // Binary search for the most lines that fit; fewer lines never take more tokens
+    let (mut low, mut high) = (0, lines.saturating_sub(1));
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        if fits(middle).is_some() {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let with_entry = fits(low)?;
+    Some((entry(low), low, lines, with_entry))
+}
+
+// The longest character prefix of `text` whose block fits in `budget` as the first in the context, with
+// how many of how many characters it kept and its token count; None when not even one character fits
+fn cut_entry(number: usize, source: &str, text: &str, budget: usize) -> Option<(String, usize, usize, usize)> {
+    let ends: Vec<usize> = text.char_indices().map(|(i, c)| i + c.len_utf8()).collect();
+    let chars = ends.len();
+    let entry = |kept: usize| format!("[{}] {} (first {} of {} characters)\n{}\n\n", number, source, kept, chars, &text[..ends[kept - 1]]);
+    let fits = |kept: usize| {
+        let with_entry = count_tokens(&entry(kept));
+        (with_entry <= budget).then_some(with_entry)
+    };
+
+    // The same search as truncated_entry, by characters; a cut mid-token can cost one token more than the
+    // prefix before it, but fits is checked again on the result
+    let (mut low, mut high) = (0, chars);
+    while low < high {
+        let middle = (low + high).div_ceil(2);
+        if fits(middle).is_some() {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let with_entry = fits(low)?;
+    Some((entry(low), low, chars, with_entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered_lines(count: usize) -> String {
+        (1..=count).map(|i| format!("line {} of the document", i)).collect::<Vec<_>>().join("\n")
+    }
+
+    #[test]
+    fn never_exceeds_the_budget() {
+        let long = numbered_lines(200);
+        let documents = [("a.rs", "fn a() {}"), ("b.rs", long.as_str()), ("c.rs", "fn c() {}")];
+        for budget in [0, 5, 20, 100, 500, 5000] {
+            for truncate_last in [false, true] {
+                let packed = pack(&documents, budget, truncate_last);
+                assert!(count_tokens(&packed.context) <= budget, "{} tokens for a budget of {}", count_tokens(&packed.context), budget);
+                assert_eq!(packed.tokens, count_tokens(&packed.context));
+            }
+        }
+    }
+
+    #[test]
+    fn documents_that_do_not_fit_are_skipped_or_cut_at_a_line() {
+        let long = numbered_lines(200);
+        let documents = [("a.rs", "fn a() {}"), ("b.rs", long.as_str()), ("c.rs", "fn c() {}")];
+
+        // Without truncation the long document is left out and the short one after it still gets in
+        let packed = pack(&documents, 100, false);
+        let placements: Vec<Placement> = packed.decisions.iter().map(|d| d.placement).collect();
+        assert!(matches!(placements[..], [Placement::Included, Placement::Excluded { .. }, Placement::Included]));
+        assert_eq!(packed.included(), [0, 2]);
+        assert!(packed.context.contains("[2] c.rs\n"));
+
+        // With it, the long document fills the rest of the budget and nothing comes after it
+        let packed = pack(&documents, 100, true);
+        let Placement::Truncated { kept, lines } = packed.decisions[1].placement else { panic!("{:?}", packed.decisions[1]) };
+        assert!(kept > 0 && kept < lines && lines == 200);
+        assert!(packed.context.contains(&format!("line {} of the document\n\n", kept)));
+        assert!(!packed.context.contains(&format!("line {} of", kept + 1)));
+        assert!(matches!(packed.decisions[2].placement, Placement::Excluded { .. }));
+    }
+
+    #[test]
+    fn an_oversized_top_document_is_cut_rather_than_dropped() {
+        let long = numbered_lines(200);
+        let packed = pack(&[("big.md", long.as_str())], 50, false);
+        assert!(matches!(packed.decisions[0].placement, Placement::Truncated { .. }));
+        assert!(packed.context.starts_with("[1] big.md (first "));
+    }
+
+    #[test]
+    fn a_top_document_of_one_huge_line_is_cut_within_it() {
+        let huge = "word ".repeat(5000);
+        let documents = [("bundle.min.js", huge.as_str()), ("b.rs", "fn b() {}")];
+        for truncate_last in [false, true] {
+            let packed = pack(&documents, 50, truncate_last);
+            let Placement::Cut { kept, chars } = packed.decisions[0].placement else { panic!("{:?}", packed.decisions[0]) };
+            assert!(kept > 0 && kept < chars && chars == huge.chars().count());
+            assert!(packed.context.starts_with(&format!("[1] bundle.min.js (first {} of {} characters)\nword word", kept, chars)));
+            assert!(packed.tokens <= 50 && packed.tokens == count_tokens(&packed.context));
+            assert!(matches!(packed.decisions[1].placement, Placement::Excluded { .. }));
+        }
+        // Only the top document is cut within a line; a later one is left out
+        let packed = pack(&[("b.rs", "fn b() {}"), ("bundle.min.js", huge.as_str())], 50, true);
+        assert!(matches!(packed.decisions[1].placement, Placement::Excluded { .. }));
+    }
+}
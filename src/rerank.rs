@@ -0,0 +1,182 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use ollama_rs::error::OllamaError;
+use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::completion::{GenerationResponse, GenerationResponseStream};
+use ollama_rs::Ollama;
+use std::time::Duration;
+
+/// Ollama model used to score retrieved documents unless `--rerank-model` says otherwise.
+pub const DEFAULT_RERANK_MODEL: &str = "hf.co/mradermacher/Qwen3-Reranker-4B-GGUF:Q4_K_M";
+
+/// A backend that generates model replies.
+///
+/// The query binary asks for every rerank judgement and answer through this trait, so tests can stand in a
+/// scripted model for Ollama.
+#[async_trait]
+pub trait Generator: Send + Sync {
+    /// Generates the complete (non-streamed) reply to `request`.
+    async fn generate(&self, request: GenerationRequest<'_>) -> Result<GenerationResponse, OllamaError>;
+
+    /// Starts generating the reply to `request`, yielding it piece by piece as the model produces it.
+    async fn generate_stream(&self, request: GenerationRequest<'_>) -> Result<GenerationResponseStream, OllamaError>;
+}
+
+#[async_trait]
+impl Generator for Ollama {
+    async fn generate(&self, request: GenerationRequest<'_>) -> Result<GenerationResponse, OllamaError> {
+        Ollama::generate(self, request).await
+    }
+
+    async fn generate_stream(&self, request: GenerationRequest<'_>) -> Result<GenerationResponseStream, OllamaError> {
+        Ollama::generate_stream(self, request).await
+    }
+}
+
+/// A generator for tests that replies to each prompt with `reply(prompt)` after a fixed latency, without
+/// any model. Streamed replies arrive one word at a time, each after the latency.
+pub struct MockGenerator {
+    reply: Box<dyn Fn(&str) -> String + Send + Sync>,
+    latency: Duration,
+}
+
+impl MockGenerator {
+    pub fn new(reply: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        Self { reply: Box::new(reply), latency: Duration::ZERO }
+    }
+
+    /// Waits `latency` before each reply, like a model taking time to generate.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+#[async_trait]
+impl Generator for MockGenerator {
+    async fn generate(&self, request: GenerationRequest<'_>) -> Result<GenerationResponse, OllamaError> {
+        tokio::time::sleep(self.latency).await;
+        Ok(mock_response(request.model_name, (self.reply)(&request.prompt), true))
+    }
+
+    async fn generate_stream(&self, request: GenerationRequest<'_>) -> Result<GenerationResponseStream, OllamaError> {
+        let reply = (self.reply)(&request.prompt);
+        let words: Vec<String> = reply.split_inclusive(' ').map(str::to_string).collect();
+        let (model, latency, last) = (request.model_name, self.latency, words.len().saturating_sub(1));
+        let stream = futures::stream::iter(words.into_iter().enumerate()).then(move |(i, word)| {
+            let model = model.clone();
+            async move {
+                tokio::time::sleep(latency).await;
+                Ok(vec![mock_response(model, word, i == last)])
+            }
+        });
+        Ok(Box::pin(stream))
+    }
+}
+
+fn mock_response(model: String, response: String, done: bool) -> GenerationResponse {
+    GenerationResponse {
+        model,
+        created_at: String::new(),
+        response,
+        done,
+        context: None,
+        total_duration: None,
+        load_duration: None,
+        prompt_eval_count: None,
+        prompt_eval_duration: None,
+        eval_count: None,
+        eval_duration: None,
+        thinking: None,
+        logprobs: None,
+    }
+}
+
+/// Combines a reranker's score with the document's vector similarity. Blending damps erratic rerank
+/// scores; a `weight` of 1.0 uses the rerank score alone and 0.0 the similarity alone.
+pub fn blend(rerank_score: f32, similarity: f32, weight: f32) -> f32 {
+    weight * rerank_score + (1.0 - weight) * similarity
+}
+
+/// Default for how far outside [0, 1] a rerank score may fall and still be clamped into range.
+pub const DEFAULT_SCORE_TOLERANCE: f32 = 0.5;
+
+/// How [`normalize_score`] brought a score into [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreAdjustment {
+    /// Already in range
+    None,
+    /// Slightly out of range and clamped to the nearest end
+    Clamped,
+    /// Read as a percentage and divided by 100
+    Percent,
+}
+
+/// Brings a score the reranker was asked to give in [0, 1] into that range, or returns `None` when it
+/// can't be trusted and the document should keep its vector similarity instead.
+///
+/// Scores up to `tolerance` outside the range are clamped. Beyond that, scores in (10, 100] are taken to
+/// be percentages. Anything else, such as `2` on an unknown scale, a large negative or NaN, is rejected.
+pub fn normalize_score(score: f32, tolerance: f32) -> Option<(f32, ScoreAdjustment)> {
+    if (0.0..=1.0).contains(&score) {
+        Some((score, ScoreAdjustment::None))
+    } else if (-tolerance..=1.0 + tolerance).contains(&score) {
+        Some((score.clamp(0.0, 1.0), ScoreAdjustment::Clamped))
+    } else if score > 10.0 && score <= 100.0 {
+        Some((score / 100.0, ScoreAdjustment::Percent))
+    } else {
+        None
+    }
+}
+
+/// Multiplier for the score of a document `age_days` old, decaying exponentially with the given half-life:
+/// 1.0 when brand new and 0.5 at one half-life. Timestamps in the future (clock skew) count as new.
+pub fn recency_factor(age_days: f64, half_life_days: f64) -> f64 {
+    0.5f64.powf(age_days.max(0.0) / half_life_days)
+}
+
+/// Sorts documents given in retrieval order by descending `score`. The sort is stable, so equal scores
+/// keep their retrieval order, and NaN scores sort last.
+pub fn sort_by_score<T>(docs: &mut [T], score: impl Fn(&T) -> f32) {
+    let key = |doc: &T| {
+        let score = score(doc);
+        if score.is_nan() { f32::NEG_INFINITY } else { score }
+    };
+    docs.sort_by(|a, b| key(b).total_cmp(&key(a)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_scores_are_clamped_rescaled_or_rejected() {
+        let tolerance = DEFAULT_SCORE_TOLERANCE;
+        assert_eq!(normalize_score(0.7, tolerance), Some((0.7, ScoreAdjustment::None)));
+        assert_eq!(normalize_score(1.2, tolerance), Some((1.0, ScoreAdjustment::Clamped)));
+        assert_eq!(normalize_score(-0.5, tolerance), Some((0.0, ScoreAdjustment::Clamped)));
+        assert_eq!(normalize_score(95.0, tolerance), Some((0.95, ScoreAdjustment::Percent)));
+        assert_eq!(normalize_score(100.0, tolerance), Some((1.0, ScoreAdjustment::Percent)));
+        // Neither a slight overshoot nor plausibly a percentage, so the scale is unknown
+        assert_eq!(normalize_score(2.0, tolerance), None);
+        assert_eq!(normalize_score(150.0, tolerance), None);
+        assert_eq!(normalize_score(-3.0, tolerance), None);
+        assert_eq!(normalize_score(f32::NAN, tolerance), None);
+        // With no tolerance, anything outside the range that isn't a percentage is rejected
+        assert_eq!(normalize_score(1.2, 0.0), None);
+    }
+
+    #[test]
+    fn ties_keep_their_order_and_nan_sorts_last() {
+        let mut tied = vec![("a", 0.5), ("b", f32::NAN), ("c", 0.9), ("d", 0.5)];
+        sort_by_score(&mut tied, |(_, score)| *score);
+        assert_eq!(tied.iter().map(|(id, _)| *id).collect::<Vec<_>>(), ["c", "a", "d", "b"]);
+    }
+
+    #[test]
+    fn recency_factor_halves_every_half_life() {
+        assert_eq!(recency_factor(0.0, 30.0), 1.0);
+        assert!((recency_factor(30.0, 30.0) - 0.5).abs() < 1e-12);
+        assert_eq!(recency_factor(-1.0, 30.0), 1.0);
+    }
+}
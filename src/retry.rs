@@ -0,0 +1,226 @@
+use ollama_rs::error::OllamaError;
+use rand::Rng;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Retry and timeout flags shared by the ingester and the query binary.
+#[derive(clap::Args, Debug, Clone)]
+pub struct RetryArgs {
+    /// Maximum attempts for each Ollama call before giving up
+    #[arg(long, default_value_t = 4)]
+    pub max_attempts: u32,
+
+    /// Base delay in milliseconds for exponential retry backoff
+    #[arg(long, default_value_t = 500)]
+    pub retry_delay_ms: u64,
+
+    /// Seconds to wait for each embedding or generation request before treating it as a (retryable)
+    /// failure, so one stuck request can't hang the run (0 = no timeout)
+    #[arg(long, visible_alias = "ollama-timeout", default_value_t = 60)]
+    pub request_timeout: u64,
+
+    /// Send at most this many embedding and generation requests per second, across all concurrent
+    /// tasks and retries, to share an Ollama server politely (fractions allowed; default no limit)
+    #[arg(long, value_parser = parse_rate)]
+    pub max_rps: Option<f64>,
+}
+
+impl RetryArgs {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy::new(self.max_attempts, self.retry_delay_ms)
+            .with_timeout((self.request_timeout > 0).then(|| Duration::from_secs(self.request_timeout)))
+            .with_rate_limit(self.max_rps.map(|rate| Arc::new(RateLimiter::new(rate))))
+    }
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    let rate: f64 = value.parse().map_err(|e| format!("{}", e))?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(format!("{} is not a positive number of requests per second", rate));
+    }
+    Ok(rate)
+}
+
+/// How many times to attempt an Ollama call, how long each attempt may take, how long to wait between
+/// attempts, and the request rate every call made with the policy (or a clone of it) shares.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub timeout: Option<Duration>,
+    pub rate_limit: Option<Arc<RateLimiter>>,
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, base_delay_ms: u64) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            base_delay: Duration::from_millis(base_delay_ms),
+            timeout: None,
+            rate_limit: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    // Exponential backoff (base * 2^(attempt - 1)) with up to 50% random jitter
+    fn delay_for(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(1 << (attempt - 1).min(16));
+        let jitter_ms = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+        backoff + Duration::from_millis(jitter_ms)
+    }
+}
+
+/// A token bucket holding up to `rate` requests (at least one) that refills at `rate` per second, so
+/// a burst after a quiet spell goes out at once and sustained load is spread evenly.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    burst: Duration,
+    // When the bucket will be full again, given the requests let through so far (the GCRA formulation
+    // of a token bucket, which needs no background refill task)
+    full_at: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        let interval = Duration::from_secs_f64(1.0 / rate);
+        RateLimiter { interval, burst: interval.mul_f64((rate.floor() - 1.0).max(0.0)), full_at: Mutex::new(Instant::now()) }
+    }
+
+    /// Waits until a request may be sent. Each caller reserves the next free slot before sleeping, so
+    /// waiting tasks go out in the order they arrived without polling.
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut full_at = self.full_at.lock().expect("rate limiter lock poisoned");
+            let now = Instant::now();
+            let wait = full_at.checked_sub(self.burst).map_or(Duration::ZERO, |earliest| earliest.saturating_duration_since(now));
+            *full_at = (*full_at).max(now) + self.interval;
+            wait
+        };
+        // Even an elapsed timer waits for the next millisecond tick, which would throttle a burst
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Errors that can tell whether retrying the failed call might succeed.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+/// An attempt exceeded the policy's timeout; always retryable.
+#[derive(Debug, Clone, Copy)]
+pub struct TimedOut(pub Duration);
+
+impl From<TimedOut> for OllamaError {
+    fn from(timed_out: TimedOut) -> Self {
+        OllamaError::Other(format!("request timed out after {:?}", timed_out.0))
+    }
+}
+
+impl Retryable for OllamaError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            // Timeouts, refused/reset connections and interrupted bodies are transient
+            OllamaError::ReqwestError(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.is_request()
+                    || e.is_body()
+                    || e.status().is_some_and(|s| s.is_server_error())
+            }
+            // ollama-rs flattens non-2xx responses into `Other(body)`, so classify by message.
+            // A missing model or a rejected input will fail the same way every time.
+            OllamaError::Other(message) | OllamaError::InternalError(ollama_rs::error::InternalOllamaError { message }) => {
+                let message = message.to_lowercase();
+                !(message.contains("not found")
+                    || message.contains("invalid")
+                    || message.contains("exceeds")
+                    || message.contains("unsupported"))
+            }
+            OllamaError::JsonError(_) | OllamaError::ToolCallError(_) => false,
+        }
+    }
+}
+
+/// Runs `op` until it succeeds, fails with a permanent error, or the policy's attempts run out. Each
+/// attempt first waits for the policy's rate limit, which doesn't count towards its timeout.
+pub async fn with_retry<T, E, F, Fut>(policy: &RetryPolicy, label: &str, mut op: F) -> Result<T, E>
+where
+    E: Retryable + From<TimedOut> + std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        if let Some(rate_limit) = &policy.rate_limit {
+            rate_limit.acquire().await;
+        }
+        let result = match policy.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, op())
+                .await
+                .unwrap_or_else(|_| Err(TimedOut(timeout).into())),
+            None => op().await,
+        };
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts && e.is_retryable() => {
+                let delay = policy.delay_for(attempt);
+                tracing::warn!(
+                    attempt,
+                    error = %e,
+                    "{} failed (attempt {}/{}): {}. Retrying in {:?}...",
+                    label, attempt, policy.max_attempts, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_burst_goes_out_at_once_and_the_rest_at_the_rate() {
+        let limiter = Arc::new(RateLimiter::new(100.0));
+        let started = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire().await;
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Ten more tasks at once share the refill, one slot every 10ms in the order they asked
+        let tasks: Vec<_> = (0..10).map(|_| tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter.acquire().await;
+                started.elapsed()
+            }
+        })).collect();
+        let sent: Vec<Duration> = futures::future::join_all(tasks).await.into_iter().map(|sent| sent.unwrap()).collect();
+        assert_eq!(sent, (1..=10).map(|slot| Duration::from_millis(10 * slot)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn slow_rates_still_let_one_request_through() {
+        let limiter = RateLimiter::new(0.5);
+        assert_eq!((limiter.interval, limiter.burst), (Duration::from_secs(2), Duration::ZERO));
+        assert!(parse_rate("0").is_err() && parse_rate("inf").is_err() && parse_rate("2.5").is_ok());
+    }
+}
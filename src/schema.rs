@@ -0,0 +1,381 @@
+use std::error::Error;
+
+use crate::embedder::Embedder;
+use crate::metric::Metric;
+use crate::retry::{with_retry, RetryPolicy};
+
+/// The table the ingester writes to and the query binary searches unless given `--table`.
+pub const DEFAULT_TABLE: &str = "embeddings";
+
+/// The most dimensions pgvector can build an HNSW or IVFFlat index over for a `vector` column.
+pub const MAX_INDEXED_DIMENSION: usize = 2000;
+
+/// Table names are interpolated into SQL, so only plain identifiers are accepted: ASCII letters, digits
+/// and underscores, not starting with a digit, and at most 63 bytes (Postgres' identifier limit).
+pub fn check_table_name(name: &str) -> Result<(), Box<dyn Error>> {
+    let valid = (1..=63).contains(&name.len())
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(format!("{:?} is not a valid table name; use letters, digits and underscores, not starting with a digit", name).into()),
+    }
+}
+
+/// Embeds a tiny probe string to learn how many dimensions the embedding model produces.
+pub async fn probe_dimension(embedder: &dyn Embedder, retry_policy: &RetryPolicy) -> Result<usize, Box<dyn Error>> {
+    let probe = ["dimension probe".to_string()];
+    let vectors = with_retry(retry_policy, "Embedding dimension probe", || embedder.embed(&probe)).await?;
+    let embedding = vectors.into_iter().next().ok_or("Embedding model returned no vector for the probe input")?;
+    Ok(embedding.len())
+}
+
+/// Reads the declared dimension of `table.vector` from the catalog.
+///
+/// Returns `None` when the table does not exist and `Some(None)` when the column has no fixed dimension.
+pub async fn column_dimension(pool: &sqlx::PgPool, table: &str) -> Result<Option<Option<usize>>, Box<dyn Error>> {
+    // pgvector stores the declared dimension in atttypmod (-1 for an unconstrained `vector`)
+    let row: Option<(i32,)> = sqlx::query_as(
+        r#"
+        SELECT a.atttypmod
+        FROM pg_attribute a
+        WHERE a.attrelid = to_regclass($1)
+          AND a.attname = 'vector'
+          AND NOT a.attisdropped;
+        "#,
+    )
+    .bind(table)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(typmod,)| usize::try_from(typmod).ok()))
+}
+
+/// Creates the embeddings table with a vector column sized for `dimension`.
+pub async fn create_table(pool: &sqlx::PgPool, dimension: usize) -> Result<(), Box<dyn Error>> {
+    sqlx::query("CREATE EXTENSION IF NOT EXISTS vector;").execute(pool).await?;
+    // The dimension is an integer we measured ourselves, so interpolating it is safe
+    sqlx::query(&format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS embeddings (
+            id TEXT PRIMARY KEY,
+            text TEXT NOT NULL,
+            vector VECTOR({}),
+            metadata JSONB,
+            created_at TIMESTAMPTZ DEFAULT NOW()
+        );
+        "#,
+        dimension
+    ))
+    .execute(pool)
+    .await?;
+    ensure_text_search(pool).await
+}
+
+/// The approximate nearest-neighbour index methods pgvector offers.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexMethod {
+    /// A graph index: the best speed/recall trade-off, slower to build and larger
+    Hnsw,
+    /// Inverted lists learned from the rows present when it's built: quick to build and small, but
+    /// built on an empty or still-growing table it needs rebuilding once the data is in
+    Ivfflat,
+}
+
+/// A vector index to build, with its build parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorIndex {
+    Hnsw { m: u32, ef_construction: u32 },
+    IvfFlat { lists: u32 },
+}
+
+impl VectorIndex {
+    /// pgvector's default build parameters for `method`.
+    pub fn defaults(method: IndexMethod) -> Self {
+        match method {
+            IndexMethod::Hnsw => VectorIndex::Hnsw { m: 16, ef_construction: 64 },
+            IndexMethod::Ivfflat => VectorIndex::IvfFlat { lists: 100 },
+        }
+    }
+
+    fn method(self) -> &'static str {
+        match self {
+            VectorIndex::Hnsw { .. } => "hnsw",
+            VectorIndex::IvfFlat { .. } => "ivfflat",
+        }
+    }
+
+    fn options(self) -> String {
+        match self {
+            VectorIndex::Hnsw { m, ef_construction } => format!("m = {}, ef_construction = {}", m, ef_construction),
+            VectorIndex::IvfFlat { lists } => format!("lists = {}", lists),
+        }
+    }
+}
+
+/// An HNSW or IVFFlat index on the embeddings table, as the catalog describes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStatus {
+    pub name: String,
+    /// `hnsw` or `ivfflat`
+    pub method: String,
+    /// Build parameters set explicitly, such as `m=16`; pgvector's defaults apply to the rest
+    pub options: Vec<String>,
+    /// False when a concurrent build failed part-way; Postgres keeps such an index updated but never uses it
+    pub valid: bool,
+    pub size_bytes: i64,
+    pub definition: String,
+}
+
+/// Sets up what ingestion and queries need, skipping whatever already exists: the `vector` extension,
+/// the embeddings table with a `vector(dimension)` column and its full-text column, and with `index` a
+/// vector index for `metric` built by [`create_vector_index`]. Returns a line per step saying what was
+/// created or skipped.
+///
+/// Fails before changing anything when the table already holds vectors of another dimension, or when an
+/// index was asked for that pgvector can't build.
+pub async fn init(pool: &sqlx::PgPool, dimension: usize, index: Option<VectorIndex>, metric: Metric) -> Result<Vec<String>, Box<dyn Error>> {
+    let existing = column_dimension(pool, DEFAULT_TABLE).await?;
+    if existing.is_some() {
+        check_dimension(pool, dimension, false).await?;
+    }
+    if index.is_some() && dimension > MAX_INDEXED_DIMENSION {
+        return Err(format!(
+            "pgvector can't index {}-dim vectors (at most {}); run init without --index to search the table exactly",
+            dimension, MAX_INDEXED_DIMENSION
+        )
+        .into());
+    }
+    let mut steps = Vec::new();
+
+    let version = |pool| async move {
+        let row: Option<(String,)> = sqlx::query_as("SELECT extversion FROM pg_extension WHERE extname = 'vector';").fetch_optional(pool).await?;
+        Ok::<_, sqlx::Error>(row.map(|(version,)| version))
+    };
+    match version(pool).await? {
+        Some(version) => steps.push(format!("Skipped extension vector: version {} is installed.", version)),
+        None => {
+            sqlx::query("CREATE EXTENSION IF NOT EXISTS vector;").execute(pool).await?;
+            steps.push(format!("Created extension vector (version {}).", version(pool).await?.unwrap_or_default()));
+        }
+    }
+
+    match existing {
+        Some(declared) => {
+            let column = declared.map_or("an unconstrained vector column".to_string(), |dimension| format!("vector({})", dimension));
+            steps.push(format!("Skipped table {}: it exists with {}.", DEFAULT_TABLE, column));
+            match has_text_search(pool, DEFAULT_TABLE).await? {
+                true => steps.push(format!("Skipped full-text column {}.text_search: it exists.", DEFAULT_TABLE)),
+                false => {
+                    ensure_text_search(pool).await?;
+                    steps.push(format!("Added full-text column {}.text_search with its GIN index.", DEFAULT_TABLE));
+                }
+            }
+        }
+        None => {
+            create_table(pool, dimension).await?;
+            steps.push(format!("Created table {} with vector({}) and a full-text text_search column.", DEFAULT_TABLE, dimension));
+        }
+    }
+
+    let Some(index) = index else {
+        steps.push("Skipped vector index: not requested (--index); searches scan the table exactly.".to_string());
+        return Ok(steps);
+    };
+    let existing = vector_indexes(pool).await?;
+    // An invalid index is still there, so building another would only sit next to it
+    match (existing.iter().find(|index| index.valid), existing.first()) {
+        (Some(valid), _) => steps.push(format!(
+            "Skipped {} index: {} already has {} index {}; drop it first to build another.",
+            index.method(),
+            DEFAULT_TABLE,
+            valid.method,
+            valid.name
+        )),
+        (None, Some(invalid)) => steps.push(format!(
+            "Skipped {} index: {} has {} index {}, left invalid by a failed build; run `rag-system index reindex` to rebuild it.",
+            index.method(),
+            DEFAULT_TABLE,
+            invalid.method,
+            invalid.name
+        )),
+        (None, None) => {
+            let name = create_vector_index(pool, index, metric).await?;
+            steps.push(format!("Created {} index {} ({}) for {} distance.", index.method(), name, index.options(), metric));
+        }
+    }
+    Ok(steps)
+}
+
+/// Builds `index` on the embeddings table's vectors for `metric`, returning its name.
+///
+/// The build runs `CONCURRENTLY`, so ingestion and queries carry on while it takes its time. A build
+/// that fails part-way leaves an invalid index behind, which is dropped before the error is returned; a
+/// valid index that was already there under the same name is left alone.
+pub async fn create_vector_index(pool: &sqlx::PgPool, index: VectorIndex, metric: Metric) -> Result<String, Box<dyn Error>> {
+    let name = format!("{}_vector_{}_idx", DEFAULT_TABLE, index.method());
+    tracing::info!(phase = "schema", index = %name, "Building {} index {}; this can take a while on a large table...", index.method(), name);
+    // Every part is a fixed name or an integer, so interpolating them is safe. CONCURRENTLY can't run in
+    // a transaction, and a pool connection outside one is what sqlx uses here.
+    let create = format!(
+        "CREATE INDEX CONCURRENTLY IF NOT EXISTS {} ON {} USING {} (vector {}) WITH ({});",
+        name,
+        DEFAULT_TABLE,
+        index.method(),
+        metric.index_ops(),
+        index.options()
+    );
+    if let Err(e) = sqlx::query(&create).execute(pool).await {
+        let valid: Option<(bool,)> = sqlx::query_as("SELECT indisvalid FROM pg_index WHERE indexrelid = to_regclass($1);").bind(&name).fetch_optional(pool).await?;
+        if let Some((false,)) = valid {
+            sqlx::query(&format!("DROP INDEX CONCURRENTLY IF EXISTS {};", name)).execute(pool).await?;
+        }
+        return Err(e.into());
+    }
+    Ok(name)
+}
+
+/// The HNSW and IVFFlat indexes on the embeddings table, by name.
+pub async fn vector_indexes(pool: &sqlx::PgPool) -> Result<Vec<IndexStatus>, Box<dyn Error>> {
+    let rows: Vec<(String, String, Vec<String>, bool, i64, String)> = sqlx::query_as(
+        "SELECT c.relname::text, am.amname::text, coalesce(c.reloptions, '{}'), i.indisvalid, pg_relation_size(c.oid), pg_get_indexdef(c.oid) \
+         FROM pg_index i JOIN pg_class c ON c.oid = i.indexrelid JOIN pg_am am ON am.oid = c.relam \
+         WHERE i.indrelid = to_regclass($1) AND am.amname IN ('hnsw', 'ivfflat') \
+         ORDER BY c.relname;",
+    )
+    .bind(DEFAULT_TABLE)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(name, method, options, valid, size_bytes, definition)| IndexStatus { name, method, options, valid, size_bytes, definition })
+        .collect())
+}
+
+/// Rebuilds every vector index on the embeddings table with `REINDEX ... CONCURRENTLY`, keeping their
+/// parameters, and returns their names. This repairs an index a failed build left invalid, and lets an
+/// IVFFlat index learn its lists again from the rows the table holds now.
+pub async fn reindex_vector_indexes(pool: &sqlx::PgPool) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut rebuilt = Vec::new();
+    for index in vector_indexes(pool).await? {
+        tracing::info!(phase = "schema", index = %index.name, "Rebuilding {} index {}...", index.method, index.name);
+        // The name comes from the catalog; quote_ident keeps it a single identifier all the same
+        let (quoted,): (String,) = sqlx::query_as("SELECT quote_ident($1);").bind(&index.name).fetch_one(pool).await?;
+        sqlx::query(&format!("REINDEX INDEX CONCURRENTLY {};", quoted)).execute(pool).await?;
+        rebuilt.push(index.name);
+    }
+    Ok(rebuilt)
+}
+
+/// The planner's estimate of the rows in the embeddings table when it has no valid vector index, so every
+/// query scans them all, or `None` when it has one.
+///
+/// The estimate is `pg_class.reltuples`, which costs nothing where a `COUNT(*)` would scan the very table
+/// that lacks an index. It is refreshed by (auto)vacuum and `ANALYZE`, and is 0 before the first of them.
+pub async fn unindexed_rows(pool: &sqlx::PgPool) -> Result<Option<i64>, Box<dyn Error>> {
+    if vector_indexes(pool).await?.iter().any(|index| index.valid) {
+        return Ok(None);
+    }
+    let (rows,): (f32,) = sqlx::query_as("SELECT reltuples FROM pg_class WHERE oid = to_regclass($1);").bind(DEFAULT_TABLE).fetch_one(pool).await?;
+    // -1 means never analyzed
+    Ok(Some(rows.max(0.0) as i64))
+}
+
+/// Adds a pointer to `rag-system init` when `error` is Postgres reporting that the embeddings table
+/// doesn't exist, so a query against a fresh database says how to set it up. Other errors are returned
+/// unchanged.
+pub fn explain_missing_table(error: Box<dyn Error>) -> Box<dyn Error> {
+    let missing = match error.downcast_ref::<sqlx::Error>() {
+        // undefined_table
+        Some(sqlx::Error::Database(e)) => e.code().as_deref() == Some("42P01") && e.message().contains(&format!("\"{}\"", DEFAULT_TABLE)),
+        _ => false,
+    };
+    match missing {
+        true => format!("{}. Run `rag-system init` to create it.", error).into(),
+        false => error,
+    }
+}
+
+/// Whether `table` has the `text_search` column used by hybrid queries.
+pub async fn has_text_search(pool: &sqlx::PgPool, table: &str) -> Result<bool, Box<dyn Error>> {
+    let row: Option<(i32,)> = sqlx::query_as(
+        "SELECT 1 FROM pg_attribute WHERE attrelid = to_regclass($1) AND attname = 'text_search' AND NOT attisdropped;",
+    )
+    .bind(table)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+/// Adds the full-text `text_search` column and its GIN index if they're missing.
+///
+/// The column is generated from `text`, so Postgres keeps it current on every insert and update.
+pub async fn ensure_text_search(pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    if !has_text_search(pool, DEFAULT_TABLE).await? {
+        // The 'simple' configuration doesn't stem or drop stopwords, which suits code identifiers
+        sqlx::query(
+            "ALTER TABLE embeddings ADD COLUMN IF NOT EXISTS text_search TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;",
+        )
+        .execute(pool)
+        .await?;
+        tracing::info!(phase = "schema", "Added full-text search column embeddings.text_search.");
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS embeddings_text_search_idx ON embeddings USING GIN (text_search);")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Creates `ingest_runs`, where runs with `--tx-mode per-batch` record how far they got, if it's missing.
+pub async fn ensure_ingest_runs(pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS ingest_runs (\
+            id BIGSERIAL PRIMARY KEY, \
+            source TEXT NOT NULL, \
+            started_at TIMESTAMPTZ NOT NULL DEFAULT now(), \
+            total INTEGER NOT NULL, \
+            stored INTEGER NOT NULL DEFAULT 0, \
+            finished_at TIMESTAMPTZ);",
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Changes `embeddings.vector` to hold `dimension`-dim vectors, clearing every stored vector.
+///
+/// Used when re-embedding with a model of a different size; text and metadata are kept.
+pub async fn resize_vector_column(pool: &sqlx::PgPool, dimension: usize) -> Result<(), Box<dyn Error>> {
+    // Old vectors can't be cast to the new size, so they're replaced with NULL until re-embedded
+    sqlx::query(&format!(
+        "ALTER TABLE embeddings ALTER COLUMN vector TYPE VECTOR({}) USING NULL;",
+        dimension
+    ))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Fails with a descriptive error when vectors of `model_dimension` can't be stored in or compared against the table.
+pub async fn check_dimension(pool: &sqlx::PgPool, model_dimension: usize, create_if_missing: bool) -> Result<(), Box<dyn Error>> {
+    match column_dimension(pool, DEFAULT_TABLE).await? {
+        Some(Some(column_dimension)) if column_dimension != model_dimension => Err(format!(
+            "model produces {}-dim vectors but table embeddings.vector is vector({}). \
+             Either switch back to an embedding model that produces {}-dim vectors, or recreate the table \
+             with `vector VECTOR({})` (existing rows must be re-ingested).",
+            model_dimension, column_dimension, column_dimension, model_dimension
+        )
+        .into()),
+        Some(_) => Ok(()),
+        None if create_if_missing => {
+            create_table(pool, model_dimension).await?;
+            tracing::info!(phase = "schema", dimension = model_dimension, "Created table embeddings with vector({}).", model_dimension);
+            Ok(())
+        }
+        None => Err(format!(
+            "table embeddings does not exist. Run `rag-system init` to create it with vector({}) for this model, \
+             or rerun ingestion with --create-table-if-missing.",
+            model_dimension
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,199 @@
+/// A doc comment with the declaration it documents, as found in a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocBlock {
+    /// Name of the documented item, such as `retry` for `pub async fn retry(...)`, or `module` for a
+    /// file's own docs
+    pub symbol: String,
+    /// The comment lines followed by the declaration line, as written
+    pub text: String,
+    /// 1-based, inclusive line span of `text` in the file
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Whether [`extract_docs`] understands the doc comments of `language` (as the ingester names languages).
+pub fn supports(language: &str) -> bool {
+    matches!(language, "rust" | "python" | "go" | "javascript" | "typescript" | "java" | "c" | "cpp" | "c++")
+}
+
+/// The doc comments of a file and the declarations they document, in file order: `///` and `//!` in
+/// Rust, docstrings in Python, `//` blocks directly above a declaration in Go and `/** */` blocks in
+/// the C-like languages. Returns nothing for other languages. Comments are matched line by line, so
+/// doc comments inside string literals can be misread.
+pub fn extract_docs(language: &str, content: &str) -> Vec<DocBlock> {
+    let lines: Vec<&str> = content.lines().collect();
+    match language {
+        "rust" => comment_blocks(&lines, |line| (line.starts_with("///") && !line.starts_with("////")) || line.starts_with("//!"), |line| line.starts_with("#[") || line.starts_with("#![")),
+        "go" => comment_blocks(&lines, |line| line.starts_with("//"), |_| false),
+        "python" => docstrings(&lines),
+        _ if supports(language) => jsdoc_blocks(&lines),
+        _ => Vec::new(),
+    }
+}
+
+// Runs of comment lines followed, after any skipped lines such as attributes, by a declaration. Rust's
+// `//!` runs document the file itself.
+fn comment_blocks(lines: &[&str], is_doc: impl Fn(&str) -> bool, skip: impl Fn(&str) -> bool) -> Vec<DocBlock> {
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if !is_doc(lines[i].trim_start()) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < lines.len() && is_doc(lines[i].trim_start()) {
+            i += 1;
+        }
+        if lines[start].trim_start().starts_with("//!") {
+            blocks.push(block("module".to_string(), lines, start, i - 1));
+            continue;
+        }
+        let mut declaration = i;
+        while declaration < lines.len() && skip(lines[declaration].trim_start()) {
+            declaration += 1;
+        }
+        // A comment followed by a blank line or the end of the file documents nothing
+        if let Some(signature) = lines.get(declaration).filter(|line| !line.trim().is_empty()) {
+            blocks.push(block(symbol_name(signature), lines, start, declaration));
+            i = declaration + 1;
+        }
+    }
+    blocks
+}
+
+// `/** ... */` blocks and the declaration after them, skipping annotations and decorators
+fn jsdoc_blocks(lines: &[&str]) -> Vec<DocBlock> {
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if !lines[i].trim_start().starts_with("/**") {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < lines.len() && !lines[i].contains("*/") {
+            i += 1;
+        }
+        let mut declaration = i + 1;
+        while declaration < lines.len() && lines[declaration].trim_start().starts_with('@') {
+            declaration += 1;
+        }
+        if let Some(signature) = lines.get(declaration).filter(|line| !line.trim().is_empty()) {
+            blocks.push(block(symbol_name(signature), lines, start, declaration));
+            i = declaration;
+        }
+        i += 1;
+    }
+    blocks
+}
+
+// A module docstring, and each `def`/`class` whose body starts with a docstring
+fn docstrings(lines: &[&str]) -> Vec<DocBlock> {
+    let mut blocks = Vec::new();
+    let first = lines.iter().position(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+    if let Some(end) = first.and_then(|first| docstring_end(lines, first)) {
+        blocks.push(block("module".to_string(), lines, first.unwrap_or(0), end));
+    }
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        let trimmed = trimmed.strip_prefix("async ").unwrap_or(trimmed);
+        if !(trimmed.starts_with("def ") || trimmed.starts_with("class ")) {
+            continue;
+        }
+        // The signature can span lines; the body starts after the line ending it with a colon
+        let Some(signature_end) = (i..lines.len()).find(|&j| lines[j].trim_end().ends_with(':')) else { continue };
+        if let Some(end) = docstring_end(lines, signature_end + 1) {
+            blocks.push(block(symbol_name(line), lines, i, end));
+        }
+    }
+    blocks
+}
+
+// The last line of the docstring starting at line `start`, if one does
+fn docstring_end(lines: &[&str], start: usize) -> Option<usize> {
+    let line = lines.get(start)?.trim_start();
+    let line = line.strip_prefix(['r', 'R']).unwrap_or(line);
+    let quote = ["\"\"\"", "'''"].into_iter().find(|quote| line.starts_with(quote))?;
+    if line[3..].contains(quote) {
+        return Some(start);
+    }
+    (start + 1..lines.len()).find(|&i| lines[i].contains(quote))
+}
+
+fn block(symbol: String, lines: &[&str], start: usize, end: usize) -> DocBlock {
+    DocBlock { symbol, text: lines[start..=end].join("\n"), start_line: start + 1, end_line: end + 1 }
+}
+
+// The name a declaration line introduces: the identifier after its keyword (skipping a Go method's
+// receiver), otherwise the identifier before its first parenthesis, as in a Java or C method, or before
+// its type or value, as in a field
+fn symbol_name(signature: &str) -> String {
+    const KEYWORDS: [&str; 16] = ["fn", "struct", "enum", "trait", "type", "union", "mod", "const", "static", "impl", "class", "interface", "def", "func", "function", "macro_rules!"];
+    let is_identifier = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    let words = signature.split(|c: char| !(is_identifier(c) || c == '!' || c == '(')).filter(|word| !word.is_empty());
+    for word in words {
+        let keyword = word.trim_end_matches('(');
+        if !KEYWORDS.contains(&keyword) {
+            continue;
+        }
+        let rest = &signature[signature.find(keyword).map_or(0, |at| at + keyword.len())..];
+        // `func (s *Server) Handle(...)`
+        let rest = match rest.trim_start().strip_prefix('(') {
+            Some(receiver) if keyword == "func" => receiver.split_once(')').map_or("", |(_, rest)| rest),
+            _ => rest,
+        };
+        let rest = rest.trim_start();
+        let name: String = rest.chars().take_while(|&c| is_identifier(c)).collect();
+        // In Java and C, `static` and `const` are modifiers (`static Query parse(...)`) rather than the
+        // `static NAME: Type` or `const name = ...` that declares NAME
+        let declares = !matches!(keyword, "static" | "const") || rest[name.len()..].trim_start().starts_with([':', '=']);
+        if !name.is_empty() && declares {
+            return name;
+        }
+    }
+    let head = match signature.split_once('(') {
+        Some((head, _)) => head,
+        None => signature.split([':', '=']).next().unwrap_or(signature),
+    };
+    match head.rsplit(|c: char| !is_identifier(c)).find(|word| !word.is_empty()) {
+        Some(name) => name.to_string(),
+        None => signature.trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(blocks: &[DocBlock]) -> Vec<(&str, usize, usize)> {
+        blocks.iter().map(|block| (block.symbol.as_str(), block.start_line, block.end_line)).collect()
+    }
+
+    #[test]
+    fn rust_doc_comments_are_paired_with_the_item_after_their_attributes() {
+        let source = "//! Retrying transient failures.\n\nuse std::time::Duration;\n\n/// How long to wait.\n/// Doubles each time.\n#[derive(Debug)]\npub struct Backoff {\n    /// Upper bound\n    pub max: Duration,\n}\n\n// Not a doc comment\nfn helper() {}\n\n/// Retries `f`.\npub async fn retry<F>(f: F) {}\n";
+        let blocks = extract_docs("rust", source);
+        assert_eq!(summary(&blocks), [("module", 1, 1), ("Backoff", 5, 8), ("max", 9, 10), ("retry", 16, 17)]);
+        assert_eq!(blocks[1].text, "/// How long to wait.\n/// Doubles each time.\n#[derive(Debug)]\npub struct Backoff {");
+    }
+
+    #[test]
+    fn python_docstrings_follow_their_signature() {
+        let source = "\"\"\"Loads settings.\"\"\"\n\nclass Config:\n    '''Settings read from disk.\n\n    Cached after the first read.\n    '''\n\n    def load(self,\n             path):\n        \"\"\"Reads path.\"\"\"\n        return path\n\n    def undocumented(self):\n        return 1\n";
+        let blocks = extract_docs("python", source);
+        assert_eq!(summary(&blocks), [("module", 1, 1), ("Config", 3, 7), ("load", 9, 11)]);
+    }
+
+    #[test]
+    fn go_and_c_like_blocks_name_their_declarations() {
+        let go = "package server\n\n// Handle serves one request.\n// It never panics.\nfunc (s *Server) Handle(w Writer) {}\n\n// A stray comment.\n\nfunc other() {}\n";
+        assert_eq!(summary(&extract_docs("go", go)), [("Handle", 3, 5)]);
+
+        let java = "/**\n * Parses a query.\n */\n@Override\npublic static Query parse(String text) {\n}\n";
+        assert_eq!(summary(&extract_docs("java", java)), [("parse", 1, 5)]);
+        let typescript = "/** Opens a connection. */\nexport async function connect(url: string) {}\n";
+        assert_eq!(summary(&extract_docs("typescript", typescript)), [("connect", 1, 2)]);
+        assert!(extract_docs("markdown", "/** not code */\ntext").is_empty());
+    }
+}
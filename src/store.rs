@@ -0,0 +1,695 @@
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgArguments, PgConnection, PgExecutor, PgTypeInfo};
+use sqlx::query::QueryAs;
+use sqlx::{Connection, Postgres};
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::metric::Metric;
+use crate::schema;
+
+// Rows per upsert batch when importing
+const IMPORT_BATCH_SIZE: usize = 500;
+
+/// Significant digits written per vector component by default with [`VectorEncoding::Text`]. pgvector
+/// stores `float4`, which holds about 7 significant digits, so longer literals only make the SQL text bigger.
+pub const DEFAULT_VECTOR_PRECISION: usize = 6;
+
+/// How vectors are sent to Postgres as query parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorEncoding {
+    /// pgvector's binary format, through the `pgvector` crate: exact f32 components in 4 bytes each
+    Binary,
+    /// A `[1,2,3]` literal cast with `::vector`, rounded to `precision` significant digits (see
+    /// [`format_vector`]), for servers or poolers that reject binary vector parameters
+    Text { precision: usize },
+}
+
+impl VectorEncoding {
+    /// Binary when the server accepts a binary vector parameter, otherwise text with `precision`
+    /// significant digits. A database without the extension yet gets binary, since it is created with
+    /// the table.
+    pub async fn detect(pool: &sqlx::PgPool, precision: usize) -> VectorEncoding {
+        if let Ok(false) = sqlx::query_scalar::<_, bool>("SELECT to_regtype('vector') IS NOT NULL;").fetch_one(pool).await {
+            return VectorEncoding::Binary;
+        }
+        match sqlx::query("SELECT $1::vector;").bind(VectorEncoding::Binary.param(&[0.0])).execute(pool).await {
+            Ok(_) => VectorEncoding::Binary,
+            Err(e) => {
+                tracing::warn!(phase = "init", error = %e, "The server doesn't accept binary vectors ({}); sending them as text with {} significant digits.", e, precision);
+                VectorEncoding::Text { precision }
+            }
+        }
+    }
+
+    /// This encoding without rounding: text literals get full f32 precision.
+    pub fn exact(self) -> VectorEncoding {
+        match self {
+            VectorEncoding::Binary => VectorEncoding::Binary,
+            VectorEncoding::Text { .. } => VectorEncoding::Text { precision: 0 },
+        }
+    }
+
+    /// `vector` as a query parameter in this encoding; the SQL casts it with `::vector` either way.
+    pub fn param(self, vector: &[f32]) -> VectorParam {
+        match self {
+            VectorEncoding::Binary => VectorParam::Binary(pgvector::Vector::from(vector.to_vec())),
+            VectorEncoding::Text { precision } => VectorParam::Text(format_vector(vector, precision)),
+        }
+    }
+}
+
+/// A vector bound in its [`VectorEncoding`]. Text literals are sent as `text`, so they go through the
+/// `::vector` cast in the SQL rather than the `vector` type's binary input.
+#[derive(Debug, Clone)]
+pub enum VectorParam {
+    Binary(pgvector::Vector),
+    Text(String),
+}
+
+impl sqlx::Type<Postgres> for VectorParam {
+    fn type_info() -> PgTypeInfo {
+        <pgvector::Vector as sqlx::Type<Postgres>>::type_info()
+    }
+}
+
+impl sqlx::Encode<'_, Postgres> for VectorParam {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        match self {
+            VectorParam::Binary(vector) => <pgvector::Vector as sqlx::Encode<Postgres>>::encode_by_ref(vector, buf),
+            VectorParam::Text(literal) => <String as sqlx::Encode<Postgres>>::encode_by_ref(literal, buf),
+        }
+    }
+
+    fn produces(&self) -> Option<PgTypeInfo> {
+        match self {
+            VectorParam::Binary(_) => None,
+            VectorParam::Text(_) => Some(<String as sqlx::Type<Postgres>>::type_info()),
+        }
+    }
+}
+
+/// WHERE predicates shared by every retrieval query. `$4` is the language list, `$5` the path LIKE pattern,
+/// `$6` the maximum distance from the query vector (`$1`), `$7` whether to drop generated files, `$8` the
+/// source tag, `$9` the time documents must have been embedded after, `$10` whether rows without an
+/// `embedded_at` still pass that filter, `$11` an id to leave out, `$12` regexes of paths to leave out and
+/// `$13` the only ids to keep. `$14` to `$16` are the keys, comparisons and values of [`MetadataFilter`]s;
+/// a NULL (or false) parameter disables its filter. `{distance}` must be replaced with the distance
+/// expression for the metric in use. [`RetrievalFilters::bind`] binds them in order.
+pub const FILTER_PREDICATES: &str = "($4::text[] IS NULL OR metadata->>'language' = ANY($4)) \
+    AND ($5::text IS NULL OR metadata->>'path' LIKE $5) \
+    AND ($6::float8 IS NULL OR {distance} <= $6) \
+    AND (NOT $7::boolean OR metadata->>'generated' IS DISTINCT FROM 'true') \
+    AND ($8::text IS NULL OR metadata->>'source' = $8) \
+    AND ($9::timestamptz IS NULL OR (metadata->>'embedded_at')::timestamptz > $9 OR ($10::boolean AND metadata->>'embedded_at' IS NULL)) \
+    AND ($11::text IS NULL OR id <> $11) \
+    AND ($12::text[] IS NULL OR NOT COALESCE(metadata->>'path' ~ ANY($12), false)) \
+    AND ($13::text[] IS NULL OR id = ANY($13)) \
+    AND ($14::text[] IS NULL OR NOT EXISTS (SELECT 1 FROM unnest($14::text[], $15::text[], $16::text[]) AS f(key, op, value) \
+        WHERE CASE f.op \
+            WHEN 'exists' THEN NOT metadata ? f.key \
+            WHEN '!=' THEN COALESCE(metadata->>f.key = f.value OR metadata->f.key @> jsonb_build_array(f.value), false) \
+            ELSE NOT COALESCE(metadata->>f.key = f.value OR metadata->f.key @> jsonb_build_array(f.value), false) END))";
+
+/// Counts the rows of `table` that pass `filters`, whose `max_distance` must be unset since there is no
+/// query vector to measure it from.
+pub async fn count_rows(pool: &sqlx::PgPool, table: &str, filters: &RetrievalFilters) -> Result<i64, Box<dyn Error>> {
+    let sql = format!("SELECT COUNT(*) FROM {} WHERE {};", table, FILTER_PREDICATES.replace("{distance}", "NULL::float8"));
+    // $1 to $3 aren't referenced; they're bound with types so the filters keep their numbering
+    let query = sqlx::query_as(&sql).bind(None::<String>).bind(None::<i64>).bind(None::<i64>);
+    let (count,): (i64,) = filters.bind(query).fetch_one(pool).await?;
+    Ok(count)
+}
+
+/// Values for the parameters of [`FILTER_PREDICATES`]; the defaults filter nothing.
+#[derive(Debug, Clone, Default)]
+pub struct RetrievalFilters {
+    /// Lowercased languages to keep
+    pub languages: Option<Vec<String>>,
+    /// A LIKE pattern the path must match
+    pub path_pattern: Option<String>,
+    pub max_distance: Option<f64>,
+    pub exclude_generated: bool,
+    pub source: Option<String>,
+    /// Only keep rows embedded after this time
+    pub after: Option<DateTime<Utc>>,
+    /// Keep rows with no `embedded_at` (ingested before it was recorded) when filtering by `after`
+    pub include_undated: bool,
+    /// Leave out the row with this id, such as the document a search started from
+    pub exclude_id: Option<String>,
+    /// Leave out rows whose path matches any of these regexes (see [`glob_regex`])
+    pub exclude_paths: Option<Vec<String>>,
+    /// Only keep rows with these ids, such as the results of an earlier search
+    pub within_ids: Option<Vec<String>>,
+    /// Conditions on arbitrary metadata fields, all of which must hold
+    pub metadata: Option<Vec<MetadataFilter>>,
+}
+
+impl RetrievalFilters {
+    /// Binds `$4` to `$16`, for a query that has already bound its own `$1` to `$3`.
+    pub fn bind<'q, O>(&'q self, query: QueryAs<'q, Postgres, O, PgArguments>) -> QueryAs<'q, Postgres, O, PgArguments> {
+        query
+            .bind(&self.languages)
+            .bind(&self.path_pattern)
+            .bind(self.max_distance)
+            .bind(self.exclude_generated)
+            .bind(&self.source)
+            .bind(self.after)
+            .bind(self.include_undated)
+            .bind(&self.exclude_id)
+            .bind(&self.exclude_paths)
+            .bind(&self.within_ids)
+            .bind(self.metadata_column(|filter| filter.key.clone()))
+            .bind(self.metadata_column(|filter| filter.comparison.operator().to_string()))
+            .bind(self.metadata_column(|filter| filter.comparison.value().unwrap_or_default().to_string()))
+    }
+
+    // One field of every metadata filter, bound as a parallel array
+    fn metadata_column(&self, field: impl Fn(&MetadataFilter) -> String) -> Option<Vec<String>> {
+        self.metadata.as_ref().map(|filters| filters.iter().map(field).collect())
+    }
+}
+
+/// A condition on one metadata field, parsed from `key=value`, `key!=value` or a bare `key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataFilter {
+    pub key: String,
+    pub comparison: MetadataComparison,
+}
+
+/// How a [`MetadataFilter`] tests its field. A field matches a value when its text (as `->>` returns it)
+/// equals the value, or when it is an array with the value as one of its strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataComparison {
+    Equals(String),
+    /// Also true when the field is missing
+    NotEquals(String),
+    /// The field is present, whatever its value
+    Exists,
+}
+
+impl MetadataComparison {
+    fn operator(&self) -> &'static str {
+        match self {
+            MetadataComparison::Equals(_) => "=",
+            MetadataComparison::NotEquals(_) => "!=",
+            MetadataComparison::Exists => "exists",
+        }
+    }
+
+    fn value(&self) -> Option<&str> {
+        match self {
+            MetadataComparison::Equals(value) | MetadataComparison::NotEquals(value) => Some(value),
+            MetadataComparison::Exists => None,
+        }
+    }
+}
+
+impl std::str::FromStr for MetadataFilter {
+    type Err = String;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        // The first = (or the != it ends) separates the key, so the key never contains an operator
+        let (key, comparison) = match filter.find('=') {
+            Some(at) if filter[..at].ends_with('!') => (&filter[..at - 1], MetadataComparison::NotEquals(filter[at + 1..].to_string())),
+            Some(at) => (&filter[..at], MetadataComparison::Equals(filter[at + 1..].to_string())),
+            None => (filter, MetadataComparison::Exists),
+        };
+        if key.is_empty() {
+            return Err(format!("{:?} has no metadata key; use key=value, key!=value or key", filter));
+        }
+        // Both readings of k=a!=b are plausible, so neither is guessed
+        if let MetadataComparison::Equals(value) | MetadataComparison::NotEquals(value) = &comparison {
+            if value.contains("!=") {
+                return Err(format!("{:?} is ambiguous: its value {:?} contains another !=; use one operator per filter", filter, value));
+            }
+        }
+        Ok(MetadataFilter { key: key.to_string(), comparison })
+    }
+}
+
+impl std::fmt::Display for MetadataFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.comparison {
+            MetadataComparison::Equals(value) => write!(f, "{}={}", self.key, value),
+            MetadataComparison::NotEquals(value) => write!(f, "{}!={}", self.key, value),
+            MetadataComparison::Exists => write!(f, "{}", self.key),
+        }
+    }
+}
+
+/// Translates a path glob into a regex that both Postgres and the `regex` crate accept. `*` and `?`
+/// match within one path segment, `**` across segments, and `**/` any number of leading directories.
+/// Like a .gitignore entry, the glob may match starting at any directory, so `tests/**` also excludes
+/// `../tests/a.rs` and `*.snap` any snapshot file.
+pub fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("(^|/)");
+    let mut rest = glob.trim_start_matches('/');
+    while let Some(c) = rest.chars().next() {
+        let (translated, length) = match c {
+            '*' if rest.starts_with("**/") => ("(.*/)?".to_string(), 3),
+            '*' if rest.starts_with("**") => (".*".to_string(), 2),
+            '*' => ("[^/]*".to_string(), 1),
+            '?' => ("[^/]".to_string(), 1),
+            c => (regex::escape(&c.to_string()), c.len_utf8()),
+        };
+        regex.push_str(&translated);
+        rest = &rest[length..];
+    }
+    regex.push('$');
+    regex
+}
+
+/// SQL for the rows of `table` nearest to `$1` by `metric` that pass [`FILTER_PREDICATES`], limited to
+/// `$2` after skipping `$3`. Ties are broken by id so consecutive pages never overlap or skip rows.
+/// `table` must have passed [`schema::check_table_name`].
+///
+/// Every filter is always present and switched off by a NULL parameter, so the text only depends on
+/// `metric` and `table`: sqlx prepares it once per connection and every later search reuses it. Postgres
+/// keeps planning each execution for its parameters, since a generic plan can't drop the disabled
+/// filters and is estimated far costlier.
+pub fn nearest_sql(metric: Metric, table: &str) -> String {
+    let distance = format!("vector {} $1::vector", metric.operator());
+    format!(
+        r#"
+        SELECT id, text, {distance} AS distance, COALESCE(metadata, '{{}}'::jsonb) AS metadata
+        FROM {table}
+        WHERE {filters}
+        ORDER BY {distance}, id
+        LIMIT $2 OFFSET $3;
+        "#,
+        filters = FILTER_PREDICATES.replace("{distance}", &distance),
+    )
+}
+
+/// Starts a transaction for a search with the given index tuning settings. They are scoped to the
+/// transaction so they apply to exactly this search and never leak onto pooled connections; `None`
+/// leaves the server default untouched.
+pub async fn begin_search(pool: &sqlx::PgPool, ef_search: Option<u32>, probes: Option<u32>) -> Result<sqlx::Transaction<'static, Postgres>, Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    for (setting, value) in [("hnsw.ef_search", ef_search), ("ivfflat.probes", probes)] {
+        if let Some(value) = value {
+            sqlx::query("SELECT set_config($1, $2, true);")
+                .bind(setting)
+                .bind(value.to_string())
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    Ok(tx)
+}
+
+/// One row of the `embeddings` table, also the line format of export files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingRow {
+    pub id: String,
+    pub text: String,
+    pub vector: Vec<f32>,
+    pub metadata: serde_json::Value,
+}
+
+/// Hex SHA-256 of a document's content, used to detect unchanged files.
+pub fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+// Helper function to format a vector for SQL insertion, rounding each component to
+// `precision` significant digits (0 keeps the shortest representation that round-trips exactly)
+pub fn format_vector(vector: &[f32], precision: usize) -> String {
+    format!("[{}]", vector.iter().map(|&f| format_component(f, precision)).collect::<Vec<_>>().join(","))
+}
+
+fn format_component(value: f32, precision: usize) -> String {
+    if precision == 0 || value == 0.0 || !value.is_finite() {
+        return value.to_string();
+    }
+    let magnitude = value.abs().log10().floor() as i32;
+    // Very small components would need long runs of leading zeros in positional notation
+    if magnitude < -4 {
+        return format!("{:.*e}", precision - 1, value);
+    }
+    let decimals = (precision as i32 - 1 - magnitude).max(0) as usize;
+    let formatted = format!("{:.*}", decimals, value);
+    if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        formatted
+    }
+}
+
+/// Scales `vector` to unit length in place. Returns false (leaving it unchanged) for an all-zero vector,
+/// which has no direction to keep.
+pub fn normalize(vector: &mut [f32]) -> bool {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 || !norm.is_finite() {
+        return false;
+    }
+    vector.iter_mut().for_each(|x| *x /= norm);
+    true
+}
+
+// Inverse of format_vector, for pgvector's text output (`[1,2,3]`)
+pub fn parse_vector(text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+    let inner = text.trim().trim_start_matches('[').trim_end_matches(']');
+    if inner.is_empty() {
+        return Ok(Vec::new());
+    }
+    inner
+        .split(',')
+        .map(|component| component.trim().parse::<f32>().map_err(|e| format!("invalid vector component '{}': {}", component, e).into()))
+        .collect()
+}
+
+/// Rows written per INSERT statement by default.
+pub const DEFAULT_DB_BATCH_SIZE: usize = 500;
+
+/// Inserts or replaces rows by id, committing them all in a single transaction.
+///
+/// Vectors are sent in `encoding`, so with [`VectorEncoding::Text`] they are rounded to its precision.
+pub async fn upsert_rows(pool: &sqlx::PgPool, rows: &[EmbeddingRow], encoding: VectorEncoding) -> Result<Vec<String>, Box<dyn Error>> {
+    upsert_rows_into(pool, schema::DEFAULT_TABLE, rows, encoding, DEFAULT_DB_BATCH_SIZE).await
+}
+
+/// [`upsert_rows`] into `table`, which must have passed [`schema::check_table_name`], `batch_size` rows
+/// per statement. A batch the database rejects is retried a row at a time, so a bad row is logged and
+/// skipped without losing the rest; the ids of skipped rows are returned. When an id appears more than
+/// once, its last row wins.
+pub async fn upsert_rows_into(pool: &sqlx::PgPool, table: &str, rows: &[EmbeddingRow], encoding: VectorEncoding, batch_size: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    let skipped = write_rows(&mut tx, table, rows, encoding, batch_size, true).await?;
+    tx.commit().await?;
+    Ok(skipped)
+}
+
+/// [`upsert_rows_into`] inside the caller's transaction, so the rows commit together with whatever else
+/// it writes. Returns the ids of the rows that were skipped. Without `skip_failed`, a rejected batch is
+/// an error instead of being retried row by row, for callers that must store every row or none.
+pub async fn write_rows(tx: &mut PgConnection, table: &str, rows: &[EmbeddingRow], encoding: VectorEncoding, batch_size: usize, skip_failed: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let conflict = "ON CONFLICT (id) DO UPDATE SET text = EXCLUDED.text, vector = EXCLUDED.vector, metadata = EXCLUDED.metadata";
+    // Vectors arrive as vector[] in binary, or as text[] cast per element
+    let batch_sql = format!(
+        "INSERT INTO {table} (id, text, vector, metadata) \
+        SELECT id, text, vector::vector, metadata FROM UNNEST($1::text[], $2::text[], $3, $4::jsonb[]) AS r(id, text, vector, metadata) {conflict};"
+    );
+    let row_sql = format!("INSERT INTO {table} (id, text, vector, metadata) VALUES ($1, $2, $3::vector, $4) {conflict};");
+    let rows = last_per_id(rows);
+    let mut skipped = Vec::new();
+    for batch in rows.chunks(batch_size.max(1)) {
+        let ids: Vec<&str> = batch.iter().map(|row| row.id.as_str()).collect();
+        let texts: Vec<&str> = batch.iter().map(|row| row.text.as_str()).collect();
+        let metadata: Vec<&serde_json::Value> = batch.iter().map(|row| &row.metadata).collect();
+        let query = sqlx::query(&batch_sql).bind(ids).bind(texts);
+        let query = match encoding {
+            VectorEncoding::Binary => query.bind(batch.iter().map(|row| pgvector::Vector::from(row.vector.clone())).collect::<Vec<_>>()),
+            VectorEncoding::Text { precision } => query.bind(batch.iter().map(|row| format_vector(&row.vector, precision)).collect::<Vec<_>>()),
+        };
+        // Each attempt runs in a savepoint, so a failed one doesn't abort the transaction
+        let mut savepoint = tx.begin().await?;
+        let Err(e) = query.bind(metadata).execute(&mut *savepoint).await else {
+            savepoint.commit().await?;
+            continue;
+        };
+        savepoint.rollback().await?;
+        if !skip_failed {
+            return Err(e.into());
+        }
+        tracing::warn!(phase = "store", rows = batch.len(), error = %e, "Storing a batch of {} rows failed ({}); retrying them one at a time.", batch.len(), e);
+        for row in batch {
+            let mut savepoint = tx.begin().await?;
+            let result = sqlx::query(&row_sql)
+                .bind(&row.id)
+                .bind(&row.text)
+                .bind(encoding.param(&row.vector))
+                .bind(&row.metadata)
+                .execute(&mut *savepoint)
+                .await;
+            match result {
+                Ok(_) => savepoint.commit().await?,
+                Err(e) => {
+                    savepoint.rollback().await?;
+                    tracing::error!(phase = "store", path = %row.id, error = %e, "Failed to store {}: {}; skipping it", row.id, e);
+                    skipped.push(row.id.clone());
+                }
+            }
+        }
+    }
+    Ok(skipped)
+}
+
+// The last row for each id, in the order of those rows. One INSERT ... ON CONFLICT can't update a row
+// twice, so this keeps what inserting them one after another would have left.
+fn last_per_id(rows: &[EmbeddingRow]) -> Vec<&EmbeddingRow> {
+    let mut seen = HashSet::new();
+    let mut rows: Vec<&EmbeddingRow> = rows.iter().rev().filter(|row| seen.insert(row.id.as_str())).collect();
+    rows.reverse();
+    rows
+}
+
+/// Replaces the vector of existing rows and merges `metadata_patch` into their metadata, in one transaction.
+pub async fn update_vectors(pool: &sqlx::PgPool, rows: &[(String, Vec<f32>)], metadata_patch: &serde_json::Value, encoding: VectorEncoding) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    for (id, vector) in rows {
+        sqlx::query("UPDATE embeddings SET vector = $2::vector, metadata = COALESCE(metadata, '{}'::jsonb) || $3 WHERE id = $1;")
+            .bind(id)
+            .bind(encoding.param(vector))
+            .bind(metadata_patch)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Whether `table` holds no rows at all, to tell an unindexed project from a search that matched nothing.
+pub async fn is_empty(pool: &sqlx::PgPool, table: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(sqlx::query_scalar(&format!("SELECT NOT EXISTS (SELECT 1 FROM {});", table)).fetch_one(pool).await?)
+}
+
+/// Rows that can't be searched correctly, each with a description of its problem: no vector, a vector
+/// whose dimension isn't `dimension`, non-finite or all-zero components (their distances are NaN), or
+/// text that no longer matches the `content_hash` the vector was embedded from. With no `dimension` (an
+/// unconstrained column), vectors are compared with the most common dimension in the table.
+pub async fn invalid_rows(pool: &sqlx::PgPool, dimension: Option<usize>) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let rows = sqlx::query_as(
+        r#"
+        WITH expected AS (
+            SELECT COALESCE($1::int, (SELECT vector_dims(vector) FROM embeddings WHERE vector IS NOT NULL GROUP BY 1 ORDER BY count(*) DESC LIMIT 1)) AS dimension
+        )
+        SELECT id, problem FROM (
+            SELECT id, CASE
+                WHEN vector IS NULL THEN 'no vector'
+                WHEN vector_dims(vector) <> expected.dimension THEN format('%s dimensions instead of %s', vector_dims(vector), expected.dimension)
+                WHEN vector_norm(vector) IN ('NaN'::float8, 'Infinity'::float8) THEN 'non-finite components'
+                WHEN vector_norm(vector) = 0 THEN 'all components are zero'
+                WHEN metadata->>'content_hash' <> encode(sha256(convert_to(text, 'UTF8')), 'hex') THEN 'text does not match its content_hash'
+            END AS problem
+            FROM embeddings, expected
+        ) checked
+        WHERE problem IS NOT NULL
+        ORDER BY id;
+        "#,
+    )
+    .bind(dimension.map(|dimension| dimension as i32))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Deletes rows by id, along with the doc comment rows (`kind: doc`) of files with those ids, returning
+/// how many existed.
+pub async fn delete_rows<'e>(executor: impl PgExecutor<'e>, ids: &[String]) -> Result<u64, Box<dyn Error>> {
+    let result = sqlx::query("DELETE FROM embeddings WHERE id = ANY($1) OR (metadata->>'kind' = 'doc' AND metadata->>'path' = ANY($1));")
+        .bind(ids)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Deletes the doc comment rows (`kind: doc`) of the files at `paths`, so they can be stored afresh.
+pub async fn delete_doc_rows<'e>(executor: impl PgExecutor<'e>, paths: &[String]) -> Result<u64, Box<dyn Error>> {
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    let result = sqlx::query("DELETE FROM embeddings WHERE metadata->>'kind' = 'doc' AND metadata->>'path' = ANY($1);")
+        .bind(paths)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Records the start of an ingest run of `total` documents in `ingest_runs` (see
+/// [`schema::ensure_ingest_runs`]), returning its id.
+pub async fn start_run(pool: &sqlx::PgPool, source: &str, total: usize) -> Result<i64, Box<dyn Error>> {
+    let (id,): (i64,) = sqlx::query_as("INSERT INTO ingest_runs (source, total) VALUES ($1, $2) RETURNING id;")
+        .bind(source)
+        .bind(total as i32)
+        .fetch_one(pool)
+        .await?;
+    Ok(id)
+}
+
+/// Records that `stored` documents of run `run` are stored, inside the transaction that stores them.
+pub async fn record_progress(tx: &mut PgConnection, run: i64, stored: usize) -> Result<(), Box<dyn Error>> {
+    sqlx::query("UPDATE ingest_runs SET stored = $2 WHERE id = $1;").bind(run).bind(stored as i32).execute(tx).await?;
+    Ok(())
+}
+
+pub async fn finish_run(pool: &sqlx::PgPool, run: i64) -> Result<(), Box<dyn Error>> {
+    sqlx::query("UPDATE ingest_runs SET finished_at = now() WHERE id = $1;").bind(run).execute(pool).await?;
+    Ok(())
+}
+
+/// How far the latest run for `source` got, as (stored, total, started_at), if it never finished.
+pub async fn unfinished_run(pool: &sqlx::PgPool, source: &str) -> Result<Option<(i32, i32, DateTime<Utc>)>, Box<dyn Error>> {
+    let sql = "SELECT stored, total, started_at FROM (SELECT * FROM ingest_runs WHERE source = $1 ORDER BY id DESC LIMIT 1) latest WHERE finished_at IS NULL;";
+    Ok(sqlx::query_as(sql).bind(source).fetch_optional(pool).await?)
+}
+
+/// Streams every row to `path` as newline-delimited JSON, returning the row count.
+pub async fn export_rows(pool: &sqlx::PgPool, path: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut writer = BufWriter::new(tokio::fs::File::create(path).await?);
+    let mut rows = sqlx::query_as::<_, (String, String, String, Option<serde_json::Value>)>(
+        "SELECT id, text, vector::text, metadata FROM embeddings ORDER BY id;",
+    )
+    .fetch(pool);
+
+    let mut count = 0;
+    while let Some((id, text, vector, metadata)) = rows.try_next().await? {
+        let row = EmbeddingRow {
+            id,
+            text,
+            vector: parse_vector(&vector)?,
+            metadata: metadata.unwrap_or(serde_json::Value::Null),
+        };
+        let mut line = serde_json::to_vec(&row)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        count += 1;
+    }
+    writer.flush().await?;
+    Ok(count)
+}
+
+/// Loads an export file back into the table in batches, after checking every vector fits the column.
+/// Returns how many rows were imported and the ids of those the database rejected.
+///
+/// Vectors are restored exactly as exported, without rounding, whatever `encoding`'s precision.
+pub async fn import_rows(pool: &sqlx::PgPool, path: &Path, create_table_if_missing: bool, encoding: VectorEncoding) -> Result<(usize, Vec<String>), Box<dyn Error>> {
+    let encoding = encoding.exact();
+    let mut lines = BufReader::new(tokio::fs::File::open(path).await?).lines();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    let mut dimension = None;
+    let mut count = 0;
+    let mut skipped = Vec::new();
+    let mut line_number = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row: EmbeddingRow = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: invalid row: {}", path.display(), line_number, e))?;
+
+        match dimension {
+            None => {
+                // Validate against the table once, using the first row's dimension
+                schema::check_dimension(pool, row.vector.len(), create_table_if_missing).await?;
+                dimension = Some(row.vector.len());
+            }
+            Some(expected) if expected != row.vector.len() => {
+                return Err(format!(
+                    "{}:{}: row {} has a {}-dim vector but earlier rows are {}-dim",
+                    path.display(), line_number, row.id, row.vector.len(), expected
+                )
+                .into());
+            }
+            Some(_) => {}
+        }
+
+        batch.push(row);
+        if batch.len() == IMPORT_BATCH_SIZE {
+            skipped.extend(upsert_rows(pool, &batch, encoding).await?);
+            count += batch.len();
+            batch.clear();
+        }
+    }
+    skipped.extend(upsert_rows(pool, &batch, encoding).await?);
+    Ok((count + batch.len() - skipped.len(), skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn magnitude(vector: &[f32]) -> f32 {
+        vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+    }
+
+    #[test]
+    fn metadata_filters_parse_equality_inequality_and_existence() {
+        let parse = |filter: &str| filter.parse::<MetadataFilter>();
+        assert_eq!(parse("tags=auth").unwrap(), MetadataFilter { key: "tags".to_string(), comparison: MetadataComparison::Equals("auth".to_string()) });
+        assert_eq!(parse("owner!=ana").unwrap(), MetadataFilter { key: "owner".to_string(), comparison: MetadataComparison::NotEquals("ana".to_string()) });
+        assert_eq!(parse("title").unwrap(), MetadataFilter { key: "title".to_string(), comparison: MetadataComparison::Exists });
+        // Only the first separator splits, so values may contain = themselves
+        assert_eq!(parse("query=a=b").unwrap().comparison, MetadataComparison::Equals("a=b".to_string()));
+        assert_eq!(parse("empty=").unwrap().comparison, MetadataComparison::Equals(String::new()));
+        assert_eq!(parse("query!=a=b").unwrap(), MetadataFilter { key: "query".to_string(), comparison: MetadataComparison::NotEquals("a=b".to_string()) });
+        // The first operator splits, so a later != can't turn part of the value into the key
+        assert!(parse("k=a!=b").is_err());
+        assert!(parse("k!=a!=b").is_err());
+        assert!(parse("!=ana").is_err());
+        assert!(parse("=auth").is_err());
+        assert!(parse("").is_err());
+        for filter in ["tags=auth", "owner!=ana", "title"] {
+            assert_eq!(parse(filter).unwrap().to_string(), filter);
+        }
+    }
+
+    #[test]
+    fn a_repeated_id_keeps_its_last_row() {
+        let row = |id: &str, text: &str| EmbeddingRow { id: id.to_string(), text: text.to_string(), vector: vec![1.0], metadata: serde_json::json!({}) };
+        let rows = [row("a.rs", "first"), row("b.rs", "only"), row("a.rs", "second"), row("c.rs", "only")];
+        let kept: Vec<(&str, &str)> = last_per_id(&rows).iter().map(|row| (row.id.as_str(), row.text.as_str())).collect();
+        assert_eq!(kept, [("b.rs", "only"), ("a.rs", "second"), ("c.rs", "only")]);
+    }
+
+    #[test]
+    fn normalized_vectors_have_unit_length() {
+        for mut vector in [vec![3.0, 4.0], vec![0.001, -0.002, 0.0005], vec![-120.0, 5.5, 33.0, 0.25]] {
+            assert!(normalize(&mut vector));
+            assert!((magnitude(&vector) - 1.0).abs() < 1e-6, "magnitude {}", magnitude(&vector));
+        }
+    }
+
+    #[test]
+    fn globs_match_from_any_directory() {
+        let matches = |glob: &str, path: &str| regex::Regex::new(&glob_regex(glob)).unwrap().is_match(path);
+        assert!(matches("tests/**", "../tests/fixtures/a.rs"));
+        assert!(matches("tests/**", "tests/a.rs"));
+        assert!(!matches("tests/**", "../contests/a.rs"));
+        assert!(matches("*.snap", "../src/snapshots/query.snap"));
+        assert!(!matches("*.snap", "../src/query.snap.rs"));
+        assert!(matches("src/*.rs", "../src/main.rs"));
+        assert!(!matches("src/*.rs", "../src/bin/query.rs"));
+        assert!(matches("src/**/*.rs", "../src/bin/query.rs"));
+        assert!(matches("src/**/*.rs", "../src/main.rs"));
+        assert!(matches("fixture_?.json", "data/fixture_1.json"));
+        assert!(matches("a+b (1).txt", "docs/a+b (1).txt"));
+    }
+
+    #[test]
+    fn zero_vectors_are_left_unchanged() {
+        let mut vector = vec![0.0; 4];
+        assert!(!normalize(&mut vector));
+        assert_eq!(vector, vec![0.0; 4]);
+    }
+}
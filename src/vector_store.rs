@@ -0,0 +1,467 @@
+use async_trait::async_trait;
+use chrono::DateTime;
+use regex::RegexSet;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::RwLock;
+
+use crate::metric::Metric;
+use crate::schema;
+use crate::store::{self, EmbeddingRow, MetadataComparison, MetadataFilter, RetrievalFilters, VectorEncoding};
+
+/// A document returned by [`VectorStore::query`].
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Hit {
+    pub id: String,
+    pub text: String,
+    /// Distance from the query vector under the store's metric; smaller is closer
+    pub distance: f64,
+    pub metadata: Value,
+}
+
+/// Where embedded documents are kept and searched.
+///
+/// The ingester stores and the query binary searches through this trait, so the pipeline doesn't depend
+/// on Postgres: [`PgVectorStore`] is the production backend and [`InMemoryStore`] needs no server at all.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Inserts rows, replacing any already stored under the same id. Returns the ids of rows that were
+    /// rejected on their own and skipped, which callers must not count as stored.
+    async fn upsert(&self, rows: &[EmbeddingRow]) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// The documents nearest to `vector` that pass `filters`, skipping the first `offset` and returning
+    /// at most `limit`. Ties in distance are broken by id, so consecutive pages never overlap.
+    async fn query(&self, vector: &[f32], limit: usize, offset: usize, filters: &RetrievalFilters) -> Result<Vec<Hit>, Box<dyn Error>>;
+
+    /// The stored vectors of `ids`, for callers that compare documents with each other (like MMR).
+    /// Ids that aren't stored are left out.
+    async fn vectors(&self, ids: &[String]) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>>;
+}
+
+/// A Postgres table (`embeddings` by default), searched with pgvector's distance operators.
+pub struct PgVectorStore {
+    pool: sqlx::PgPool,
+    table: String,
+    metric: Metric,
+    // store::nearest_sql for the table, built once so every search sends the same statement
+    nearest_sql: String,
+    encoding: VectorEncoding,
+    batch_size: usize,
+    ef_search: Option<u32>,
+    probes: Option<u32>,
+}
+
+impl PgVectorStore {
+    /// Sends vectors in `encoding` (see [`VectorEncoding::detect`]) and searches by `metric`.
+    pub fn new(pool: sqlx::PgPool, metric: Metric, encoding: VectorEncoding) -> Self {
+        let nearest_sql = store::nearest_sql(metric, schema::DEFAULT_TABLE);
+        Self { pool, table: schema::DEFAULT_TABLE.to_string(), metric, nearest_sql, encoding, batch_size: store::DEFAULT_DB_BATCH_SIZE, ef_search: None, probes: None }
+    }
+
+    /// Uses `table` instead of `embeddings`. The name is interpolated into SQL, so it must have passed
+    /// [`schema::check_table_name`].
+    pub fn with_table(self, table: &str) -> Self {
+        Self { table: table.to_string(), nearest_sql: store::nearest_sql(self.metric, table), ..self }
+    }
+
+    /// Writes `batch_size` rows per INSERT statement instead of [`store::DEFAULT_DB_BATCH_SIZE`].
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self { batch_size, ..self }
+    }
+
+    /// Sets `hnsw.ef_search` and `ivfflat.probes` for each query; `None` keeps the server default.
+    pub fn with_index_settings(self, ef_search: Option<u32>, probes: Option<u32>) -> Self {
+        Self { ef_search, probes, ..self }
+    }
+}
+
+#[async_trait]
+impl VectorStore for PgVectorStore {
+    async fn upsert(&self, rows: &[EmbeddingRow]) -> Result<Vec<String>, Box<dyn Error>> {
+        store::upsert_rows_into(&self.pool, &self.table, rows, self.encoding, self.batch_size).await
+    }
+
+    async fn query(&self, vector: &[f32], limit: usize, offset: usize, filters: &RetrievalFilters) -> Result<Vec<Hit>, Box<dyn Error>> {
+        let mut tx = store::begin_search(&self.pool, self.ef_search, self.probes).await?;
+        let query = sqlx::query_as(&self.nearest_sql)
+            .bind(self.encoding.param(vector))
+            .bind(limit as i64)
+            .bind(offset as i64);
+        let hits = filters.bind(query).fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(hits)
+    }
+
+    async fn vectors(&self, ids: &[String]) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>> {
+        let sql = format!("SELECT id, vector::text FROM {} WHERE id = ANY($1) AND vector IS NOT NULL;", self.table);
+        let rows: Vec<(String, String)> = sqlx::query_as(&sql)
+            .bind(ids)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter().map(|(id, vector)| Ok((id, store::parse_vector(&vector)?))).collect()
+    }
+}
+
+/// A store that lives in process memory, for tests and for embedding the crate without a database.
+/// Searches are exact (every row is compared) and apply filters the way [`store::FILTER_PREDICATES`] does.
+pub struct InMemoryStore {
+    metric: Metric,
+    rows: RwLock<BTreeMap<String, EmbeddingRow>>,
+}
+
+impl InMemoryStore {
+    pub fn new(metric: Metric) -> Self {
+        Self { metric, rows: RwLock::new(BTreeMap::new()) }
+    }
+}
+
+#[async_trait]
+impl VectorStore for InMemoryStore {
+    async fn upsert(&self, rows: &[EmbeddingRow]) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut stored = self.rows.write().map_err(|_| "in-memory store lock poisoned")?;
+        for row in rows {
+            stored.insert(row.id.clone(), row.clone());
+        }
+        Ok(Vec::new())
+    }
+
+    async fn query(&self, vector: &[f32], limit: usize, offset: usize, filters: &RetrievalFilters) -> Result<Vec<Hit>, Box<dyn Error>> {
+        let exclude_paths = filters.exclude_paths.as_deref().map(RegexSet::new).transpose()?;
+        let stored = self.rows.read().map_err(|_| "in-memory store lock poisoned")?;
+        let mut hits = Vec::new();
+        // Rows iterate in id order and the sort is stable, so equal distances stay ordered by id
+        for row in stored.values() {
+            if row.vector.len() != vector.len() {
+                return Err(format!("document {} has {} dimensions, but the query vector has {}", row.id, row.vector.len(), vector.len()).into());
+            }
+            let distance = self.metric.distance(vector, &row.vector);
+            let excluded = exclude_paths.as_ref().is_some_and(|exclude_paths| metadata_text(&row.metadata, "path").is_some_and(|path| exclude_paths.is_match(&path)));
+            if !excluded && passes(filters, &row.id, &row.metadata, distance) {
+                hits.push(Hit { id: row.id.clone(), text: row.text.clone(), distance, metadata: row.metadata.clone() });
+            }
+        }
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(hits.into_iter().skip(offset).take(limit).collect())
+    }
+
+    async fn vectors(&self, ids: &[String]) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>> {
+        let stored = self.rows.read().map_err(|_| "in-memory store lock poisoned")?;
+        Ok(ids.iter().filter_map(|id| stored.get(id).map(|row| (id.clone(), row.vector.clone()))).collect())
+    }
+}
+
+/// Several named stores searched as one, such as the tables of different projects. Ids are only unique
+/// within a store, so every hit's id is namespaced as `store:id` (`project_a:src/lib.rs`), and its
+/// metadata gets a `table` key naming the store. The hits of all stores are merged by distance.
+/// Read-only: upserts must go to one of the stores.
+pub struct MergedStore {
+    stores: Vec<(String, Box<dyn VectorStore>)>,
+}
+
+impl MergedStore {
+    pub fn new(stores: Vec<(String, Box<dyn VectorStore>)>) -> Self {
+        Self { stores }
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.stores.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+// The id a [`MergedStore`] id has in the store `name`: a `store:id` belongs to its own store only, while
+// a bare id (one not starting with a store's name) is looked for in every store
+fn local_id<'a>(names: &[&str], name: &str, id: &'a str) -> Option<&'a str> {
+    match id.split_once(':') {
+        Some((store, local)) if names.contains(&store) => (store == name).then_some(local),
+        _ => Some(id),
+    }
+}
+
+/// `filters` as they apply to the store `name` of a [`MergedStore`] merging `names`: `--exclude-id` and
+/// `--within-ids` ids namespaced for that store lose their prefix, those of other stores are dropped, and
+/// bare ids are kept.
+pub fn store_filters(names: &[&str], name: &str, filters: &RetrievalFilters) -> RetrievalFilters {
+    let local = |id: &String| local_id(names, name, id).map(str::to_string);
+    RetrievalFilters {
+        exclude_id: filters.exclude_id.as_ref().and_then(local),
+        within_ids: filters.within_ids.as_ref().map(|ids| ids.iter().filter_map(local).collect()),
+        ..filters.clone()
+    }
+}
+
+#[async_trait]
+impl VectorStore for MergedStore {
+    async fn upsert(&self, _rows: &[EmbeddingRow]) -> Result<Vec<String>, Box<dyn Error>> {
+        Err("a merged store is read-only; upsert into one of the stores it merges".into())
+    }
+
+    async fn query(&self, vector: &[f32], limit: usize, offset: usize, filters: &RetrievalFilters) -> Result<Vec<Hit>, Box<dyn Error>> {
+        let names = self.names();
+        let mut hits = Vec::new();
+        for (name, store) in &self.stores {
+            // Any one store could hold the whole merged page, so each is searched up to the page's end
+            for mut hit in store.query(vector, limit + offset, 0, &store_filters(&names, name, filters)).await? {
+                if let Value::Object(metadata) = &mut hit.metadata {
+                    metadata.insert("table".to_string(), Value::String(name.clone()));
+                }
+                hit.id = format!("{}:{}", name, hit.id);
+                hits.push(hit);
+            }
+        }
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance).then_with(|| a.id.cmp(&b.id)));
+        Ok(hits.into_iter().skip(offset).take(limit).collect())
+    }
+
+    // Keyed by the ids asked for. A bare id shared by several stores gets the first store's vector.
+    async fn vectors(&self, ids: &[String]) -> Result<HashMap<String, Vec<f32>>, Box<dyn Error>> {
+        let names = self.names();
+        let mut vectors = HashMap::new();
+        for (name, store) in self.stores.iter().rev() {
+            let mut asked: HashMap<&str, Vec<&String>> = HashMap::new();
+            for id in ids {
+                if let Some(local) = local_id(&names, name, id) {
+                    asked.entry(local).or_default().push(id);
+                }
+            }
+            let locals: Vec<String> = asked.keys().map(|local| local.to_string()).collect();
+            for (local, vector) in store.vectors(&locals).await? {
+                for &id in &asked[local.as_str()] {
+                    vectors.insert(id.clone(), vector.clone());
+                }
+            }
+        }
+        Ok(vectors)
+    }
+}
+
+// The checks of store::FILTER_PREDICATES, for one row at `distance` from the query, except the path
+// exclusions, whose regexes are compiled once per query
+fn passes(filters: &RetrievalFilters, id: &str, metadata: &Value, distance: f64) -> bool {
+    if filters.exclude_id.as_deref() == Some(id) {
+        return false;
+    }
+    if filters.within_ids.as_ref().is_some_and(|ids| !ids.iter().any(|within| within == id)) {
+        return false;
+    }
+    if let Some(metadata_filters) = &filters.metadata {
+        let matches = |key: &str, value: &str| match metadata.get(key) {
+            Some(Value::Array(items)) => items.iter().any(|item| item.as_str() == Some(value)),
+            _ => metadata_text(metadata, key).as_deref() == Some(value),
+        };
+        let passes = |filter: &MetadataFilter| match &filter.comparison {
+            MetadataComparison::Equals(value) => matches(&filter.key, value),
+            MetadataComparison::NotEquals(value) => !matches(&filter.key, value),
+            MetadataComparison::Exists => metadata.get(&filter.key).is_some(),
+        };
+        if !metadata_filters.iter().all(passes) {
+            return false;
+        }
+    }
+    if let Some(languages) = &filters.languages {
+        if !metadata_text(metadata, "language").is_some_and(|language| languages.contains(&language)) {
+            return false;
+        }
+    }
+    if let Some(pattern) = &filters.path_pattern {
+        if !metadata_text(metadata, "path").is_some_and(|path| like(pattern, &path)) {
+            return false;
+        }
+    }
+    // Postgres sorts NaN above every number, so an undefined distance is never within range
+    if filters.max_distance.is_some_and(|max_distance| distance > max_distance || distance.is_nan()) {
+        return false;
+    }
+    if filters.exclude_generated && metadata_text(metadata, "generated").as_deref() == Some("true") {
+        return false;
+    }
+    if filters.source.is_some() && metadata_text(metadata, "source") != filters.source {
+        return false;
+    }
+    if let Some(after) = filters.after {
+        return match metadata_text(metadata, "embedded_at") {
+            Some(embedded_at) => DateTime::parse_from_rfc3339(&embedded_at).is_ok_and(|embedded_at| embedded_at > after),
+            None => filters.include_undated,
+        };
+    }
+    true
+}
+
+// A metadata field as Postgres' ->> returns it: strings unquoted, other values as JSON, null as None
+fn metadata_text(metadata: &Value, key: &str) -> Option<String> {
+    match metadata.get(key)? {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+// SQL LIKE: `%` matches any run of characters, `_` any one, and a backslash escapes the next character
+fn like(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['%', rest @ ..] => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+            ['_', rest @ ..] => !text.is_empty() && matches(rest, &text[1..]),
+            ['\\', escaped, rest @ ..] | [escaped, rest @ ..] => text.first() == Some(escaped) && matches(rest, &text[1..]),
+        }
+    }
+    matches(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn row(id: &str, language: &str, vector: [f32; 2]) -> EmbeddingRow {
+        EmbeddingRow { id: id.to_string(), text: id.to_string(), vector: vector.to_vec(), metadata: json!({ "path": id, "language": language }) }
+    }
+
+    async fn ids(store: &dyn VectorStore, limit: usize, offset: usize, filters: &RetrievalFilters) -> Vec<String> {
+        store.query(&[1.0, 0.0], limit, offset, filters).await.unwrap().into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[tokio::test]
+    async fn in_memory_queries_are_nearest_first_and_filtered() {
+        let store = InMemoryStore::new(Metric::Cosine);
+        store
+            .upsert(&[row("src/b.rs", "rust", [1.0, 0.0]), row("src/a.rs", "rust", [2.0, 0.0]), row("docs/c.md", "markdown", [1.0, 1.0]), row("src/d.py", "python", [0.0, 1.0])])
+            .await
+            .unwrap();
+        let everything = RetrievalFilters::default();
+
+        // a and b are equally close, so id breaks the tie and pages don't overlap
+        assert_eq!(ids(&store, 10, 0, &everything).await, ["src/a.rs", "src/b.rs", "docs/c.md", "src/d.py"]);
+        assert_eq!(ids(&store, 2, 1, &everything).await, ["src/b.rs", "docs/c.md"]);
+
+        let rust = RetrievalFilters { languages: Some(vec!["rust".to_string()]), ..Default::default() };
+        assert_eq!(ids(&store, 10, 0, &rust).await, ["src/a.rs", "src/b.rs"]);
+        let under_src = RetrievalFilters { path_pattern: Some("src/%".to_string()), max_distance: Some(0.5), ..Default::default() };
+        assert_eq!(ids(&store, 10, 0, &under_src).await, ["src/a.rs", "src/b.rs"]);
+        let like_a = RetrievalFilters { exclude_id: Some("src/a.rs".to_string()), ..Default::default() };
+        assert_eq!(ids(&store, 10, 0, &like_a).await, ["src/b.rs", "docs/c.md", "src/d.py"]);
+        let not_python_or_docs = RetrievalFilters { exclude_paths: Some(vec![store::glob_regex("*.py"), store::glob_regex("docs/**")]), ..Default::default() };
+        assert_eq!(ids(&store, 2, 0, &not_python_or_docs).await, ["src/a.rs", "src/b.rs"]);
+        let refined = RetrievalFilters { within_ids: Some(vec!["src/d.py".to_string(), "src/b.rs".to_string()]), ..Default::default() };
+        assert_eq!(ids(&store, 10, 0, &refined).await, ["src/b.rs", "src/d.py"]);
+        let python = RetrievalFilters { metadata: Some(vec!["language=python".parse().unwrap()]), ..Default::default() };
+        assert_eq!(ids(&store, 10, 0, &python).await, ["src/d.py"]);
+    }
+
+    #[tokio::test]
+    async fn metadata_filters_match_values_and_array_members() {
+        let store = InMemoryStore::new(Metric::L2);
+        let tagged = |id: &str, metadata: Value| EmbeddingRow { id: id.to_string(), text: String::new(), vector: vec![1.0, 0.0], metadata };
+        store
+            .upsert(&[
+                tagged("a.md", json!({ "owner": "ana", "tags": ["auth", "api"], "reviewed": true })),
+                tagged("b.md", json!({ "owner": "bo", "tags": ["api"] })),
+                tagged("c.md", json!({ "tags": "auth" })),
+            ])
+            .await
+            .unwrap();
+        let filter = |filters: &[&str]| RetrievalFilters { metadata: Some(filters.iter().map(|filter| filter.parse().unwrap()).collect()), ..Default::default() };
+
+        assert_eq!(ids(&store, 10, 0, &filter(&["tags=auth"])).await, ["a.md", "c.md"]);
+        assert_eq!(ids(&store, 10, 0, &filter(&["tags=api", "owner=bo"])).await, ["b.md"]);
+        // Non-string values compare as their JSON text, as with Postgres' ->>
+        assert_eq!(ids(&store, 10, 0, &filter(&["reviewed=true"])).await, ["a.md"]);
+        assert!(ids(&store, 10, 0, &filter(&["missing=x"])).await.is_empty());
+        // Inequality keeps rows without the field; existence only needs the key
+        assert_eq!(ids(&store, 10, 0, &filter(&["owner!=ana"])).await, ["b.md", "c.md"]);
+        assert_eq!(ids(&store, 10, 0, &filter(&["tags!=auth"])).await, ["b.md"]);
+        assert_eq!(ids(&store, 10, 0, &filter(&["owner"])).await, ["a.md", "b.md"]);
+    }
+
+    #[tokio::test]
+    async fn in_memory_upsert_replaces_by_id() {
+        let store = InMemoryStore::new(Metric::L2);
+        store.upsert(&[row("a", "rust", [0.0, 1.0])]).await.unwrap();
+        store.upsert(&[row("a", "rust", [1.0, 0.0])]).await.unwrap();
+
+        let hits = store.query(&[1.0, 0.0], 10, 0, &RetrievalFilters::default()).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].distance, 0.0);
+        assert_eq!(store.vectors(&["a".to_string(), "missing".to_string()]).await.unwrap(), HashMap::from([("a".to_string(), vec![1.0, 0.0])]));
+    }
+
+    #[tokio::test]
+    async fn merged_stores_interleave_by_distance_and_tag_their_table() {
+        let (project_a, project_b) = (InMemoryStore::new(Metric::L2), InMemoryStore::new(Metric::L2));
+        project_a.upsert(&[row("src/lib.rs", "rust", [1.0, 0.0]), row("src/far.rs", "rust", [-1.0, 0.0])]).await.unwrap();
+        project_b.upsert(&[row("src/lib.rs", "rust", [0.9, 0.1]), row("src/near.rs", "rust", [1.0, 0.05])]).await.unwrap();
+        let merged = MergedStore::new(vec![("project_a".to_string(), Box::new(project_a)), ("project_b".to_string(), Box::new(project_b))]);
+
+        let hits = merged.query(&[1.0, 0.0], 10, 0, &RetrievalFilters::default()).await.unwrap();
+        let tagged: Vec<(&str, &str)> = hits.iter().map(|hit| (hit.metadata["table"].as_str().unwrap(), hit.id.as_str())).collect();
+        assert_eq!(tagged, [("project_a", "project_a:src/lib.rs"), ("project_b", "project_b:src/near.rs"), ("project_b", "project_b:src/lib.rs"), ("project_a", "project_a:src/far.rs")]);
+        assert_eq!(ids(&merged, 2, 1, &RetrievalFilters::default()).await, ["project_b:src/near.rs", "project_b:src/lib.rs"]);
+        assert!(merged.upsert(&[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_id_in_two_merged_stores_names_one_row() {
+        let (project_a, project_b) = (InMemoryStore::new(Metric::L2), InMemoryStore::new(Metric::L2));
+        project_a.upsert(&[row("src/lib.rs", "rust", [1.0, 0.0]), row("README.md", "markdown", [0.5, 0.5])]).await.unwrap();
+        project_b.upsert(&[row("src/lib.rs", "rust", [0.0, 1.0])]).await.unwrap();
+        let merged = MergedStore::new(vec![("project_a".to_string(), Box::new(project_a)), ("project_b".to_string(), Box::new(project_b))]);
+
+        let wanted = ["project_a:src/lib.rs".to_string(), "project_b:src/lib.rs".to_string(), "src/lib.rs".to_string()];
+        let vectors = merged.vectors(&wanted).await.unwrap();
+        assert_eq!((&vectors[&wanted[0]], &vectors[&wanted[1]]), (&vec![1.0, 0.0], &vec![0.0, 1.0]));
+        // A bare id still finds the first store's row
+        assert_eq!(vectors[&wanted[2]], vec![1.0, 0.0]);
+
+        let not_a = RetrievalFilters { exclude_id: Some("project_a:src/lib.rs".to_string()), ..Default::default() };
+        assert_eq!(ids(&merged, 10, 0, &not_a).await, ["project_a:README.md", "project_b:src/lib.rs"]);
+        let only_b = RetrievalFilters { within_ids: Some(vec!["project_b:src/lib.rs".to_string()]), ..Default::default() };
+        assert_eq!(ids(&merged, 10, 0, &only_b).await, ["project_b:src/lib.rs"]);
+    }
+
+    #[tokio::test]
+    async fn the_default_text_precision_keeps_the_retrieval_order() {
+        use crate::embedder::{Embedder, MockEmbedder};
+        use crate::store::{format_vector, DEFAULT_VECTOR_PRECISION};
+
+        // What pgvector parses from a --text-vectors literal
+        let rounded = |vector: &[f32]| -> Vec<f32> {
+            format_vector(vector, DEFAULT_VECTOR_PRECISION).trim_matches(['[', ']']).split(',').map(|component| component.parse().unwrap()).collect()
+        };
+        let texts: Vec<String> = (0..200).map(|i| format!("document {}", i)).collect();
+        let mut vectors = MockEmbedder::new(256).embed(&texts).await.unwrap();
+        for vector in &mut vectors {
+            store::normalize(vector);
+        }
+        let (exact, text) = (InMemoryStore::new(Metric::Cosine), InMemoryStore::new(Metric::Cosine));
+        let rows = |round: bool| -> Vec<EmbeddingRow> {
+            texts
+                .iter()
+                .zip(&vectors)
+                .map(|(id, vector)| EmbeddingRow { id: id.clone(), text: id.clone(), vector: if round { rounded(vector) } else { vector.clone() }, metadata: json!({}) })
+                .collect()
+        };
+        exact.upsert(&rows(false)).await.unwrap();
+        text.upsert(&rows(true)).await.unwrap();
+
+        for query in &vectors[..20] {
+            let everything = RetrievalFilters::default();
+            let exact_hits = exact.query(query, 200, 0, &everything).await.unwrap();
+            let text_hits = text.query(&rounded(query), 200, 0, &everything).await.unwrap();
+            let order = |hits: &[Hit]| hits.iter().map(|hit| hit.id.clone()).collect::<Vec<_>>();
+            assert_eq!(order(&exact_hits), order(&text_hits));
+            for (exact_hit, text_hit) in exact_hits.iter().zip(&text_hits) {
+                assert!((exact_hit.distance - text_hit.distance).abs() < 1e-5, "{} moved from {} to {}", exact_hit.id, exact_hit.distance, text_hit.distance);
+            }
+        }
+    }
+
+    #[test]
+    fn like_matches_wildcards_and_escapes() {
+        assert!(like("src/%", "src/main.rs"));
+        assert!(like("src/_ain.rs", "src/main.rs"));
+        assert!(!like("src/%", "tests/src/main.rs"));
+        assert!(like("100\\%%", "100%.md"));
+        assert!(!like("100\\%%", "1000.md"));
+    }
+}
@@ -0,0 +1,356 @@
+//! Tests against a real Postgres server with the pgvector extension. They run when TEST_DATABASE_URL is
+//! set and are skipped otherwise. Each test works in its own schema, so existing tables are never touched.
+
+use rag_system::metric::Metric;
+use rag_system::schema::{self, IndexMethod, VectorIndex};
+use rag_system::store::{self, EmbeddingRow, RetrievalFilters, VectorEncoding};
+use rag_system::vector_store::{PgVectorStore, VectorStore};
+use serde_json::json;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Executor, PgPool};
+
+// A pool whose connections resolve `embeddings` to a fresh table in `schema_name`, or None without a database
+async fn test_pool(schema_name: &str) -> Option<PgPool> {
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set; skipping");
+        return None;
+    };
+    let setup = PgPoolOptions::new().max_connections(1).connect(&url).await.unwrap();
+    setup.execute("CREATE EXTENSION IF NOT EXISTS vector;").await.unwrap();
+    setup
+        .execute(format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};", schema_name).as_str())
+        .await
+        .unwrap();
+    let search_path = format!("SET search_path TO {}, public;", schema_name);
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .after_connect(move |conn, _| {
+            let search_path = search_path.clone();
+            Box::pin(async move { conn.execute(search_path.as_str()).await.map(|_| ()) })
+        })
+        .connect(&url)
+        .await
+        .unwrap();
+    schema::check_dimension(&pool, 3, true).await.unwrap();
+    Some(pool)
+}
+
+async fn drop_schema(pool: PgPool, schema_name: &str) {
+    pool.execute(format!("DROP SCHEMA {} CASCADE;", schema_name).as_str()).await.unwrap();
+}
+
+fn row(id: &str, source: &str, vector: [f32; 3]) -> EmbeddingRow {
+    EmbeddingRow {
+        id: id.to_string(),
+        text: format!("contents of {}", id),
+        vector: vector.to_vec(),
+        metadata: json!({ "path": id, "language": "text", "source": source }),
+    }
+}
+
+// Ids nearest to `vector` by cosine distance, restricted by `filters` the way the query binary filters
+async fn search(pool: &PgPool, vector: [f32; 3], filters: &RetrievalFilters) -> Vec<String> {
+    let vector_store = PgVectorStore::new(pool.clone(), Metric::Cosine, VectorEncoding::Binary);
+    vector_store.query(&vector, 10, 0, filters).await.unwrap().into_iter().map(|hit| hit.id).collect()
+}
+
+fn source(source: Option<&str>) -> RetrievalFilters {
+    RetrievalFilters { source: source.map(str::to_string), ..Default::default() }
+}
+
+#[tokio::test]
+async fn sources_do_not_bleed_into_each_other() {
+    let Some(pool) = test_pool("turborag_test_sources").await else {
+        return;
+    };
+    // The closest document to the query is in docs, so a leaking filter would put it first
+    let rows = [
+        row("docs/retries.md", "docs", [1.0, 0.0, 0.0]),
+        row("src/retry.rs", "codebase", [0.8, 0.2, 0.0]),
+        row("src/store.rs", "codebase", [0.0, 1.0, 0.0]),
+        row("tickets/42", "tickets", [0.9, 0.1, 0.0]),
+    ];
+    store::upsert_rows(&pool, &rows, VectorEncoding::Binary).await.unwrap();
+    let query = [1.0, 0.0, 0.0];
+
+    assert_eq!(search(&pool, query, &source(Some("codebase"))).await, ["src/retry.rs", "src/store.rs"]);
+    assert_eq!(search(&pool, query, &source(Some("docs"))).await, ["docs/retries.md"]);
+    assert_eq!(search(&pool, query, &source(Some("wiki"))).await, Vec::<String>::new());
+    assert_eq!(search(&pool, query, &source(None)).await, ["docs/retries.md", "tickets/42", "src/retry.rs", "src/store.rs"]);
+
+    drop_schema(pool, "turborag_test_sources").await;
+}
+
+#[tokio::test]
+async fn after_keeps_recent_rows_and_optionally_undated_ones() {
+    let Some(pool) = test_pool("turborag_test_after").await else {
+        return;
+    };
+    let mut old = row("src/old.rs", "codebase", [1.0, 0.0, 0.0]);
+    old.metadata["embedded_at"] = json!("2026-01-01T00:00:00Z");
+    let mut new = row("src/new.rs", "codebase", [0.9, 0.1, 0.0]);
+    new.metadata["embedded_at"] = json!("2026-10-01T09:30:00+02:00");
+    let undated = row("src/undated.rs", "codebase", [0.8, 0.2, 0.0]);
+    store::upsert_rows(&pool, &[old, new, undated], VectorEncoding::Binary).await.unwrap();
+    let query = [1.0, 0.0, 0.0];
+    let after = "2026-06-01T00:00:00Z".parse().unwrap();
+
+    let recent = RetrievalFilters { after: Some(after), ..Default::default() };
+    assert_eq!(search(&pool, query, &recent).await, ["src/new.rs"]);
+    let with_undated = RetrievalFilters { include_undated: true, ..recent };
+    assert_eq!(search(&pool, query, &with_undated).await, ["src/new.rs", "src/undated.rs"]);
+    // Without --after, --include-undated has nothing to relax
+    let everything = RetrievalFilters { include_undated: true, ..Default::default() };
+    assert_eq!(search(&pool, query, &everything).await, ["src/old.rs", "src/new.rs", "src/undated.rs"]);
+
+    drop_schema(pool, "turborag_test_after").await;
+}
+
+#[tokio::test]
+async fn index_settings_apply_inside_the_search_transaction_only() {
+    let Some(pool) = test_pool("turborag_test_index_settings").await else {
+        return;
+    };
+    let settings = "SELECT current_setting('hnsw.ef_search', true), current_setting('ivfflat.probes', true);";
+
+    // The settings are visible to statements on the search transaction's connection...
+    let mut tx = store::begin_search(&pool, Some(123), Some(7)).await.unwrap();
+    let inside: (Option<String>, Option<String>) = sqlx::query_as(settings).fetch_one(&mut *tx).await.unwrap();
+    assert_eq!(inside, (Some("123".to_string()), Some("7".to_string())));
+    tx.commit().await.unwrap();
+
+    // ...and gone once it commits, on every pooled connection including the one it ran on
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let mut connection = pool.acquire().await.unwrap();
+        let after: (Option<String>, Option<String>) = sqlx::query_as(settings).fetch_one(&mut *connection).await.unwrap();
+        assert_ne!(after.0.as_deref(), Some("123"));
+        assert_ne!(after.1.as_deref(), Some("7"));
+        connections.push(connection);
+    }
+    drop(connections);
+
+    // pgvector accepts the values it validates when the store applies them before searching
+    store::upsert_rows(&pool, &[row("a", "codebase", [1.0, 0.0, 0.0]), row("b", "codebase", [0.0, 1.0, 0.0])], VectorEncoding::Binary).await.unwrap();
+    let tuned = PgVectorStore::new(pool.clone(), Metric::Cosine, VectorEncoding::Binary).with_index_settings(Some(100), Some(10));
+    let hits = tuned.query(&[1.0, 0.0, 0.0], 10, 0, &RetrievalFilters::default()).await.unwrap();
+    assert_eq!(hits.into_iter().map(|hit| hit.id).collect::<Vec<_>>(), ["a", "b"]);
+
+    drop_schema(pool, "turborag_test_index_settings").await;
+}
+
+#[tokio::test]
+async fn invalid_rows_finds_vectors_that_cannot_be_searched() {
+    let Some(pool) = test_pool("turborag_test_invalid_rows").await else {
+        return;
+    };
+    let mut hashed = row("hashed.rs", "codebase", [1.0, 0.0, 0.0]);
+    hashed.metadata["content_hash"] = json!(store::content_hash(&hashed.text));
+    let mut edited = row("edited.rs", "codebase", [0.0, 1.0, 0.0]);
+    edited.metadata["content_hash"] = json!(store::content_hash("what was embedded"));
+    let rows = [hashed, edited, row("zero.rs", "codebase", [0.0, 0.0, 0.0]), row("missing.rs", "codebase", [0.0, 0.0, 1.0])];
+    store::upsert_rows(&pool, &rows, VectorEncoding::Binary).await.unwrap();
+    pool.execute("UPDATE embeddings SET vector = NULL WHERE id = 'missing.rs';").await.unwrap();
+
+    let invalid = store::invalid_rows(&pool, Some(3)).await.unwrap();
+    let invalid: Vec<(&str, &str)> = invalid.iter().map(|(id, problem)| (id.as_str(), problem.as_str())).collect();
+    assert_eq!(
+        invalid,
+        [("edited.rs", "text does not match its content_hash"), ("missing.rs", "no vector"), ("zero.rs", "all components are zero")]
+    );
+    drop_schema(pool, "turborag_test_invalid_rows").await;
+}
+
+#[tokio::test]
+async fn batched_upserts_keep_the_last_row_for_each_id() {
+    let Some(pool) = test_pool("turborag_test_batched_upserts").await else {
+        return;
+    };
+    store::upsert_rows(&pool, &[row("a.rs", "codebase", [1.0, 0.0, 0.0])], VectorEncoding::Binary).await.unwrap();
+    // a.rs is already stored and repeated within the batch, b.rs is repeated across two batches of 2
+    let mut rows = vec![row("a.rs", "codebase", [0.0, 1.0, 0.0]), row("b.rs", "codebase", [1.0, 0.0, 0.0]), row("a.rs", "docs", [0.0, 0.0, 1.0])];
+    rows.push(row("b.rs", "docs", [0.0, 1.0, 0.0]));
+    for encoding in [VectorEncoding::Binary, VectorEncoding::Text { precision: 0 }] {
+        store::upsert_rows_into(&pool, "embeddings", &rows, encoding, 2).await.unwrap();
+        let stored: Vec<(String, String, String)> = sqlx::query_as("SELECT id, metadata->>'source', vector::text FROM embeddings ORDER BY id;").fetch_all(&pool).await.unwrap();
+        assert_eq!(stored, [("a.rs".to_string(), "docs".to_string(), "[0,0,1]".to_string()), ("b.rs".to_string(), "docs".to_string(), "[0,1,0]".to_string())]);
+    }
+
+    // A row the column rejects is skipped, reported, and not stored; the rest of its batch is
+    let wrong_size = EmbeddingRow { vector: vec![1.0, 0.0], ..row("c.rs", "codebase", [0.0; 3]) };
+    let skipped = store::upsert_rows_into(&pool, "embeddings", &[row("d.rs", "codebase", [1.0, 0.0, 0.0]), wrong_size], VectorEncoding::Binary, 10).await.unwrap();
+    assert_eq!(skipped, ["c.rs"]);
+    let ids: Vec<String> = sqlx::query_scalar("SELECT id FROM embeddings ORDER BY id;").fetch_all(&pool).await.unwrap();
+    assert_eq!(ids, ["a.rs", "b.rs", "d.rs"]);
+    drop_schema(pool, "turborag_test_batched_upserts").await;
+}
+
+#[tokio::test]
+async fn run_writes_commit_or_roll_back_together() {
+    let Some(pool) = test_pool("turborag_test_run_transactions").await else {
+        return;
+    };
+    store::upsert_rows(&pool, &[row("deleted.rs", "codebase", [1.0, 0.0, 0.0])], VectorEncoding::Binary).await.unwrap();
+    let ids = || async { sqlx::query_scalar::<_, String>("SELECT id FROM embeddings ORDER BY id;").fetch_all(&pool).await.unwrap() };
+
+    // A per-run transaction that never commits leaves the table as it was
+    let mut tx = pool.begin().await.unwrap();
+    store::delete_rows(&mut *tx, &["deleted.rs".to_string()]).await.unwrap();
+    store::write_rows(&mut tx, "embeddings", &[row("new.rs", "codebase", [0.0, 1.0, 0.0])], VectorEncoding::Binary, 10, false).await.unwrap();
+    drop(tx);
+    assert_eq!(ids().await, ["deleted.rs"]);
+    // ... and a row the column rejects fails it rather than being skipped
+    let mut tx = pool.begin().await.unwrap();
+    let wrong_size = EmbeddingRow { vector: vec![1.0, 0.0], ..row("bad.rs", "codebase", [0.0; 3]) };
+    assert!(store::write_rows(&mut tx, "embeddings", &[row("new.rs", "codebase", [0.0, 1.0, 0.0]), wrong_size], VectorEncoding::Binary, 10, false).await.is_err());
+    drop(tx);
+
+    // Per-batch progress is recorded with the rows, and reported until the run finishes
+    schema::ensure_ingest_runs(&pool).await.unwrap();
+    let run = store::start_run(&pool, "codebase", 2).await.unwrap();
+    let mut tx = pool.begin().await.unwrap();
+    store::write_rows(&mut tx, "embeddings", &[row("new.rs", "codebase", [0.0, 1.0, 0.0])], VectorEncoding::Binary, 10, true).await.unwrap();
+    store::record_progress(&mut tx, run, 1).await.unwrap();
+    tx.commit().await.unwrap();
+    let (stored, total, _) = store::unfinished_run(&pool, "codebase").await.unwrap().unwrap();
+    assert_eq!((stored, total), (1, 2));
+    assert_eq!(ids().await, ["deleted.rs", "new.rs"]);
+    store::finish_run(&pool, run).await.unwrap();
+    assert!(store::unfinished_run(&pool, "codebase").await.unwrap().is_none());
+    drop_schema(pool, "turborag_test_run_transactions").await;
+}
+
+#[tokio::test]
+async fn init_creates_what_is_missing_and_skips_the_rest() {
+    let Some(pool) = test_pool("turborag_test_init").await else {
+        return;
+    };
+    pool.execute("DROP TABLE embeddings;").await.unwrap();
+    let hnsw = Some(VectorIndex::Hnsw { m: 8, ef_construction: 32 });
+    let created = schema::init(&pool, 3, hnsw, Metric::Cosine).await.unwrap();
+    assert!(created[1].starts_with("Created table embeddings with vector(3)"), "{:?}", created);
+    assert!(created[2].starts_with("Created hnsw index"), "{:?}", created);
+
+    let again = schema::init(&pool, 3, Some(VectorIndex::defaults(IndexMethod::Ivfflat)), Metric::Cosine).await.unwrap();
+    assert!(again.iter().all(|step| step.starts_with("Skipped")), "{:?}", again);
+    assert!(schema::init(&pool, 4, None, Metric::Cosine).await.is_err());
+
+    // The parameters survive a rebuild
+    let indexes = schema::vector_indexes(&pool).await.unwrap();
+    assert_eq!((indexes.len(), indexes[0].options.clone(), indexes[0].valid), (1, vec!["m=8".to_string(), "ef_construction=32".to_string()], true));
+    assert_eq!(schema::reindex_vector_indexes(&pool).await.unwrap(), [indexes[0].name.clone()]);
+    let rebuilt = schema::vector_indexes(&pool).await.unwrap();
+    assert_eq!((&rebuilt[0].options, &rebuilt[0].definition), (&indexes[0].options, &indexes[0].definition));
+    assert_eq!(schema::unindexed_rows(&pool).await.unwrap(), None);
+
+    // An invalid index isn't built over again, and doesn't count as indexing the table
+    pool.execute("UPDATE pg_index SET indisvalid = false WHERE indexrelid = to_regclass('embeddings_vector_hnsw_idx');").await.unwrap();
+    let invalid = schema::init(&pool, 3, hnsw, Metric::Cosine).await.unwrap();
+    assert!(invalid.last().unwrap().contains("`rag-system index reindex`"), "{:?}", invalid);
+    assert_eq!(schema::vector_indexes(&pool).await.unwrap().len(), 1);
+    pool.execute("ANALYZE embeddings;").await.unwrap();
+    assert_eq!(schema::unindexed_rows(&pool).await.unwrap(), Some(0));
+    drop_schema(pool, "turborag_test_init").await;
+}
+
+#[tokio::test]
+async fn vectors_round_trip_bit_exact_in_both_encodings() {
+    let Some(pool) = test_pool("turborag_test_vector_encoding").await else {
+        return;
+    };
+    // Components that rounding to a few significant digits would change
+    let vector = [std::f32::consts::PI, 1.0 / 3.0, f32::MIN];
+    assert_eq!(VectorEncoding::detect(&pool, 6).await, VectorEncoding::Binary);
+    for encoding in [VectorEncoding::Binary, VectorEncoding::Text { precision: 0 }] {
+        store::upsert_rows(&pool, &[row("pi.rs", "codebase", vector)], encoding).await.unwrap();
+        let (stored,): (pgvector::Vector,) = sqlx::query_as("SELECT vector FROM embeddings WHERE id = 'pi.rs';").fetch_one(&pool).await.unwrap();
+        let bits = |components: &[f32]| components.iter().map(|component| component.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(stored.as_slice()), bits(&vector), "{:?}", encoding);
+    }
+    drop_schema(pool, "turborag_test_vector_encoding").await;
+}
+
+// Serves the OpenAI embeddings API on a free local port, embedding every input as `vector`, and returns
+// the base URL to pass as --embed-url
+async fn fake_embedding_server(vector: [f32; 3]) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            // Read the headers, then as much body as they announce
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let body_start = loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length: usize = headers.lines().find_map(|line| line.strip_prefix("content-length:")).unwrap().trim().parse().unwrap();
+            while request.len() < body_start + length {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let body: serde_json::Value = serde_json::from_slice(&request[body_start..]).unwrap();
+            let inputs = body["input"].as_array().unwrap().len();
+            let data: Vec<_> = (0..inputs).map(|index| json!({ "index": index, "embedding": vector })).collect();
+            let response = json!({ "data": data }).to_string();
+            let reply = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", response.len(), response);
+            socket.write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn query_output_keeps_results_on_stdout_and_progress_on_stderr() {
+    let schema_name = "turborag_test_cli_streams";
+    let Some(pool) = test_pool(schema_name).await else {
+        return;
+    };
+    let rows = [row("src/a.rs", "codebase", [1.0, 0.0, 0.0]), row("src/b.rs", "codebase", [0.8, 0.6, 0.0]), row("src/c.rs", "codebase", [0.0, 1.0, 0.0])];
+    store::upsert_rows(&pool, &rows, VectorEncoding::Binary).await.unwrap();
+    let embed_url = fake_embedding_server([1.0, 0.0, 0.0]).await;
+    // The binary connects on its own, so the schema goes into its connection options
+    let url = std::env::var("TEST_DATABASE_URL").unwrap();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let database_url = format!("{}{}options=-c%20search_path%3D{}", url, separator, schema_name);
+
+    let run = |extra: &'static [&'static str]| {
+        let (database_url, embed_url) = (database_url.clone(), embed_url.clone());
+        async move {
+            let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_query"))
+                .args(["--query", "retries", "--no-rerank", "--embedder", "openai", "--embed-url", &embed_url])
+                .args(extra)
+                .env("DATABASE_URL", database_url)
+                .env("NO_COLOR", "1")
+                .output()
+                .await
+                .unwrap();
+            let (stdout, stderr) = (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap());
+            assert!(output.status.success(), "query failed: {}", stderr);
+            (stdout, stderr)
+        }
+    };
+
+    // Human output: results on stdout, progress and the summary on stderr
+    let (stdout, stderr) = run(&[]).await;
+    assert!(stdout.contains("--- Top 3 Vector Results ---") && stdout.contains("ID: src/a.rs"), "{}", stdout);
+    assert!(!stdout.contains("Generating embedding") && !stdout.contains("Summary:"), "{}", stdout);
+    assert!(stderr.contains("Generating embedding for query...") && stderr.contains("Summary:"), "{}", stderr);
+
+    // JSON output is one document on stdout
+    let (stdout, _) = run(&["--format", "json"]).await;
+    let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(parsed["results"][0]["id"], "src/a.rs");
+
+    // --quiet prints the ids alone and no progress
+    let (stdout, stderr) = run(&["--quiet"]).await;
+    assert_eq!(stdout, "src/a.rs\nsrc/b.rs\nsrc/c.rs\n");
+    assert!(!stderr.contains("Generating embedding") && !stderr.contains("Summary:"), "{}", stderr);
+
+    drop_schema(pool, schema_name).await;
+}
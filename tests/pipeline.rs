@@ -0,0 +1,86 @@
+//! Store and search tests on the mock embedder and the in-memory store, so they need neither Ollama nor
+//! Postgres. The ingester's embed-and-store path and the query binary's reranking are tested against the
+//! same backends in `src/main.rs` and `src/bin/query.rs`.
+
+use rag_system::embedder::{Embedder, MockEmbedder};
+use rag_system::metric::Metric;
+use rag_system::store::{self, EmbeddingRow, RetrievalFilters};
+use rag_system::vector_store::{Hit, InMemoryStore, VectorStore};
+use serde_json::json;
+
+const DIMENSION: usize = 16;
+
+const DOCUMENTS: [(&str, &str, &str); 4] = [
+    ("src/retry.rs", "rust", "fn with_retry retries transient failures with exponential backoff"),
+    ("src/store.rs", "rust", "fn upsert_rows inserts or replaces rows in a single transaction"),
+    ("docs/retries.md", "markdown", "Retries back off exponentially and give up after --max-attempts"),
+    ("scripts/load.py", "python", "def load(path): read a CSV file into the embeddings table"),
+];
+
+// Embeds (path, language, text) documents and writes them straight into the store
+async fn store_documents(embedder: &dyn Embedder, vector_store: &dyn VectorStore, documents: &[(&str, &str, &str)]) {
+    let texts: Vec<String> = documents.iter().map(|(_, _, text)| text.to_string()).collect();
+    let vectors = embedder.embed(&texts).await.unwrap();
+    let rows: Vec<EmbeddingRow> = documents
+        .iter()
+        .zip(vectors)
+        .map(|((path, language, text), mut vector)| {
+            store::normalize(&mut vector);
+            EmbeddingRow { id: path.to_string(), text: text.to_string(), vector, metadata: json!({ "path": path, "language": language, "source": "codebase" }) }
+        })
+        .collect();
+    vector_store.upsert(&rows).await.unwrap();
+}
+
+async fn search(embedder: &dyn Embedder, vector_store: &dyn VectorStore, query: &str, filters: &RetrievalFilters) -> Vec<Hit> {
+    let mut vector = embedder.embed(&[query.to_string()]).await.unwrap().remove(0);
+    store::normalize(&mut vector);
+    vector_store.query(&vector, 10, 0, filters).await.unwrap()
+}
+
+#[tokio::test]
+async fn upsert_then_query_returns_the_inserted_document() {
+    let (embedder, vector_store) = (MockEmbedder::new(DIMENSION), InMemoryStore::new(Metric::Cosine));
+    store_documents(&embedder, &vector_store, &DOCUMENTS).await;
+
+    // The mock embeds equal texts to equal vectors, so a document's own text finds it first
+    for (path, _, text) in DOCUMENTS {
+        let hits = search(&embedder, &vector_store, text, &RetrievalFilters::default()).await;
+        assert_eq!(hits.len(), DOCUMENTS.len());
+        assert_eq!(hits[0].id, path);
+        assert!(hits[0].distance.abs() < 1e-6, "{} is {} from its own text", path, hits[0].distance);
+    }
+
+    // Re-ingesting a path replaces its document rather than adding another
+    let edited = "fn upsert_rows now batches rows with UNNEST";
+    store_documents(&embedder, &vector_store, &[("src/store.rs", "rust", edited)]).await;
+    let hits = search(&embedder, &vector_store, edited, &RetrievalFilters::default()).await;
+    assert_eq!(hits.len(), DOCUMENTS.len());
+    assert_eq!((hits[0].id.as_str(), hits[0].text.as_str()), ("src/store.rs", edited));
+}
+
+#[tokio::test]
+async fn metadata_filters_narrow_the_results() {
+    let (embedder, vector_store) = (MockEmbedder::new(DIMENSION), InMemoryStore::new(Metric::Cosine));
+    store_documents(&embedder, &vector_store, &DOCUMENTS).await;
+    let query = "how are retries handled";
+    let search = |filters: RetrievalFilters| {
+        let (embedder, vector_store) = (&embedder, &vector_store);
+        async move {
+            let mut found: Vec<String> = search(embedder, vector_store, query, &filters).await.into_iter().map(|hit| hit.id).collect();
+            found.sort();
+            found
+        }
+    };
+
+    let rust = RetrievalFilters { languages: Some(vec!["rust".to_string()]), ..Default::default() };
+    assert_eq!(search(rust).await, ["src/retry.rs", "src/store.rs"]);
+    let under_src = RetrievalFilters { path_pattern: Some("src/%".to_string()), ..Default::default() };
+    assert_eq!(search(under_src).await, ["src/retry.rs", "src/store.rs"]);
+    let markdown_or_python = RetrievalFilters { languages: Some(vec!["markdown".to_string(), "python".to_string()]), ..Default::default() };
+    assert_eq!(search(markdown_or_python).await, ["docs/retries.md", "scripts/load.py"]);
+    let other_source = RetrievalFilters { source: Some("wiki".to_string()), ..Default::default() };
+    assert_eq!(search(other_source).await, Vec::<String>::new());
+    let nothing_close = RetrievalFilters { max_distance: Some(-1.0), ..Default::default() };
+    assert_eq!(search(nothing_close).await, Vec::<String>::new());
+}